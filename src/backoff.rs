@@ -0,0 +1,65 @@
+use log::warn;
+use std::time::Duration;
+use tokio::time::Instant;
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Errors further apart than this aren't considered to be in a row.
+const ERROR_WINDOW: Duration = Duration::from_secs(10);
+
+/// Slows down a loop that keeps hitting errors, so that a persistent failure doesn't spin.
+pub struct ErrorBackoff {
+	tolerated_errors: u32,
+	consecutive_errors: u32,
+	last_error: Option<Instant>,
+}
+
+impl ErrorBackoff {
+	/// Creates a backoff that only starts waiting once more than tolerated_errors errors happen in a row.
+	pub fn new(tolerated_errors: u32) -> Self {
+		Self {
+			tolerated_errors,
+			consecutive_errors: 0,
+			last_error: None,
+		}
+	}
+	
+	/// Records an error, waiting before returning if errors are coming in too quickly.
+	pub async fn on_error(&mut self) {
+		if self.last_error.is_some_and(|last_error| last_error.elapsed() > ERROR_WINDOW) {
+			self.consecutive_errors = 0;
+		}
+		
+		self.consecutive_errors += 1;
+		
+		if self.consecutive_errors > self.tolerated_errors {
+			let delay = backoff_delay(self.consecutive_errors - self.tolerated_errors);
+			
+			warn!("{} errors in a row, backing off for {}ms", self.consecutive_errors, delay.as_millis());
+			
+			tokio::time::sleep(delay).await;
+		}
+		
+		// Set after sleeping so that the wait doesn't count towards the window
+		self.last_error = Some(Instant::now());
+	}
+}
+
+/// How long to wait after the nth error past the tolerated ones, doubling each time up to MAX_DELAY.
+fn backoff_delay(excess_errors: u32) -> Duration {
+	INITIAL_DELAY.saturating_mul(1 << (excess_errors - 1).min(16)).min(MAX_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn delay_doubles_up_to_max() {
+		assert_eq!(backoff_delay(1), INITIAL_DELAY);
+		assert_eq!(backoff_delay(2), INITIAL_DELAY * 2);
+		assert_eq!(backoff_delay(3), INITIAL_DELAY * 4);
+		assert_eq!(backoff_delay(100), MAX_DELAY);
+	}
+}
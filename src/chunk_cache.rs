@@ -1,17 +1,29 @@
-use crate::dedup::ChunkKey;
+use crate::content_hash::{HashAlgorithm, CONTENT_HASH};
+use crate::dedup::{ChunkKey, FactorioWorldDescription};
 use crate::utils;
 use bytes::Bytes;
 use hashlink::LinkedHashMap;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufWriter, Read, Write};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::mem;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::sync::Semaphore;
+use std::time::{Duration, Instant};
+use tokio::select;
+use tokio::sync::{mpsc, Semaphore};
 
 pub struct ChunkCache {
 	inner: Mutex<ChunkCacheInner>,
+	/// Chunks from read-only cache files, which are checked after the cache itself. Nothing is ever evicted from or
+	///  added to these, and they aren't saved, so new chunks only go into the cache.
+	cold_chunks: HashMap<ChunkKey, Bytes>,
+	flush_sender: Mutex<Option<mpsc::Sender<()>>>,
+	/// A copy of the raw cache's, so that new chunks can be measured before taking the lock.
+	limit_basis: CacheLimitBasis,
 }
 
 struct ChunkCacheInner {
@@ -21,19 +33,34 @@ struct ChunkCacheInner {
 }
 
 impl ChunkCache {
-	pub fn new(max_size: u64) -> Self {
+	pub fn new(max_size: u64, limit_basis: CacheLimitBasis) -> Self {
 		Self {
 			inner: Mutex::new(ChunkCacheInner {
-				raw_cache: RawChunkCache::new(max_size),
+				raw_cache: RawChunkCache::new(max_size, limit_basis),
 				pending_chunks: HashMap::new(),
 				needs_saving: false,
 			}),
+			cold_chunks: HashMap::new(),
+			flush_sender: Mutex::new(None),
+			limit_basis,
 		}
 	}
 	
-	pub async fn load_from_file(max_size: u64, cache_path: PathBuf) -> anyhow::Result<Self> {
+	pub async fn load_from_file(max_size: u64, limit_basis: CacheLimitBasis, cache_path: PathBuf) -> anyhow::Result<Self> {
 		let raw_cache = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
-			let mut raw_cache = RawChunkCache::new(max_size);
+			let mut raw_cache = RawChunkCache::new(max_size, limit_basis);
+			
+			// Saves only replace the cache file once they're complete, so a leftover temp file is from an interrupted
+			//  save and the cache file itself is still good
+			let temp_path = cache_path.with_extension("tmp");
+			
+			if temp_path.exists() {
+				warn!("Removing {} left over from an interrupted cache save", temp_path.display());
+				
+				if let Err(err) = std::fs::remove_file(&temp_path) {
+					warn!("Failed to remove {}: {}", temp_path.display(), err);
+				}
+			}
 			
 			if cache_path.exists() {
 				read_chunk_cache(&mut raw_cache, &cache_path)?;
@@ -48,120 +75,229 @@ impl ChunkCache {
 				pending_chunks: HashMap::new(),
 				needs_saving: false,
 			}),
+			cold_chunks: HashMap::new(),
+			flush_sender: Mutex::new(None),
+			limit_basis,
+		})
+	}
+	
+	/// Loads a cache stored as a directory of shards. Shards that can't be read are skipped, keeping whatever chunks
+	///  could be read from them, and are rewritten by the next save.
+	pub async fn load_sharded(max_size: u64, limit_basis: CacheLimitBasis, cache_dir: PathBuf) -> anyhow::Result<Self> {
+		let raw_cache = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+			if !cache_dir.is_dir() {
+				return Err(anyhow::anyhow!("{} isn't a directory of cache shards", cache_dir.display()));
+			}
+			
+			for entry in std::fs::read_dir(&cache_dir)? {
+				let path = entry?.path();
+				
+				if path.extension().is_some_and(|extension| extension == "tmp") {
+					warn!("Removing {} left over from an interrupted cache save", path.display());
+					
+					if let Err(err) = std::fs::remove_file(&path) {
+						warn!("Failed to remove {}: {}", path.display(), err);
+					}
+				}
+			}
+			
+			let (shards, corrupt_shards) = read_shards(&cache_dir);
+			
+			let mut raw_cache = RawChunkCache::new(max_size, limit_basis);
+			
+			for (key, chunk) in merge_shards(shards) {
+				raw_cache.insert(key, chunk);
+			}
+			
+			raw_cache.changed_shards = [false; SHARD_COUNT];
+			
+			for shard_index in corrupt_shards {
+				raw_cache.changed_shards[shard_index] = true;
+			}
+			
+			Ok(raw_cache)
+		}).await??;
+		
+		let needs_saving = raw_cache.changed_shards.contains(&true);
+		
+		Ok(Self {
+			inner: Mutex::new(ChunkCacheInner {
+				raw_cache,
+				pending_chunks: HashMap::new(),
+				needs_saving,
+			}),
+			cold_chunks: HashMap::new(),
+			flush_sender: Mutex::new(None),
+			limit_basis,
 		})
 	}
 	
-	pub fn start_writer(self: &Arc<Self>, cache_path: PathBuf, interval: Duration) {
+	/// Loads a cache file or shard directory as a read-only tier below the cache, returning how many new chunks it
+	///  had. All of its chunks are kept regardless of the cache limit.
+	pub async fn add_cold_tier(&mut self, cache_path: PathBuf) -> anyhow::Result<usize> {
+		let raw_cache = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+			let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+			
+			if cache_path.is_dir() {
+				for (key, chunk) in merge_shards(read_shards(&cache_path).0) {
+					raw_cache.insert(key, chunk);
+				}
+			} else {
+				read_chunk_cache(&mut raw_cache, &cache_path)?;
+			}
+			
+			Ok(raw_cache)
+		}).await??;
+		
+		let old_len = self.cold_chunks.len();
+		
+		for (key, chunk) in raw_cache.chunks {
+			self.cold_chunks.entry(key).or_insert(chunk);
+		}
+		
+		Ok(self.cold_chunks.len() - old_len)
+	}
+	
+	pub fn cold_tier_size(&self) -> (usize, u64) {
+		(self.cold_chunks.len(), self.cold_chunks.values().map(|chunk| chunk.len() as u64).sum())
+	}
+	
+	/// Starts saving the cache periodically. Each interval is varied by up to the jitter fraction of it, so that
+	///  clients sharing storage that were started together don't all save at once.
+	pub fn start_writer(self: &Arc<Self>, cache_path: PathBuf, layout: CacheLayout, interval: Duration, jitter: f64) {
 		let arc_self = Arc::clone(self);
+		let (flush_sender, mut flush_receiver) = mpsc::channel(1);
+		
+		*self.flush_sender.lock().unwrap() = Some(flush_sender);
 		
 		tokio::spawn(async move {
 			loop {
-				tokio::time::sleep(interval).await;
+				let save_delay = jittered_interval(interval, jitter);
+				debug!("Next cache save in {}ms", save_delay.as_millis());
 				
-				if let Err(err) = arc_self.try_save(cache_path.clone()).await {
-					error!("Failed to save chunk cache: {}", err);
+				let manual_flush = select! {
+					_ = tokio::time::sleep(save_delay) => false,
+					Some(()) = flush_receiver.recv() => true,
+				};
+				
+				match arc_self.try_save(cache_path.clone(), layout, manual_flush).await {
+					Ok(Some(compressed_size)) if manual_flush => {
+						info!("Manual flush complete, cache file size: {}B", utils::abbreviate_number(compressed_size));
+					}
+					Ok(_) => {}
+					Err(err) if is_disk_full(&err) => {
+						// The last save is still on disk, and the cache is kept in memory until there's room again
+						warn!("Not enough disk space to save the chunk cache, will try again at the next save: {}", err);
+					}
+					Err(err) => error!("Failed to save chunk cache: {}", err),
 				}
 			}
 		});
 	}
 	
-	async fn try_save(&self, cache_path: PathBuf) -> anyhow::Result<()> {
+	/// Asks the writer to save the cache to disk immediately, even if nothing has changed since the last save.
+	pub fn flush_now(&self) {
+		match self.flush_sender.lock().unwrap().as_ref() {
+			Some(flush_sender) => {
+				// A full channel means a flush is already queued
+				let _ = flush_sender.try_send(());
+			}
+			None => warn!("Tried to flush the cache before the writer was started"),
+		}
+	}
+	
+	/// Saves the cache if it has changed, or unconditionally if forced. Returns the size of the written file, or of
+	///  all the shards for a sharded cache.
+	async fn try_save(&self, cache_path: PathBuf, layout: CacheLayout, force: bool) -> anyhow::Result<Option<u64>> {
 		let total_size;
+		let changed_shards;
 		
 		let cache_entries: Vec<_> = {
 			let mut inner = self.inner.lock().expect("chunk cache poisoned");
 			
-			if !inner.needs_saving {
-				return Ok(());
+			if !inner.needs_saving && !force {
+				return Ok(None);
 			}
 			
 			info!("Saving cache");
 			
 			inner.needs_saving = false;
 			total_size = inner.raw_cache.total_size;
+			changed_shards = mem::replace(&mut inner.raw_cache.changed_shards, [false; SHARD_COUNT]);
 			
+			// Entries are written in least recently used order, which keeps the file layout stable between saves
+			//  and lets loading rebuild the same eviction order. Sharded caches only write the shards that changed.
 			inner.raw_cache.chunks.iter()
-				.map(|(k, v)| (k.clone(), v.clone()))
+				.filter(|(key, _)| layout == CacheLayout::File || force || changed_shards[shard_index(key)])
+				.map(|(k, v)| (*k, v.clone()))
 				.collect()
 		};
 		
 		let chunk_count = cache_entries.len();
 		
-		let compressed_size = tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
-			let temp_path = cache_path.with_extension("tmp");
-			
-			write_chunk_cache(&cache_entries, &temp_path)?;
-			
-			let written_size = std::fs::metadata(&temp_path)?.len();
-			std::fs::rename(&temp_path, &cache_path)?;
-			
-			Ok(written_size)
-		}).await??;
+		let result = match layout {
+			CacheLayout::File => tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
+				write_chunk_cache_atomically(&cache_entries, &cache_path, CHUNK_CACHE_COMPRESSION_LEVEL)?;
+				
+				Ok(std::fs::metadata(&cache_path)?.len())
+			}).await?,
+			CacheLayout::Sharded => {
+				let shards_to_write = (0..SHARD_COUNT)
+					.filter(|&shard_index| force || changed_shards[shard_index])
+					.collect::<Vec<_>>();
+				
+				info!("Writing {} of the cache's {} shards", shards_to_write.len(), SHARD_COUNT);
+				
+				tokio::task::spawn_blocking(move || -> anyhow::Result<u64> {
+					write_shards(&cache_path, cache_entries, &shards_to_write)?;
+					
+					Ok(stored_size(&cache_path)?)
+				}).await?
+			}
+		};
+		
+		let compressed_size = match result {
+			Ok(compressed_size) => compressed_size,
+			Err(err) => {
+				// The shards that didn't get written still need to be
+				let mut inner = self.inner.lock().expect("chunk cache poisoned");
+				inner.needs_saving = true;
+				
+				for (shard_changed, was_changed) in inner.raw_cache.changed_shards.iter_mut().zip(changed_shards) {
+					*shard_changed |= was_changed;
+				}
+				
+				return Err(err);
+			}
+		};
 		
 		info!("Saved {} chunks to the cache ({}B, {}B compressed)", chunk_count,
 			utils::abbreviate_number(total_size), utils::abbreviate_number(compressed_size));
 		
-		Ok(())
+		Ok(Some(compressed_size))
 	}
 	
 	/// Gets all requested chunks, or builds a batch to be fetched.
 	/// 
 	/// All requested chunks currently in the cache will be placed into chunk_out.
 	/// Any remaining chunks that aren't currently being fetched by another task will be bundled into a batch
-	///  of at most batch_size chunks and returned. The caller can then fetch these and insert them into the cache by
+	///  within batch_limit and returned. The caller can then fetch these and insert them into the cache by
 	///  using the BatchChunkRequest's fulfill function.
 	/// Finally, if all requested chunks are being fetched by other tasks, then wait for those tasks to complete and
-	///  place the final chunks into chunk_out.
+	///  place the final chunks into chunk_out. Any that were evicted in the meantime are put back into
+	///  chunks_requested.
 	/// 
 	/// Returns None when all requests have been fulfilled.
 	pub async fn get_chunks_batched(&self,
 		chunks_requested: &mut Vec<ChunkKey>,
 		chunk_out: &mut HashMap<ChunkKey, Bytes>,
-		batch_size: usize,
-	) -> Option<BatchChunkRequest> {
+		batch_limit: BatchLimit<'_>,
+	) -> Option<BatchChunkRequest<'_>> {
 		let pending_requests = {
 			let mut inner = self.inner.lock().unwrap();
 			
-			let mut batch_set = HashSet::with_capacity(batch_size);
-			let mut batch = Vec::new();
-			
-			chunks_requested.retain(|&key| {
-				let mut retain = true;
-				
-				// If the requested chunk is already in the cache, remove it from requested and output it.
-				if let Some(chunk) = inner.raw_cache.get(&key) {
-					chunk_out.insert(key, chunk.clone());
-					
-					retain = false;
-				} else if !inner.pending_chunks.contains_key(&key) &&
-					batch.len() < batch_size &&
-					!batch_set.contains(&key)
-				{
-					// If the requested chunk is not in the cache, and it's not currently being requested, then add it to
-					//  the batch and remove it from requested.
-					batch.push(key);
-					batch_set.insert(key);
-					
-					retain = false;
-				}
-				
-				retain
-			});
-			
-			// If we built a batch, then mark all chunks in the batch as pending and return the chunk keys to be
-			//  fetched.
-			if !batch.is_empty() {
-				let event = Arc::new(Semaphore::new(0));
-				
-				for &key in &batch {
-					inner.pending_chunks.insert(key, event.clone());
-				}
-				
-				return Some(BatchChunkRequest {
-					event,
-					batch_keys: batch,
-					cache: self,
-				});
+			if let Some(batch) = self.build_batch(&mut inner, chunks_requested, chunk_out, batch_limit) {
+				return Some(batch);
 			}
 			
 			// Otherwise, collect all chunks currently being fetched by somebody else and wait for them to finish.
@@ -194,14 +330,206 @@ impl ChunkCache {
 			let inner = self.inner.lock().unwrap();
 			
 			for (key, _event) in pending_requests {
-				let chunk = inner.raw_cache.get(&key)
-					.expect("waited on chunk, but chunk was not put in cache");
+				match inner.raw_cache.get(&key) {
+					Some(chunk) => {
+						chunk_out.insert(key, chunk.clone());
+					}
+					// The chunk was already evicted again, which always happens when caching is disabled, so it needs
+					//  to be fetched by this task instead
+					None => chunks_requested.push(key),
+				}
+			}
+		}
+		
+		None
+	}
+	
+	/// Like get_chunks_batched, but never waits on chunks being fetched by other tasks, leaving those in
+	///  chunks_requested instead. Returns None if there's nothing left to fetch right now.
+	pub fn try_get_chunks_batched(&self,
+		chunks_requested: &mut Vec<ChunkKey>,
+		chunk_out: &mut HashMap<ChunkKey, Bytes>,
+		batch_limit: BatchLimit<'_>,
+	) -> Option<BatchChunkRequest<'_>> {
+		let mut inner = self.inner.lock().unwrap();
+		
+		self.build_batch(&mut inner, chunks_requested, chunk_out, batch_limit)
+	}
+	
+	fn build_batch(&self,
+		inner: &mut ChunkCacheInner,
+		chunks_requested: &mut Vec<ChunkKey>,
+		chunk_out: &mut HashMap<ChunkKey, Bytes>,
+		batch_limit: BatchLimit<'_>,
+	) -> Option<BatchChunkRequest<'_>> {
+		let mut batch_set = HashSet::with_capacity(batch_limit.max_chunks);
+		let mut batch = Vec::new();
+		let mut batch_bytes = 0;
+		let mut batch_full = false;
+		
+		chunks_requested.retain(|&key| {
+			let mut retain = true;
+			
+			// If the requested chunk is already in the cache, remove it from requested and output it. It's also
+			//  marked as recently used so that chunks shared between worlds survive eviction.
+			if let Some(chunk) = inner.raw_cache.touch(&key).or_else(|| self.cold_chunks.get(&key)) {
+				chunk_out.insert(key, chunk.clone());
+				
+				retain = false;
+			} else if !inner.pending_chunks.contains_key(&key) &&
+				!batch_full &&
+				!batch_set.contains(&key)
+			{
+				// Batches always get at least one chunk, however big it is. Once a chunk doesn't fit, the batch is
+				//  closed rather than filled with later chunks, so that chunks are still fetched in the order requested.
+				if let Some((max_size, estimated_sizes)) = batch_limit.max_size {
+					let chunk_size = estimated_sizes.get(&key).copied().unwrap_or(0);
+					
+					if !batch.is_empty() && batch_bytes + chunk_size > max_size {
+						batch_full = true;
+						return true;
+					}
+					
+					batch_bytes += chunk_size;
+				}
 				
+				// If the requested chunk is not in the cache, and it's not currently being requested, then add it to
+				//  the batch and remove it from requested.
+				batch.push(key);
+				batch_set.insert(key);
+				
+				retain = false;
+				batch_full = batch.len() >= batch_limit.max_chunks;
+			}
+			
+			retain
+		});
+		
+		if batch.is_empty() {
+			return None;
+		}
+		
+		// Mark all chunks in the batch as pending and return the chunk keys to be fetched.
+		let event = Arc::new(Semaphore::new(0));
+		
+		for &key in &batch {
+			inner.pending_chunks.insert(key, event.clone());
+		}
+		
+		Some(BatchChunkRequest {
+			event,
+			batch_keys: batch,
+			cache: self,
+		})
+	}
+	
+	/// Moves every requested chunk that's cached into chunk_out, marking them as recently used. The chunks that aren't
+	///  cached are left in chunks_requested.
+	pub fn take_cached_chunks(&self, chunks_requested: &mut Vec<ChunkKey>, chunk_out: &mut HashMap<ChunkKey, Bytes>) {
+		let mut inner = self.inner.lock().unwrap();
+		
+		chunks_requested.retain(|&key| match inner.raw_cache.touch(&key).or_else(|| self.cold_chunks.get(&key)) {
+			Some(chunk) => {
 				chunk_out.insert(key, chunk.clone());
+				false
+			}
+			None => true,
+		});
+	}
+	
+	/// Gets all the requested chunks from the cache without fetching anything, or None if any of them are missing.
+	pub fn get_cached_chunks(&self, keys: &[ChunkKey]) -> Option<HashMap<ChunkKey, Bytes>> {
+		let mut inner = self.inner.lock().unwrap();
+		
+		if !keys.iter().all(|key| inner.raw_cache.contains(key) || self.cold_chunks.contains_key(key)) {
+			return None;
+		}
+		
+		Some(keys.iter()
+			.map(|&key| (key, inner.raw_cache.touch(&key).or_else(|| self.cold_chunks.get(&key)).unwrap().clone()))
+			.collect())
+	}
+	
+	/// Checks how many of a world's chunks are cached, including in read-only caches, without marking any of them as
+	///  used. A world that's fully covered can be joined without fetching any chunks.
+	pub fn coverage(&self, world_desc: &FactorioWorldDescription) -> CacheCoverage {
+		let inner = self.inner.lock().unwrap();
+		
+		let keys = world_desc.files.iter()
+			.flat_map(|file| file.content_chunks.iter())
+			.collect::<HashSet<_>>();
+		
+		CacheCoverage {
+			cached_chunks: keys.iter().filter(|&&key| inner.raw_cache.contains(key) || self.cold_chunks.contains_key(key)).count(),
+			total_chunks: keys.len(),
+		}
+	}
+	
+	pub fn get_chunk(&self, key: &ChunkKey) -> Option<Bytes> {
+		self.inner.lock().unwrap().raw_cache.touch(key).or_else(|| self.cold_chunks.get(key)).cloned()
+	}
+	
+	/// Inserts chunks that aren't already cached or being fetched, returning how many were inserted.
+	pub fn insert_chunks(&self, chunks: impl IntoIterator<Item = (ChunkKey, Bytes)>) -> usize {
+		let chunks = self.measure_chunks(chunks);
+		
+		let mut inner = self.inner.lock().unwrap();
+		let mut inserted = 0;
+		
+		for (key, chunk, compressed_size) in chunks {
+			if !inner.raw_cache.contains(&key) && !inner.pending_chunks.contains_key(&key) && !self.cold_chunks.contains_key(&key) {
+				inner.raw_cache.insert_measured(key, chunk, compressed_size);
+				inserted += 1;
 			}
 		}
 		
-		None
+		if inserted > 0 {
+			inner.needs_saving = true;
+		}
+		
+		inserted
+	}
+	
+	/// Swaps each chunk for the cached copy if there is one, marking it as recently used, and caches the rest. Returns
+	///  how many were already cached. This lets worlds that have chunks in common share their memory.
+	pub fn share_chunks<'a>(&self, chunks: impl IntoIterator<Item = (&'a ChunkKey, &'a mut Bytes)>) -> usize {
+		let mut shared_count = 0;
+		let mut uncached_chunks = Vec::new();
+		
+		{
+			let mut inner = self.inner.lock().unwrap();
+			
+			for (key, chunk) in chunks {
+				match inner.raw_cache.touch(key) {
+					Some(cached_chunk) => {
+						*chunk = cached_chunk.clone();
+						shared_count += 1;
+					}
+					None => uncached_chunks.push((*key, chunk.clone())),
+				}
+			}
+		}
+		
+		let uncached_chunks = self.measure_chunks(uncached_chunks);
+		let mut inner = self.inner.lock().unwrap();
+		
+		for (key, chunk, compressed_size) in uncached_chunks {
+			if !inner.raw_cache.contains(&key) {
+				inner.raw_cache.insert_measured(key, chunk, compressed_size);
+			}
+		}
+		
+		shared_count
+	}
+	
+	/// Works out the compressed sizes that the cache limit needs, which is too slow to do while holding the lock.
+	fn measure_chunks(&self, chunks: impl IntoIterator<Item = (ChunkKey, Bytes)>) -> Vec<(ChunkKey, Bytes, Option<u64>)> {
+		chunks.into_iter()
+			.map(|(key, chunk)| {
+				let compressed_size = self.limit_basis.measure(&chunk);
+				(key, chunk, compressed_size)
+			})
+			.collect()
 	}
 	
 	// pub fn insert(&self, key: ChunkKey, chunk: Bytes) {
@@ -225,6 +553,80 @@ impl ChunkCache {
 		let inner = self.inner.lock().unwrap();
 		inner.raw_cache.total_size
 	}
+	
+	/// Keeps the given chunks from being evicted until the returned pins are dropped, including any of them that are
+	///  only inserted later.
+	pub fn pin_chunks(&self, keys: &[ChunkKey]) -> ChunkPins<'_> {
+		let keys: Vec<ChunkKey> = keys.iter().copied().collect::<HashSet<_>>().into_iter().collect();
+		let mut inner = self.inner.lock().unwrap();
+		
+		for &key in &keys {
+			inner.raw_cache.pin(key);
+		}
+		
+		ChunkPins {
+			keys,
+			cache: self,
+		}
+	}
+	
+	/// Sizes of all cached chunks, from least to most recently used.
+	pub fn chunk_sizes(&self) -> Vec<usize> {
+		let inner = self.inner.lock().unwrap();
+		inner.raw_cache.chunks.values().map(Bytes::len).collect()
+	}
+}
+
+/// How many of a world's distinct chunks are cached.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CacheCoverage {
+	pub cached_chunks: usize,
+	pub total_chunks: usize,
+}
+
+impl CacheCoverage {
+	pub fn is_complete(&self) -> bool {
+		self.cached_chunks == self.total_chunks
+	}
+	
+	/// The fraction of the chunks that are cached, where a world without any chunks counts as fully cached.
+	pub fn fraction(&self) -> f64 {
+		match self.total_chunks {
+			0 => 1.0,
+			total_chunks => self.cached_chunks as f64 / total_chunks as f64,
+		}
+	}
+}
+
+/// The number and total size of some chunks.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default)]
+pub struct ChunkSetSize {
+	pub chunk_count: usize,
+	pub total_size: u64,
+}
+
+impl ChunkSetSize {
+	fn add(&mut self, chunk: &Bytes) {
+		self.chunk_count += 1;
+		self.total_size += chunk.len() as u64;
+	}
+}
+
+/// Which chunks two caches have in common.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct CacheDiff {
+	pub only_in_first: ChunkSetSize,
+	pub only_in_second: ChunkSetSize,
+	pub shared: ChunkSetSize,
+}
+
+/// How large a batch of chunks to fetch can get.
+#[derive(Copy, Clone)]
+pub struct BatchLimit<'a> {
+	pub max_chunks: usize,
+	/// A limit on the total estimated size of the chunks in a batch, along with the estimated size of each chunk.
+	///  Chunks without an estimate count as empty.
+	pub max_size: Option<(u64, &'a HashMap<ChunkKey, u64>)>,
 }
 
 pub struct BatchChunkRequest<'a> {
@@ -241,116 +643,1318 @@ impl<'a> BatchChunkRequest<'a> {
 	pub fn fulfill(self, chunks: &[Bytes]) {
 		assert_eq!(self.batch_keys.len(), chunks.len());
 		
+		let chunks = self.cache.measure_chunks(self.batch_keys.iter().copied().zip(chunks.iter().cloned()));
+		
 		{
 			let mut inner = self.cache.inner.lock().unwrap();
 			
-			for (&key, chunk) in self.batch_keys.iter().zip(chunks.iter()) {
-				inner.raw_cache.insert(key, chunk.clone());
+			for (key, chunk, compressed_size) in chunks {
+				inner.raw_cache.insert_measured(key, chunk, compressed_size);
 				inner.pending_chunks.remove(&key);
 			}
 		}
 		
 		self.event.close();
 	}
+	
+	/// Fulfills some of the batch's chunks, leaving the rest of the batch to be fetched again. Tasks waiting on the
+	///  batch keep waiting until the whole batch is fulfilled.
+	pub fn fulfill_partial(&mut self, chunks: &[(ChunkKey, Bytes)]) {
+		let measured_chunks = self.cache.measure_chunks(chunks.iter().cloned());
+		let mut inner = self.cache.inner.lock().unwrap();
+		
+		for (key, chunk, compressed_size) in measured_chunks {
+			inner.raw_cache.insert_measured(key, chunk, compressed_size);
+			inner.pending_chunks.remove(&key);
+		}
+		
+		let fulfilled_keys = chunks.iter().map(|(key, _)| key).collect::<HashSet<_>>();
+		self.batch_keys.retain(|key| !fulfilled_keys.contains(key));
+	}
+}
+
+/// Chunks pinned for a transfer, unpinned when dropped.
+pub struct ChunkPins<'a> {
+	keys: Vec<ChunkKey>,
+	cache: &'a ChunkCache,
+}
+
+impl Drop for ChunkPins<'_> {
+	fn drop(&mut self) {
+		let mut inner = self.cache.inner.lock().unwrap();
+		
+		for &key in &self.keys {
+			inner.raw_cache.unpin(key);
+		}
+		
+		// Anything that was only kept around by these pins can go now
+		inner.raw_cache.evict();
+	}
 }
 
 struct RawChunkCache {
 	chunks: LinkedHashMap<ChunkKey, Bytes>,
 	total_size: u64,
 	max_size: u64,
+	limit_basis: CacheLimitBasis,
+	/// Total size of the cached chunks as counted by limit_basis, which is what max_size limits.
+	limited_size: u64,
+	/// Estimated compressed size of each cached chunk, only tracked when limiting by compressed size.
+	compressed_sizes: HashMap<ChunkKey, u64>,
+	/// Shards with chunks that were added or removed since the last save, only used by sharded caches.
+	changed_shards: [bool; SHARD_COUNT],
+	/// How many transfers are using each pinned chunk. Pinned chunks are never evicted, even if that puts the cache
+	///  over its limit.
+	pins: HashMap<ChunkKey, u32>,
+	/// Total size of the cached chunks that are pinned, counted the same way as limited_size.
+	pinned_size: u64,
 }
 
 impl RawChunkCache {
-	pub fn new(max_size: u64) -> Self {
+	pub fn new(max_size: u64, limit_basis: CacheLimitBasis) -> Self {
 		Self {
 			chunks: LinkedHashMap::new(),
 			total_size: 0,
 			max_size,
+			limit_basis,
+			limited_size: 0,
+			compressed_sizes: HashMap::new(),
+			pins: HashMap::new(),
+			pinned_size: 0,
+			changed_shards: [false; SHARD_COUNT],
 		}
 	}
 	
 	pub fn insert(&mut self, key: ChunkKey, chunk: Bytes) {
-		self.total_size += chunk.len() as u64;
-		
-		if let Some(old_chunk) = self.chunks.insert(key, chunk) {
+		let compressed_size = self.limit_basis.measure(&chunk);
+		self.insert_measured(key, chunk, compressed_size);
+	}
+	
+	/// Inserts a chunk whose compressed size was already worked out by CacheLimitBasis::measure.
+	pub fn insert_measured(&mut self, key: ChunkKey, chunk: Bytes, compressed_size: Option<u64>) {
+		if let Some(old_chunk) = self.chunks.remove(&key) {
 			warn!("Inserting chunk twice: {}", key.0);
-			self.total_size -= old_chunk.len() as u64;
+			self.remove_size(key, &old_chunk);
 		}
 		
-		while self.total_size > self.max_size {
-			let (_, evicted_chunk) = self.chunks.pop_front().unwrap();
-			self.total_size -= evicted_chunk.len() as u64;
+		let limited_size = match self.limit_basis {
+			CacheLimitBasis::Uncompressed => chunk.len() as u64,
+			CacheLimitBasis::Compressed => {
+				let compressed_size = compressed_size.unwrap_or_else(|| estimate_compressed_size(&chunk));
+				self.compressed_sizes.insert(key, compressed_size);
+				
+				compressed_size
+			}
+		};
+		
+		self.total_size += chunk.len() as u64;
+		self.limited_size += limited_size;
+		
+		if self.pins.contains_key(&key) {
+			self.pinned_size += limited_size;
 		}
+		
+		self.chunks.insert(key, chunk);
+		self.changed_shards[shard_index(&key)] = true;
+		
+		self.evict();
 	}
 	
-	pub fn get(&self, key: &ChunkKey) -> Option<&Bytes> {
-		self.chunks.get(key)
+	/// Size of a cached chunk as counted by limit_basis.
+	fn limited_size_of(&self, key: &ChunkKey, chunk: &Bytes) -> u64 {
+		match self.limit_basis {
+			CacheLimitBasis::Uncompressed => chunk.len() as u64,
+			CacheLimitBasis::Compressed => self.compressed_sizes[key],
+		}
 	}
-}
-
-pub const CHUNK_CACHE_COMPRESSION_LEVEL: i32 = 8;
-
-fn read_chunk_cache(cache: &mut RawChunkCache, cache_path: &Path) -> anyhow::Result<()> {
-	let file = std::fs::File::open(cache_path)?;
-	let mut decoder = zstd::Decoder::new(file)?;
-	
-	let mut u32_buf = [0u8; 4];
 	
-	decoder.read_exact(&mut u32_buf)?;
-	let chunks_in_file = u32::from_le_bytes(u32_buf);
-	
-	for _ in 0..chunks_in_file {
-		let mut chunk_key_bytes = [0; 32];
-		decoder.read_exact(&mut chunk_key_bytes)?;
+	/// Stops counting a chunk that was removed from chunks.
+	fn remove_size(&mut self, key: ChunkKey, chunk: &Bytes) {
+		let limited_size = self.limited_size_of(&key, chunk);
 		
-		let chunk_key = ChunkKey(blake3::Hash::from(chunk_key_bytes));
-		
-		decoder.read_exact(&mut u32_buf)?;
-		let chunk_length = u32::from_le_bytes(u32_buf);
+		self.total_size -= chunk.len() as u64;
+		self.limited_size -= limited_size;
+		self.compressed_sizes.remove(&key);
+		self.changed_shards[shard_index(&key)] = true;
 		
-		if chunk_length > 20_000_000 {
-			return Err(anyhow::anyhow!("Chunk length too large: {}", chunk_length));
+		if self.pins.contains_key(&key) {
+			self.pinned_size -= limited_size;
 		}
-		
-		let mut chunk_data = vec![0; chunk_length as usize];
-		decoder.read_exact(&mut chunk_data)?;
-		
-		let data_hash = blake3::hash(&chunk_data);
-		
-		if data_hash != chunk_key.0 {
-			error!("Chunk hash mismatch while loading cache, expected {}, got {}", chunk_key.0, data_hash);
-			continue;
+	}
+	
+	/// Evicts the least recently used unpinned chunks until the cache is within its limit, or only pinned chunks
+	///  are left.
+	fn evict(&mut self) {
+		while self.limited_size > self.max_size && self.limited_size > self.pinned_size {
+			let (key, chunk) = self.chunks.pop_front().unwrap();
+			
+			if self.pins.contains_key(&key) {
+				// Pinned chunks are in use, so they're treated as recently used
+				self.chunks.insert(key, chunk);
+			} else {
+				self.remove_size(key, &chunk);
+			}
 		}
+	}
+	
+	fn pin(&mut self, key: ChunkKey) {
+		let pin_count = self.pins.entry(key).or_insert(0);
+		*pin_count += 1;
 		
-		cache.insert(chunk_key, chunk_data.into());
+		if *pin_count == 1 {
+			if let Some(chunk) = self.chunks.get(&key) {
+				self.pinned_size += self.limited_size_of(&key, chunk);
+			}
+		}
 	}
 	
-	Ok(())
+	fn unpin(&mut self, key: ChunkKey) {
+		let Some(pin_count) = self.pins.get_mut(&key) else { return; };
+		*pin_count -= 1;
+		
+		if *pin_count == 0 {
+			self.pins.remove(&key);
+			
+			if let Some(chunk) = self.chunks.get(&key) {
+				self.pinned_size -= self.limited_size_of(&key, chunk);
+			}
+		}
+	}
+	
+	pub fn get(&self, key: &ChunkKey) -> Option<&Bytes> {
+		self.chunks.get(key)
+	}
+	
+	/// Gets a chunk and moves it to the back of the eviction order. This doesn't count as a change to the chunk's
+	///  shard, since rewriting shards just to keep the order would mean rewriting most of them after every world, so
+	///  the order is only roughly kept when a sharded cache is loaded again.
+	pub fn touch(&mut self, key: &ChunkKey) -> Option<&Bytes> {
+		self.chunks.to_back(key).map(|chunk| &*chunk)
+	}
+	
+	pub fn contains(&self, key: &ChunkKey) -> bool {
+		self.chunks.contains_key(key)
+	}
+}
+
+pub const CHUNK_CACHE_COMPRESSION_LEVEL: i32 = 8;
+
+/// How a cache is stored on disk.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CacheLayout {
+	/// A single file holding every chunk.
+	File,
+	/// A directory of files that each hold the chunks whose keys start with a particular byte. Saves only rewrite the
+	///  shards that changed, and a corrupt shard only loses its own chunks.
+	Sharded,
+}
+
+impl FromStr for CacheLayout {
+	type Err = anyhow::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"file" => Ok(CacheLayout::File),
+			"sharded" => Ok(CacheLayout::Sharded),
+			_ => Err(anyhow::anyhow!("Expected 'file' or 'sharded'")),
+		}
+	}
+}
+
+const SHARD_COUNT: usize = 256;
+
+fn shard_index(key: &ChunkKey) -> usize {
+	key.0.as_bytes()[0] as usize
+}
+
+fn shard_path(cache_dir: &Path, shard_index: usize) -> PathBuf {
+	cache_dir.join(format!("{:02x}", shard_index))
+}
+
+/// How much space a cache file, or all the files of a sharded cache, take up.
+pub fn stored_size(cache_path: &Path) -> std::io::Result<u64> {
+	if !cache_path.is_dir() {
+		return Ok(std::fs::metadata(cache_path)?.len());
+	}
+	
+	let mut total_size = 0;
+	
+	for entry in std::fs::read_dir(cache_path)? {
+		total_size += entry?.metadata()?.len();
+	}
+	
+	Ok(total_size)
+}
+
+/// Runs a function for every shard index on as many threads as there are cores.
+fn for_each_shard<T: Send>(shard_indexes: &[usize], f: impl Fn(usize) -> T + Sync) -> Vec<(usize, T)> {
+	let thread_count = std::thread::available_parallelism().map_or(1, |parallelism| parallelism.get());
+	let next_shard = AtomicUsize::new(0);
+	
+	std::thread::scope(|scope| {
+		let threads = (0..thread_count.min(shard_indexes.len()))
+			.map(|_| scope.spawn(|| {
+				let mut results = Vec::new();
+				
+				while let Some(&shard_index) = shard_indexes.get(next_shard.fetch_add(1, Ordering::Relaxed)) {
+					results.push((shard_index, f(shard_index)));
+				}
+				
+				results
+			}))
+			.collect::<Vec<_>>();
+		
+		threads.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+	})
+}
+
+/// Reads every shard of a sharded cache, each in its saved order. Also returns the shards that couldn't be read,
+///  which still include the chunks read before the error.
+fn read_shards(cache_dir: &Path) -> (Vec<Vec<(ChunkKey, Bytes)>>, Vec<usize>) {
+	let all_shards = (0..SHARD_COUNT).collect::<Vec<_>>();
+	
+	let results = for_each_shard(&all_shards, |shard_index| {
+		let path = shard_path(cache_dir, shard_index);
+		let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		
+		let result = match path.exists() {
+			true => read_chunk_cache(&mut raw_cache, &path),
+			false => Ok(()),
+		};
+		
+		if let Err(err) = &result {
+			warn!("Failed to read cache shard {}, keeping the {} chunks read before the error: {}",
+				path.display(), raw_cache.chunks.len(), err);
+		}
+		
+		(raw_cache.chunks.into_iter().collect::<Vec<_>>(), result.is_err())
+	});
+	
+	let corrupt_shards = results.iter()
+		.filter(|(_, (_, corrupt))| *corrupt)
+		.map(|&(shard_index, _)| shard_index)
+		.collect();
+	
+	(results.into_iter().map(|(_, (chunks, _))| chunks).collect(), corrupt_shards)
 }
 
-fn write_chunk_cache(cache_entries: &[(ChunkKey, Bytes)], cache_path: &Path) -> anyhow::Result<()> {
+/// Puts the chunks of every shard back into one eviction order. Keys are hashes, so each shard is an even sample of
+///  the whole order, and interleaving the shards by how far into each one a chunk is comes close to the saved order.
+fn merge_shards(shards: Vec<Vec<(ChunkKey, Bytes)>>) -> Vec<(ChunkKey, Bytes)> {
+	let mut entries = shards.into_iter()
+		.flat_map(|shard| {
+			let shard_len = shard.len() as f64;
+			
+			shard.into_iter()
+				.enumerate()
+				.map(move |(index, entry)| ((index as f64 + 0.5) / shard_len, entry))
+		})
+		.collect::<Vec<_>>();
+	
+	entries.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+	
+	entries.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Writes the given shards of a sharded cache from its entries, which have to include every chunk in those shards.
+fn write_shards(cache_dir: &Path, cache_entries: Vec<(ChunkKey, Bytes)>, shards_to_write: &[usize]) -> anyhow::Result<()> {
+	std::fs::create_dir_all(cache_dir)?;
+	
+	let mut shards = vec![Vec::new(); SHARD_COUNT];
+	
+	for (key, chunk) in cache_entries {
+		shards[shard_index(&key)].push((key, chunk));
+	}
+	
+	let results = for_each_shard(shards_to_write, |shard_index| -> anyhow::Result<()> {
+		write_chunk_cache_atomically(&shards[shard_index], &shard_path(cache_dir, shard_index), CHUNK_CACHE_COMPRESSION_LEVEL)
+	});
+	
+	results.into_iter().try_for_each(|(_, result)| result)
+}
+
+/// What the cache's size limit is measured in.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CacheLimitBasis {
+	/// The size of the chunks themselves.
+	Uncompressed,
+	/// How much space the chunks take up in the cache file.
+	Compressed,
+}
+
+impl FromStr for CacheLimitBasis {
+	type Err = anyhow::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"uncompressed" => Ok(CacheLimitBasis::Uncompressed),
+			"compressed" => Ok(CacheLimitBasis::Compressed),
+			_ => Err(anyhow::anyhow!("Expected 'uncompressed' or 'compressed'")),
+		}
+	}
+}
+
+impl CacheLimitBasis {
+	/// The chunk's compressed size, if this counts it.
+	fn measure(self, chunk: &[u8]) -> Option<u64> {
+		match self {
+			CacheLimitBasis::Uncompressed => None,
+			CacheLimitBasis::Compressed => Some(estimate_compressed_size(chunk)),
+		}
+	}
+}
+
+/// Estimates how much space a chunk takes up in the cache file, where chunks that don't compress are stored as is.
+fn estimate_compressed_size(chunk: &[u8]) -> u64 {
+	zstd::bulk::compress(chunk, CHUNK_CACHE_COMPRESSION_LEVEL)
+		.map_or(chunk.len() as u64, |compressed| compressed.len().min(chunk.len()) as u64)
+}
+
+/// Starts cache files that store each chunk compressed on its own, or uncompressed when compressing it doesn't make it
+///  any smaller, like chunks of data factorio already compressed. Files without it are a single zstd stream.
+const CHUNK_CACHE_PER_CHUNK_MAGIC: [u8; 4] = *b"FCC\x02";
+/// Starts zstd stream cache files that record which hash algorithm their keys were made with. Older files start with
+///  their chunk count instead and always use blake3.
+const CHUNK_CACHE_MAGIC: [u8; 4] = *b"FCC\xFF";
+
+/// Flags each chunk in a per-chunk compressed cache file with how it's stored.
+const CHUNK_STORED: u8 = 0;
+const CHUNK_ZSTD: u8 = 1;
+
+const MAX_CACHED_CHUNK_LENGTH: usize = 20_000_000;
+
+fn read_chunk_cache(cache: &mut RawChunkCache, cache_path: &Path) -> anyhow::Result<()> {
+	let mut file = std::fs::File::open(cache_path)?;
+	
+	let mut u32_buf = [0u8; 4];
+	
+	file.read_exact(&mut u32_buf)?;
+	
+	if u32_buf == CHUNK_CACHE_PER_CHUNK_MAGIC {
+		let mut reader = BufReader::new(file);
+		
+		let hash_algorithm = read_hash_algorithm(&mut reader)?;
+		reader.read_exact(&mut u32_buf)?;
+		
+		return read_chunk_entries(cache, &mut reader, hash_algorithm, u32::from_le_bytes(u32_buf), true);
+	}
+	
+	file.rewind()?;
+	
+	let mut decoder = zstd::Decoder::new(file)?;
+	
+	decoder.read_exact(&mut u32_buf)?;
+	
+	let hash_algorithm = if u32_buf == CHUNK_CACHE_MAGIC {
+		let hash_algorithm = read_hash_algorithm(&mut decoder)?;
+		
+		decoder.read_exact(&mut u32_buf)?;
+		hash_algorithm
+	} else {
+		check_hash_algorithm(HashAlgorithm::Blake3)?
+	};
+	
+	read_chunk_entries(cache, &mut decoder, hash_algorithm, u32::from_le_bytes(u32_buf), false)
+}
+
+/// Reads the id of the hash algorithm a cache file's keys were made with, which has to be the one in use.
+fn read_hash_algorithm(reader: &mut impl Read) -> anyhow::Result<HashAlgorithm> {
+	let mut id = [0u8; 1];
+	reader.read_exact(&mut id)?;
+	
+	let hash_algorithm = HashAlgorithm::from_id(id[0])
+		.ok_or_else(|| anyhow::anyhow!("Unknown hash algorithm id {} in cache file", id[0]))?;
+	
+	check_hash_algorithm(hash_algorithm)
+}
+
+fn check_hash_algorithm(hash_algorithm: HashAlgorithm) -> anyhow::Result<HashAlgorithm> {
+	if hash_algorithm != CONTENT_HASH {
+		return Err(anyhow::anyhow!("Cache file addresses chunks with {}, but {} is in use", hash_algorithm, CONTENT_HASH));
+	}
+	
+	Ok(hash_algorithm)
+}
+
+/// Reads the chunks following a cache file's header, which each start with a flag saying how they're stored if they
+///  were compressed one by one.
+fn read_chunk_entries(
+	cache: &mut RawChunkCache,
+	reader: &mut impl Read,
+	hash_algorithm: HashAlgorithm,
+	chunks_in_file: u32,
+	per_chunk_compression: bool,
+) -> anyhow::Result<()> {
+	let mut decompressor = zstd::bulk::Decompressor::new()?;
+	let mut u32_buf = [0u8; 4];
+	
+	for _ in 0..chunks_in_file {
+		let mut chunk_key_bytes = [0; 32];
+		reader.read_exact(&mut chunk_key_bytes)?;
+		
+		let chunk_key = ChunkKey(blake3::Hash::from(chunk_key_bytes));
+		
+		let mut flag = [CHUNK_STORED];
+		
+		if per_chunk_compression {
+			reader.read_exact(&mut flag)?;
+		}
+		
+		reader.read_exact(&mut u32_buf)?;
+		let chunk_length = u32::from_le_bytes(u32_buf);
+		
+		if chunk_length as usize > MAX_CACHED_CHUNK_LENGTH {
+			return Err(anyhow::anyhow!("Chunk length too large: {}", chunk_length));
+		}
+		
+		let mut chunk_data = vec![0; chunk_length as usize];
+		reader.read_exact(&mut chunk_data)?;
+		
+		let chunk_data = match flag[0] {
+			CHUNK_STORED => chunk_data,
+			CHUNK_ZSTD => decompressor.decompress(&chunk_data, MAX_CACHED_CHUNK_LENGTH)?,
+			flag => return Err(anyhow::anyhow!("Unknown chunk storage flag {} in cache file", flag)),
+		};
+		
+		let data_hash = hash_algorithm.hash(&chunk_data);
+		
+		if data_hash != chunk_key {
+			error!("Chunk hash mismatch while loading cache, expected {}, got {}", chunk_key.0, data_hash.0);
+			continue;
+		}
+		
+		cache.insert(chunk_key, chunk_data.into());
+	}
+	
+	Ok(())
+}
+
+/// Rewrites a cache file at a different compression level, keeping every chunk and their eviction order.
+/// Returns the number of chunks written.
+pub fn compact_cache_file(cache_path: &Path, output_path: &Path, compression_level: i32) -> anyhow::Result<usize> {
+	// Nothing gets evicted while loading since the limit only applies to the running cache
+	let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+	read_chunk_cache(&mut raw_cache, cache_path)?;
+	
+	let cache_entries: Vec<_> = raw_cache.chunks.into_iter().collect();
+	
+	write_chunk_cache_atomically(&cache_entries, output_path, compression_level)?;
+	
+	Ok(cache_entries.len())
+}
+
+/// Compares the chunks in two cache files.
+pub fn diff_cache_files(first_path: &Path, second_path: &Path) -> anyhow::Result<CacheDiff> {
+	let mut first = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+	read_chunk_cache(&mut first, first_path)?;
+	
+	let mut second = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+	read_chunk_cache(&mut second, second_path)?;
+	
+	let mut diff = CacheDiff {
+		only_in_first: ChunkSetSize::default(),
+		only_in_second: ChunkSetSize::default(),
+		shared: ChunkSetSize::default(),
+	};
+	
+	for (key, chunk) in &first.chunks {
+		match second.contains(key) {
+			true => diff.shared.add(chunk),
+			false => diff.only_in_first.add(chunk),
+		}
+	}
+	
+	for (key, chunk) in &second.chunks {
+		if !first.contains(key) {
+			diff.only_in_second.add(chunk);
+		}
+	}
+	
+	Ok(diff)
+}
+
+/// Writes every chunk in a cache file to a file of its own in a directory, named by the hex of its key, so that the
+///  cache can be kept in or synced with storage that deduplicates by file name. Chunks already in the directory are
+///  skipped. Returns the number of chunks written and the number that were already there.
+pub fn export_cas(cache_path: &Path, cas_dir: &Path) -> anyhow::Result<(usize, usize)> {
+	let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+	read_chunk_cache(&mut raw_cache, cache_path)?;
+	
+	std::fs::create_dir_all(cas_dir)?;
+	
+	let mut written_count = 0;
+	let mut existing_count = 0;
+	
+	for (key, chunk) in &raw_cache.chunks {
+		let chunk_path = cas_dir.join(key.0.to_hex().as_str());
+		
+		if chunk_path.exists() {
+			existing_count += 1;
+			continue;
+		}
+		
+		// Files named by a key are always complete, so an interrupted export can just be run again
+		let temp_path = chunk_path.with_extension("tmp");
+		std::fs::write(&temp_path, chunk)?;
+		std::fs::rename(&temp_path, &chunk_path)?;
+		
+		written_count += 1;
+	}
+	
+	Ok((written_count, existing_count))
+}
+
+/// Adds the chunks in a directory written by export_cas to a cache file, creating the file if it doesn't exist.
+///  Files that aren't named by the key of their contents are skipped. Returns the number of chunks added and the
+///  number of files skipped.
+pub fn import_cas(cas_dir: &Path, cache_path: &Path) -> anyhow::Result<(usize, usize)> {
+	let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+	
+	if cache_path.exists() {
+		read_chunk_cache(&mut raw_cache, cache_path)?;
+	}
+	
+	let mut chunk_paths = std::fs::read_dir(cas_dir)?
+		.map(|entry| Ok(entry?.path()))
+		.collect::<std::io::Result<Vec<_>>>()?;
+	
+	chunk_paths.sort_unstable();
+	
+	let mut imported_count = 0;
+	let mut skipped_count = 0;
+	
+	for chunk_path in chunk_paths {
+		let key = chunk_path.file_name()
+			.and_then(|file_name| file_name.to_str())
+			.and_then(|file_name| blake3::Hash::from_hex(file_name).ok())
+			.map(ChunkKey);
+		
+		let Some(key) = key else {
+			skipped_count += 1;
+			continue;
+		};
+		
+		if raw_cache.contains(&key) {
+			continue;
+		}
+		
+		let chunk = Bytes::from(std::fs::read(&chunk_path)?);
+		
+		if CONTENT_HASH.hash(&chunk) != key {
+			warn!("{} doesn't match the key it's named by, skipping it", chunk_path.display());
+			skipped_count += 1;
+			continue;
+		}
+		
+		raw_cache.insert(key, chunk);
+		imported_count += 1;
+	}
+	
+	let cache_entries: Vec<_> = raw_cache.chunks.into_iter().collect();
+	write_chunk_cache_atomically(&cache_entries, cache_path, CHUNK_CACHE_COMPRESSION_LEVEL)?;
+	
+	Ok((imported_count, skipped_count))
+}
+
+/// Writes a cache file next to its destination and then replaces it, so that a failed write leaves the old file
+///  untouched.
+fn write_chunk_cache_atomically(cache_entries: &[(ChunkKey, Bytes)], cache_path: &Path, compression_level: i32) -> anyhow::Result<()> {
+	let temp_path = cache_path.with_extension("tmp");
+	
+	if let Err(err) = write_chunk_cache(cache_entries, &temp_path, compression_level) {
+		// A partly written file only takes up space, which is usually what ran out
+		let _ = std::fs::remove_file(&temp_path);
+		return Err(err);
+	}
+	
+	replace_file(&temp_path, cache_path)?;
+	
+	Ok(())
+}
+
+/// Whether an error was caused by the disk or the user's quota being full.
+fn is_disk_full(err: &anyhow::Error) -> bool {
+	err.chain()
+		.filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+		.any(|err| matches!(err.kind(), std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded))
+}
+
+fn write_chunk_cache(cache_entries: &[(ChunkKey, Bytes)], cache_path: &Path, compression_level: i32) -> anyhow::Result<()> {
 	let file = std::fs::File::create(cache_path)?;
-	let writer = BufWriter::new(file);
-	let mut encoder = zstd::Encoder::new(writer, CHUNK_CACHE_COMPRESSION_LEVEL)?;
+	let mut writer = BufWriter::new(file);
+	let mut compressor = zstd::bulk::Compressor::new(compression_level)?;
+	
+	writer.write_all(&CHUNK_CACHE_PER_CHUNK_MAGIC)?;
+	writer.write_all(&[CONTENT_HASH.id()])?;
 	
-	encoder.write_all(&u32::try_from(cache_entries.len())
+	writer.write_all(&u32::try_from(cache_entries.len())
 		.expect("Chunk count wouldn't fit into a u32")
 		.to_le_bytes()
 	)?;
 	
 	for (key, chunk) in cache_entries {
-		encoder.write_all(key.0.as_bytes())?;
+		let compressed = compressor.compress(chunk)?;
 		
-		encoder.write_all(&u32::try_from(chunk.len())
+		let (flag, stored_data) = match compressed.len() < chunk.len() {
+			true => (CHUNK_ZSTD, &compressed[..]),
+			false => (CHUNK_STORED, &chunk[..]),
+		};
+		
+		writer.write_all(key.0.as_bytes())?;
+		writer.write_all(&[flag])?;
+		
+		writer.write_all(&u32::try_from(stored_data.len())
 			.expect("Chunk size wouldn't fit into a u32")
 			.to_le_bytes()
 		)?;
 		
-		encoder.write_all(&chunk)?;
+		writer.write_all(stored_data)?;
 	}
 	
-	let mut writer = encoder.finish()?;
 	writer.flush()?;
 	
+	// Make sure the data is on disk before the file can be renamed over the old cache
+	writer.get_ref().sync_all()?;
+	
+	Ok(())
+}
+
+/// Renames a fully written file over another, so that the destination is either the old file or the new one even if
+///  the process dies or the power goes out partway through.
+fn replace_file(temp_path: &Path, path: &Path) -> std::io::Result<()> {
+	std::fs::rename(temp_path, path)?;
+	
+	// The rename itself is only durable once the directory is synced, which can only be done on unix
+	#[cfg(unix)]
+	{
+		let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+		std::fs::File::open(dir)?.sync_all()?;
+	}
+	
+	Ok(())
+}
+
+/// Varies an interval randomly by up to the given fraction of it in either direction.
+fn jittered_interval(interval: Duration, jitter: f64) -> Duration {
+	// Only needs to differ between clients, so the std hasher's random keys are enough
+	let random = RandomState::new().hash_one(Instant::now()) as f64 / u64::MAX as f64;
+	
+	interval.mul_f64(1.0 + jitter * (random * 2.0 - 1.0))
+}
+
+/// Memory that the rest of the client needs on top of the cache, mostly for reconstructing worlds, which is also
+///  locked once the cache is.
+const LOCKED_MEMORY_HEADROOM: u64 = 1_000_000_000;
+
+/// Locks the process's memory so that the cache can't be swapped out. The cache is made up of lots of separate
+///  allocations, so everything is locked, including future allocations. Nothing is locked if the memlock limit might
+///  not fit the cache, since allocating past the limit would fail afterwards. cache_size counts every tier.
+#[cfg(unix)]
+pub fn lock_cache_memory(cache_size: u64) -> std::io::Result<()> {
+	let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+	
+	// SAFETY: limit is valid to write an rlimit to
+	if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	
+	let required_size = cache_size.saturating_add(LOCKED_MEMORY_HEADROOM);
+	
+	// rlim_t is only 32 bits on some platforms
+	#[allow(clippy::unnecessary_cast)]
+	let memlock_limit = limit.rlim_cur as u64;
+	
+	if limit.rlim_cur != libc::RLIM_INFINITY && memlock_limit < required_size {
+		return Err(std::io::Error::other(format!(
+			"the memlock limit of {}B is too low, it needs to be at least {}B or unlimited (see ulimit -l)",
+			utils::abbreviate_number(memlock_limit),
+			utils::abbreviate_number(required_size),
+		)));
+	}
+	
+	// SAFETY: mlockall only changes how the process's memory is paged
+	if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	
 	Ok(())
 }
+
+#[cfg(not(unix))]
+pub fn lock_cache_memory(_cache_size: u64) -> std::io::Result<()> {
+	Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "locking memory is not supported on this platform"))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dedup::{FactorioFileDescription, FactorioFileType};
+	use crate::temp_file::TestDir;
+	
+	const BATCH_LIMIT: BatchLimit = BatchLimit {
+		max_chunks: 512,
+		max_size: None,
+	};
+	
+	fn make_chunks(tag: u8, count: u8) -> Vec<(ChunkKey, Bytes)> {
+		(0..count)
+			.map(|i| {
+				let chunk = Bytes::from(vec![tag, i, 0, 0, 0, 0, 0, 0, 0, 0]);
+				(CONTENT_HASH.hash(&chunk), chunk)
+			})
+			.collect()
+	}
+	
+	async fn join_world(cache: &ChunkCache, world: &[(ChunkKey, Bytes)]) -> HashMap<ChunkKey, Bytes> {
+		let chunks: HashMap<_, _> = world.iter().cloned().collect();
+		let mut requested: Vec<_> = world.iter().map(|&(key, _)| key).collect();
+		let mut local_cache = HashMap::new();
+		
+		while let Some(batch) = cache.get_chunks_batched(&mut requested, &mut local_cache, BATCH_LIMIT).await {
+			let fetched: Vec<_> = batch.batch_keys().iter().map(|key| chunks[key].clone()).collect();
+			
+			for (&key, chunk) in batch.batch_keys().iter().zip(fetched.iter()) {
+				local_cache.insert(key, chunk.clone());
+			}
+			
+			batch.fulfill(&fetched);
+		}
+		
+		local_cache
+	}
+	
+	#[tokio::test]
+	async fn cache_hits_survive_eviction() {
+		let world_a = make_chunks(b'a', 2);
+		let world_b = make_chunks(b'b', 2);
+		let world_c = make_chunks(b'c', 2);
+		
+		// Room for exactly four chunks
+		let cache = ChunkCache::new(40, CacheLimitBasis::Uncompressed);
+		
+		join_world(&cache, &world_a).await;
+		join_world(&cache, &world_b).await;
+		
+		// Rejoining world A should hit the cache and mark its chunks as recently used
+		assert_eq!(join_world(&cache, &world_a).await.len(), world_a.len());
+		
+		// Loading world C evicts two chunks, which should be world B's rather than the older world A's
+		join_world(&cache, &world_c).await;
+		
+		let inner = cache.inner.lock().unwrap();
+		
+		for (key, _) in world_a.iter().chain(world_c.iter()) {
+			assert!(inner.raw_cache.contains(key));
+		}
+		
+		for (key, _) in &world_b {
+			assert!(!inner.raw_cache.contains(key));
+		}
+	}
+	
+	#[test]
+	fn cached_chunks_are_taken_without_batching() {
+		let cached_world = make_chunks(b'a', 3);
+		let new_world = make_chunks(b'b', 2);
+		
+		let cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		cache.insert_chunks(cached_world.clone());
+		
+		let mut requested: Vec<_> = cached_world.iter().chain(&new_world).chain(&cached_world).map(|(key, _)| *key).collect();
+		let mut local_cache = HashMap::new();
+		
+		cache.take_cached_chunks(&mut requested, &mut local_cache);
+		
+		assert_eq!(requested, new_world.iter().map(|(key, _)| *key).collect::<Vec<_>>());
+		assert_eq!(local_cache, cached_world.into_iter().collect());
+		assert!(cache.inner.lock().unwrap().pending_chunks.is_empty());
+	}
+	
+	#[test]
+	fn shared_chunks_reuse_the_cached_copies() {
+		let cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		
+		let mut old_world: LinkedHashMap<_, _> = make_chunks(b'a', 3).into_iter().collect();
+		assert_eq!(cache.share_chunks(old_world.iter_mut()), 0);
+		
+		// The new world's copies of the chunks it has in common with the old one are swapped for the old world's
+		let mut new_world: LinkedHashMap<_, _> = make_chunks(b'a', 2).into_iter().chain(make_chunks(b'b', 2)).collect();
+		assert_eq!(cache.share_chunks(new_world.iter_mut()), 2);
+		
+		for (key, chunk) in &old_world {
+			if let Some(new_chunk) = new_world.get(key) {
+				assert_eq!(new_chunk.as_ptr(), chunk.as_ptr());
+			}
+		}
+		
+		assert_eq!(cache.len(), 5);
+	}
+	
+	#[tokio::test]
+	async fn waiting_on_evicted_chunks_refetches_them() {
+		let world = make_chunks(b'a', 2);
+		let keys: Vec<_> = world.iter().map(|&(key, _)| key).collect();
+		
+		// Nothing fits, like when caching is disabled
+		let cache = ChunkCache::new(0, CacheLimitBasis::Uncompressed);
+		
+		let mut requested_a = keys.clone();
+		let mut local_cache_a = HashMap::new();
+		let batch = cache.get_chunks_batched(&mut requested_a, &mut local_cache_a, BATCH_LIMIT).await.unwrap();
+		
+		let mut requested_b = keys.clone();
+		let mut local_cache_b = HashMap::new();
+		
+		let (waited, ()) = tokio::join!(
+			cache.get_chunks_batched(&mut requested_b, &mut local_cache_b, BATCH_LIMIT),
+			async {
+				let chunks: Vec<_> = world.iter().map(|(_, chunk)| chunk.clone()).collect();
+				batch.fulfill(&chunks);
+			},
+		);
+		
+		assert!(waited.is_none());
+		assert!(local_cache_b.is_empty());
+		assert_eq!(requested_b.len(), keys.len());
+		
+		// The second task can now fetch the chunks itself
+		let batch = cache.get_chunks_batched(&mut requested_b, &mut local_cache_b, BATCH_LIMIT).await.unwrap();
+		assert_eq!(batch.batch_keys().len(), keys.len());
+	}
+	
+	#[tokio::test]
+	async fn partially_fulfilled_batches_stay_pending() {
+		let world = make_chunks(b'a', 3);
+		let keys: Vec<_> = world.iter().map(|&(key, _)| key).collect();
+		
+		let cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		
+		let mut requested = keys.clone();
+		let mut local_cache = HashMap::new();
+		let mut batch = cache.get_chunks_batched(&mut requested, &mut local_cache, BATCH_LIMIT).await.unwrap();
+		
+		batch.fulfill_partial(&world[..2]);
+		assert_eq!(batch.batch_keys(), &keys[2..]);
+		
+		// Another transfer gets the fulfilled chunks, and leaves the one still being fetched alone
+		let mut other_requested = keys.clone();
+		let mut other_local_cache = HashMap::new();
+		assert!(cache.try_get_chunks_batched(&mut other_requested, &mut other_local_cache, BATCH_LIMIT).is_none());
+		assert_eq!(other_local_cache.len(), 2);
+		assert_eq!(other_requested, &keys[2..]);
+		
+		batch.fulfill(&[world[2].1.clone()]);
+		assert_eq!(cache.len(), 3);
+	}
+	
+	#[tokio::test]
+	async fn batches_stop_at_the_size_limit() {
+		let cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		let world = make_chunks(b'a', 7);
+		
+		let estimated_sizes = world.iter()
+			.zip([400, 400, 300, 900, 10, 10, 10])
+			.map(|((key, _), size)| (*key, size))
+			.collect::<HashMap<_, _>>();
+		
+		let batch_limit = BatchLimit {
+			max_chunks: 3,
+			max_size: Some((1000, &estimated_sizes)),
+		};
+		
+		let mut requested: Vec<_> = world.iter().map(|(key, _)| *key).collect();
+		let mut local_cache = HashMap::new();
+		let mut batch_lengths = Vec::new();
+		
+		// A chunk bigger than the limit still gets a batch of its own, and batches never skip ahead to smaller chunks
+		while let Some(batch) = cache.try_get_chunks_batched(&mut requested, &mut local_cache, batch_limit) {
+			batch_lengths.push(batch.batch_keys().len());
+			
+			let chunks = batch.batch_keys().iter().map(|key| world.iter().find(|(k, _)| k == key).unwrap().1.clone()).collect::<Vec<_>>();
+			batch.fulfill(&chunks);
+		}
+		
+		assert_eq!(batch_lengths, [2, 1, 3, 1]);
+		assert_eq!(local_cache.len(), 0);
+		assert!(requested.is_empty());
+	}
+	
+	#[tokio::test]
+	async fn compacted_cache_loads_identically() {
+		let temp_dir = TestDir::new("compact");
+		
+		let cache_path = temp_dir.join("cache");
+		let compacted_path = temp_dir.join("compacted");
+		
+		let entries: Vec<_> = make_chunks(b'a', 8).into_iter().chain(make_chunks(b'b', 8)).collect();
+		write_chunk_cache(&entries, &cache_path, 1).unwrap();
+		
+		assert_eq!(compact_cache_file(&cache_path, &compacted_path, 19).unwrap(), entries.len());
+		
+		let original = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, cache_path).await.unwrap();
+		let compacted = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, compacted_path).await.unwrap();
+		
+		let original_chunks: Vec<_> = original.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		let compacted_chunks: Vec<_> = compacted.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		
+		assert_eq!(original_chunks, entries);
+		assert_eq!(compacted_chunks, entries);
+	}
+	
+	#[tokio::test]
+	async fn saving_the_same_chunks_twice_gives_identical_files() {
+		let temp_dir = TestDir::new("stable-save");
+		
+		let entries: Vec<_> = make_chunks(b'a', 16).into_iter().chain(make_chunks(b'b', 16)).collect();
+		
+		let first = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		let second = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		first.insert_chunks(entries.clone());
+		second.insert_chunks(entries);
+		
+		let paths = [temp_dir.join("first"), temp_dir.join("first-again"), temp_dir.join("second")];
+		
+		first.try_save(paths[0].clone(), CacheLayout::File, true).await.unwrap();
+		first.try_save(paths[1].clone(), CacheLayout::File, true).await.unwrap();
+		second.try_save(paths[2].clone(), CacheLayout::File, true).await.unwrap();
+		
+		let saved_file = std::fs::read(&paths[0]).unwrap();
+		assert_eq!(std::fs::read(&paths[1]).unwrap(), saved_file);
+		assert_eq!(std::fs::read(&paths[2]).unwrap(), saved_file);
+	}
+	
+	#[test]
+	fn cache_diffs_count_each_side() {
+		let temp_dir = TestDir::new("diff");
+		
+		let first_path = temp_dir.join("first");
+		let second_path = temp_dir.join("second");
+		
+		let shared = make_chunks(b'a', 3);
+		let first_entries: Vec<_> = shared.iter().cloned().chain(make_chunks(b'b', 2)).collect();
+		let second_entries: Vec<_> = make_chunks(b'c', 1).into_iter().chain(shared).collect();
+		
+		write_chunk_cache(&first_entries, &first_path, 1).unwrap();
+		write_chunk_cache(&second_entries, &second_path, 1).unwrap();
+		
+		assert_eq!(diff_cache_files(&first_path, &second_path).unwrap(), CacheDiff {
+			only_in_first: ChunkSetSize { chunk_count: 2, total_size: 20 },
+			only_in_second: ChunkSetSize { chunk_count: 1, total_size: 10 },
+			shared: ChunkSetSize { chunk_count: 3, total_size: 30 },
+		});
+	}
+	
+	#[test]
+	fn exported_chunks_import_into_a_new_cache() {
+		let temp_dir = TestDir::new("cas");
+		
+		let cache_path = temp_dir.join("cache");
+		let cas_dir = temp_dir.join("cas");
+		let imported_path = temp_dir.join("imported");
+		
+		let entries = make_chunks(b'a', 8);
+		write_chunk_cache(&entries, &cache_path, 1).unwrap();
+		
+		assert_eq!(export_cas(&cache_path, &cas_dir).unwrap(), (8, 0));
+		assert_eq!(export_cas(&cache_path, &cas_dir).unwrap(), (0, 8));
+		
+		// A chunk that doesn't match its name and a file that isn't a chunk are left out
+		std::fs::write(cas_dir.join(entries[0].0.0.to_hex().as_str()), b"corrupted").unwrap();
+		std::fs::write(cas_dir.join("README"), b"not a chunk").unwrap();
+		
+		assert_eq!(import_cas(&cas_dir, &imported_path).unwrap(), (7, 2));
+		
+		let mut raw_cache = RawChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		read_chunk_cache(&mut raw_cache, &imported_path).unwrap();
+		
+		assert_eq!(raw_cache.chunks.len(), 7);
+		assert!(entries[1..].iter().all(|(key, chunk)| raw_cache.get(key) == Some(chunk)));
+	}
+	
+	#[tokio::test]
+	async fn incompressible_chunks_are_stored_uncompressed() {
+		let temp_dir = TestDir::new("per-chunk");
+		
+		let mut random = vec![0; 10_000];
+		blake3::Hasher::new().update(b"already compressed").finalize_xof().fill(&mut random);
+		let random = Bytes::from(random);
+		let repetitive = Bytes::from(vec![b'r'; 10_000]);
+		
+		let entries = vec![(CONTENT_HASH.hash(&random), random.clone()), (CONTENT_HASH.hash(&repetitive), repetitive)];
+		
+		let cache_path = temp_dir.join("cache");
+		write_chunk_cache(&entries, &cache_path, CHUNK_CACHE_COMPRESSION_LEVEL).unwrap();
+		
+		// The random chunk is stored as is right after its header, and the repetitive one takes up next to nothing
+		let file_data = std::fs::read(&cache_path).unwrap();
+		let header_size = 4 + 1 + 4;
+		let chunk_header_size = 32 + 1 + 4;
+		
+		assert_eq!(file_data[header_size + 32], CHUNK_STORED);
+		assert_eq!(&file_data[header_size + chunk_header_size..][..random.len()], &random[..]);
+		assert!(file_data.len() < header_size + 2 * chunk_header_size + random.len() + 100);
+		
+		let loaded = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, cache_path).await.unwrap();
+		let loaded_chunks: Vec<_> = loaded.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		assert_eq!(loaded_chunks, entries);
+		
+		// Cache files from before chunks were compressed one by one still load
+		let mut data = Vec::new();
+		data.extend_from_slice(&CHUNK_CACHE_MAGIC);
+		data.push(CONTENT_HASH.id());
+		data.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+		
+		for (key, chunk) in &entries {
+			data.extend_from_slice(key.0.as_bytes());
+			data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+			data.extend_from_slice(chunk);
+		}
+		
+		let stream_path = temp_dir.join("stream-cache");
+		std::fs::write(&stream_path, zstd::encode_all(&data[..], 1).unwrap()).unwrap();
+		
+		let loaded = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, stream_path).await.unwrap();
+		let loaded_chunks: Vec<_> = loaded.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		assert_eq!(loaded_chunks, entries);
+	}
+	
+	#[tokio::test]
+	async fn cache_files_without_a_header_load_as_blake3() {
+		let temp_dir = TestDir::new("legacy");
+		let cache_path = temp_dir.join("cache");
+		let entries = make_chunks(b'a', 4);
+		
+		let mut data = Vec::new();
+		data.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+		
+		for (key, chunk) in &entries {
+			data.extend_from_slice(key.0.as_bytes());
+			data.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+			data.extend_from_slice(chunk);
+		}
+		
+		std::fs::write(&cache_path, zstd::encode_all(&data[..], 1).unwrap()).unwrap();
+		
+		let cache = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, cache_path.clone()).await.unwrap();
+		let chunks: Vec<_> = cache.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		
+		assert_eq!(chunks, entries);
+	}
+	
+	#[tokio::test]
+	async fn interrupted_saves_leave_the_cache_intact() {
+		let temp_dir = TestDir::new("interrupted");
+		
+		let cache_path = temp_dir.join("cache");
+		let temp_path = cache_path.with_extension("tmp");
+		
+		let entries = make_chunks(b'a', 4);
+		let cache = Arc::new(ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed));
+		cache.insert_chunks(entries.clone());
+		cache.try_save(cache_path.clone(), CacheLayout::File, true).await.unwrap();
+		
+		assert!(!temp_path.exists());
+		
+		// A save that died partway through leaves a truncated temp file behind
+		std::fs::write(&temp_path, b"truncated").unwrap();
+		
+		let loaded = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, cache_path).await.unwrap();
+		let chunks: Vec<_> = loaded.inner.lock().unwrap().raw_cache.chunks.clone().into_iter().collect();
+		
+		assert_eq!(chunks, entries);
+		assert!(!temp_path.exists());
+	}
+	
+	#[tokio::test]
+	async fn saves_that_run_out_of_space_are_retried() {
+		let temp_dir = TestDir::new("disk-full");
+		
+		let cache_path = temp_dir.join("cache");
+		let temp_path = cache_path.with_extension("tmp");
+		
+		let cache = Arc::new(ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed));
+		cache.insert_chunks(make_chunks(b'a', 4));
+		cache.try_save(cache_path.clone(), CacheLayout::File, true).await.unwrap();
+		let saved_file = std::fs::read(&cache_path).unwrap();
+		
+		cache.insert_chunks(make_chunks(b'b', 4));
+		
+		// A directory where the temp file goes makes the write fail, like a full disk would
+		std::fs::create_dir(&temp_path).unwrap();
+		assert!(cache.try_save(cache_path.clone(), CacheLayout::File, false).await.is_err());
+		assert_eq!(std::fs::read(&cache_path).unwrap(), saved_file);
+		assert!(cache.inner.lock().unwrap().needs_saving);
+		
+		std::fs::remove_dir(&temp_path).unwrap();
+		assert!(cache.try_save(cache_path.clone(), CacheLayout::File, false).await.unwrap().is_some());
+		
+		let disk_full = anyhow::Error::from(std::io::Error::from(std::io::ErrorKind::StorageFull)).context("Saving");
+		assert!(is_disk_full(&disk_full));
+		assert!(!is_disk_full(&anyhow::anyhow!("Something else")));
+	}
+	
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn writes_to_a_full_disk_are_recognized() {
+		let err = write_chunk_cache(&make_chunks(b'a', 4), Path::new("/dev/full"), 1).unwrap_err();
+		assert!(is_disk_full(&err), "{:?}", err);
+	}
+	
+	#[tokio::test]
+	async fn cold_tiers_are_read_but_never_written() {
+		let temp_dir = TestDir::new("cold-tier");
+		let cold_path = temp_dir.join("cold-cache");
+		
+		let cold_world = make_chunks(b'a', 4);
+		let new_world = make_chunks(b'b', 2);
+		
+		write_chunk_cache(&cold_world, &cold_path, 1).unwrap();
+		let cold_file = std::fs::read(&cold_path).unwrap();
+		
+		let mut cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		assert_eq!(cache.add_cold_tier(cold_path.clone()).await.unwrap(), cold_world.len());
+		
+		// Chunks that appear more than once in a world only count once
+		let world_desc = FactorioWorldDescription {
+			files: vec![FactorioFileDescription {
+				file_type: FactorioFileType::Normal,
+				file_name: "level.dat0".to_owned(),
+				content_size: 0,
+				content_chunks: cold_world.iter().chain(&new_world).chain(&cold_world).map(|&(key, _)| key).collect(),
+			}],
+			aux_data: Bytes::new(),
+		};
+		
+		assert_eq!(cache.coverage(&world_desc), CacheCoverage { cached_chunks: 4, total_chunks: 6 });
+		
+		let mut requested: Vec<_> = cold_world.iter().chain(&new_world).map(|&(key, _)| key).collect();
+		let mut local_cache = HashMap::new();
+		
+		let batch = cache.get_chunks_batched(&mut requested, &mut local_cache, BATCH_LIMIT).await.unwrap();
+		assert_eq!(local_cache.len(), cold_world.len());
+		assert_eq!(batch.batch_keys(), new_world.iter().map(|&(key, _)| key).collect::<Vec<_>>());
+		
+		batch.fulfill(&new_world.iter().map(|(_, chunk)| chunk.clone()).collect::<Vec<_>>());
+		
+		// Only the fetched chunks went into the cache itself, and the cold tier's file is untouched
+		assert!(cache.coverage(&world_desc).is_complete());
+		assert_eq!(cache.len(), new_world.len());
+		assert_eq!(cache.insert_chunks(cold_world.clone()), 0);
+		assert_eq!(std::fs::read(&cold_path).unwrap(), cold_file);
+	}
+	
+	#[tokio::test]
+	async fn sharded_saves_only_rewrite_changed_shards() {
+		let temp_dir = TestDir::new("sharded");
+		let cache_dir = temp_dir.join("cache");
+		
+		let entries = make_chunks(b'a', 32);
+		let cache = ChunkCache::new(u64::MAX, CacheLimitBasis::Uncompressed);
+		cache.insert_chunks(entries.clone());
+		cache.try_save(cache_dir.clone(), CacheLayout::Sharded, false).await.unwrap();
+		
+		// Removing a shard that hasn't changed since shows whether the next save rewrote it
+		let untouched_shard = shard_path(&cache_dir, shard_index(&entries[0].0));
+		std::fs::remove_file(&untouched_shard).unwrap();
+		
+		let new_chunk = make_chunks(b'b', 1).into_iter()
+			.find(|(key, _)| shard_index(key) != shard_index(&entries[0].0))
+			.unwrap();
+		cache.insert_chunks(vec![new_chunk.clone()]);
+		cache.try_save(cache_dir.clone(), CacheLayout::Sharded, false).await.unwrap();
+		
+		assert!(!untouched_shard.exists());
+		assert!(shard_path(&cache_dir, shard_index(&new_chunk.0)).exists());
+		
+		// A corrupt shard only loses its own chunks, and gets rewritten by the next save
+		let corrupt_shard = shard_index(&new_chunk.0);
+		std::fs::write(shard_path(&cache_dir, corrupt_shard), b"corrupt").unwrap();
+		
+		let loaded = ChunkCache::load_sharded(u64::MAX, CacheLimitBasis::Uncompressed, cache_dir.clone()).await.unwrap();
+		let inner = loaded.inner.lock().unwrap();
+		
+		let expected_keys = entries.iter().chain([&new_chunk])
+			.map(|(key, _)| key)
+			.filter(|key| shard_index(key) != shard_index(&entries[0].0) && shard_index(key) != corrupt_shard)
+			.collect::<HashSet<_>>();
+		
+		assert_eq!(inner.raw_cache.chunks.keys().collect::<HashSet<_>>(), expected_keys);
+		assert!(inner.needs_saving);
+		assert_eq!(inner.raw_cache.changed_shards.iter().filter(|&&changed| changed).count(), 1);
+		assert!(inner.raw_cache.changed_shards[corrupt_shard]);
+		
+		// Using a chunk only reorders it, which isn't worth rewriting its shard for
+		let (used_key, used_chunk) = entries.iter().find(|(key, _)| expected_keys.contains(key)).unwrap();
+		
+		drop(inner);
+		assert_eq!(loaded.share_chunks([(used_key, &mut used_chunk.clone())]), 1);
+		
+		let inner = loaded.inner.lock().unwrap();
+		assert_eq!(inner.raw_cache.changed_shards.iter().filter(|&&changed| changed).count(), 1);
+	}
+	
+	#[test]
+	fn save_intervals_stay_within_the_jitter() {
+		let interval = Duration::from_secs(60);
+		
+		for _ in 0..100 {
+			let jittered = jittered_interval(interval, 0.1);
+			assert!(jittered >= Duration::from_secs(54) && jittered <= Duration::from_secs(66), "{:?}", jittered);
+		}
+		
+		assert_eq!(jittered_interval(interval, 0.0), interval);
+	}
+	
+	#[tokio::test]
+	async fn pinned_chunks_survive_eviction() {
+		let world_a = make_chunks(b'a', 2);
+		let world_b = make_chunks(b'b', 2);
+		
+		// Room for only one chunk
+		let cache = ChunkCache::new(10, CacheLimitBasis::Uncompressed);
+		
+		let keys_a: Vec<_> = world_a.iter().map(|&(key, _)| key).collect();
+		let pins = cache.pin_chunks(&keys_a);
+		
+		join_world(&cache, &world_a).await;
+		join_world(&cache, &world_b).await;
+		
+		{
+			let inner = cache.inner.lock().unwrap();
+			
+			// The pinned chunks push the cache over its limit rather than being evicted
+			assert!(keys_a.iter().all(|key| inner.raw_cache.contains(key)));
+			assert_eq!(inner.raw_cache.total_size, 20);
+		}
+		
+		drop(pins);
+		
+		let inner = cache.inner.lock().unwrap();
+		
+		assert_eq!(inner.raw_cache.total_size, 10);
+		assert_eq!(inner.raw_cache.pinned_size, 0);
+	}
+	
+	#[test]
+	fn compressed_limit_fits_more_compressible_chunks() {
+		let chunks: Vec<_> = (0..8u8)
+			.map(|i| {
+				let chunk = Bytes::from(vec![i; 1000]);
+				(CONTENT_HASH.hash(&chunk), chunk)
+			})
+			.collect();
+		
+		let mut uncompressed = RawChunkCache::new(4000, CacheLimitBasis::Uncompressed);
+		let mut compressed = RawChunkCache::new(4000, CacheLimitBasis::Compressed);
+		
+		for (key, chunk) in &chunks {
+			uncompressed.insert(*key, chunk.clone());
+			compressed.insert(*key, chunk.clone());
+		}
+		
+		assert_eq!(uncompressed.chunks.len(), 4);
+		assert_eq!(compressed.chunks.len(), 8);
+		assert_eq!(compressed.total_size, 8000);
+		assert!(compressed.limited_size < 4000);
+	}
+}
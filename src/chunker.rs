@@ -88,3 +88,51 @@ impl<'a> Iterator for Chunker<'a> {
 		Some(chunk)
 	}
 }
+
+/// Generates data for benchmarking that's shaped like a world's level data, made of records that repeat with small
+///  differences, some runs of unique bytes, and repeats of larger earlier stretches.
+pub fn generate_bench_data(size: usize) -> Vec<u8> {
+	// xorshift, so that the same data is generated every time
+	let mut state: u64 = 0x2545F4914F6CDD1D;
+	let mut next_random = move || {
+		state ^= state << 13;
+		state ^= state >> 7;
+		state ^= state << 17;
+		state
+	};
+	
+	let records = (0..256)
+		.map(|_| {
+			let length = 16 + (next_random() % 240) as usize;
+			(0..length).map(|_| next_random() as u8).collect::<Vec<_>>()
+		})
+		.collect::<Vec<_>>();
+	
+	let mut data = Vec::with_capacity(size + 256);
+	
+	while data.len() < size {
+		let random = next_random();
+		
+		if random % 16 == 0 {
+			let length = (random >> 8) as usize % 1024;
+			data.extend((0..length).map(|_| next_random() as u8));
+		} else if random % 16 == 1 && data.len() > 1 << 16 {
+			// Repeat an earlier stretch, like an area of the map that's laid out the same as another
+			let length = (1 << 12) + (random >> 8) as usize % (1 << 14);
+			let start = (random >> 32) as usize % (data.len() - length);
+			data.extend_from_within(start..start + length);
+		} else {
+			let start = data.len();
+			data.extend_from_slice(&records[(random >> 8) as usize % records.len()]);
+			
+			// Tweak a byte, like an entity whose position or health differs
+			if random % 4 == 0 {
+				let offset = start + (random >> 24) as usize % (data.len() - start);
+				data[offset] = (random >> 32) as u8;
+			}
+		}
+	}
+	
+	data.truncate(size);
+	data
+}
@@ -0,0 +1,60 @@
+use crate::dedup::ChunkKey;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The algorithm used to address chunks by their contents.
+pub const CONTENT_HASH: HashAlgorithm = Blake3Hasher::ALGORITHM;
+
+/// A hash function that chunks are addressed by.
+pub trait ContentHasher {
+	const ALGORITHM: HashAlgorithm;
+	
+	fn hash(data: &[u8]) -> ChunkKey;
+}
+
+pub struct Blake3Hasher;
+
+impl ContentHasher for Blake3Hasher {
+	const ALGORITHM: HashAlgorithm = HashAlgorithm::Blake3;
+	
+	fn hash(data: &[u8]) -> ChunkKey {
+		ChunkKey(blake3::hash(data))
+	}
+}
+
+/// Identifies a ContentHasher, so that keys made by different algorithms are never mixed up.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Default, Deserialize, Serialize)]
+pub enum HashAlgorithm {
+	#[default]
+	Blake3,
+}
+
+impl HashAlgorithm {
+	pub fn hash(self, data: &[u8]) -> ChunkKey {
+		match self {
+			HashAlgorithm::Blake3 => Blake3Hasher::hash(data),
+		}
+	}
+	
+	/// The id written to cache files.
+	pub fn id(self) -> u8 {
+		match self {
+			HashAlgorithm::Blake3 => 0,
+		}
+	}
+	
+	pub fn from_id(id: u8) -> Option<Self> {
+		match id {
+			0 => Some(HashAlgorithm::Blake3),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for HashAlgorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			HashAlgorithm::Blake3 => f.write_str("blake3"),
+		}
+	}
+}
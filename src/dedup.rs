@@ -1,16 +1,23 @@
 use crate::chunker::Chunker;
-use crate::factorio_protocol::{FACTORIO_CRC, FACTORIO_REV_CRC, TRANSFER_BLOCK_SIZE};
+use crate::content_hash::CONTENT_HASH;
+use crate::factorio_protocol::{self, FACTORIO_CRC, FACTORIO_REV_CRC};
 use crate::rev_crc;
 use crate::zip_writer::ZipWriter;
 use bytes::{BufMut, Bytes, BytesMut};
+use hashlink::LinkedHashMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::Infallible;
 use std::io::{Cursor, Read};
+use std::str::FromStr;
 use zip::ZipArchive;
 
 pub const RECONSTRUCT_DEFLATE_LEVEL: u8 = 1;
 
+/// Files that are already compressed don't deduplicate, so by default they're sent whole.
+const DEFAULT_WHOLE_FILE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg"];
+
 #[derive(Deserialize, Serialize)]
 pub struct FactorioWorldDescription {
 	pub files: Vec<FactorioFileDescription>,
@@ -36,13 +43,56 @@ pub struct FactorioFile<'a> {
 	pub data: Cow<'a, [u8]>,
 }
 
+/// Which files in a world are split into chunks. The rest are kept whole as a single chunk, which saves describing and
+///  requesting lots of chunks for files that won't deduplicate anyway.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChunkingPolicy {
+	whole_file_extensions: Vec<String>,
+}
+
+impl ChunkingPolicy {
+	fn is_whole_file(&self, file_name: &str) -> bool {
+		let name = file_name.rsplit_once('/').map(|(_, last)| last).unwrap_or(file_name);
+		
+		name.rsplit_once('.').is_some_and(|(_, extension)| {
+			self.whole_file_extensions.iter().any(|whole_file_extension| whole_file_extension.eq_ignore_ascii_case(extension))
+		})
+	}
+}
+
+impl Default for ChunkingPolicy {
+	fn default() -> Self {
+		Self {
+			whole_file_extensions: DEFAULT_WHOLE_FILE_EXTENSIONS.iter().map(|&extension| extension.to_owned()).collect(),
+		}
+	}
+}
+
+/// Parses a comma separated list of the extensions of files to keep whole, where an empty list chunks every file.
+impl FromStr for ChunkingPolicy {
+	type Err = Infallible;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(Self {
+			whole_file_extensions: s.split(',')
+				.map(|extension| extension.trim().trim_start_matches('.'))
+				.filter(|extension| !extension.is_empty())
+				.map(str::to_owned)
+				.collect(),
+		})
+	}
+}
+
 pub fn deconstruct_world(
 	world_data: &[u8],
 	aux_data: &[u8],
-) -> anyhow::Result<(FactorioWorldDescription, HashMap<ChunkKey, Bytes>)> {
+	chunking_policy: &ChunkingPolicy,
+) -> anyhow::Result<(FactorioWorldDescription, LinkedHashMap<ChunkKey, Bytes>)> {
 	let mut zip_reader = ZipArchive::new(Cursor::new(&world_data))?;
 	
-	let mut chunks = HashMap::new();
+	// Chunks are kept in the order they first appear in the world so that anything built from them has a stable
+	//  layout from one deconstruction to the next
+	let mut chunks = LinkedHashMap::new();
 	let mut files = Vec::new();
 	
 	let mut buf = Vec::new();
@@ -55,7 +105,12 @@ pub fn deconstruct_world(
 		
 		let decoded_file = decode_factorio_file(zip_file.name(), &buf)?;
 		
-		files.push(chunk_file(zip_file.name(), &decoded_file, &mut chunks)?);
+		let file_desc = match chunking_policy.is_whole_file(zip_file.name()) {
+			true => whole_file(zip_file.name(), &decoded_file, &mut chunks),
+			false => chunk_file(zip_file.name(), &decoded_file, &mut chunks)?,
+		};
+		
+		files.push(file_desc);
 	}
 	
 	let world = FactorioWorldDescription {
@@ -87,34 +142,34 @@ impl WorldReconstructor {
 		chunks: &HashMap<ChunkKey, Bytes>,
 		buf: &mut BytesMut,
 	) -> Result<[Bytes; 2], NeedsMoreData> {
-		buf.clear();
-		
-		for &chunk_key in &file_desc.content_chunks {
-			if let Some(chunk) = chunks.get(&chunk_key) {
-				buf.put_slice(chunk);
-			} else {
-				return Err(NeedsMoreData);
-			}
-		}
+		gather_file_content(file_desc, chunks, buf)?;
 		
 		let file = FactorioFile {
 			file_type: file_desc.file_type,
-			data: Cow::Borrowed(&buf),
+			data: Cow::Borrowed(buf),
 		};
 		
-		let file_data = encode_factorio_file(&file);
-		let header = self.zip_writer.encode_file_header(&file_desc.file_name, &file_data);
+		let file_data = encode_factorio_file(&file).into_owned().into();
+		
+		Ok(self.add_encoded_file(&file_desc.file_name, file_data))
+	}
+	
+	/// Adds a file that was already encoded with encode_file_content, returning its zip header and data. Files have to
+	///  be added in the order they're described in.
+	pub fn add_encoded_file(&mut self, file_name: &str, file_data: Bytes) -> [Bytes; 2] {
+		let header = self.zip_writer.encode_file_header(file_name, &file_data);
 		
 		self.crc_hasher.update(&header);
 		self.crc_hasher.update(&file_data);
 		
-		Ok([header, file_data.into_owned().into()])
+		[header, file_data]
 	}
 	
 	pub fn finalize_world_file(mut self,
 		world_desc: &FactorioWorldDescription,
 		target_world_size: usize,
 		target_crc: u32,
+		transfer_block_size: u32,
 	) -> anyhow::Result<Bytes> {
 		let current_size = self.zip_writer.current_size();
 		let zip_footer_size = self.zip_writer.central_directory_size();
@@ -168,11 +223,11 @@ impl WorldReconstructor {
 		
 		// Now align the world data to the nearest block
 		
-		let world_block_count = (target_world_size as u32 + TRANSFER_BLOCK_SIZE - 1) / TRANSFER_BLOCK_SIZE;
-		let aux_block_count = (world_desc.aux_data.len() as u32 + TRANSFER_BLOCK_SIZE - 1) / TRANSFER_BLOCK_SIZE;
+		let world_block_count = factorio_protocol::transfer_block_count(target_world_size as u32, transfer_block_size);
+		let aux_block_count = factorio_protocol::transfer_block_count(world_desc.aux_data.len() as u32, transfer_block_size);
 		
-		let world_aligned_length = (world_block_count * TRANSFER_BLOCK_SIZE) as usize;
-		let aux_aligned_length = (aux_block_count * TRANSFER_BLOCK_SIZE) as usize;
+		let world_aligned_length = (world_block_count * transfer_block_size) as usize;
+		let aux_aligned_length = (aux_block_count * transfer_block_size) as usize;
 		
 		output.resize(world_aligned_length - target_world_size + output.len(), 0);
 		
@@ -181,12 +236,45 @@ impl WorldReconstructor {
 		output.resize(aux_aligned_length - world_desc.aux_data.len() + output.len(), 0);
 		
 		// Verify that the final data is properly aligned to the nearest block
-		assert_eq!((current_size + output.len()) % TRANSFER_BLOCK_SIZE as usize, 0);
+		assert_eq!((current_size + output.len()) % transfer_block_size as usize, 0);
 		
 		Ok(output.freeze())
 	}
 }
 
+/// Joins a file's chunks back together into buf.
+pub fn gather_file_content(
+	file_desc: &FactorioFileDescription,
+	chunks: &HashMap<ChunkKey, Bytes>,
+	buf: &mut BytesMut,
+) -> Result<(), NeedsMoreData> {
+	buf.clear();
+	
+	for &chunk_key in &file_desc.content_chunks {
+		if let Some(chunk) = chunks.get(&chunk_key) {
+			buf.put_slice(chunk);
+		} else {
+			return Err(NeedsMoreData);
+		}
+	}
+	
+	Ok(())
+}
+
+/// Encodes a file's content the way it's stored in the world zip. This is slow for compressed files, so it should be
+///  run with spawn_blocking.
+pub fn encode_file_content(file_type: FactorioFileType, content: Bytes) -> Bytes {
+	let file = FactorioFile {
+		file_type,
+		data: Cow::Borrowed(&content),
+	};
+	
+	match encode_factorio_file(&file) {
+		Cow::Borrowed(_) => content.clone(),
+		Cow::Owned(file_data) => file_data.into(),
+	}
+}
+
 pub fn decode_factorio_file<'a>(file_name: &str, file_data: &'a [u8]) -> anyhow::Result<FactorioFile<'a>> {
 	let name = file_name.rsplit_once('/').map(|(_, last)| last).unwrap_or(file_name);
 	
@@ -216,16 +304,16 @@ pub fn encode_factorio_file<'a>(file: &'a FactorioFile<'a>) -> Cow<'a, [u8]> {
 	}
 }
 
-pub fn chunk_file(file_name: &str, file: &FactorioFile, chunks: &mut HashMap<ChunkKey, Bytes>) -> anyhow::Result<FactorioFileDescription> {
+pub fn chunk_file(file_name: &str, file: &FactorioFile, chunks: &mut LinkedHashMap<ChunkKey, Bytes>) -> anyhow::Result<FactorioFileDescription> {
 	let chunker = Chunker::new(&file.data);
 	
 	let mut content_chunks = Vec::new();
 	
 	for chunk in chunker {
-		let hash = ChunkKey(blake3::hash(chunk));
+		let hash = CONTENT_HASH.hash(chunk);
 		
 		content_chunks.push(hash);
-		chunks.insert(hash, chunk.to_vec().into());
+		chunks.entry(hash).or_insert_with(|| chunk.to_vec().into());
 	}
 	
 	Ok(FactorioFileDescription {
@@ -236,6 +324,27 @@ pub fn chunk_file(file_name: &str, file: &FactorioFile, chunks: &mut HashMap<Chu
 	})
 }
 
+/// Describes a file as a single chunk of its whole content, which reconstructs the same way as a chunked file.
+fn whole_file(file_name: &str, file: &FactorioFile, chunks: &mut LinkedHashMap<ChunkKey, Bytes>) -> FactorioFileDescription {
+	let mut content_chunks = Vec::new();
+	
+	// Empty files have no chunks, just like when they're chunked
+	if !file.data.is_empty() {
+		let hash = CONTENT_HASH.hash(&file.data);
+		
+		content_chunks.push(hash);
+		chunks.entry(hash).or_insert_with(|| file.data.to_vec().into());
+	}
+	
+	FactorioFileDescription {
+		file_type: file.file_type,
+		file_name: file_name.to_owned(),
+		content_size: file.data.len() as u64,
+		content_chunks,
+	}
+}
+
+/// The content hash of a chunk. Which algorithm made it is tracked alongside the keys, see content_hash.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct ChunkKey(pub blake3::Hash);
 
@@ -255,4 +364,177 @@ impl Serialize for ChunkKey {
 	{
 		serializer.serialize_bytes(self.0.as_bytes())
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	use zip::write::SimpleFileOptions;
+	
+	fn make_save() -> Vec<u8> {
+		let mut level_data = vec![0u8; 200_000];
+		blake3::Hasher::new().update(b"level data").finalize_xof().fill(&mut level_data);
+		let level_data_zlib = miniz_oxide::deflate::compress_to_vec_zlib(&level_data, 6);
+		
+		let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+		let deflated = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+		
+		writer.start_file("test-save/control.lua", deflated).unwrap();
+		writer.write_all(b"script.on_init(function() end)\n").unwrap();
+		writer.start_file("test-save/level.dat0", deflated).unwrap();
+		writer.write_all(&level_data_zlib).unwrap();
+		writer.start_file("test-save/level-init.dat", deflated).unwrap();
+		writer.write_all(&level_data[..10_000]).unwrap();
+		writer.start_file("test-save/preview.png", SimpleFileOptions::default()).unwrap();
+		writer.write_all(&level_data[..20_000]).unwrap();
+		
+		writer.finish().unwrap().into_inner()
+	}
+	
+	fn assert_round_trips(aux_data: &[u8]) {
+		let world_data = make_save();
+		
+		let target_world_size = world_data.len() * 2;
+		let target_crc = FACTORIO_CRC.checksum(&world_data);
+		
+		let (world_desc, chunks) = deconstruct_world(&world_data, aux_data, &ChunkingPolicy::default()).unwrap();
+		let chunks: HashMap<_, _> = chunks.into_iter().collect();
+		
+		let mut reconstructor = WorldReconstructor::new();
+		let mut reconstructed = BytesMut::new();
+		let mut buf = BytesMut::new();
+		
+		for file_desc in &world_desc.files {
+			let Ok(data_blocks) = reconstructor.reconstruct_world_file(file_desc, &chunks, &mut buf) else {
+				panic!("Missing chunks for {}", file_desc.file_name);
+			};
+			
+			for data in data_blocks {
+				reconstructed.put_slice(&data);
+			}
+		}
+		
+		let last_data = reconstructor.finalize_world_file(&world_desc, target_world_size, target_crc, factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE).unwrap();
+		reconstructed.put_slice(&last_data);
+		
+		let reconstructed_world = &reconstructed[..target_world_size];
+		
+		// The whole world plus aux data must carry the forged CRC that the Factorio client checks
+		let mut crc_hasher = FACTORIO_CRC.digest();
+		crc_hasher.update(reconstructed_world);
+		crc_hasher.update(aux_data);
+		assert_eq!(crc_hasher.finalize(), target_crc);
+		
+		let mut original = ZipArchive::new(Cursor::new(&world_data)).unwrap();
+		let mut reconstructed = ZipArchive::new(Cursor::new(reconstructed_world)).unwrap();
+		
+		assert_eq!(original.len(), reconstructed.len());
+		
+		for i in 0..original.len() {
+			let mut original_file = original.by_index(i).unwrap();
+			let mut reconstructed_file = reconstructed.by_index(i).unwrap();
+			
+			assert_eq!(original_file.name(), reconstructed_file.name());
+			
+			let mut original_content = Vec::new();
+			let mut reconstructed_content = Vec::new();
+			
+			// Reading to the end also makes the zip crate check each entry's CRC against its contents
+			original_file.read_to_end(&mut original_content).unwrap();
+			reconstructed_file.read_to_end(&mut reconstructed_content).unwrap();
+			
+			let original_decoded = decode_factorio_file(original_file.name(), &original_content).unwrap();
+			let reconstructed_decoded = decode_factorio_file(reconstructed_file.name(), &reconstructed_content).unwrap();
+			
+			assert_eq!(original_decoded.file_type, reconstructed_decoded.file_type);
+			assert_eq!(original_decoded.data, reconstructed_decoded.data);
+			
+			// Files that aren't recompressed are stored verbatim, so their entry CRCs match the original save's
+			if original_decoded.file_type == FactorioFileType::Normal {
+				assert_eq!(original_file.crc32(), reconstructed_file.crc32());
+			}
+		}
+	}
+	
+	#[test]
+	fn reconstructed_world_round_trips() {
+		assert_round_trips(b"auxiliary data");
+	}
+	
+	#[test]
+	fn world_without_aux_data_round_trips() {
+		assert_round_trips(b"");
+	}
+	
+	#[test]
+	fn files_are_kept_whole_by_extension() {
+		let world_data = make_save();
+		
+		let chunk_counts = |chunking_policy: &ChunkingPolicy| {
+			let (world_desc, _) = deconstruct_world(&world_data, b"", chunking_policy).unwrap();
+			
+			world_desc.files.iter()
+				.map(|file| (file.file_name.clone(), file.content_chunks.len()))
+				.collect::<HashMap<_, _>>()
+		};
+		
+		let default_counts = chunk_counts(&ChunkingPolicy::default());
+		assert_eq!(default_counts["test-save/preview.png"], 1);
+		assert!(default_counts["test-save/level-init.dat"] > 1);
+		
+		let chunked_counts = chunk_counts(&"".parse().unwrap());
+		assert!(chunked_counts["test-save/preview.png"] > 1);
+		
+		let custom_counts = chunk_counts(&" .DAT , lua".parse().unwrap());
+		assert_eq!(custom_counts["test-save/level-init.dat"], 1);
+		assert!(custom_counts["test-save/preview.png"] > 1);
+		assert!(custom_counts["test-save/level.dat0"] > 1);
+	}
+	
+	#[test]
+	fn separately_encoded_files_match_reconstructed_ones() {
+		let (world_desc, chunks) = deconstruct_world(&make_save(), b"", &ChunkingPolicy::default()).unwrap();
+		let chunks: HashMap<_, _> = chunks.into_iter().collect();
+		
+		let mut buf = BytesMut::new();
+		
+		let mut reconstructor = WorldReconstructor::new();
+		let reconstructed = world_desc.files.iter()
+			.flat_map(|file_desc| reconstructor.reconstruct_world_file(file_desc, &chunks, &mut buf).ok().unwrap())
+			.collect::<Vec<_>>();
+		
+		// Encoding can finish in any order, as long as the files are added in order
+		let mut encoded_files = world_desc.files.iter().rev()
+			.map(|file_desc| {
+				gather_file_content(file_desc, &chunks, &mut buf).ok().unwrap();
+				encode_file_content(file_desc.file_type, buf.split().freeze())
+			})
+			.collect::<Vec<_>>();
+		encoded_files.reverse();
+		
+		let mut reconstructor = WorldReconstructor::new();
+		let added = world_desc.files.iter().zip(encoded_files)
+			.flat_map(|(file_desc, file_data)| reconstructor.add_encoded_file(&file_desc.file_name, file_data))
+			.collect::<Vec<_>>();
+		
+		assert_eq!(added, reconstructed);
+	}
+	
+	#[test]
+	fn empty_worlds_are_rejected() {
+		// An empty world isn't a valid zip, so both sides have to fail cleanly rather than slice out of bounds
+		assert!(deconstruct_world(b"", b"", &ChunkingPolicy::default()).is_err());
+		assert!(deconstruct_world(b"", b"auxiliary data", &ChunkingPolicy::default()).is_err());
+		
+		for aux_data in [Bytes::new(), Bytes::from_static(b"auxiliary data")] {
+			let world_desc = FactorioWorldDescription {
+				files: Vec::new(),
+				aux_data,
+			};
+			
+			let result = WorldReconstructor::new().finalize_world_file(&world_desc, 0, 0, factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE);
+			assert!(result.is_err());
+		}
+	}
+}
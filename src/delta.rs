@@ -0,0 +1,282 @@
+use crate::chunker::RabinKarpHash;
+use crate::dedup::ChunkKey;
+use anyhow::anyhow;
+use bytes::Bytes;
+use hashlink::LinkedHashMap;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use zstd::zstd_safe::{self, CCtx, CParameter, DCtx};
+
+/// How many min-hashes make up a chunk's sketch.
+const FEATURE_COUNT: usize = 4;
+/// Chunks sharing at least this many features are considered similar enough to diff against each other.
+const MIN_MATCHING_FEATURES: usize = 2;
+/// How many chunks are remembered for each feature value, so that a chunk that's in a lot of worlds can't crowd out
+///  the others.
+const MAX_KEYS_PER_FEATURE: usize = 4;
+/// Chunks smaller than this are mostly zstd frame overhead as deltas, so they're always sent whole.
+const MIN_DELTA_CHUNK_SIZE: usize = 256;
+/// Deltas claiming to decompress to more than this are rejected, chunks are never anywhere near it.
+const MAX_DELTA_CHUNK_SIZE: u64 = 1 << 20;
+
+const DELTA_COMPRESSION_LEVEL: i32 = 11;
+
+const FEATURE_SEEDS: [u64; FEATURE_COUNT] = [
+	0x243F6A8885A308D3,
+	0x13198A2E03707344,
+	0xA4093822299F31D0,
+	0x082EFA98EC4E6C89,
+];
+
+/// A sketch of a chunk's contents, where similar chunks are likely to share some of the features.
+type Features = [u64; FEATURE_COUNT];
+
+/// Computes a min-hash of the rolling hashes of every window in the chunk for each seed. A small edit only changes the
+///  windows around it, so most of the minimums survive.
+fn features(chunk: &[u8]) -> Features {
+	let mut rolling_hash = RabinKarpHash::new();
+	let mut features = [u64::MAX; FEATURE_COUNT];
+	
+	for (index, &byte) in chunk.iter().enumerate() {
+		let hash = rolling_hash.update(byte);
+		
+		if index + 1 < RabinKarpHash::WINDOW_SIZE {
+			continue;
+		}
+		
+		for (feature, seed) in features.iter_mut().zip(FEATURE_SEEDS) {
+			*feature = (*feature).min(mix(hash as u64 ^ seed));
+		}
+	}
+	
+	features
+}
+
+/// The splitmix64 finalizer, so that each seed orders the window hashes differently.
+fn mix(mut value: u64) -> u64 {
+	value = (value ^ (value >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+	value = (value ^ (value >> 27)).wrapping_mul(0x94D049BB133111EB);
+	value ^ (value >> 31)
+}
+
+/// Encodes a chunk as the difference from a similar reference chunk, using the reference as a zstd prefix like
+///  `zstd --patch-from` does.
+pub fn encode_delta(reference: &[u8], chunk: &[u8]) -> anyhow::Result<Bytes> {
+	let mut cctx = CCtx::create();
+	cctx.set_parameter(CParameter::CompressionLevel(DELTA_COMPRESSION_LEVEL)).map_err(zstd_error)?;
+	cctx.ref_prefix(reference).map_err(zstd_error)?;
+	
+	let mut delta = Vec::with_capacity(zstd_safe::compress_bound(chunk.len()));
+	cctx.compress2(&mut delta, chunk).map_err(zstd_error)?;
+	
+	Ok(delta.into())
+}
+
+pub fn decode_delta(reference: &[u8], delta: &[u8]) -> anyhow::Result<Bytes> {
+	let chunk_size = zstd_safe::get_frame_content_size(delta)
+		.map_err(|_| anyhow!("Invalid chunk delta"))?
+		.filter(|&size| size <= MAX_DELTA_CHUNK_SIZE)
+		.ok_or_else(|| anyhow!("Chunk delta doesn't have a valid size"))?;
+	
+	let mut dctx = DCtx::create();
+	dctx.ref_prefix(reference).map_err(zstd_error)?;
+	
+	let mut chunk = Vec::with_capacity(chunk_size as usize);
+	dctx.decompress(&mut chunk, delta).map_err(zstd_error)?;
+	
+	Ok(chunk.into())
+}
+
+fn zstd_error(code: zstd_safe::ErrorCode) -> anyhow::Error {
+	anyhow!("zstd error: {}", zstd_safe::get_error_name(code))
+}
+
+/// A chunk that another chunk can be sent as a delta against.
+#[derive(Clone)]
+pub struct DeltaReference {
+	pub key: ChunkKey,
+	pub data: Bytes,
+}
+
+/// Keeps the chunks of recent worlds, up to a size budget, indexed by their features so that chunks of new worlds
+///  can be matched up with similar ones that clients are likely to already have.
+pub struct DeltaIndex {
+	inner: Mutex<DeltaIndexInner>,
+}
+
+struct DeltaIndexInner {
+	/// Oldest first, so that the chunks of worlds that haven't been seen in a while are evicted first.
+	chunks: LinkedHashMap<ChunkKey, IndexedChunk>,
+	feature_index: [HashMap<u64, Vec<ChunkKey>>; FEATURE_COUNT],
+	total_size: u64,
+	max_size: u64,
+}
+
+struct IndexedChunk {
+	data: Bytes,
+	features: Features,
+	/// The similar chunk that was found when this one was first seen. This is kept so that every client gets the same
+	///  reference, instead of ones that the first client's world added.
+	reference: Option<ChunkKey>,
+}
+
+impl DeltaIndex {
+	pub fn new(max_size: u64) -> Self {
+		Self {
+			inner: Mutex::new(DeltaIndexInner {
+				chunks: LinkedHashMap::new(),
+				feature_index: Default::default(),
+				total_size: 0,
+				max_size,
+			}),
+		}
+	}
+	
+	/// Adds a world's chunks so that later worlds can be diffed against them, returning a similar chunk from an
+	///  earlier world for each of the chunks that has one. This is slow for large worlds, so it should be run with
+	///  spawn_blocking.
+	pub fn add_world<'a>(&self, chunks: impl IntoIterator<Item = (&'a ChunkKey, &'a Bytes)>) -> HashMap<ChunkKey, DeltaReference> {
+		let mut references = HashMap::new();
+		let mut inner = self.inner.lock().unwrap();
+		
+		for (&key, chunk) in chunks {
+			if chunk.len() < MIN_DELTA_CHUNK_SIZE {
+				continue;
+			}
+			
+			let reference_key = match inner.chunks.to_back(&key) {
+				// Chunks that are seen again are moved to the back so that they're kept while they're still in use
+				Some(indexed_chunk) => indexed_chunk.reference,
+				None => {
+					let features = features(chunk);
+					let reference_key = inner.find_similar(&key, &features);
+					
+					inner.insert(key, chunk.clone(), features, reference_key);
+					
+					reference_key
+				}
+			};
+			
+			let reference = reference_key.and_then(|reference_key| Some(DeltaReference {
+				key: reference_key,
+				data: inner.chunks.get(&reference_key)?.data.clone(),
+			}));
+			
+			if let Some(reference) = reference {
+				references.insert(key, reference);
+			}
+		}
+		
+		inner.evict();
+		
+		references
+	}
+}
+
+impl DeltaIndexInner {
+	fn find_similar(&self, key: &ChunkKey, features: &Features) -> Option<ChunkKey> {
+		let mut matches: HashMap<ChunkKey, usize> = HashMap::new();
+		
+		for (feature_index, feature) in self.feature_index.iter().zip(features) {
+			for &candidate in feature_index.get(feature).into_iter().flatten() {
+				if candidate != *key {
+					*matches.entry(candidate).or_default() += 1;
+				}
+			}
+		}
+		
+		let (best_key, match_count) = matches.into_iter().max_by_key(|&(_, match_count)| match_count)?;
+		
+		(match_count >= MIN_MATCHING_FEATURES).then_some(best_key)
+	}
+	
+	fn insert(&mut self, key: ChunkKey, chunk: Bytes, features: Features, reference: Option<ChunkKey>) {
+		for (feature_index, feature) in self.feature_index.iter_mut().zip(features) {
+			let keys = feature_index.entry(feature).or_default();
+			
+			if keys.len() >= MAX_KEYS_PER_FEATURE {
+				keys.remove(0);
+			}
+			
+			keys.push(key);
+		}
+		
+		self.total_size += chunk.len() as u64;
+		self.chunks.insert(key, IndexedChunk {
+			data: chunk,
+			features,
+			reference,
+		});
+	}
+	
+	fn evict(&mut self) {
+		while self.total_size > self.max_size {
+			let Some((key, indexed_chunk)) = self.chunks.pop_front() else { break; };
+			
+			self.total_size -= indexed_chunk.data.len() as u64;
+			
+			for (feature_index, feature) in self.feature_index.iter_mut().zip(indexed_chunk.features) {
+				if let Some(keys) = feature_index.get_mut(&feature) {
+					keys.retain(|&other| other != key);
+					
+					if keys.is_empty() {
+						feature_index.remove(&feature);
+					}
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::content_hash::CONTENT_HASH;
+	
+	fn pseudo_random_chunk(seed: u64, len: usize) -> Bytes {
+		(0..len as u64).map(|index| mix(seed ^ index) as u8).collect::<Vec<_>>().into()
+	}
+	
+	#[test]
+	fn similar_chunks_are_sent_as_small_deltas() {
+		let old_chunk = pseudo_random_chunk(1, 4000);
+		let unrelated_chunk = pseudo_random_chunk(2, 4000);
+		
+		let mut new_chunk = old_chunk.to_vec();
+		new_chunk[2000..2004].copy_from_slice(b"edit");
+		let new_chunk = Bytes::from(new_chunk);
+		
+		let old_key = CONTENT_HASH.hash(&old_chunk);
+		let unrelated_key = CONTENT_HASH.hash(&unrelated_chunk);
+		let new_key = CONTENT_HASH.hash(&new_chunk);
+		
+		let index = DeltaIndex::new(u64::MAX);
+		assert!(index.add_world([(&old_key, &old_chunk), (&unrelated_key, &unrelated_chunk)]).is_empty());
+		
+		let references = index.add_world([(&new_key, &new_chunk)]);
+		let reference = &references[&new_key];
+		assert_eq!(reference.key, old_key);
+		
+		// Clients that download the same world later get the same reference
+		assert_eq!(index.add_world([(&new_key, &new_chunk)])[&new_key].key, old_key);
+		
+		let delta = encode_delta(&reference.data, &new_chunk).unwrap();
+		assert!(delta.len() < 100, "delta is {} bytes", delta.len());
+		assert_eq!(decode_delta(&old_chunk, &delta).unwrap(), new_chunk);
+	}
+	
+	#[test]
+	fn evicted_chunks_are_no_longer_referenced() {
+		let old_chunk = pseudo_random_chunk(1, 4000);
+		let old_key = CONTENT_HASH.hash(&old_chunk);
+		
+		let index = DeltaIndex::new(1000);
+		index.add_world([(&old_key, &old_chunk)]);
+		
+		let mut new_chunk = old_chunk.to_vec();
+		new_chunk[0] ^= 1;
+		let new_chunk = Bytes::from(new_chunk);
+		
+		assert!(index.add_world([(&CONTENT_HASH.hash(&new_chunk), &new_chunk)]).is_empty());
+	}
+}
@@ -0,0 +1,126 @@
+use crate::dedup::ChunkKey;
+use quinn::{ConnectionError, ReadError, WriteError};
+use thiserror::Error;
+
+/// Why a world transfer, or one of the messages making it up, failed. Functions further up wrap these in anyhow, so
+///  they can be told apart with downcast_ref.
+#[derive(Debug, Error)]
+pub enum TransferError {
+	/// The QUIC connection to the other side was lost.
+	#[error("Connection lost")]
+	Connection(#[from] quinn::ConnectionError),
+	/// Reading from or writing to one of the connection's streams failed.
+	#[error("Stream error")]
+	Stream(#[from] std::io::Error),
+	#[error("Failed to decode message")]
+	Decode(#[source] Box<dyn std::error::Error + Send + Sync>),
+	#[error("Failed to encode message")]
+	Encode(#[source] Box<dyn std::error::Error + Send + Sync>),
+	#[error("Message of {0}B exceeds the size limit")]
+	MessageTooLarge(usize),
+	/// The other side sent something it shouldn't have, like a chunk batch that wasn't requested.
+	#[error("Protocol error: {0}")]
+	Protocol(String),
+	/// A chunk's contents didn't match its key.
+	#[error("Chunk hash mismatch for {0:?}")]
+	HashMismatch(ChunkKey),
+	/// The other side took too long, with what was being waited on.
+	#[error("Timed out {0}")]
+	Timeout(&'static str),
+}
+
+impl TransferError {
+	/// Whether the other side finished the stream before a whole message was read, which is how some streams end.
+	pub fn is_end_of_stream(&self) -> bool {
+		matches!(self, TransferError::Stream(err) if err.kind() == std::io::ErrorKind::UnexpectedEof)
+	}
+	
+	/// Whether the other side went away, by closing the connection or resetting a stream, rather than something going
+	///  wrong. Players cancelling their join does this.
+	pub fn is_disconnect(&self) -> bool {
+		match self {
+			TransferError::Connection(err) => is_connection_closed(err),
+			TransferError::Stream(err) => is_stream_closed(err),
+			_ => false,
+		}
+	}
+}
+
+/// Like TransferError::is_disconnect, but for any error a transfer can fail with.
+pub fn is_disconnect(err: &anyhow::Error) -> bool {
+	err.chain().any(|cause| {
+		if let Some(err) = cause.downcast_ref::<TransferError>() {
+			err.is_disconnect()
+		} else if let Some(err) = cause.downcast_ref::<ConnectionError>() {
+			is_connection_closed(err)
+		} else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+			is_stream_closed(err)
+		} else if let Some(err) = cause.downcast_ref::<ReadError>() {
+			is_read_closed(err)
+		} else if let Some(err) = cause.downcast_ref::<WriteError>() {
+			is_write_closed(err)
+		} else {
+			false
+		}
+	})
+}
+
+fn is_connection_closed(err: &ConnectionError) -> bool {
+	matches!(err,
+		ConnectionError::ApplicationClosed(_) | ConnectionError::ConnectionClosed(_) | ConnectionError::Reset | ConnectionError::LocallyClosed)
+}
+
+/// Stream errors reach the transfer as io errors, from reading and writing the streams with AsyncRead and AsyncWrite.
+fn is_stream_closed(err: &std::io::Error) -> bool {
+	let Some(inner) = err.get_ref() else { return false; };
+	
+	if let Some(err) = inner.downcast_ref::<ReadError>() {
+		is_read_closed(err)
+	} else if let Some(err) = inner.downcast_ref::<WriteError>() {
+		is_write_closed(err)
+	} else {
+		false
+	}
+}
+
+fn is_read_closed(err: &ReadError) -> bool {
+	match err {
+		ReadError::Reset(_) | ReadError::ClosedStream => true,
+		ReadError::ConnectionLost(err) => is_connection_closed(err),
+		_ => false,
+	}
+}
+
+fn is_write_closed(err: &WriteError) -> bool {
+	match err {
+		WriteError::Stopped(_) | WriteError::ClosedStream => true,
+		WriteError::ConnectionLost(err) => is_connection_closed(err),
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use quinn::VarInt;
+	
+	#[test]
+	fn disconnects_are_told_apart_from_failures() {
+		let reset = TransferError::Stream(ReadError::Reset(VarInt::from_u32(0)).into());
+		let stopped = TransferError::Stream(WriteError::Stopped(VarInt::from_u32(0)).into());
+		let closed = TransferError::Connection(ConnectionError::LocallyClosed);
+		
+		assert!(reset.is_disconnect());
+		assert!(stopped.is_disconnect());
+		assert!(closed.is_disconnect());
+		
+		assert!(is_disconnect(&anyhow::Error::from(reset).context("Reading a chunk request")));
+		assert!(is_disconnect(&anyhow::Error::from(WriteError::ConnectionLost(ConnectionError::Reset))));
+		
+		let timed_out = TransferError::Connection(ConnectionError::TimedOut);
+		let truncated = TransferError::Stream(std::io::ErrorKind::UnexpectedEof.into());
+		
+		assert!(!timed_out.is_disconnect());
+		assert!(!truncated.is_disconnect());
+	}
+}
@@ -3,24 +3,46 @@ use crate::utils::BufExt;
 use bitflags::bitflags;
 use bytes::{Buf, BufMut, Bytes, BytesMut, TryGetError};
 use crc::Crc;
+use hashlink::LinkedHashMap;
+use std::collections::BTreeMap;
 use serde::{Deserialize, Serialize};
 
 pub const FACTORIO_CRC: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
 pub const FACTORIO_REV_CRC: RevCRC = RevCRC::new(&FACTORIO_CRC);
 
-pub const TRANSFER_BLOCK_SIZE: u32 = 503;
+/// Block size used by Factorio 1.x for map transfers.
+pub const DEFAULT_TRANSFER_BLOCK_SIZE: u32 = 503;
+
+/// Number of transfer blocks needed to hold `data_size` bytes, the last block being padded.
+pub fn transfer_block_count(data_size: u32, transfer_block_size: u32) -> u32 {
+	data_size.div_ceil(transfer_block_size)
+}
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 pub enum PacketType {
+	/// The server's answer to a connection request, which is also how it turns clients away.
+	ConnectionAcceptOrDeny,
 	ServerToClientHeartbeat,
 	TransferBlockRequest,
 	TransferBlock,
 	Unknown(u8),
 }
 
+impl PacketType {
+	/// Packet types below this were all used by factorio when this was written, even though only a few of them need to
+	///  be understood to proxy it.
+	const FIRST_UNUSED_TYPE: u8 = 19;
+	
+	/// Whether this isn't a type that factorio was known to use, which likely means a newer version changed the protocol.
+	pub fn is_unrecognized(self) -> bool {
+		matches!(self, PacketType::Unknown(val) if val >= Self::FIRST_UNUSED_TYPE)
+	}
+}
+
 impl From<u8> for PacketType {
 	fn from(val: u8) -> Self {
 		match val {
+			5 => PacketType::ConnectionAcceptOrDeny,
 			7 => PacketType::ServerToClientHeartbeat,
 			12 => PacketType::TransferBlockRequest,
 			13 => PacketType::TransferBlock,
@@ -29,9 +51,10 @@ impl From<u8> for PacketType {
 	}
 }
 
-impl Into<u8> for PacketType {
-	fn into(self) -> u8 {
-		match self {
+impl From<PacketType> for u8 {
+	fn from(val: PacketType) -> Self {
+		match val {
+			PacketType::ConnectionAcceptOrDeny => 5,
 			PacketType::ServerToClientHeartbeat => 7,
 			PacketType::TransferBlockRequest => 12,
 			PacketType::TransferBlock => 13,
@@ -76,6 +99,97 @@ impl FactorioPacketHeader {
 	}
 }
 
+/// Follows the packet header of each fragment of a message that factorio split over several packets.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct FragmentHeader {
+	pub message_id: u16,
+	pub fragment_id: u16,
+}
+
+impl FragmentHeader {
+	/// Set on the message id when a list of confirmed message ids comes after the fragment id.
+	const HAS_CONFIRMATIONS: u16 = 0x8000;
+	
+	/// Decodes the fragment header, skipping any confirmations, and returns the fragment's part of the message.
+	pub fn decode(mut data: Bytes) -> Result<(Self, Bytes), TryGetError> {
+		let message_id = data.try_get_u16_le()?;
+		let fragment_id = data.try_get_factorio_varint16()?;
+		
+		if message_id & Self::HAS_CONFIRMATIONS != 0 {
+			let confirmations_size = data.try_get_u8()? as usize * 4;
+			
+			if data.remaining() < confirmations_size {
+				return Err(TryGetError { requested: confirmations_size, available: data.remaining() });
+			}
+			
+			data.advance(confirmations_size);
+		}
+		
+		let header = Self {
+			message_id: message_id & !Self::HAS_CONFIRMATIONS,
+			fragment_id,
+		};
+		
+		Ok((header, data))
+	}
+}
+
+/// Joins fragmented messages back together, whatever order their fragments arrive in.
+#[derive(Default)]
+pub struct FragmentReassembler {
+	/// Messages that are still missing fragments by message id, oldest first.
+	messages: LinkedHashMap<u16, PartialMessage>,
+}
+
+#[derive(Default)]
+struct PartialMessage {
+	fragments: BTreeMap<u16, Bytes>,
+	/// Known once the last fragment has arrived.
+	fragment_count: Option<u32>,
+}
+
+impl FragmentReassembler {
+	/// Messages past this many that are still missing fragments are dropped, oldest first, so that fragments that never
+	///  get completed can't pile up.
+	const MAX_PARTIAL_MESSAGES: usize = 256;
+	
+	/// Adds a fragment, returning the whole message once every one of its fragments has arrived.
+	pub fn add(&mut self, fragment_header: FragmentHeader, is_last_fragment: bool, data: Bytes) -> Option<Bytes> {
+		let message_id = fragment_header.message_id;
+		let message = self.messages.entry(message_id).or_insert_with(PartialMessage::default);
+		
+		message.fragments.insert(fragment_header.fragment_id, data);
+		
+		if is_last_fragment {
+			message.fragment_count = Some(fragment_header.fragment_id as u32 + 1);
+		}
+		
+		// Fragment ids start at 0, so the message is complete once it has as many fragments as the last one's id says
+		//  and none past it
+		let is_complete = message.fragment_count.is_some_and(|fragment_count| {
+			message.fragments.len() as u32 == fragment_count &&
+				message.fragments.keys().next_back().is_some_and(|&last_id| last_id as u32 + 1 == fragment_count)
+		});
+		
+		if is_complete {
+			let message = self.messages.remove(&message_id).unwrap();
+			let mut message_data = BytesMut::new();
+			
+			for fragment in message.fragments.values() {
+				message_data.extend_from_slice(fragment);
+			}
+			
+			return Some(message_data.freeze());
+		}
+		
+		while self.messages.len() > Self::MAX_PARTIAL_MESSAGES {
+			self.messages.pop_front();
+		}
+		
+		None
+	}
+}
+
 pub trait FactorioPacket {
 	const PACKET_TYPE: PacketType;
 	
@@ -208,4 +322,69 @@ impl FactorioWorldMetadata {
 		buf.put_u32_le(self.no_idea2);
 		buf.put_u32_le(self.world_crc);
 	}
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn block_count_rounds_up() {
+		for transfer_block_size in [DEFAULT_TRANSFER_BLOCK_SIZE, 1024] {
+			assert_eq!(transfer_block_count(0, transfer_block_size), 0);
+			assert_eq!(transfer_block_count(1, transfer_block_size), 1);
+			assert_eq!(transfer_block_count(transfer_block_size - 1, transfer_block_size), 1);
+			assert_eq!(transfer_block_count(transfer_block_size, transfer_block_size), 1);
+			assert_eq!(transfer_block_count(transfer_block_size + 1, transfer_block_size), 2);
+			assert_eq!(transfer_block_count(transfer_block_size * 100, transfer_block_size), 100);
+		}
+		
+		assert_eq!(transfer_block_count(1_000_000, DEFAULT_TRANSFER_BLOCK_SIZE), 1989);
+		assert_eq!(transfer_block_count(1_000_000, 1024), 977);
+	}
+	
+	#[test]
+	fn fragment_headers_skip_confirmations() {
+		let mut buf = BytesMut::new();
+		buf.put_u16_le(0x1234);
+		buf.put_u8(0xFF);
+		buf.put_u16_le(300);
+		buf.put_slice(b"fragment");
+		
+		let (header, data) = FragmentHeader::decode(buf.split().freeze()).unwrap();
+		assert_eq!(header, FragmentHeader { message_id: 0x1234, fragment_id: 300 });
+		assert_eq!(data, Bytes::from_static(b"fragment"));
+		
+		buf.put_u16_le(0x1234 | FragmentHeader::HAS_CONFIRMATIONS);
+		buf.put_u8(2);
+		buf.put_u8(2); // Confirmation count
+		buf.put_u32_le(10);
+		buf.put_u32_le(11);
+		buf.put_slice(b"fragment");
+		
+		let (header, data) = FragmentHeader::decode(buf.split().freeze()).unwrap();
+		assert_eq!(header, FragmentHeader { message_id: 0x1234, fragment_id: 2 });
+		assert_eq!(data, Bytes::from_static(b"fragment"));
+		
+		assert!(FragmentHeader::decode(Bytes::from_static(&[0x34, 0x92, 0, 3, 0])).is_err());
+	}
+	
+	#[test]
+	fn fragments_are_reassembled_in_any_order() {
+		let mut reassembler = FragmentReassembler::default();
+		let fragment = |message_id, fragment_id| FragmentHeader { message_id, fragment_id };
+		
+		assert_eq!(reassembler.add(fragment(1, 2), true, Bytes::from_static(b"c")), None);
+		assert_eq!(reassembler.add(fragment(2, 0), true, Bytes::from_static(b"other")), Some(Bytes::from_static(b"other")));
+		assert_eq!(reassembler.add(fragment(1, 0), false, Bytes::from_static(b"a")), None);
+		assert_eq!(reassembler.add(fragment(1, 1), false, Bytes::from_static(b"b")), Some(Bytes::from_static(b"abc")));
+		
+		// Abandoned messages are eventually dropped
+		for message_id in 0..=FragmentReassembler::MAX_PARTIAL_MESSAGES as u16 {
+			reassembler.add(fragment(message_id, 1), true, Bytes::new());
+		}
+		
+		assert_eq!(reassembler.messages.len(), FragmentReassembler::MAX_PARTIAL_MESSAGES);
+		assert!(!reassembler.messages.contains_key(&0));
+	}
+}
@@ -0,0 +1,85 @@
+use log::{debug, info};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const REQUEST_SIZE_LIMIT: usize = 4096;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness state reported by the health check endpoint.
+pub struct HealthState {
+	draining: AtomicBool,
+}
+
+impl HealthState {
+	pub fn new() -> Self {
+		Self {
+			draining: AtomicBool::new(false),
+		}
+	}
+	
+	pub fn set_draining(&self) {
+		self.draining.store(true, Ordering::Relaxed);
+	}
+	
+	pub fn is_healthy(&self) -> bool {
+		!self.draining.load(Ordering::Relaxed)
+	}
+}
+
+/// Serves `GET /healthz`, returning 200 while healthy and 503 once draining.
+pub async fn run_health_server(listen_address: SocketAddr, state: Arc<HealthState>) -> anyhow::Result<()> {
+	let listener = TcpListener::bind(listen_address).await?;
+	
+	info!("Serving health checks on {}", listen_address);
+	
+	loop {
+		let (stream, _) = listener.accept().await?;
+		let state = state.clone();
+		
+		tokio::spawn(async move {
+			match tokio::time::timeout(REQUEST_TIMEOUT, handle_request(stream, &state)).await {
+				Ok(Err(err)) => debug!("Error handling health check: {:?}", err),
+				Err(_) => debug!("Health check request timed out"),
+				Ok(Ok(())) => {}
+			}
+		});
+	}
+}
+
+async fn handle_request(mut stream: TcpStream, state: &HealthState) -> anyhow::Result<()> {
+	let mut request = Vec::new();
+	let mut buf = [0u8; 1024];
+	
+	while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+		let read = stream.read(&mut buf).await?;
+		
+		if read == 0 || request.len() + read > REQUEST_SIZE_LIMIT {
+			return Ok(());
+		}
+		
+		request.extend_from_slice(&buf[..read]);
+	}
+	
+	let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+	let mut parts = request_line.split(|&b| b == b' ');
+	
+	let (status, body) = match (parts.next(), parts.next()) {
+		(Some(b"GET"), Some(b"/healthz")) if state.is_healthy() => ("200 OK", "ok\n"),
+		(Some(b"GET"), Some(b"/healthz")) => ("503 Service Unavailable", "draining\n"),
+		_ => ("404 Not Found", "not found\n"),
+	};
+	
+	let response = format!(
+		"HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status, body.len(), body
+	);
+	
+	stream.write_all(response.as_bytes()).await?;
+	stream.shutdown().await?;
+	
+	Ok(())
+}
@@ -0,0 +1,89 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often messages with the same key are logged.
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+pub static LOG_LIMITER: LazyLock<LogLimiter<&'static str>> = LazyLock::new(LogLimiter::default);
+
+/// Like the log macros, but each message template is only logged once every 10s, so that a misbehaving peer can't flood
+///  the log with it. How many messages were left out is added to the next one that's logged, so it's never reported
+///  if no more messages with the template come.
+macro_rules! limited_log {
+	($level:ident, $template:literal $(, $arg:expr)* $(,)?) => {
+		if let Some(skipped_count) = $crate::log_limit::LOG_LIMITER.should_log($template, std::time::Instant::now()) {
+			log::$level!(concat!($template, "{}") $(, $arg)*, $crate::log_limit::skipped_suffix(skipped_count));
+		}
+	};
+}
+
+pub(crate) use limited_log;
+
+/// Tracks when messages with each key were last logged. limited_log keys on message templates rather than the
+///  formatted messages, so there's only ever an entry per call site.
+pub struct LogLimiter<K> {
+	/// When each key was last logged, and how many messages with it weren't logged since.
+	last_logged: Mutex<HashMap<K, (Instant, u64)>>,
+}
+
+impl<K> Default for LogLimiter<K> {
+	fn default() -> Self {
+		Self { last_logged: Mutex::new(HashMap::new()) }
+	}
+}
+
+impl<K: Hash + Eq> LogLimiter<K> {
+	/// Returns how many messages with the key weren't logged since the last one that was, or None if this one
+	///  shouldn't be logged either.
+	pub fn should_log(&self, key: K, now: Instant) -> Option<u64> {
+		let mut last_logged = self.last_logged.lock().unwrap();
+		
+		match last_logged.get_mut(&key) {
+			Some((last_time, skipped_count)) if now.saturating_duration_since(*last_time) < LOG_INTERVAL => {
+				*skipped_count += 1;
+				None
+			}
+			Some((last_time, skipped_count)) => {
+				*last_time = now;
+				Some(std::mem::take(skipped_count))
+			}
+			None => {
+				last_logged.insert(key, (now, 0));
+				Some(0)
+			}
+		}
+	}
+}
+
+pub fn skipped_suffix(skipped_count: u64) -> String {
+	match skipped_count {
+		0 => String::new(),
+		skipped_count => format!(" ({} more since the last one)", skipped_count),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn repeated_templates_are_collapsed() {
+		let limiter = LogLimiter::default();
+		let start = Instant::now();
+		
+		assert_eq!(limiter.should_log("a {}", start), Some(0));
+		assert_eq!(limiter.should_log("a {}", start + Duration::from_secs(1)), None);
+		assert_eq!(limiter.should_log("a {}", start + Duration::from_secs(2)), None);
+		
+		// Other templates aren't held back
+		assert_eq!(limiter.should_log("b {}", start + Duration::from_secs(2)), Some(0));
+		
+		assert_eq!(limiter.should_log("a {}", start + LOG_INTERVAL), Some(2));
+		assert_eq!(limiter.should_log("a {}", start + LOG_INTERVAL * 2), Some(0));
+		
+		assert_eq!(skipped_suffix(0), "");
+		assert_eq!(skipped_suffix(2), " (2 more since the last one)");
+	}
+}
@@ -1,15 +1,36 @@
-use crate::chunk_cache::ChunkCache;
-use crate::proxy::{client_proxy, server_proxy};
+use crate::backoff::ErrorBackoff;
+use crate::chunk_cache::{CacheLayout, CacheLimitBasis, ChunkCache};
+use crate::chunker::Chunker;
+use crate::dedup::ChunkingPolicy;
+use crate::delta::DeltaIndex;
+use crate::health::HealthState;
+use crate::packet_trace::PacketTracer;
+use crate::log_limit::limited_log;
+use crate::report::StatsReporter;
+use crate::stats::StatsSummary;
+use crate::proxy::direct_proxy::OfflineWorlds;
+use crate::world_store::WorldStore;
+use crate::popular_chunks::PopularChunks;
+use crate::quic::{CongestionController, MtuConfig};
+use crate::proxy::client_proxy::{ClientProxyConfig, DownloadLimiter, PeerMatching};
+use crate::proxy::server_proxy::{ConnectionGroups, ServerProxyConfig, WorldCache};
+use crate::proxy::{client_proxy, direct_proxy, replay, server_proxy, EarlyPackets};
 use anyhow::Context;
 use argh::FromArgs;
-use log::{error, info};
-use quinn::Endpoint;
+use log::{error, info, warn};
+use quinn::{Endpoint, EndpointConfig, TokioRuntime};
+use rustls::pki_types::ServerName;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::{lookup_host, UdpSocket};
+use std::time::{Duration, SystemTime};
+use tokio::net::UdpSocket;
 use tokio::select;
+use tokio::task::JoinSet;
 
 mod chunker;
 mod factorio_protocol;
@@ -20,11 +41,42 @@ mod protocol;
 mod zip_writer;
 mod dedup;
 mod chunk_cache;
+mod packet_trace;
+mod backoff;
+mod content_hash;
 mod rev_crc;
+mod popular_chunks;
+mod health;
+mod stats;
+mod net;
+mod world_store;
+mod manifest;
+mod report;
+mod delta;
+mod error;
+mod log_limit;
+mod temp_file;
 
 #[derive(FromArgs)]
 /// Factorio cacher
 struct Args {
+	#[argh(option)]
+	/// number of async runtime worker threads, defaults to the number of CPU cores
+	worker_threads: Option<usize>,
+	
+	#[argh(option)]
+	/// maximum number of threads for blocking work such as deconstructing worlds, defaults to 512
+	max_blocking_threads: Option<usize>,
+	
+	#[argh(option)]
+	/// name to tag every log line and server stats record with, for telling instances apart, disabled by default
+	instance_name: Option<String>,
+	
+	#[argh(option, default = "ColorOutput::Auto")]
+	/// whether to color log output, one of auto, always or never, where auto colors it for terminals unless NO_COLOR
+	/// is set, defaults to auto
+	color: ColorOutput,
+	
 	#[argh(subcommand)]
     subcommand: Subcommand,
 }
@@ -34,6 +86,15 @@ struct Args {
 enum Subcommand {
 	Client(ClientArgs),
 	Server(ServerArgs),
+	CompactCache(CompactCacheArgs),
+	CacheInfo(CacheInfoArgs),
+	BenchChunker(BenchChunkerArgs),
+	Manifest(ManifestArgs),
+	StatsSummary(StatsSummaryArgs),
+	Replay(ReplayArgs),
+	ExportCas(ExportCasArgs),
+	ImportCas(ImportCasArgs),
+	CacheDiff(CacheDiffArgs),
 }
 
 #[derive(FromArgs)]
@@ -49,20 +110,200 @@ struct ClientArgs {
 	host: IpAddr,
 	
 	#[argh(positional)]
-	/// factorio-cacher server address in host:port form
-	server_address: String,
+	/// factorio-cacher server addresses in host:port form, tried in order until one connects
+	server_addresses: Vec<String>,
+	
+	#[argh(option)]
+	/// factorio server address to forward traffic to directly if no factorio-cacher server can be reached
+	direct_fallback: Option<String>,
+	
+	#[argh(option, default = "10")]
+	/// how long to wait when connecting to each factorio-cacher server in seconds, defaults to 10s
+	connect_timeout: u64,
+	
+	#[argh(switch)]
+	/// use transfer connections before their handshake finishes when resuming the TLS session of an earlier connection to
+	/// the same server, which saves a round trip on each. The main connection always finishes its handshake, so that an
+	/// unreachable server is still noticed. Session tickets are only kept in memory and aren't saved to a file, so they
+	/// don't outlive the process
+	zero_rtt: bool,
+	
+	#[argh(option)]
+	/// TLS server name to check the server's certificate against, defaults to localhost, which the bundled certificate is issued for
+	sni: Option<String>,
+	
+	#[argh(switch)]
+	/// keep descriptions of downloaded worlds so that fully cached worlds can be served with --direct-fallback
+	offline_worlds: bool,
+	
+	#[argh(switch)]
+	/// set SO_REUSEPORT on the listening socket, allowing multiple instances to share the port on some platforms
+	reuse_port: bool,
+	
+	#[argh(option, default = "10")]
+	/// how often to log packets dropped because a peer's queue was full in seconds, 0 disables, defaults to 10s
+	dropped_packet_log_interval: u64,
+	
+	#[argh(option, default = "0")]
+	/// how often to log how full each peer's packet queues are in seconds, for tuning --udp-queue-size, 0 disables,
+	/// defaults to 0
+	queue_depth_log_interval: u64,
+	
+	#[argh(option, default = "proxy::DEFAULT_UDP_QUEUE_SIZE")]
+	/// number of packets that can be queued for each peer before new ones are dropped, defaults to 512
+	udp_queue_size: usize,
+	
+	#[argh(option)]
+	/// compress packets of at least this many bytes sent to the other cacher, for slow connections, when it makes them
+	/// smaller, disabled by default
+	compress_datagrams_above: Option<usize>,
+	
+	#[argh(option)]
+	/// DSCP class (0-63) to mark QUIC traffic with, only supported on linux, android, macos and the BSDs, disabled by default
+	dscp: Option<u8>,
+	
+	#[argh(option, default = "CongestionController::Cubic")]
+	/// congestion control algorithm for QUIC connections, one of cubic, bbr or newreno, defaults to cubic
+	congestion: CongestionController,
+	
+	#[argh(option, default = "quic::MIN_MTU")]
+	/// UDP payload size that QUIC connections start with before MTU discovery raises it, at least 1200, defaults to
+	/// 1200 which every path can carry
+	initial_mtu: u16,
+	
+	#[argh(option, default = "1452")]
+	/// largest UDP payload size that MTU discovery probes for, up to 65527, defaults to 1452 which fits in an ethernet
+	/// frame
+	max_mtu: u16,
+	
+	#[argh(switch)]
+	/// keep QUIC packets at --initial-mtu instead of probing for the largest size the path can carry
+	no_mtu_discovery: bool,
+	
+	#[argh(option, default = "30")]
+	/// how long to keep a peer's world data after it stops responding, so that it can be reused if the factorio client
+	/// comes back from the same address, in seconds, defaults to 30s
+	reconnect_grace_period: u64,
+	
+	#[argh(option, default = "60")]
+	/// close a peer's proxy when neither the factorio client nor the server has sent it anything for this many seconds.
+	/// Keep it well above factorio's own timeout for dropping an unresponsive player, so that lag spikes don't end
+	/// sessions factorio would have kept, defaults to 60s
+	peer_idle_timeout: u64,
+	
+	#[argh(switch)]
+	/// after serving each world, reconstruct it again from the chunk cache alone and check that it comes out the same,
+	/// which later joins of the same world rely on, for catching reconstruction bugs
+	verify_reconstruct: bool,
+	
+	#[argh(option)]
+	/// file to write each reconstructed world's save file to before serving it, replacing the previous one, for
+	/// debugging worlds that fail their CRC check. The factorio client can't start downloading until it's written
+	dump_world: Option<PathBuf>,
+	
+	#[argh(option)]
+	/// directory to write temp files to, like world dumps while they're being written. Worth pointing at a disk with
+	/// room for a whole world when the system temp dir is a small tmpfs, defaults to the system temp dir
+	temp_dir: Option<PathBuf>,
+	
+	#[argh(option, default = "0")]
+	/// max number of world blocks to send to each factorio client per second, spreading out bursts of blocks that
+	/// could overflow its receive buffer, 0 disables, defaults to 0
+	block_send_rate: u32,
+	
+	#[argh(option)]
+	/// https URL to send anonymous stats about how much each world transfer saved to, disabled by default
+	report_url: Option<String>,
+	
+	#[argh(switch)]
+	/// don't load or save the cache, so that every world is downloaded cold, for benchmarking
+	no_cache: bool,
+	
+	#[argh(switch)]
+	/// reconnect to the servers instead of exiting when the connection to the server is lost
+	restart_on_error: bool,
 	
 	#[argh(option, short = 'c')]
-	/// location of cache file, defaults to 'persistent-cache' in the CWD
-	cache_path: Option<PathBuf>,
+	/// location of cache file, defaults to 'persistent-cache' in the CWD, can be given more than once to add read-only
+	/// caches that are checked after the first one, which is the only one that's saved to
+	cache_path: Vec<PathBuf>,
 	
 	#[argh(option, default = "500_000_000")]
 	/// max size of the chunk cache, defaults to 500MB
 	cache_limit: u64,
 	
+	#[argh(option, default = "CacheLimitBasis::Uncompressed")]
+	/// what the cache limit is measured in, either 'uncompressed' chunk sizes or the 'compressed' space they take up
+	/// in the cache file, defaults to uncompressed
+	cache_limit_basis: CacheLimitBasis,
+	
+	#[argh(option, default = "CacheLayout::File")]
+	/// how the cache is stored, either a single 'file' or a 'sharded' directory of 256 files where saves only rewrite
+	/// the shards that changed, defaults to file
+	cache_layout: CacheLayout,
+	
 	#[argh(option, default = "60")]
 	/// how often to try to save the cache in seconds, defaults to 60s
 	cache_save_interval: u64,
+	
+	#[argh(option, default = "0.1")]
+	/// fraction of the save interval to randomly vary each save by, so that clients sharing storage don't all save at
+	/// once, defaults to 0.1
+	cache_save_jitter: f64,
+	
+	#[argh(switch)]
+	/// lock the cache in memory so that it can't be swapped out, which needs the memlock limit to fit the cache
+	lock_cache_memory: bool,
+	
+	#[argh(option)]
+	/// rough limit on the memory used by the cache and world transfers together, past which smaller chunk batches are
+	/// requested, disabled by default
+	memory_limit: Option<u64>,
+	
+	#[argh(option, default = "512")]
+	/// max number of chunks to request from the server at once, defaults to 512
+	chunk_batch_size: usize,
+	
+	#[argh(option)]
+	/// also limit each chunk batch to roughly this many bytes of uncompressed chunks, estimated from the sizes of the
+	/// files they're in, so that batches of large chunks don't get huge, disabled by default
+	chunk_batch_bytes: Option<u64>,
+	
+	#[argh(option, default = "4")]
+	/// max number of chunk batches to have requested from the server at once, defaults to 4
+	inflight_batches: usize,
+	
+	#[argh(option, default = "0")]
+	/// max bytes per second to download chunks at, shared by every world transfer, so that downloading a world
+	/// doesn't saturate a shared connection, 0 disables, defaults to 0
+	download_limit: u64,
+	
+	#[argh(option, default = "PeerMatching::Address")]
+	/// how packets from factorio clients are matched up with peers, either by 'address', or by 'ip' only so that a join
+	/// survives a NAT changing the client's port, which mixes up clients sharing an IP like behind carrier-grade NAT,
+	/// defaults to address
+	peer_matching: PeerMatching,
+	
+	#[argh(option, default = "1")]
+	/// how many QUIC connections to spread world transfers over, for fast links where one connection can't use all the bandwidth, defaults to 1
+	transfer_connections: usize,
+	
+	#[argh(option, default = "10")]
+	/// how long to wait for a new peer's stream to be set up in seconds, defaults to 10s
+	handshake_timeout: u64,
+	
+	#[argh(switch)]
+	/// log every packet exchanged with factorio clients, for debugging
+	trace_packets: bool,
+	
+	#[argh(switch)]
+	/// log the raw bytes of packets exchanged with factorio clients with types that factorio wasn't known to use, at most once
+	/// every 10s per type, for working out protocol changes in new factorio versions
+	log_unrecognized_packets: bool,
+	
+	#[argh(option)]
+	/// file to write a pcap capture of every packet exchanged with factorio clients to, for debugging
+	trace_pcap: Option<PathBuf>,
 }
 
 #[derive(FromArgs)]
@@ -80,37 +321,384 @@ struct ServerArgs {
 	#[argh(positional)]
 	/// factorio server address in host:port form
 	factorio_address: String,
+	
+	#[argh(option)]
+	/// push up to this many bytes of the most frequently requested chunks to newly connected clients, disabled by default
+	push_popular_chunks: Option<u64>,
+	
+	#[argh(option)]
+	/// address to serve HTTP health checks on (GET /healthz), disabled by default
+	health_addr: Option<SocketAddr>,
+	
+	#[argh(option, default = "10")]
+	/// how long to wait for a new peer's stream to be set up in seconds, defaults to 10s
+	handshake_timeout: u64,
+	
+	#[argh(option, default = "60")]
+	/// close a peer's proxy when neither the factorio server nor the client has sent it anything for this many seconds.
+	/// Keep it well above factorio's own timeout for dropping an unresponsive player, so that a server stalled by a save
+	/// or a lag spike doesn't lose its players to the proxy, defaults to 60s
+	peer_idle_timeout: u64,
+	
+	#[argh(option)]
+	/// file to append a CSV record of every completed world transfer to, disabled by default
+	stats_file: Option<PathBuf>,
+	
+	#[argh(switch)]
+	/// set SO_REUSEPORT on the listening socket, allowing multiple instances to share the port on some platforms
+	reuse_port: bool,
+	
+	#[argh(option, default = "10")]
+	/// how often to log packets dropped because a peer's queue was full in seconds, 0 disables, defaults to 10s
+	dropped_packet_log_interval: u64,
+	
+	#[argh(option, default = "0")]
+	/// how often to log how full each peer's packet queues are in seconds, for tuning --udp-queue-size, 0 disables,
+	/// defaults to 0
+	queue_depth_log_interval: u64,
+	
+	#[argh(option, default = "proxy::DEFAULT_UDP_QUEUE_SIZE")]
+	/// number of packets that can be queued for each peer before new ones are dropped, defaults to 512
+	udp_queue_size: usize,
+	
+	#[argh(option)]
+	/// compress packets of at least this many bytes sent to the other cacher, for slow connections, when it makes them
+	/// smaller, disabled by default
+	compress_datagrams_above: Option<usize>,
+	
+	#[argh(option)]
+	/// DSCP class (0-63) to mark QUIC traffic with, only supported on linux, android, macos and the BSDs, disabled by default
+	dscp: Option<u8>,
+	
+	#[argh(option, default = "CongestionController::Cubic")]
+	/// congestion control algorithm for QUIC connections, one of cubic, bbr or newreno, defaults to cubic
+	congestion: CongestionController,
+	
+	#[argh(option, default = "quic::MIN_MTU")]
+	/// UDP payload size that QUIC connections start with before MTU discovery raises it, at least 1200, defaults to
+	/// 1200 which every path can carry
+	initial_mtu: u16,
+	
+	#[argh(option, default = "1452")]
+	/// largest UDP payload size that MTU discovery probes for, up to 65527, defaults to 1452 which fits in an ethernet
+	/// frame
+	max_mtu: u16,
+	
+	#[argh(switch)]
+	/// keep QUIC packets at --initial-mtu instead of probing for the largest size the path can carry
+	no_mtu_discovery: bool,
+	
+	#[argh(option, default = "factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE")]
+	/// size of the blocks the factorio server sends the map in, must match the factorio server's version, defaults to 503
+	transfer_block_size: u32,
+	
+	#[argh(option, default = "30")]
+	/// warn if the factorio server hasn't sent a map to a new peer within this many seconds, 0 disables, defaults to 30s
+	world_ready_timeout: u64,
+	
+	#[argh(option, default = "0")]
+	/// give up on downloading a world from the factorio server after this many seconds, forwarding the client's own block
+	/// requests to it instead, 0 disables, defaults to 0
+	max_download_time: u64,
+	
+	#[argh(option, default = "100_000")]
+	/// worlds smaller than this many bytes are forwarded to clients without deduplicating them, defaults to 100KB
+	min_dedup_size: u32,
+	
+	#[argh(option, default = "ChunkingPolicy::default()")]
+	/// comma separated extensions of files in the world zip that are sent whole instead of being split into chunks,
+	/// since they're already compressed, an empty list chunks every file, defaults to png,jpg,jpeg
+	whole_file_extensions: ChunkingPolicy,
+	
+	#[argh(switch)]
+	/// log every packet exchanged with the factorio server, for debugging
+	trace_packets: bool,
+	
+	#[argh(switch)]
+	/// log the raw bytes of packets exchanged with the factorio server with types that factorio wasn't known to use, at most once
+	/// every 10s per type, for working out protocol changes in new factorio versions
+	log_unrecognized_packets: bool,
+	
+	#[argh(option)]
+	/// file to write a pcap capture of every packet exchanged with the factorio server to, for debugging
+	trace_pcap: Option<PathBuf>,
+	
+	#[argh(option, default = "1024")]
+	/// number of incoming connections that can wait to be accepted before new ones are refused, defaults to 1024
+	accept_backlog: usize,
+	
+	#[argh(option, default = "32")]
+	/// number of incoming connections to handshake with at once, defaults to 32
+	accept_concurrency: usize,
+	
+	#[argh(option, default = "0")]
+	/// close client connections that have had no peers and no datagrams for this many seconds. A client's transfer
+	/// connections count as active while any of its connections is, 0 disables, defaults to 0
+	max_idle_connection_time: u64,
+	
+	#[argh(option)]
+	/// keep up to this many bytes of chunks from recent worlds, and send new chunks that are similar to one of them as
+	/// deltas against it, disabled by default
+	chunk_deltas: Option<u64>,
+	
+	#[argh(option)]
+	/// keep up to this many bytes of chunks from recent worlds in memory, so that worlds with chunks in common with
+	/// them share those chunks instead of each holding a copy, disabled by default
+	chunk_cache_limit: Option<u64>,
+	
+	#[argh(switch)]
+	/// check each world downloaded from the factorio server against the CRC it gave for the world before
+	/// deconstructing it, downloading it again if it doesn't match. This holds up the peer's packets while the CRC is
+	/// computed
+	verify_upstream_crc: bool,
+	
+	#[argh(switch)]
+	/// don't replace the world size that the factorio server announces with the reconstructed world's, which breaks
+	/// every deduplicated join, for debugging whether factorio rejects a world because of the rewrite
+	no_rewrite: bool,
+	
+	#[argh(option, default = "60")]
+	/// how long to reuse a deconstructed world for other clients downloading the same world in seconds, 0 disables,
+	/// defaults to 60s
+	world_cache_ttl: u64,
+}
+
+#[derive(FromArgs)]
+/// Recompress a cache file, keeping all of its chunks
+#[argh(subcommand, name = "compact-cache")]
+struct CompactCacheArgs {
+	#[argh(option, short = 'c')]
+	/// location of cache file, defaults to 'persistent-cache' in the CWD
+	cache_path: Option<PathBuf>,
+	
+	#[argh(option, short = 'o')]
+	/// where to write the compacted cache, defaults to replacing the cache file
+	output_path: Option<PathBuf>,
+	
+	#[argh(option, default = "19")]
+	/// zstd compression level to use, defaults to 19
+	level: i32,
+}
+
+#[derive(FromArgs)]
+/// Print a summary of a cache file's contents
+#[argh(subcommand, name = "cache-info")]
+struct CacheInfoArgs {
+	#[argh(option, short = 'c')]
+	/// location of cache file, defaults to 'persistent-cache' in the CWD
+	cache_path: Option<PathBuf>,
+}
+
+#[derive(FromArgs)]
+/// Measure how fast the chunker splits data into chunks
+#[argh(subcommand, name = "bench-chunker")]
+struct BenchChunkerArgs {
+	#[argh(option, short = 'f')]
+	/// file to chunk, such as a world's decompressed level data, defaults to generated data
+	file: Option<PathBuf>,
+	
+	#[argh(option, default = "64_000_000")]
+	/// size of the data to generate when no file is given, defaults to 64MB
+	size: usize,
+	
+	#[argh(option, default = "5")]
+	/// number of times to chunk the data, defaults to 5
+	iterations: u32,
+}
+
+#[derive(FromArgs)]
+/// Write a JSON list of the chunks a world save is split into
+#[argh(subcommand, name = "manifest")]
+struct ManifestArgs {
+	#[argh(positional)]
+	/// world save zip to list the chunks of
+	world_path: PathBuf,
+	
+	#[argh(option, short = 'o')]
+	/// where to write the manifest, defaults to stdout
+	output_path: Option<PathBuf>,
+}
+
+#[derive(FromArgs)]
+/// Print totals over the transfers in one or more server stats files
+#[argh(subcommand, name = "stats-summary")]
+struct StatsSummaryArgs {
+	#[argh(positional)]
+	/// stats files written by the server's --stats-file, which may cover overlapping time ranges
+	stats_paths: Vec<PathBuf>,
 }
 
-#[tokio::main()]
-async fn main() {
+#[derive(FromArgs)]
+/// Replay a server's --trace-pcap capture offline, checking that every world in it deconstructs and reconstructs
+/// correctly
+#[argh(subcommand, name = "replay")]
+struct ReplayArgs {
+	#[argh(positional)]
+	/// pcap file written by the server's --trace-pcap
+	trace_path: PathBuf,
+	
+	#[argh(option)]
+	/// address of the factorio server in the trace, defaults to where the first traced packet was sent
+	factorio_address: Option<SocketAddr>,
+	
+	#[argh(option, default = "factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE")]
+	/// size of the blocks the factorio server sent the map in, defaults to 503
+	transfer_block_size: u32,
+}
+
+#[derive(FromArgs)]
+/// Write each chunk in a cache file to its own file named by the chunk's key, for syncing or storing the cache with
+/// tools that deduplicate by file name
+#[argh(subcommand, name = "export-cas")]
+struct ExportCasArgs {
+	#[argh(option, short = 'c')]
+	/// location of cache file, defaults to 'persistent-cache' in the CWD
+	cache_path: Option<PathBuf>,
+	
+	#[argh(positional)]
+	/// directory to write the chunks to, chunks that are already there are skipped
+	cas_dir: PathBuf,
+}
+
+#[derive(FromArgs)]
+/// Add the chunks in a directory written by export-cas to a cache file
+#[argh(subcommand, name = "import-cas")]
+struct ImportCasArgs {
+	#[argh(option, short = 'c')]
+	/// location of cache file, defaults to 'persistent-cache' in the CWD, which is created if it doesn't exist
+	cache_path: Option<PathBuf>,
+	
+	#[argh(positional)]
+	/// directory to read the chunks from
+	cas_dir: PathBuf,
+}
+
+#[derive(FromArgs)]
+/// Compare the chunks in two cache files
+#[argh(subcommand, name = "cache-diff")]
+struct CacheDiffArgs {
+	#[argh(positional)]
+	/// first cache file
+	first_path: PathBuf,
+	
+	#[argh(positional)]
+	/// second cache file
+	second_path: PathBuf,
+	
+	#[argh(switch)]
+	/// print the comparison as JSON
+	json: bool,
+}
+
+fn main() {
 	let args: Args = argh::from_env();
 	
-	setup_logging();
+	if let Some(instance_name) = &args.instance_name {
+		if instance_name.is_empty() || instance_name.contains(|c: char| c == ',' || c.is_control()) {
+			panic!("Instance name must be non-empty and can't contain commas or control characters");
+		}
+	}
+	
+	let trace_packets = match &args.subcommand {
+		Subcommand::Client(client_args) => client_args.trace_packets,
+		Subcommand::Server(server_args) => server_args.trace_packets,
+		_ => false,
+	};
+	
+	setup_logging(args.instance_name.clone(), args.color, trace_packets);
 	
-	match args.subcommand {
-		Subcommand::Client(client_args) => subcommand_client(client_args).await,
-		Subcommand::Server(server_args) => subcommand_server(server_args).await,
+	let mut runtime_builder = tokio::runtime::Builder::new_multi_thread();
+	runtime_builder.enable_all();
+	
+	if let Some(worker_threads) = args.worker_threads {
+		if worker_threads == 0 {
+			panic!("Worker thread count must be at least 1");
+		}
+		
+		info!("Using {} worker threads", worker_threads);
+		runtime_builder.worker_threads(worker_threads);
 	}
+	
+	if let Some(max_blocking_threads) = args.max_blocking_threads {
+		if max_blocking_threads == 0 {
+			panic!("Blocking thread count must be at least 1");
+		}
+		
+		info!("Using up to {} blocking threads", max_blocking_threads);
+		runtime_builder.max_blocking_threads(max_blocking_threads);
+	}
+	
+	let runtime = runtime_builder.build().expect("Failed to build async runtime");
+	
+	runtime.block_on(async move {
+		let instance_name = args.instance_name;
+		
+		match args.subcommand {
+			Subcommand::Client(client_args) => subcommand_client(client_args).await,
+			Subcommand::Server(server_args) => subcommand_server(server_args, instance_name).await,
+			Subcommand::CompactCache(compact_args) => subcommand_compact_cache(compact_args).await,
+			Subcommand::CacheInfo(info_args) => subcommand_cache_info(info_args).await,
+			Subcommand::BenchChunker(bench_args) => subcommand_bench_chunker(bench_args).await,
+			Subcommand::Manifest(manifest_args) => subcommand_manifest(manifest_args).await,
+			Subcommand::StatsSummary(summary_args) => subcommand_stats_summary(summary_args).await,
+			Subcommand::Replay(replay_args) => subcommand_replay(replay_args).await,
+			Subcommand::ExportCas(export_args) => subcommand_export_cas(export_args).await,
+			Subcommand::ImportCas(import_args) => subcommand_import_cas(import_args).await,
+			Subcommand::CacheDiff(diff_args) => subcommand_cache_diff(diff_args).await,
+		}
+	});
 }
 
 async fn subcommand_client(args: ClientArgs) {
-	let server_address = lookup_host(args.server_address.as_str()).await
-		.expect("Error looking up host")
-		.next()
-		.expect("No server address found");
+	if args.server_addresses.is_empty() {
+		error!("At least one server address is required");
+		std::process::exit(1);
+	}
 	
-	let local_address = SocketAddr::new(if server_address.is_ipv6() {
+	// A broken CRC would otherwise only show up after downloading a world, as a CRC mismatch
+	rev_crc::self_test().expect("CRC self-test failed, this build can't reconstruct worlds");
+	
+	let mut server_addresses = Vec::new();
+	
+	for server_address in &args.server_addresses {
+		match net::lookup_host(server_address.as_str()).await.map(|mut addrs| addrs.next()) {
+			Ok(Some(addr)) => server_addresses.push(addr),
+			Ok(None) => warn!("No address found for server {}", server_address),
+			Err(err) => warn!("Error looking up server {}: {}", server_address, err),
+		}
+	}
+	
+	let direct_fallback = match &args.direct_fallback {
+		Some(factorio_address) => Some(net::lookup_host(factorio_address.as_str()).await
+			.expect("Error looking up host")
+			.next()
+			.expect("No factorio address found")),
+		None => None,
+	};
+	
+	let local_address = SocketAddr::new(if server_addresses.iter().any(SocketAddr::is_ipv6) {
 		Ipv6Addr::UNSPECIFIED.into()
 	} else {
 		Ipv4Addr::UNSPECIFIED.into()
 	}, 0);
 	
-	let mut endpoint = Endpoint::client(local_address).unwrap();
-	endpoint.set_default_client_config(quic::make_client_config());
+	check_dscp(args.dscp).unwrap();
+	
+	let socket = std::net::UdpSocket::bind(local_address).expect("Error binding socket");
+	
+	let mut endpoint = Endpoint::new_with_abstract_socket(
+		EndpointConfig::default(),
+		None,
+		net::quic_socket(socket, args.dscp).expect("Error setting up QUIC socket"),
+		Arc::new(TokioRuntime),
+	).unwrap();
+	
+	info!("Using {} congestion control", args.congestion);
+	let mtu_config = check_mtu(args.initial_mtu, args.max_mtu, args.no_mtu_discovery).unwrap();
+	endpoint.set_default_client_config(quic::make_client_config(args.congestion, mtu_config));
 	
 	select! {
-		result = run_client(&endpoint, server_address, &args) => result.unwrap(),
+		result = run_client(&endpoint, &server_addresses, direct_fallback, &args) => result.unwrap(),
 		_ = tokio::signal::ctrl_c() => {}
 	}
 	
@@ -124,62 +712,360 @@ async fn subcommand_client(args: ClientArgs) {
 	info!("Shutdown");
 }
 
-async fn run_client(endpoint: &Endpoint, server_address: SocketAddr, args: &ClientArgs) -> anyhow::Result<()> {
-	let cache_path = args.cache_path.clone()
-		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
+async fn run_client(
+	endpoint: &Endpoint,
+	server_addresses: &[SocketAddr],
+	direct_fallback: Option<SocketAddr>,
+	args: &ClientArgs,
+) -> anyhow::Result<()> {
+	if args.chunk_batch_size < 1 {
+		return Err(anyhow::anyhow!("Chunk batch size must be at least 1"));
+	}
+	
+	if args.chunk_batch_bytes == Some(0) {
+		return Err(anyhow::anyhow!("Chunk batch byte budget must be at least 1"));
+	}
+	
+	if args.inflight_batches < 1 {
+		return Err(anyhow::anyhow!("Inflight batches must be at least 1"));
+	}
+	
+	if args.transfer_connections < 1 {
+		return Err(anyhow::anyhow!("Transfer connections must be at least 1"));
+	}
+	
+	if args.peer_idle_timeout == 0 {
+		return Err(anyhow::anyhow!("Peer idle timeout must be at least 1s"));
+	}
+	
+	if let Some(sni) = &args.sni {
+		ServerName::try_from(sni.as_str()).map_err(|_| anyhow::anyhow!("Invalid TLS server name {}", sni))?;
+	}
 	
-	info!("Connecting...");
+	check_udp_queue_size(args.udp_queue_size)?;
 	
-	let quic_connection = Arc::new(endpoint.connect(server_address, "localhost")?.await.context("QUIC connecting")?);
+	if !(0.0..=1.0).contains(&args.cache_save_jitter) {
+		return Err(anyhow::anyhow!("--cache-save-jitter must be between 0 and 1"));
+	}
+	
+	if args.memory_limit.is_some_and(|memory_limit| memory_limit <= args.cache_limit) {
+		warn!("--memory-limit is no larger than --cache-limit, so transfers will be slowed down once the cache fills up");
+	}
+	
+	if args.no_cache && args.offline_worlds {
+		return Err(anyhow::anyhow!("--offline-worlds can't be used with --no-cache"));
+	}
+	
+	if direct_fallback.is_none() && args.offline_worlds {
+		return Err(anyhow::anyhow!("--offline-worlds needs --direct-fallback, which is what offline worlds are served through"));
+	}
+	
+	let temp_dir = args.temp_dir.clone().unwrap_or_else(std::env::temp_dir);
+	temp_file::check_temp_dir(&temp_dir).with_context(|| format!("Temp dir {} can't be used", temp_dir.display()))?;
+	
+	let cache_path = args.cache_path.first().cloned()
+		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
 	
 	let listen_address = SocketAddr::new(args.host, args.port);
-	let socket = Arc::new(UdpSocket::bind(listen_address).await?);
+	let socket = Arc::new(net::bind_tokio_udp_socket(listen_address, args.reuse_port)?);
 	
-	info!("Connected");
+	// Worlds are stored for the factorio server they'll be served for, so that worlds from different servers can't mix
+	let world_store = direct_fallback
+		.filter(|_| args.offline_worlds)
+		.map(|factorio_address| Arc::new(WorldStore::new(cache_path.with_extension("worlds"), factorio_address)));
 	
 	let chunk_cache;
 	
-	if cache_path.exists() {
-		info!("Loading cache from {}", cache_path.display());
+	if args.no_cache {
+		warn!("Caching is disabled, every world will be downloaded without using previously downloaded chunks");
 		
-		let compressed_size = tokio::fs::metadata(&cache_path).await?.len();
-		chunk_cache = Arc::new(ChunkCache::load_from_file(args.cache_limit, cache_path.clone()).await?);
-		
-		info!(
-			"Loaded {} chunks ({}B, {}B compressed) from the cache",
-			chunk_cache.len(),
-			utils::abbreviate_number(chunk_cache.total_size()),
-			utils::abbreviate_number(compressed_size)
-		);
+		// With no room in the cache, chunks only live in each transfer's local cache
+		chunk_cache = Arc::new(ChunkCache::new(0, CacheLimitBasis::Uncompressed));
 	} else {
-		chunk_cache = Arc::new(ChunkCache::new(args.cache_limit));
+		let mut hot_cache = if cache_path.exists() {
+			info!("Loading cache from {}", cache_path.display());
+			
+			let compressed_size = chunk_cache::stored_size(&cache_path)?;
+			let hot_cache = match args.cache_layout {
+				CacheLayout::File => ChunkCache::load_from_file(args.cache_limit, args.cache_limit_basis, cache_path.clone()).await?,
+				CacheLayout::Sharded => ChunkCache::load_sharded(args.cache_limit, args.cache_limit_basis, cache_path.clone()).await?,
+			};
+			
+			info!(
+				"Loaded {} chunks ({}B, {}B compressed) from the cache",
+				hot_cache.len(),
+				utils::abbreviate_number(hot_cache.total_size()),
+				utils::abbreviate_number(compressed_size)
+			);
+			
+			hot_cache
+		} else {
+			ChunkCache::new(args.cache_limit, args.cache_limit_basis)
+		};
+		
+		for cold_cache_path in args.cache_path.iter().skip(1) {
+			info!("Loading read-only cache from {}", cold_cache_path.display());
+			
+			let new_chunks = hot_cache.add_cold_tier(cold_cache_path.clone()).await
+				.with_context(|| format!("Loading read-only cache {}", cold_cache_path.display()))?;
+			
+			info!("Loaded {} chunks not in earlier caches from {}", new_chunks, cold_cache_path.display());
+		}
+		
+		if args.cache_path.len() > 1 {
+			let (cold_chunk_count, cold_size) = hot_cache.cold_tier_size();
+			info!("The read-only caches hold {} chunks ({}B)", cold_chunk_count, utils::abbreviate_number(cold_size));
+		}
+		
+		chunk_cache = Arc::new(hot_cache);
+		
+		match args.cache_limit_basis {
+			CacheLimitBasis::Uncompressed => info!("The cache has a limit of {}B", utils::abbreviate_number(args.cache_limit)),
+			CacheLimitBasis::Compressed => info!("The cache has a limit of {}B compressed", utils::abbreviate_number(args.cache_limit)),
+		}
+		
+		if args.lock_cache_memory {
+			// The read-only caches are never evicted from, so all of them stay in memory on top of the limit
+			let cache_size = args.cache_limit.saturating_add(chunk_cache.cold_tier_size().1);
+			
+			match chunk_cache::lock_cache_memory(cache_size) {
+				Ok(()) => info!("Locked the cache in memory"),
+				Err(err) => warn!("Not locking the cache in memory: {}", err),
+			}
+		}
+		
+		chunk_cache.start_writer(cache_path, args.cache_layout, Duration::from_secs(args.cache_save_interval), args.cache_save_jitter);
+		
+		#[cfg(unix)]
+		{
+			use tokio::signal::unix::{signal, SignalKind};
+			
+			let mut flush_signal = signal(SignalKind::user_defined1())?;
+			let chunk_cache = chunk_cache.clone();
+			
+			tokio::spawn(async move {
+				while flush_signal.recv().await.is_some() {
+					info!("Received SIGUSR1, flushing the cache");
+					
+					chunk_cache.flush_now();
+				}
+			});
+		}
+	}
+	
+	let proxy_config = Arc::new(ClientProxyConfig {
+		chunk_batch_size: args.chunk_batch_size,
+		chunk_batch_bytes: args.chunk_batch_bytes,
+		inflight_batches: args.inflight_batches,
+		handshake_timeout: Duration::from_secs(args.handshake_timeout),
+		dropped_packet_log_interval: (args.dropped_packet_log_interval > 0)
+			.then(|| Duration::from_secs(args.dropped_packet_log_interval)),
+		queue_depth_log_interval: (args.queue_depth_log_interval > 0)
+			.then(|| Duration::from_secs(args.queue_depth_log_interval)),
+		udp_queue_size: args.udp_queue_size,
+		world_store,
+		reconnect_grace_period: Duration::from_secs(args.reconnect_grace_period),
+		peer_idle_timeout: Duration::from_secs(args.peer_idle_timeout),
+		verify_reconstruct: args.verify_reconstruct,
+		dump_world: args.dump_world.clone(),
+		temp_dir,
+		memory_limit: args.memory_limit,
+		compress_datagrams_above: args.compress_datagrams_above,
+		block_send_interval: (args.block_send_rate > 0)
+			.then(|| Duration::from_secs(1) / args.block_send_rate),
+		packet_tracer: PacketTracer::new(args.trace_packets, args.log_unrecognized_packets, args.trace_pcap.as_deref())?.map(Arc::new),
+		stats_reporter: args.report_url.as_deref().map(StatsReporter::new).transpose()?.map(Arc::new),
+		download_limiter: (args.download_limit > 0).then(|| Arc::new(DownloadLimiter::new(args.download_limit))),
+		peer_matching: args.peer_matching,
+		zero_rtt: args.zero_rtt,
+	});
+	
+	if args.peer_matching == PeerMatching::Ip {
+		warn!("Matching factorio clients by IP only, so clients sharing an IP will be mixed up");
+	}
+	
+	if args.download_limit > 0 {
+		info!("Limiting chunk downloads to {}B/s", utils::abbreviate_number(args.download_limit));
+	}
+	
+	// Restarts aren't tolerated without waiting, since each one means reconnecting to the server
+	let mut backoff = ErrorBackoff::new(0);
+	
+	loop {
+		let result = run_client_session(endpoint, server_addresses, direct_fallback, &socket, &chunk_cache, &proxy_config, args).await;
+		
+		match result {
+			Err(err) if args.restart_on_error => {
+				error!("Error running client: {:?}, restarting", err);
+				
+				backoff.on_error().await;
+			}
+			result => return result,
+		}
 	}
+}
+
+/// Connects to a server and proxies factorio clients through it until something goes wrong.
+async fn run_client_session(
+	endpoint: &Endpoint,
+	server_addresses: &[SocketAddr],
+	direct_fallback: Option<SocketAddr>,
+	socket: &Arc<UdpSocket>,
+	chunk_cache: &Arc<ChunkCache>,
+	proxy_config: &Arc<ClientProxyConfig>,
+	args: &ClientArgs,
+) -> anyhow::Result<()> {
+	let listen_address = socket.local_addr()?;
+	
+	// Factorio clients may already be sending packets, which are handled once it's known where they should go
+	let mut early_packets = EarlyPackets::new(args.udp_queue_size);
+	
+	let connecting = connect_to_any_server(endpoint, server_addresses, server_name(args), Duration::from_secs(args.connect_timeout));
+	let quic_connection = early_packets.buffer_until(socket, connecting).await;
+	
+	let quic_connection = match (quic_connection, direct_fallback) {
+		(Some(connection), _) => Arc::new(connection),
+		(None, Some(factorio_address)) => {
+			warn!("Unable to reach any server, forwarding directly to {}", factorio_address);
+			info!("Listening on {}", listen_address);
+			
+			let offline_worlds = proxy_config.world_store.clone().map(|world_store| Arc::new(OfflineWorlds {
+				world_store,
+				chunk_cache: chunk_cache.clone(),
+			}));
+			
+			return direct_proxy::run_direct_proxy(socket.clone(), factorio_address, args.udp_queue_size, offline_worlds, early_packets).await;
+		}
+		(None, None) => return Err(anyhow::anyhow!("Unable to connect to any server")),
+	};
 	
-	info!("The cache has a limit of {}B", utils::abbreviate_number(args.cache_limit));
+	info!("Connected");
 	
-	chunk_cache.start_writer(cache_path, Duration::from_secs(args.cache_save_interval));
+	let connecting = connect_transfer_connections(endpoint, quic_connection.remote_address(), args);
+	let transfer_connections = early_packets.buffer_until(socket, connecting).await;
 	
+	match args.chunk_batch_bytes {
+		Some(chunk_batch_bytes) => info!("Requesting chunks in batches of at most {} or around {}B", args.chunk_batch_size,
+			utils::abbreviate_number(chunk_batch_bytes)),
+		None => info!("Requesting chunks in batches of at most {}", args.chunk_batch_size),
+	}
 	info!("Listening on {}", listen_address);
 	
-	client_proxy::run_client_proxy(socket.clone(), quic_connection.clone(), chunk_cache.clone()).await?;
+	client_proxy::run_client_proxy(socket.clone(), early_packets, quic_connection, transfer_connections, chunk_cache.clone(), proxy_config.clone()).await
+}
+
+/// Opens the extra connections to spread world transfers over. Ones that fail are left out rather than failing the
+///  session, since the main connection can carry transfers on its own.
+async fn connect_transfer_connections(endpoint: &Endpoint, server_address: SocketAddr, args: &ClientArgs) -> Vec<Arc<quinn::Connection>> {
+	let mut transfer_connections = Vec::new();
 	
-	Ok(())
+	for _ in 1..args.transfer_connections {
+		match connect(endpoint, server_address, server_name(args), Duration::from_secs(args.connect_timeout), args.zero_rtt).await {
+			Ok(connection) => transfer_connections.push(Arc::new(connection)),
+			Err(err) => warn!("Failed to open transfer connection to {}: {:#}", server_address, err),
+		}
+	}
+	
+	if !transfer_connections.is_empty() {
+		info!("Spreading world transfers over {} connections", transfer_connections.len() + 1);
+	}
+	
+	transfer_connections
 }
 
-async fn subcommand_server(args: ServerArgs) {
-	let factorio_address = lookup_host(args.factorio_address.as_str()).await
-		.expect("Error looking up host")
-		.next()
-		.expect("No server address found");
+async fn connect_to_any_server(
+	endpoint: &Endpoint,
+	server_addresses: &[SocketAddr],
+	server_name: &str,
+	connect_timeout: Duration,
+) -> Option<quinn::Connection> {
+	for &server_address in server_addresses {
+		info!("Connecting to {}...", server_address);
+		
+		match connect(endpoint, server_address, server_name, connect_timeout, false).await {
+			Ok(connection) => return Some(connection),
+			Err(err) => warn!("Failed to connect to {}: {:#}", server_address, err),
+		}
+	}
+	
+	None
+}
+
+async fn connect(
+	endpoint: &Endpoint,
+	server_address: SocketAddr,
+	server_name: &str,
+	connect_timeout: Duration,
+	zero_rtt: bool,
+) -> anyhow::Result<quinn::Connection> {
+	let mut connecting = endpoint.connect(server_address, server_name)?;
+	
+	// A resumed session can be used straight away. The server only acts on anything sent before the handshake finishes
+	//  once it does finish, so none of it can be replayed, but it's lost if the server rejects it. Only transfer
+	//  connections do this, since the connection is returned before it's known whether the server can be reached.
+	if zero_rtt {
+		match connecting.into_0rtt() {
+			Ok((connection, zero_rtt_accepted)) => {
+				tokio::spawn(async move {
+					if !zero_rtt_accepted.await {
+						info!("Server rejected 0-RTT data for a resumed connection, resending it");
+					}
+				});
+				
+				return Ok(connection);
+			}
+			Err(not_resumed) => connecting = not_resumed,
+		}
+	}
+	
+	tokio::time::timeout(connect_timeout, connecting).await
+		.context("Timed out")?
+		.context("QUIC connecting")
+}
+
+fn server_name(args: &ClientArgs) -> &str {
+	args.sni.as_deref().unwrap_or(quic::BUNDLED_CERT_SERVER_NAME)
+}
+
+async fn subcommand_server(args: ServerArgs, instance_name: Option<String>) {
+	let factorio_address = Arc::new(net::UpstreamAddress::resolve(&args.factorio_address).await
+		.expect("Error looking up factorio server"));
 	
 	let listen_address = SocketAddr::new(args.host, args.port);
-	let endpoint = Endpoint::server(quic::make_server_config(), listen_address).unwrap();
+	let socket = net::bind_udp_socket(listen_address, args.reuse_port).expect("Error binding socket");
+	
+	check_dscp(args.dscp).unwrap();
+	
+	info!("Using {} congestion control", args.congestion);
+	
+	let mtu_config = check_mtu(args.initial_mtu, args.max_mtu, args.no_mtu_discovery).unwrap();
+	
+	let endpoint = Endpoint::new_with_abstract_socket(
+		EndpointConfig::default(),
+		Some(quic::make_server_config(args.accept_backlog, args.congestion, mtu_config)),
+		net::quic_socket(socket, args.dscp).expect("Error setting up QUIC socket"),
+		Arc::new(TokioRuntime),
+	).unwrap();
+	
+	let health_state = Arc::new(HealthState::new());
+	
+	if let Some(health_addr) = args.health_addr {
+		let health_state = health_state.clone();
+		
+		tokio::spawn(async move {
+			if let Err(err) = health::run_health_server(health_addr, health_state).await {
+				error!("Error running health check server: {:?}", err);
+			}
+		});
+	}
 	
 	select! {
-		result = run_server(&endpoint, factorio_address) => result.unwrap(),
+		result = run_server(&endpoint, factorio_address, &args, instance_name) => result.unwrap(),
 		_ = tokio::signal::ctrl_c() => {}
 	}
 	
+	health_state.set_draining();
+	
 	endpoint.close(0u32.into(), b"quit");
 	
 	select! {
@@ -190,18 +1076,121 @@ async fn subcommand_server(args: ServerArgs) {
 	info!("Shutdown");
 }
 
-async fn run_server(endpoint: &Endpoint, factorio_address: SocketAddr) -> anyhow::Result<()> {
+async fn run_server(
+	endpoint: &Endpoint,
+	factorio_address: Arc<net::UpstreamAddress>,
+	args: &ServerArgs,
+	instance_name: Option<String>,
+) -> anyhow::Result<()> {
+	check_udp_queue_size(args.udp_queue_size)?;
+	
+	if args.transfer_block_size == 0 {
+		return Err(anyhow::anyhow!("Transfer block size must be at least 1"));
+	}
+	
+	if args.peer_idle_timeout == 0 {
+		return Err(anyhow::anyhow!("Peer idle timeout must be at least 1s"));
+	}
+	
+	if args.accept_backlog == 0 || args.accept_concurrency == 0 {
+		return Err(anyhow::anyhow!("Accept backlog and accept concurrency must be at least 1"));
+	}
+	
+	let popular_chunks = args.push_popular_chunks.map(|max_size| {
+		info!("Pushing up to {}B of popular chunks to new clients", utils::abbreviate_number(max_size));
+		
+		Arc::new(PopularChunks::new(max_size))
+	});
+	
+	let delta_index = args.chunk_deltas.map(|max_size| {
+		info!("Keeping up to {}B of chunks from recent worlds to send similar chunks as deltas against", utils::abbreviate_number(max_size));
+		
+		Arc::new(DeltaIndex::new(max_size))
+	});
+	
+	let chunk_cache = args.chunk_cache_limit.map(|max_size| {
+		info!("Keeping up to {}B of chunks from recent worlds in memory", utils::abbreviate_number(max_size));
+		
+		Arc::new(ChunkCache::new(max_size, CacheLimitBasis::Uncompressed))
+	});
+	
+	info!("Forwarding worlds smaller than {}B without deduplicating them", utils::abbreviate_number(args.min_dedup_size as u64));
+	
+	if args.no_rewrite {
+		warn!("--no-rewrite is set: world info won't be rewritten, so factorio clients will reject every deduplicated world. This is only meant for debugging!");
+	}
+	
+	let proxy_config = Arc::new(ServerProxyConfig {
+		handshake_timeout: Duration::from_secs(args.handshake_timeout),
+		peer_idle_timeout: Duration::from_secs(args.peer_idle_timeout),
+		dropped_packet_log_interval: (args.dropped_packet_log_interval > 0)
+			.then(|| Duration::from_secs(args.dropped_packet_log_interval)),
+		queue_depth_log_interval: (args.queue_depth_log_interval > 0)
+			.then(|| Duration::from_secs(args.queue_depth_log_interval)),
+		udp_queue_size: args.udp_queue_size,
+		stats_file: args.stats_file.clone(),
+		transfer_block_size: args.transfer_block_size,
+		world_ready_timeout: (args.world_ready_timeout > 0)
+			.then(|| Duration::from_secs(args.world_ready_timeout)),
+		max_download_time: (args.max_download_time > 0)
+			.then(|| Duration::from_secs(args.max_download_time)),
+		min_dedup_size: args.min_dedup_size,
+		chunking_policy: args.whole_file_extensions.clone(),
+		packet_tracer: PacketTracer::new(args.trace_packets, args.log_unrecognized_packets, args.trace_pcap.as_deref())?.map(Arc::new),
+		max_idle_connection_time: (args.max_idle_connection_time > 0)
+			.then(|| Duration::from_secs(args.max_idle_connection_time)),
+		delta_index,
+		chunk_cache,
+		rewrite_world_info: !args.no_rewrite,
+		verify_upstream_crc: args.verify_upstream_crc,
+		instance_name,
+		compress_datagrams_above: args.compress_datagrams_above,
+		world_cache: (args.world_cache_ttl > 0)
+			.then(|| WorldCache::new(Duration::from_secs(args.world_cache_ttl))),
+	});
+	
+	let connection_groups = Arc::new(ConnectionGroups::default());
+	
 	info!("Started");
 	
+	// Handshakes run concurrently so that when lots of clients reconnect at once, like after a popular server restarts,
+	//  they aren't stuck waiting on each other's round trips
+	let mut handshakes = JoinSet::new();
+	
 	loop {
-		let connection = endpoint.accept().await.unwrap().await?;
+		let result = select! {
+			incoming = endpoint.accept(), if handshakes.len() < args.accept_concurrency => {
+				let Some(incoming) = incoming else {
+					return Ok(());
+				};
+				
+				handshakes.spawn(async move { incoming.await });
+				continue;
+			}
+			Some(result) = handshakes.join_next() => result?,
+		};
+		
+		// A failed handshake only concerns the peer it was with, so the accept loop carries on right away rather than
+		//  letting one peer hold up everyone else's connections
+		let connection = match result {
+			Ok(connection) => connection,
+			Err(err) => {
+				limited_log!(warn, "Failed to accept connection: {}", err);
+				continue;
+			}
+		};
+		
+		let factorio_address = factorio_address.clone();
+		let popular_chunks = popular_chunks.clone();
+		let connection_groups = connection_groups.clone();
+		let proxy_config = proxy_config.clone();
 		
 		tokio::spawn(async move {
 			let client_address = connection.remote_address();
 			
 			info!("Client from {:?} connected", client_address);
 			
-			if let Err(err) = server_proxy::run_server_proxy(Arc::new(connection), factorio_address).await {
+			if let Err(err) = server_proxy::run_server_proxy(Arc::new(connection), factorio_address, popular_chunks, connection_groups, proxy_config).await {
 				error!("Error running server: {:?}", err);
 			}
 			
@@ -210,13 +1199,468 @@ async fn run_server(endpoint: &Endpoint, factorio_address: SocketAddr) -> anyhow
 	}
 }
 
-fn setup_logging() {
+async fn subcommand_compact_cache(args: CompactCacheArgs) {
+	let cache_path = args.cache_path
+		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
+	let output_path = args.output_path.unwrap_or_else(|| cache_path.clone());
+	
+	if !zstd::compression_level_range().contains(&args.level) {
+		panic!("Compression level must be within {:?}", zstd::compression_level_range());
+	}
+	
+	let before_size = std::fs::metadata(&cache_path).expect("Error reading cache file").len();
+	
+	info!("Compacting {} at level {}", cache_path.display(), args.level);
+	
+	let chunk_count = {
+		let output_path = output_path.clone();
+		
+		tokio::task::spawn_blocking(move || chunk_cache::compact_cache_file(&cache_path, &output_path, args.level))
+			.await
+			.unwrap()
+			.expect("Error compacting cache")
+	};
+	
+	let after_size = std::fs::metadata(&output_path).expect("Error reading compacted cache file").len();
+	
+	info!("Compacted {} chunks from {}B to {}B, written to {}",
+		chunk_count,
+		utils::abbreviate_number(before_size),
+		utils::abbreviate_number(after_size),
+		output_path.display(),
+	);
+}
+
+async fn subcommand_cache_info(args: CacheInfoArgs) {
+	let cache_path = args.cache_path
+		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
+	
+	let file_size = std::fs::metadata(&cache_path).expect("Error reading cache file").len();
+	
+	// Load without a size limit so that the whole file is summarized
+	let chunk_cache = ChunkCache::load_from_file(u64::MAX, CacheLimitBasis::Uncompressed, cache_path.clone()).await
+		.expect("Error loading cache");
+	
+	let mut chunk_sizes = chunk_cache.chunk_sizes();
+	chunk_sizes.sort_unstable();
+	
+	println!("Cache file: {}", cache_path.display());
+	println!("Chunk count: {}", chunk_sizes.len());
+	println!("Total size: {}B", utils::abbreviate_number(chunk_cache.total_size()));
+	println!("File size: {}B", utils::abbreviate_number(file_size));
+	
+	let (Some(&min_size), Some(&max_size)) = (chunk_sizes.first(), chunk_sizes.last()) else {
+		return;
+	};
+	
+	println!("Chunk size: min {}B, avg {}B, max {}B",
+		utils::abbreviate_number(min_size as u64),
+		utils::abbreviate_number(chunk_cache.total_size() / chunk_sizes.len() as u64),
+		utils::abbreviate_number(max_size as u64),
+	);
+	
+	// Bucket chunks by the power of two at or below their size
+	let mut histogram: BTreeMap<u32, usize> = BTreeMap::new();
+	
+	for &size in &chunk_sizes {
+		*histogram.entry(size.max(1).ilog2()).or_default() += 1;
+	}
+	
+	let largest_bucket = histogram.values().copied().max().unwrap_or(1);
+	
+	println!("Chunk size histogram:");
+	
+	for (&bucket, &count) in &histogram {
+		let bar_length = (count * 50).div_ceil(largest_bucket);
+		
+		println!("  {:>6}B - {:>6}B: {:>8} {}",
+			utils::abbreviate_number(1 << bucket),
+			utils::abbreviate_number((1 << (bucket + 1)) - 1),
+			count,
+			"#".repeat(bar_length),
+		);
+	}
+}
+
+async fn subcommand_bench_chunker(args: BenchChunkerArgs) {
+	if args.iterations == 0 {
+		panic!("Iteration count must be at least 1");
+	}
+	
+	let data = match &args.file {
+		Some(file) => std::fs::read(file).expect("Error reading file"),
+		None => chunker::generate_bench_data(args.size),
+	};
+	
+	if data.is_empty() {
+		panic!("Nothing to chunk");
+	}
+	
+	println!("Chunking {}B of {} {} times", utils::abbreviate_number(data.len() as u64),
+		args.file.as_ref().map_or("generated data".to_owned(), |file| file.display().to_string()), args.iterations);
+	
+	tokio::task::spawn_blocking(move || {
+		let mut chunk_sizes = Vec::new();
+		let mut throughputs = Vec::new();
+		
+		for iteration in 0..args.iterations {
+			chunk_sizes.clear();
+			
+			let start_time = std::time::Instant::now();
+			chunk_sizes.extend(Chunker::new(&data).map(<[u8]>::len));
+			let elapsed = start_time.elapsed();
+			
+			let throughput = data.len() as f64 / elapsed.as_secs_f64();
+			throughputs.push(throughput);
+			
+			println!("Iteration {}: {}ms, {}B/s", iteration + 1, elapsed.as_millis(), utils::abbreviate_number(throughput as u64));
+		}
+		
+		let best = throughputs.iter().copied().fold(0.0, f64::max);
+		let average = throughputs.iter().sum::<f64>() / throughputs.len() as f64;
+		
+		println!("Throughput: avg {}B/s, best {}B/s", utils::abbreviate_number(average as u64), utils::abbreviate_number(best as u64));
+		
+		let unique_size: usize = {
+			let mut unique_chunks = std::collections::HashSet::new();
+			
+			Chunker::new(&data)
+				.filter(|chunk| unique_chunks.insert(*chunk))
+				.map(<[u8]>::len)
+				.sum()
+		};
+		
+		chunk_sizes.sort_unstable();
+		
+		println!("Chunk count: {}", chunk_sizes.len());
+		println!("Chunk size: min {}B, avg {}B, max {}B",
+			utils::abbreviate_number(chunk_sizes[0] as u64),
+			utils::abbreviate_number((data.len() / chunk_sizes.len()) as u64),
+			utils::abbreviate_number(chunk_sizes[chunk_sizes.len() - 1] as u64),
+		);
+		println!("Unique chunk data: {}B ({:.1}%)", utils::abbreviate_number(unique_size as u64),
+			unique_size as f64 / data.len() as f64 * 100.0);
+	}).await.unwrap();
+}
+
+async fn subcommand_manifest(args: ManifestArgs) {
+	let world_data = std::fs::read(&args.world_path).expect("Error reading world");
+	
+	tokio::task::spawn_blocking(move || {
+		// A save file on its own has no aux data
+		let (world_desc, chunks) = dedup::deconstruct_world(&world_data, &[], &ChunkingPolicy::default()).expect("Error deconstructing world");
+		
+		match &args.output_path {
+			Some(output_path) => {
+				let mut writer = BufWriter::new(File::create(output_path).expect("Error creating output file"));
+				
+				manifest::write_manifest(&world_desc, &chunks, &mut writer)
+					.and_then(|_| writer.flush())
+					.expect("Error writing manifest");
+			}
+			None => manifest::write_manifest(&world_desc, &chunks, &mut std::io::stdout().lock()).expect("Error writing manifest"),
+		}
+	}).await.unwrap();
+}
+
+async fn subcommand_stats_summary(args: StatsSummaryArgs) {
+	if args.stats_paths.is_empty() {
+		panic!("At least one stats file is required");
+	}
+	
+	let mut records = Vec::new();
+	
+	for stats_path in &args.stats_paths {
+		let (file_records, bad_lines) = stats::read_transfer_stats(stats_path).expect("Error reading stats file");
+		
+		if bad_lines > 0 {
+			warn!("Skipped {} lines of {} that aren't transfer records", bad_lines, stats_path.display());
+		}
+		
+		records.extend(file_records);
+	}
+	
+	let Some(summary) = StatsSummary::new(&records) else {
+		println!("No transfers recorded");
+		return;
+	};
+	
+	let format_time = |timestamp: SystemTime| time::OffsetDateTime::from(timestamp)
+		.format(time::macros::format_description!("[year]-[month]-[day] [hour]:[minute]:[second] UTC"))
+		.unwrap();
+	
+	println!("Transfers: {}", summary.transfer_count);
+	
+	if summary.duplicate_count > 0 {
+		println!("Duplicate records skipped: {}", summary.duplicate_count);
+	}
+	
+	println!("Time range: {} to {}", format_time(summary.first_timestamp), format_time(summary.last_timestamp));
+	println!("Original world data: {}B", utils::abbreviate_number(summary.total_original_size));
+	println!("Transferred: {}B ({:.2}%)", utils::abbreviate_number(summary.total_transferred), summary.dedup_ratio() * 100.0);
+	println!("Saved: {}B", utils::abbreviate_number(summary.bytes_saved()));
+	
+	let [p50, p90, p99, max] = summary.duration_percentiles.map(|duration| duration.as_millis());
+	println!("Transfer duration: p50 {}ms, p90 {}ms, p99 {}ms, max {}ms", p50, p90, p99, max);
+}
+
+fn check_udp_queue_size(udp_queue_size: usize) -> anyhow::Result<()> {
+	if udp_queue_size < proxy::MIN_UDP_QUEUE_SIZE {
+		return Err(anyhow::anyhow!("UDP queue size must be at least {}", proxy::MIN_UDP_QUEUE_SIZE));
+	}
+	
+	info!("Queueing up to {} packets per peer", udp_queue_size);
+	
+	Ok(())
+}
+
+fn check_dscp(dscp: Option<u8>) -> anyhow::Result<()> {
+	let Some(dscp) = dscp else { return Ok(()); };
+	
+	if dscp > 63 {
+		return Err(anyhow::anyhow!("DSCP class must be between 0 and 63"));
+	}
+	
+	info!("Marking QUIC traffic with DSCP class {}", dscp);
+	
+	Ok(())
+}
+
+fn check_mtu(initial_mtu: u16, max_mtu: u16, no_mtu_discovery: bool) -> anyhow::Result<MtuConfig> {
+	if !(quic::MIN_MTU..=quic::MAX_MTU).contains(&initial_mtu) {
+		return Err(anyhow::anyhow!("Initial MTU must be between {} and {}", quic::MIN_MTU, quic::MAX_MTU));
+	}
+	
+	if no_mtu_discovery {
+		info!("QUIC packets are sized for a {}B MTU", initial_mtu);
+		
+		return Ok(MtuConfig { initial_mtu, max_mtu: None });
+	}
+	
+	if !(initial_mtu..=quic::MAX_MTU).contains(&max_mtu) {
+		return Err(anyhow::anyhow!("Max MTU must be between the initial MTU and {}", quic::MAX_MTU));
+	}
+	
+	info!("QUIC packets start out sized for a {}B MTU, probing for up to {}B", initial_mtu, max_mtu);
+	
+	Ok(MtuConfig { initial_mtu, max_mtu: Some(max_mtu) })
+}
+
+/// Whether log output is colored.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+enum ColorOutput {
+	Auto,
+	Always,
+	Never,
+}
+
+impl FromStr for ColorOutput {
+	type Err = anyhow::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"auto" => Ok(ColorOutput::Auto),
+			"always" => Ok(ColorOutput::Always),
+			"never" => Ok(ColorOutput::Never),
+			_ => Err(anyhow::anyhow!("Expected 'auto', 'always' or 'never'")),
+		}
+	}
+}
+
+/// With trace_packets, trace level messages are let through for traced packets alone, so that dependencies' trace
+///  logging stays hidden.
+fn setup_logging(instance_name: Option<String>, color: ColorOutput, trace_packets: bool) {
 	use simplelog::*;
 	
+	// https://no-color.org, which only changes the default so that --color always still works
+	let color_choice = match color {
+		ColorOutput::Auto if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) => ColorChoice::Never,
+		ColorOutput::Auto => ColorChoice::Auto,
+		ColorOutput::Always => ColorChoice::Always,
+		ColorOutput::Never => ColorChoice::Never,
+	};
+	
 	let config = ConfigBuilder::new()
 		.set_time_format_custom(format_description!("[[[hour repr:12]:[minute]:[second] [period]]"))
 		.set_time_offset_to_local().unwrap()
 		.build();
 	
-	TermLogger::init(LevelFilter::Info, config, TerminalMode::Stdout, ColorChoice::Auto).expect("Unable to init logger");
+	let level = if trace_packets { LevelFilter::Trace } else { LevelFilter::Info };
+	
+	if instance_name.is_none() && !trace_packets {
+		TermLogger::init(level, config, TerminalMode::Stdout, color_choice).expect("Unable to init logger");
+		return;
+	}
+	
+	let logger = FilteredLogger {
+		instance_name,
+		trace_packets,
+		inner: TermLogger::new(level, config, TerminalMode::Stdout, color_choice),
+	};
+	
+	log::set_boxed_logger(Box::new(logger)).expect("Unable to init logger");
+	log::set_max_level(level);
+}
+
+/// Tags every message with the instance's name if there is one, and only lets through trace level messages that are
+///  traced packets.
+struct FilteredLogger {
+	instance_name: Option<String>,
+	trace_packets: bool,
+	inner: Box<simplelog::TermLogger>,
+}
+
+impl log::Log for FilteredLogger {
+	fn enabled(&self, metadata: &log::Metadata) -> bool {
+		let traced_packet = self.trace_packets && metadata.target() == packet_trace::LOG_TARGET;
+		
+		(metadata.level() <= log::Level::Info || traced_packet) && self.inner.enabled(metadata)
+	}
+	
+	fn log(&self, record: &log::Record) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+		
+		let Some(instance_name) = &self.instance_name else {
+			self.inner.log(record);
+			return;
+		};
+		
+		self.inner.log(&log::Record::builder()
+			.args(format_args!("[{}] {}", instance_name, record.args()))
+			.metadata(record.metadata().clone())
+			.module_path(record.module_path())
+			.file(record.file())
+			.line(record.line())
+			.build());
+	}
+	
+	fn flush(&self) {
+		self.inner.flush();
+	}
+}
+
+async fn subcommand_replay(args: ReplayArgs) {
+	let trace_data = std::fs::read(&args.trace_path).expect("Error reading trace");
+	let packets = packet_trace::read_pcap(trace_data.into()).expect("Error reading trace");
+	
+	// The server proxy sends the first packet of every peer to the factorio server
+	let Some(factorio_addr) = args.factorio_address.or_else(|| packets.first().map(|packet| packet.to)) else {
+		println!("Trace is empty");
+		return;
+	};
+	
+	println!("Replaying {} packets exchanged with {}", packets.len(), factorio_addr);
+	
+	// The defaults check every world in the trace, even ones the server forwarded untouched, and leave checking each
+	//  world's CRC to the replay
+	let config = Arc::new(ServerProxyConfig {
+		handshake_timeout: Duration::ZERO,
+		transfer_block_size: args.transfer_block_size,
+		..ServerProxyConfig::default()
+	});
+	
+	let replayed_worlds = tokio::task::spawn_blocking(move || replay::replay_server_trace(packets, factorio_addr, config))
+		.await
+		.unwrap();
+	
+	if replayed_worlds.is_empty() {
+		println!("No complete world downloads found in the trace");
+		return;
+	}
+	
+	let describe = |crc_matches: bool| if crc_matches { "CRC matches" } else { "CRC MISMATCH" };
+	
+	for replayed_world in &replayed_worlds {
+		println!("Peer {}: {}B world, {}B aux data", replayed_world.peer_addr,
+			utils::abbreviate_number(replayed_world.world_info.world_size as u64),
+			utils::abbreviate_number(replayed_world.world_info.aux_size as u64));
+		println!("  Download: {}", describe(replayed_world.download_crc_matches));
+		
+		match replayed_world.reconstruction_crc_matches {
+			Some(crc_matches) => println!("  Reconstruction: {}", describe(crc_matches)),
+			None => println!("  Reconstruction: FAILED"),
+		}
+	}
+	
+	let failed_count = replayed_worlds.iter().filter(|replayed_world| !replayed_world.is_ok()).count();
+	
+	if failed_count > 0 {
+		panic!("{} of {} replayed worlds failed", failed_count, replayed_worlds.len());
+	}
+}
+
+async fn subcommand_export_cas(args: ExportCasArgs) {
+	let cache_path = args.cache_path
+		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
+	
+	info!("Exporting {} to {}", cache_path.display(), args.cas_dir.display());
+	
+	let (written_count, existing_count) = {
+		let cas_dir = args.cas_dir.clone();
+		
+		tokio::task::spawn_blocking(move || chunk_cache::export_cas(&cache_path, &cas_dir))
+			.await
+			.unwrap()
+			.expect("Error exporting cache")
+	};
+	
+	info!("Wrote {} chunks to {}, {} were already there", written_count, args.cas_dir.display(), existing_count);
+}
+
+async fn subcommand_import_cas(args: ImportCasArgs) {
+	let cache_path = args.cache_path
+		.unwrap_or_else(|| std::path::absolute("persistent-cache").unwrap());
+	
+	info!("Importing {} into {}", args.cas_dir.display(), cache_path.display());
+	
+	let (imported_count, skipped_count) = {
+		let cache_path = cache_path.clone();
+		
+		tokio::task::spawn_blocking(move || chunk_cache::import_cas(&args.cas_dir, &cache_path))
+			.await
+			.unwrap()
+			.expect("Error importing chunks")
+	};
+	
+	if skipped_count > 0 {
+		warn!("Skipped {} files that weren't valid chunks", skipped_count);
+	}
+	
+	info!("Added {} chunks to {}", imported_count, cache_path.display());
+}
+
+async fn subcommand_cache_diff(args: CacheDiffArgs) {
+	let diff = {
+		let first_path = args.first_path.clone();
+		let second_path = args.second_path.clone();
+		
+		tokio::task::spawn_blocking(move || chunk_cache::diff_cache_files(&first_path, &second_path))
+			.await
+			.unwrap()
+			.expect("Error comparing caches")
+	};
+	
+	let sets = [
+		("only_in_first", "Only in first", diff.only_in_first),
+		("only_in_second", "Only in second", diff.only_in_second),
+		("shared", "Shared", diff.shared),
+	];
+	
+	if args.json {
+		let fields = sets.iter()
+			.map(|(key, _, set)| format!("\"{}\": {{\"chunks\": {}, \"bytes\": {}}}", key, set.chunk_count, set.total_size))
+			.collect::<Vec<_>>();
+		
+		println!("{{{}}}", fields.join(", "));
+		return;
+	}
+	
+	println!("First: {}", args.first_path.display());
+	println!("Second: {}", args.second_path.display());
+	
+	for (_, name, set) in sets {
+		println!("{}: {} chunks, {}B", name, set.chunk_count, utils::abbreviate_number(set.total_size));
+	}
 }
@@ -0,0 +1,129 @@
+use crate::dedup::{ChunkKey, FactorioFileType, FactorioWorldDescription};
+use bytes::Bytes;
+use hashlink::LinkedHashMap;
+use std::fmt::Write as _;
+use std::io::Write;
+
+/// Writes a world's description as JSON, with one chunk per line so that manifests of two worlds diff cleanly.
+pub fn write_manifest(
+	world_desc: &FactorioWorldDescription,
+	chunks: &LinkedHashMap<ChunkKey, Bytes>,
+	out: &mut impl Write,
+) -> std::io::Result<()> {
+	writeln!(out, "{{")?;
+	writeln!(out, "  \"aux_size\": {},", world_desc.aux_data.len())?;
+	writeln!(out, "  \"files\": [")?;
+	
+	for (file_index, file) in world_desc.files.iter().enumerate() {
+		let file_type = match file.file_type {
+			FactorioFileType::Normal => "normal",
+			FactorioFileType::Zlib => "zlib",
+		};
+		
+		writeln!(out, "    {{")?;
+		writeln!(out, "      \"file_name\": {},", json_string(&file.file_name))?;
+		writeln!(out, "      \"file_type\": \"{}\",", file_type)?;
+		writeln!(out, "      \"content_size\": {},", file.content_size)?;
+		writeln!(out, "      \"content_chunks\": [")?;
+		
+		for (chunk_index, key) in file.content_chunks.iter().enumerate() {
+			let size = chunks.get(key).map_or(0, Bytes::len);
+			let separator = if chunk_index + 1 < file.content_chunks.len() { "," } else { "" };
+			
+			writeln!(out, "        {{\"key\": \"{}\", \"size\": {}}}{}", key.0.to_hex(), size, separator)?;
+		}
+		
+		writeln!(out, "      ]")?;
+		writeln!(out, "    }}{}", if file_index + 1 < world_desc.files.len() { "," } else { "" })?;
+	}
+	
+	writeln!(out, "  ]")?;
+	writeln!(out, "}}")?;
+	
+	Ok(())
+}
+
+fn json_string(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len() + 2);
+	escaped.push('"');
+	
+	for c in value.chars() {
+		match c {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			'\r' => escaped.push_str("\\r"),
+			'\t' => escaped.push_str("\\t"),
+			c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+			c => escaped.push(c),
+		}
+	}
+	
+	escaped.push('"');
+	escaped
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::content_hash::CONTENT_HASH;
+	use crate::dedup::FactorioFileDescription;
+	
+	#[test]
+	fn manifest_lists_every_chunk_on_its_own_line() {
+		let chunk = Bytes::from_static(b"chunk");
+		let key = CONTENT_HASH.hash(&chunk);
+		
+		let mut chunks = LinkedHashMap::new();
+		chunks.insert(key, chunk);
+		
+		let world_desc = FactorioWorldDescription {
+			files: vec![
+				FactorioFileDescription {
+					file_type: FactorioFileType::Zlib,
+					file_name: "save/\"level\".dat0".to_owned(),
+					content_size: 10,
+					content_chunks: vec![key, key],
+				},
+				FactorioFileDescription {
+					file_type: FactorioFileType::Normal,
+					file_name: "save/empty".to_owned(),
+					content_size: 0,
+					content_chunks: Vec::new(),
+				},
+			],
+			aux_data: Bytes::from_static(b"aux"),
+		};
+		
+		let mut out = Vec::new();
+		write_manifest(&world_desc, &chunks, &mut out).unwrap();
+		
+		let chunk_line = format!("        {{\"key\": \"{}\", \"size\": 5}}", key.0.to_hex());
+		let expected = [
+			"{",
+			"  \"aux_size\": 3,",
+			"  \"files\": [",
+			"    {",
+			"      \"file_name\": \"save/\\\"level\\\".dat0\",",
+			"      \"file_type\": \"zlib\",",
+			"      \"content_size\": 10,",
+			"      \"content_chunks\": [",
+			&format!("{},", chunk_line),
+			&chunk_line,
+			"      ]",
+			"    },",
+			"    {",
+			"      \"file_name\": \"save/empty\",",
+			"      \"file_type\": \"normal\",",
+			"      \"content_size\": 0,",
+			"      \"content_chunks\": [",
+			"      ]",
+			"    }",
+			"  ]",
+			"}",
+			"",
+		].join("\n");
+		
+		assert_eq!(String::from_utf8(out).unwrap(), expected);
+	}
+}
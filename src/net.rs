@@ -0,0 +1,295 @@
+use quinn::{udp, AsyncUdpSocket, Runtime, TokioRuntime, UdpPoller};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt::Debug;
+use std::future::Future;
+use std::io::IoSliceMut;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::Interest;
+
+/// Looks an address up like tokio's lookup_host, but also accepts link-local IPv6 addresses scoped by interface name,
+///  like `[fe80::1%eth0]:34197`, which the standard parsing only accepts with a numeric scope id.
+pub async fn lookup_host(address: &str) -> std::io::Result<impl Iterator<Item = SocketAddr>> {
+	let addrs = match parse_address(address)? {
+		Some(addr) => vec![addr],
+		None => tokio::net::lookup_host(address).await?.collect(),
+	};
+	
+	Ok(addrs.into_iter())
+}
+
+/// Parses a literal socket address, keeping the scope id of scoped IPv6 addresses. Returns None for host names.
+fn parse_address(address: &str) -> std::io::Result<Option<SocketAddr>> {
+	if let Ok(addr) = address.parse::<SocketAddr>() {
+		return Ok(Some(addr));
+	}
+	
+	let scoped_address = address.rsplit_once(':')
+		.and_then(|(host, port)| Some((host.strip_prefix('[')?.strip_suffix(']')?.split_once('%')?, port)));
+	
+	let Some(((ip, scope), port)) = scoped_address else {
+		return Ok(None);
+	};
+	
+	let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid address {}", address));
+	let ip = ip.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+	let port = port.parse::<u16>().map_err(|_| invalid())?;
+	
+	let scope_id = match scope.parse::<u32>() {
+		Ok(scope_id) => scope_id,
+		Err(_) => interface_index(scope)?,
+	};
+	
+	Ok(Some(SocketAddrV6::new(ip, port, 0, scope_id).into()))
+}
+
+#[cfg(unix)]
+fn interface_index(interface_name: &str) -> std::io::Result<u32> {
+	let c_name = std::ffi::CString::new(interface_name)
+		.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Interface name contains a nul byte"))?;
+	
+	// SAFETY: c_name is a valid nul terminated string that outlives the call
+	match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+		0 => Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No network interface named {}", interface_name))),
+		index => Ok(index),
+	}
+}
+
+#[cfg(not(unix))]
+fn interface_index(_interface_name: &str) -> std::io::Result<u32> {
+	Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Scope ids must be interface indexes on this platform"))
+}
+
+/// An upstream server's address, which is looked up again on request if it was given as a host name.
+pub struct UpstreamAddress {
+	host_name: Option<String>,
+	current: Mutex<SocketAddr>,
+}
+
+impl UpstreamAddress {
+	pub async fn resolve(address: &str) -> anyhow::Result<Self> {
+		let current = lookup_host(address).await?
+			.next()
+			.ok_or_else(|| anyhow::anyhow!("No address found for {}", address))?;
+		
+		Ok(Self {
+			host_name: parse_address(address)?.is_none().then(|| address.to_owned()),
+			current: Mutex::new(current),
+		})
+	}
+	
+	pub fn get(&self) -> SocketAddr {
+		*self.current.lock().unwrap()
+	}
+	
+	/// Looks the host name up again, returning the new address. Keeps the old one if the lookup fails.
+	pub async fn re_resolve(&self) -> SocketAddr {
+		let Some(host_name) = &self.host_name else {
+			return self.get();
+		};
+		
+		match lookup_host(host_name.as_str()).await.map(|mut addrs| addrs.next()) {
+			Ok(Some(addr)) => {
+				let old_addr = std::mem::replace(&mut *self.current.lock().unwrap(), addr);
+				
+				if old_addr != addr {
+					log::info!("{} moved from {} to {}", host_name, old_addr, addr);
+				}
+				
+				addr
+			}
+			Ok(None) => {
+				log::warn!("No address found for {}", host_name);
+				self.get()
+			}
+			Err(err) => {
+				log::warn!("Error looking up {}: {}", host_name, err);
+				self.get()
+			}
+		}
+	}
+}
+
+/// Binds a UDP socket, setting SO_REUSEPORT when asked for, since what it allows varies between platforms.
+pub fn bind_udp_socket(address: SocketAddr, reuse_port: bool) -> std::io::Result<std::net::UdpSocket> {
+	let socket = Socket::new(Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+	
+	if reuse_port {
+		#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+		socket.set_reuse_port(true)?;
+		
+		#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+		log::warn!("SO_REUSEPORT is not supported on this platform");
+	}
+	
+	socket.set_nonblocking(true)?;
+	socket.bind(&address.into())?;
+	
+	Ok(socket.into())
+}
+
+pub fn bind_tokio_udp_socket(address: SocketAddr, reuse_port: bool) -> std::io::Result<tokio::net::UdpSocket> {
+	tokio::net::UdpSocket::from_std(bind_udp_socket(address, reuse_port)?)
+}
+
+/// Wraps a bound socket for use by a QUIC endpoint, marking all of its packets with a DSCP class if one is given.
+pub fn quic_socket(socket: std::net::UdpSocket, dscp: Option<u8>) -> std::io::Result<Arc<dyn AsyncUdpSocket>> {
+	let Some(dscp) = dscp else {
+		return TokioRuntime.wrap_udp_socket(socket);
+	};
+	
+	set_dscp(&socket, dscp)?;
+	
+	Ok(Arc::new(DscpUdpSocket {
+		state: udp::UdpSocketState::new((&socket).into())?,
+		io: tokio::net::UdpSocket::from_std(socket)?,
+	}))
+}
+
+#[cfg(any(
+	target_os = "android",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "linux",
+	target_os = "macos",
+	target_os = "netbsd",
+	target_os = "openbsd",
+))]
+fn set_dscp(socket: &std::net::UdpSocket, dscp: u8) -> std::io::Result<()> {
+	// DSCP is the upper 6 bits of the traffic class, the lower 2 are for ECN
+	let traffic_class = (dscp as u32) << 2;
+	let socket = socket2::SockRef::from(socket);
+	
+	if socket.local_addr()?.is_ipv6() {
+		socket.set_tclass_v6(traffic_class)?;
+		
+		// Dual stack sockets use the IPv4 option for IPv4 destinations, but not every platform allows setting it
+		if let Err(err) = socket.set_tos(traffic_class) {
+			log::debug!("Couldn't set IP_TOS on IPv6 socket: {}", err);
+		}
+	} else {
+		socket.set_tos(traffic_class)?;
+	}
+	
+	Ok(())
+}
+
+#[cfg(not(any(
+	target_os = "android",
+	target_os = "dragonfly",
+	target_os = "freebsd",
+	target_os = "linux",
+	target_os = "macos",
+	target_os = "netbsd",
+	target_os = "openbsd",
+)))]
+fn set_dscp(_socket: &std::net::UdpSocket, _dscp: u8) -> std::io::Result<()> {
+	Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "DSCP marking is not supported on this platform"))
+}
+
+/// QUIC socket that sends with the traffic class set on the socket. quinn normally sets the traffic class on every
+///  packet it sends in order to carry ECN, which would replace the DSCP bits, so this sends plain datagrams instead,
+///  giving up ECN and segmentation offload.
+#[derive(Debug)]
+struct DscpUdpSocket {
+	io: tokio::net::UdpSocket,
+	state: udp::UdpSocketState,
+}
+
+impl AsyncUdpSocket for DscpUdpSocket {
+	fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+		Box::pin(WritablePoller {
+			socket: self,
+			writable: None,
+		})
+	}
+	
+	fn try_send(&self, transmit: &udp::Transmit) -> std::io::Result<()> {
+		// Only one datagram is sent per transmit since max_transmit_segments isn't overridden
+		self.io.try_send_to(transmit.contents, transmit.destination)?;
+		
+		Ok(())
+	}
+	
+	fn poll_recv(
+		&self,
+		cx: &mut Context,
+		bufs: &mut [IoSliceMut<'_>],
+		meta: &mut [udp::RecvMeta],
+	) -> Poll<std::io::Result<usize>> {
+		loop {
+			ready!(self.io.poll_recv_ready(cx))?;
+			
+			if let Ok(result) = self.io.try_io(Interest::READABLE, || self.state.recv((&self.io).into(), bufs, meta)) {
+				return Poll::Ready(Ok(result));
+			}
+		}
+	}
+	
+	fn local_addr(&self) -> std::io::Result<SocketAddr> {
+		self.io.local_addr()
+	}
+	
+	fn max_receive_segments(&self) -> usize {
+		self.state.gro_segments()
+	}
+	
+	fn may_fragment(&self) -> bool {
+		self.state.may_fragment()
+	}
+}
+
+type WritableFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + Sync>>;
+
+struct WritablePoller {
+	socket: Arc<DscpUdpSocket>,
+	writable: Option<WritableFuture>,
+}
+
+impl UdpPoller for WritablePoller {
+	fn poll_writable(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::io::Result<()>> {
+		let this = &mut *self;
+		
+		let writable = this.writable.get_or_insert_with(|| {
+			let socket = this.socket.clone();
+			Box::pin(async move { socket.io.writable().await })
+		});
+		
+		let result = ready!(writable.as_mut().poll(cx));
+		this.writable = None;
+		
+		Poll::Ready(result)
+	}
+}
+
+impl Debug for WritablePoller {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("WritablePoller").finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn scoped_addresses_keep_their_scope_id() {
+		let addr = parse_address("[fe80::1%3]:34197").unwrap().unwrap();
+		assert_eq!(addr, SocketAddrV6::new("fe80::1".parse().unwrap(), 34197, 0, 3).into());
+		
+		assert!(parse_address("[fe80::1%no-such-interface]:34197").is_err());
+		assert!(parse_address("[fe80::1%3]:port").is_err());
+		assert_eq!(parse_address("factorio.example.com:34197").unwrap(), None);
+	}
+	
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn scope_ids_can_be_interface_names() {
+		let SocketAddr::V6(addr) = parse_address("[fe80::1%lo]:34197").unwrap().unwrap() else { panic!() };
+		
+		assert_ne!(addr.scope_id(), 0);
+		assert_eq!(addr.port(), 34197);
+	}
+}
@@ -0,0 +1,383 @@
+use crate::factorio_protocol::FactorioPacketHeader;
+use crate::log_limit::{self, limited_log, LogLimiter};
+use anyhow::anyhow;
+use bytes::{Buf, Bytes};
+use log::{error, info, trace};
+use quinn_proto::VarInt;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The target that traced packets are logged with, at trace level so that logging them is easy to filter.
+pub const LOG_TARGET: &str = "packets";
+
+/// pcap link type for packets that start straight at the IP header.
+const LINKTYPE_RAW: u32 = 101;
+
+/// How much of an unrecognized packet is logged.
+const MAX_LOGGED_PACKET_BYTES: usize = 64;
+/// How many packets can be waiting to be written to the pcap file before new ones are dropped.
+const PCAP_QUEUE_SIZE: usize = 4096;
+
+/// Records the factorio packets a proxy sends and receives, by logging them, writing them to a pcap file, or both.
+pub struct PacketTracer {
+	log_packets: bool,
+	unrecognized_packets: Option<UnrecognizedPacketLog>,
+	/// Packets are written by a thread of their own, so that proxies never wait on the disk.
+	pcap: Option<SyncSender<PcapRecord>>,
+}
+
+/// A packet waiting to be written to the pcap file.
+struct PcapRecord {
+	timestamp: Duration,
+	from: SocketAddr,
+	to: SocketAddr,
+	packet_data: Vec<u8>,
+}
+
+impl PacketTracer {
+	/// Returns None if there's nothing to trace to, so that tracing costs nothing when disabled.
+	pub fn new(log_packets: bool, log_unrecognized_packets: bool, pcap_path: Option<&Path>) -> anyhow::Result<Option<Self>> {
+		let pcap = match pcap_path {
+			Some(pcap_path) => {
+				let mut writer = BufWriter::new(File::create(pcap_path)?);
+				write_pcap_header(&mut writer)?;
+				writer.flush()?;
+				
+				let (sender, receiver) = mpsc::sync_channel(PCAP_QUEUE_SIZE);
+				
+				std::thread::Builder::new()
+					.name("pcap writer".into())
+					.spawn(move || write_pcap_records(writer, receiver))?;
+				
+				Some(sender)
+			}
+			None => None,
+		};
+		
+		if !log_packets && !log_unrecognized_packets && pcap.is_none() {
+			return Ok(None);
+		}
+		
+		Ok(Some(Self {
+			log_packets,
+			unrecognized_packets: log_unrecognized_packets.then(UnrecognizedPacketLog::default),
+			pcap,
+		}))
+	}
+	
+	pub fn trace(&self, peer_id: VarInt, from: SocketAddr, to: SocketAddr, packet_data: &[u8]) {
+		if self.log_packets {
+			// Only the header is needed to describe the packet
+			match FactorioPacketHeader::decode(Bytes::copy_from_slice(&packet_data[..packet_data.len().min(1)])) {
+				Ok((header, _)) => trace!(target: LOG_TARGET, "Peer {} {} -> {}: {:?}{}, {}B", peer_id, from, to,
+					header.packet_type, if header.is_fragmented { " (fragment)" } else { "" }, packet_data.len()),
+				Err(_) => trace!(target: LOG_TARGET, "Peer {} {} -> {}: empty packet", peer_id, from, to),
+			}
+		}
+		
+		if let Some(unrecognized_packets) = &self.unrecognized_packets {
+			unrecognized_packets.log(peer_id, from, to, packet_data, Instant::now());
+		}
+		
+		if let Some(pcap) = &self.pcap {
+			let record = PcapRecord {
+				timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+				from,
+				to,
+				packet_data: packet_data.to_vec(),
+			};
+			
+			// Once the writer has failed there's nowhere for packets to go, and it already logged why
+			if let Err(TrySendError::Full(_)) = pcap.try_send(record) {
+				limited_log!(warn, "Dropped a packet from the packet trace because the disk couldn't keep up");
+			}
+		}
+	}
+}
+
+/// Writes queued packets until the tracer is dropped. Flushed whenever the queue runs dry, so that the trace is
+///  complete even if the process is killed.
+fn write_pcap_records(mut writer: BufWriter<File>, receiver: Receiver<PcapRecord>) {
+	while let Ok(record) = receiver.recv() {
+		let result = std::iter::once(record).chain(receiver.try_iter())
+			.try_for_each(|record| write_pcap_record(&mut writer, record.timestamp, record.from, record.to, &record.packet_data))
+			.and_then(|_| writer.flush());
+		
+		if let Err(err) = result {
+			error!("Failed to write packet trace, stopping it: {}", err);
+			return;
+		}
+	}
+}
+
+/// Logs the raw bytes of packets whose type factorio wasn't known to use, to help with working out what a new factorio
+///  version changed. Each type is only logged once every 10s, so that a flood of them doesn't drown out the log.
+#[derive(Default)]
+struct UnrecognizedPacketLog {
+	limiter: LogLimiter<u8>,
+}
+
+impl UnrecognizedPacketLog {
+	fn log(&self, peer_id: VarInt, from: SocketAddr, to: SocketAddr, packet_data: &[u8], now: Instant) {
+		let Ok((header, _)) = FactorioPacketHeader::decode(Bytes::copy_from_slice(&packet_data[..packet_data.len().min(1)])) else { return; };
+		
+		if !header.packet_type.is_unrecognized() {
+			return;
+		}
+		
+		let Some(skipped_count) = self.limiter.should_log(header.packet_type.into(), now) else { return; };
+		
+		let mut hex = String::new();
+		
+		for byte in &packet_data[..packet_data.len().min(MAX_LOGGED_PACKET_BYTES)] {
+			write!(hex, "{:02x}", byte).unwrap();
+		}
+		
+		info!("Peer {} {} -> {}: unrecognized packet type {}, {}B: {}{}{}", peer_id, from, to, u8::from(header.packet_type),
+			packet_data.len(), hex, if packet_data.len() > MAX_LOGGED_PACKET_BYTES { "..." } else { "" },
+			log_limit::skipped_suffix(skipped_count));
+	}
+}
+
+fn write_pcap_header(writer: &mut impl Write) -> std::io::Result<()> {
+	writer.write_all(&0xA1B2C3D4u32.to_le_bytes())?;
+	writer.write_all(&2u16.to_le_bytes())?; // Major version
+	writer.write_all(&4u16.to_le_bytes())?; // Minor version
+	writer.write_all(&0i32.to_le_bytes())?; // Timezone offset
+	writer.write_all(&0u32.to_le_bytes())?; // Timestamp accuracy
+	writer.write_all(&u16::MAX.to_le_bytes())?; // Snapshot length
+	writer.write_all(&0u16.to_le_bytes())?;
+	writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+	
+	Ok(())
+}
+
+/// Writes a packet wrapped in synthetic IP and UDP headers, so that tools like Wireshark can show where it went.
+fn write_pcap_record(writer: &mut impl Write, timestamp: Duration, from: SocketAddr, to: SocketAddr, packet_data: &[u8]) -> std::io::Result<()> {
+	let mut frame = Vec::with_capacity(48 + packet_data.len());
+	let udp_length = (8 + packet_data.len()) as u16;
+	
+	match to_common_family(from.ip(), to.ip()) {
+		(IpAddr::V4(from_ip), IpAddr::V4(to_ip)) => {
+			let total_length = 20 + udp_length;
+			
+			frame.extend_from_slice(&[0x45, 0]);
+			frame.extend_from_slice(&total_length.to_be_bytes());
+			frame.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]); // Identification, fragment, TTL, UDP, checksum
+			frame.extend_from_slice(&from_ip.octets());
+			frame.extend_from_slice(&to_ip.octets());
+			
+			let checksum = ipv4_checksum(&frame);
+			frame[10..12].copy_from_slice(&checksum.to_be_bytes());
+		}
+		(from_ip, to_ip) => {
+			let from_ip = to_ipv6(from_ip);
+			let to_ip = to_ipv6(to_ip);
+			
+			frame.extend_from_slice(&[0x60, 0, 0, 0]);
+			frame.extend_from_slice(&udp_length.to_be_bytes());
+			frame.extend_from_slice(&[17, 64]); // UDP, hop limit
+			frame.extend_from_slice(&from_ip.octets());
+			frame.extend_from_slice(&to_ip.octets());
+		}
+	}
+	
+	frame.extend_from_slice(&from.port().to_be_bytes());
+	frame.extend_from_slice(&to.port().to_be_bytes());
+	frame.extend_from_slice(&udp_length.to_be_bytes());
+	frame.extend_from_slice(&[0, 0]); // No checksum
+	frame.extend_from_slice(packet_data);
+	
+	writer.write_all(&(timestamp.as_secs() as u32).to_le_bytes())?;
+	writer.write_all(&timestamp.subsec_micros().to_le_bytes())?;
+	writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+	writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+	writer.write_all(&frame)?;
+	
+	Ok(())
+}
+
+/// A packet read back from a pcap trace.
+#[derive(Debug, Eq, PartialEq)]
+pub struct TracedPacket {
+	pub from: SocketAddr,
+	pub to: SocketAddr,
+	pub data: Bytes,
+}
+
+/// Reads the packets from a pcap trace written by PacketTracer, in the order they were traced.
+pub fn read_pcap(mut data: Bytes) -> anyhow::Result<Vec<TracedPacket>> {
+	if data.remaining() < 24 || data.get_u32_le() != 0xA1B2C3D4 {
+		return Err(anyhow!("Not a little endian pcap file"));
+	}
+	
+	data.advance(16);
+	
+	if data.get_u32_le() != LINKTYPE_RAW {
+		return Err(anyhow!("Only pcap files written by --trace-pcap are supported"));
+	}
+	
+	let mut packets = Vec::new();
+	
+	while data.has_remaining() {
+		if data.remaining() < 16 {
+			return Err(anyhow!("Truncated pcap record header"));
+		}
+		
+		data.advance(8); // Timestamp
+		let captured_length = data.get_u32_le() as usize;
+		data.advance(4); // Original length
+		
+		if data.remaining() < captured_length {
+			return Err(anyhow!("Truncated pcap record"));
+		}
+		
+		packets.push(parse_frame(data.split_to(captured_length))?);
+	}
+	
+	Ok(packets)
+}
+
+fn parse_frame(mut frame: Bytes) -> anyhow::Result<TracedPacket> {
+	let (from_ip, to_ip) = match frame.first().map(|byte| byte >> 4) {
+		Some(4) if frame.len() >= 20 => {
+			let header_length = (frame[0] & 0x0F) as usize * 4;
+			
+			if frame[9] != 17 || frame.len() < header_length {
+				return Err(anyhow!("Traced packet isn't UDP"));
+			}
+			
+			let from_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&frame[12..16]).unwrap());
+			let to_ip = Ipv4Addr::from(<[u8; 4]>::try_from(&frame[16..20]).unwrap());
+			frame.advance(header_length);
+			
+			(IpAddr::V4(from_ip), IpAddr::V4(to_ip))
+		}
+		Some(6) if frame.len() >= 40 => {
+			if frame[6] != 17 {
+				return Err(anyhow!("Traced packet isn't UDP"));
+			}
+			
+			let from_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&frame[8..24]).unwrap());
+			let to_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&frame[24..40]).unwrap());
+			frame.advance(40);
+			
+			// Mixed families were written as IPv6, so they're mapped back to compare equal to the original addresses
+			(from_ip.to_canonical(), to_ip.to_canonical())
+		}
+		_ => return Err(anyhow!("Traced packet has an invalid IP header")),
+	};
+	
+	if frame.remaining() < 8 {
+		return Err(anyhow!("Traced packet has a truncated UDP header"));
+	}
+	
+	let from_port = frame.get_u16();
+	let to_port = frame.get_u16();
+	frame.advance(4); // Length and checksum
+	
+	Ok(TracedPacket {
+		from: SocketAddr::new(from_ip, from_port),
+		to: SocketAddr::new(to_ip, to_port),
+		data: frame,
+	})
+}
+
+/// Both addresses need to be the same family to fit in one IP header, so IPv4 is mapped to IPv6 if they differ.
+fn to_common_family(from: IpAddr, to: IpAddr) -> (IpAddr, IpAddr) {
+	match (from, to) {
+		(IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => (from, to),
+		_ => (IpAddr::V6(to_ipv6(from)), IpAddr::V6(to_ipv6(to))),
+	}
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+	match ip {
+		IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+		IpAddr::V6(ip) => ip,
+	}
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+	let sum = header.chunks(2)
+		.map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+		.sum::<u32>();
+	
+	let folded = (sum & 0xFFFF) + (sum >> 16);
+	
+	!(((folded & 0xFFFF) + (folded >> 16)) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::factorio_protocol::PacketType;
+	
+	#[test]
+	fn pcap_records_wrap_packets_in_ip_and_udp_headers() {
+		let from: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+		let to: SocketAddr = "[::1]:34197".parse().unwrap();
+		
+		let mut record = Vec::new();
+		write_pcap_record(&mut record, Duration::ZERO, from, from, b"hello").unwrap();
+		
+		let frame = &record[16..];
+		assert_eq!(frame.len(), 20 + 8 + 5);
+		assert_eq!(ipv4_checksum(&frame[..20]), 0);
+		assert_eq!(&frame[28..], b"hello");
+		
+		// Mixed families are written as IPv6
+		let mut record = Vec::new();
+		write_pcap_record(&mut record, Duration::ZERO, from, to, b"hello").unwrap();
+		
+		let frame = &record[16..];
+		assert_eq!(frame[0] >> 4, 6);
+		assert_eq!(frame.len(), 40 + 8 + 5);
+		assert_eq!(u16::from_be_bytes([frame[42], frame[43]]), 34197);
+	}
+	
+	#[test]
+	fn unrecognized_packets_are_rate_limited_per_type() {
+		let log = UnrecognizedPacketLog::default();
+		let start = Instant::now();
+		
+		assert_eq!(log.limiter.should_log(25, start), Some(0));
+		assert_eq!(log.limiter.should_log(25, start + Duration::from_secs(1)), None);
+		assert_eq!(log.limiter.should_log(25, start + Duration::from_secs(2)), None);
+		assert_eq!(log.limiter.should_log(26, start + Duration::from_secs(2)), Some(0));
+		assert_eq!(log.limiter.should_log(25, start + log_limit::LOG_INTERVAL), Some(2));
+		
+		// Types factorio is known to use aren't unrecognized, even ones the proxy doesn't understand
+		assert!(PacketType::from(25).is_unrecognized());
+		assert!(!PacketType::from(6).is_unrecognized());
+		assert!(!PacketType::TransferBlock.is_unrecognized());
+	}
+	
+	#[test]
+	fn pcap_traces_are_read_back() {
+		let v4_addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+		let v6_addr: SocketAddr = "[::1]:34197".parse().unwrap();
+		
+		let mut trace = Vec::new();
+		write_pcap_header(&mut trace).unwrap();
+		write_pcap_record(&mut trace, Duration::ZERO, v4_addr, v4_addr, b"first").unwrap();
+		write_pcap_record(&mut trace, Duration::ZERO, v4_addr, v6_addr, b"second").unwrap();
+		write_pcap_record(&mut trace, Duration::ZERO, v6_addr, v6_addr, b"").unwrap();
+		
+		let packets = read_pcap(trace.clone().into()).unwrap();
+		
+		assert_eq!(packets, [
+			TracedPacket { from: v4_addr, to: v4_addr, data: Bytes::from_static(b"first") },
+			TracedPacket { from: v4_addr, to: v6_addr, data: Bytes::from_static(b"second") },
+			TracedPacket { from: v6_addr, to: v6_addr, data: Bytes::new() },
+		]);
+		
+		trace.pop();
+		assert!(read_pcap(trace.into()).is_err());
+	}
+}
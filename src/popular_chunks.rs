@@ -0,0 +1,89 @@
+use crate::dedup::ChunkKey;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Tracks the chunks most frequently requested by clients across all transfers, keeping their data around
+///  (up to a size budget) so they can be pushed to newly connected clients.
+pub struct PopularChunks {
+	inner: Mutex<PopularChunksInner>,
+}
+
+struct PopularChunksInner {
+	entries: HashMap<ChunkKey, PopularChunkEntry>,
+	total_size: u64,
+	max_size: u64,
+}
+
+struct PopularChunkEntry {
+	request_count: u64,
+	data: Bytes,
+}
+
+impl PopularChunks {
+	pub fn new(max_size: u64) -> Self {
+		Self {
+			inner: Mutex::new(PopularChunksInner {
+				entries: HashMap::new(),
+				total_size: 0,
+				max_size,
+			}),
+		}
+	}
+	
+	pub fn record_requests<'a>(&self, chunks: impl IntoIterator<Item = (ChunkKey, &'a Bytes)>) {
+		let mut inner = self.inner.lock().unwrap();
+		
+		for (key, chunk) in chunks {
+			if let Some(entry) = inner.entries.get_mut(&key) {
+				entry.request_count += 1;
+			} else {
+				inner.total_size += chunk.len() as u64;
+				inner.entries.insert(key, PopularChunkEntry {
+					request_count: 1,
+					data: chunk.clone(),
+				});
+			}
+		}
+		
+		if inner.total_size > inner.max_size {
+			inner.evict_least_popular();
+		}
+	}
+	
+	/// Returns the tracked chunks, most popular first.
+	pub fn snapshot(&self) -> Vec<(ChunkKey, Bytes)> {
+		let inner = self.inner.lock().unwrap();
+		
+		let mut entries: Vec<_> = inner.entries.iter().collect();
+		entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.request_count));
+		
+		entries.into_iter()
+			.map(|(&key, entry)| (key, entry.data.clone()))
+			.collect()
+	}
+}
+
+impl PopularChunksInner {
+	fn evict_least_popular(&mut self) {
+		let mut by_count: Vec<_> = self.entries.iter()
+			.map(|(&key, entry)| (entry.request_count, key))
+			.collect();
+		
+		by_count.sort_unstable_by_key(|&(count, _)| count);
+		
+		for (_, key) in by_count {
+			if self.total_size <= self.max_size {
+				break;
+			}
+			
+			let entry = self.entries.remove(&key).unwrap();
+			self.total_size -= entry.data.len() as u64;
+		}
+		
+		// Age the surviving counts so that chunks which stop being requested eventually make room for new ones
+		for entry in self.entries.values_mut() {
+			entry.request_count /= 2;
+		}
+	}
+}
@@ -1,15 +1,89 @@
+use std::collections::HashMap;
 use std::time::Duration;
 use crate::dedup::{ChunkKey, FactorioWorldDescription};
-use bytes::{BufMut, Bytes, BytesMut};
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use quinn_proto::coding::Codec;
 use quinn_proto::VarInt;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use crate::factorio_protocol::FactorioWorldMetadata;
+use crate::content_hash::HashAlgorithm;
+use crate::error::TransferError;
+use crate::factorio_protocol::{FactorioWorldMetadata, DEFAULT_TRANSFER_BLOCK_SIZE};
 
+/// How long a peer can go without any packets before its proxy is closed, unless configured otherwise. Factorio
+///  clients and servers send each other heartbeats every tick while connected, even while the game is paused, so a
+///  quiet peer has almost always gone away. This has to stay longer than factorio's own timeout for dropping a peer
+///  that stopped responding, or the proxy would close sessions that factorio is still trying to keep alive.
 pub const UDP_PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// Written at the start of every unidirectional stream, saying what it carries.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum UniStreamType {
+	/// A sequence of PopularChunksMessages.
+	PopularChunks,
+	/// A ChunkBatchHeader followed by a single SendChunksMessage.
+	ChunkBatch,
+	/// Opened by the client, with the u64 id of the group of connections this one belongs to. The server spreads the
+	///  chunk batches for peers on any connection in a group across all of them.
+	ConnectionGroup,
+	Unknown(u8),
+}
+
+impl From<u8> for UniStreamType {
+	fn from(val: u8) -> Self {
+		match val {
+			0 => UniStreamType::PopularChunks,
+			1 => UniStreamType::ChunkBatch,
+			2 => UniStreamType::ConnectionGroup,
+			val => UniStreamType::Unknown(val),
+		}
+	}
+}
+
+impl From<UniStreamType> for u8 {
+	fn from(val: UniStreamType) -> Self {
+		match val {
+			UniStreamType::PopularChunks => 0,
+			UniStreamType::ChunkBatch => 1,
+			UniStreamType::ConnectionGroup => 2,
+			UniStreamType::Unknown(val) => val,
+		}
+	}
+}
+
+/// Identifies which peer's request a chunk batch stream answers.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct ChunkBatchHeader {
+	pub peer_id: u32,
+	pub batch_id: u32,
+}
+
+impl ChunkBatchHeader {
+	pub async fn read<R: AsyncRead + Unpin>(io: &mut R) -> Result<Self, TransferError> {
+		Ok(Self {
+			peer_id: io.read_u32_le().await?,
+			batch_id: io.read_u32_le().await?,
+		})
+	}
+	
+	pub async fn write<W: AsyncWrite + Unpin>(&self, io: &mut W) -> Result<(), TransferError> {
+		io.write_u32_le(self.peer_id).await?;
+		io.write_u32_le(self.batch_id).await?;
+		
+		Ok(())
+	}
+}
+
+/// Written after a datagram's peer id, saying whether its payload was compressed.
+const DATAGRAM_UNCOMPRESSED: u8 = 0;
+const DATAGRAM_ZSTD: u8 = 1;
+
+const DATAGRAM_COMPRESSION_LEVEL: i32 = 3;
+/// Compressed payloads claiming to decompress to more than this are rejected, since no UDP packet is that large.
+const MAX_DATAGRAM_PAYLOAD_SIZE: u64 = 65535;
+
 #[derive(Debug, Eq, PartialEq)]
 pub struct Datagram {
 	pub peer_id: VarInt,
@@ -27,48 +101,85 @@ impl Datagram {
 	pub fn decode(mut data: Bytes) -> anyhow::Result<Self> {
 		let peer_id = VarInt::decode(&mut data)?;
 		
+		if !data.has_remaining() {
+			return Err(anyhow!("Datagram is missing its compression flag"));
+		}
+		
+		let data = match data.get_u8() {
+			DATAGRAM_UNCOMPRESSED => data,
+			DATAGRAM_ZSTD => decompress_datagram_payload(&data)?,
+			flag => return Err(anyhow!("Unknown datagram compression flag {}", flag)),
+		};
+		
 		Ok(Self {
 			peer_id,
 			data,
 		})
 	}
 	
-	pub fn encode(&self, buffer: &mut BytesMut) {
+	/// Encodes the datagram, compressing payloads of at least `compress_above` bytes if that makes them smaller.
+	pub fn encode(&self, buffer: &mut BytesMut, compress_above: Option<usize>) {
 		self.peer_id.encode(buffer);
-		buffer.put_slice(&self.data);
+		
+		let compressed = compress_above
+			.filter(|&min_size| self.data.len() >= min_size)
+			.and_then(|_| zstd::bulk::compress(&self.data, DATAGRAM_COMPRESSION_LEVEL).ok())
+			.filter(|compressed| compressed.len() < self.data.len());
+		
+		match compressed {
+			Some(compressed) => {
+				buffer.put_u8(DATAGRAM_ZSTD);
+				buffer.put_slice(&compressed);
+			}
+			None => {
+				buffer.put_u8(DATAGRAM_UNCOMPRESSED);
+				buffer.put_slice(&self.data);
+			}
+		}
 	}
 }
 
+fn decompress_datagram_payload(data: &[u8]) -> anyhow::Result<Bytes> {
+	let size = zstd::zstd_safe::get_frame_content_size(data)
+		.map_err(|_| anyhow!("Invalid compressed datagram"))?
+		.filter(|&size| size <= MAX_DATAGRAM_PAYLOAD_SIZE)
+		.ok_or_else(|| anyhow!("Compressed datagram doesn't have a valid size"))?;
+	
+	Ok(zstd::bulk::decompress(data, size as usize)?.into())
+}
+
 const ZSTD_COMPRESSION_LEVEL: i32 = 11;
 const MESSAGE_SIZE_LIMIT: usize = 20_000_000;
 
-pub fn encode_message<T: Serialize>(message: &T) -> anyhow::Result<Bytes> {
+pub fn encode_message<T: Serialize>(message: &T) -> Result<Bytes, TransferError> {
 	let mut data: Vec<u8> = Vec::new();
 	
-	let mut encoder = zstd::Encoder::new(&mut data, ZSTD_COMPRESSION_LEVEL)?;
-	rmp_serde::encode::write(&mut encoder, message)?;
-	encoder.finish()?;
+	let mut encoder = zstd::Encoder::new(&mut data, ZSTD_COMPRESSION_LEVEL).map_err(|err| TransferError::Encode(err.into()))?;
+	rmp_serde::encode::write(&mut encoder, message).map_err(|err| TransferError::Encode(err.into()))?;
+	encoder.finish().map_err(|err| TransferError::Encode(err.into()))?;
 	
 	Ok(data.into())
 }
 
-pub async fn encode_message_async<T: Serialize + Send + 'static>(message: T) -> anyhow::Result<Bytes> {
-	tokio::task::spawn_blocking(move || encode_message(&message)).await?
+pub async fn encode_message_async<T: Serialize + Send + 'static>(message: T) -> Result<Bytes, TransferError> {
+	tokio::task::spawn_blocking(move || encode_message(&message)).await
+		.map_err(|err| TransferError::Encode(err.into()))?
 }
 
-pub fn decode_message<T: DeserializeOwned>(msg_data: &[u8]) -> anyhow::Result<T> {
-	let decoder = zstd::Decoder::new(msg_data)?;
+pub fn decode_message<T: DeserializeOwned>(msg_data: &[u8]) -> Result<T, TransferError> {
+	let decoder = zstd::Decoder::new(msg_data).map_err(|err| TransferError::Decode(err.into()))?;
 	
-	Ok(rmp_serde::decode::from_read(decoder)?)
+	rmp_serde::decode::from_read(decoder).map_err(|err| TransferError::Decode(err.into()))
 }
 
-pub async fn decode_message_async<T: DeserializeOwned + Send + 'static>(msg_data: Bytes) -> anyhow::Result<T> {
-	tokio::task::spawn_blocking(move || decode_message::<T>(&msg_data)).await?
+pub async fn decode_message_async<T: DeserializeOwned + Send + 'static>(msg_data: Bytes) -> Result<T, TransferError> {
+	tokio::task::spawn_blocking(move || decode_message::<T>(&msg_data)).await
+		.map_err(|err| TransferError::Decode(err.into()))?
 }
 
-pub async fn write_message<W: AsyncWrite + Unpin>(io: &mut W, msg_data: Bytes) -> anyhow::Result<()> {
+pub async fn write_message<W: AsyncWrite + Unpin>(io: &mut W, msg_data: Bytes) -> Result<(), TransferError> {
 	if msg_data.len() > MESSAGE_SIZE_LIMIT {
-		panic!("Message size exceeded limit");
+		return Err(TransferError::MessageTooLarge(msg_data.len()));
 	}
 	
 	io.write_u32_le(msg_data.len() as u32).await?;
@@ -77,11 +188,11 @@ pub async fn write_message<W: AsyncWrite + Unpin>(io: &mut W, msg_data: Bytes) -
 	Ok(())
 }
 
-pub async fn read_message<R: AsyncRead + Unpin>(io: &mut R, buffer: &mut BytesMut) -> anyhow::Result<Bytes> {
+pub async fn read_message<R: AsyncRead + Unpin>(io: &mut R, buffer: &mut BytesMut) -> Result<Bytes, TransferError> {
 	let msg_size = io.read_u32_le().await? as usize;
 	
 	if msg_size > MESSAGE_SIZE_LIMIT {
-		panic!("Message size exceeded limit");
+		return Err(TransferError::MessageTooLarge(msg_size));
 	}
 	
 	buffer.resize(msg_size, 0);
@@ -95,14 +206,174 @@ pub struct WorldReadyMessage {
 	pub world: FactorioWorldDescription,
 	pub old_info: FactorioWorldMetadata,
 	pub new_info: FactorioWorldMetadata,
+	#[serde(default = "default_transfer_block_size")]
+	pub transfer_block_size: u32,
+	/// The algorithm the world's chunk keys were made with.
+	#[serde(default)]
+	pub hash_algorithm: HashAlgorithm,
+	/// Chunks that the server can send as a delta against a similar chunk, keyed by the chunk to send.
+	#[serde(default)]
+	pub delta_references: HashMap<ChunkKey, ChunkKey>,
+	/// Whether the server accepts chunks requested by their index in the world's chunk list, which is every file's
+	///  content_chunks one after the other.
+	#[serde(default)]
+	pub accepts_chunk_indices: bool,
 }
 
+fn default_transfer_block_size() -> u32 {
+	DEFAULT_TRANSFER_BLOCK_SIZE
+}
+
+/// Sent by the client over a peer's bidirectional stream. The server answers each request with a SendChunksMessage
+///  on its own unidirectional stream, so that several batches can be in flight at once.
 #[derive(Deserialize, Serialize)]
 pub struct RequestChunksMessage {
+	pub batch_id: u32,
 	pub requested_chunks: Vec<ChunkKey>,
+	/// Chunks to send as deltas against the references from the WorldReadyMessage, which the client already has.
+	#[serde(default)]
+	pub requested_deltas: Vec<ChunkKey>,
+	/// Chunks requested by their index in the world's chunk list instead of by key, which only servers that accept
+	///  chunk indices understand. These come after the requested_chunks.
+	#[serde(default)]
+	pub requested_chunk_indices: Vec<u32>,
+	/// Like requested_chunk_indices, but for the requested_deltas.
+	#[serde(default)]
+	pub requested_delta_indices: Vec<u32>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct SendChunksMessage {
 	pub chunks: Vec<Bytes>,
+	/// Deltas for the requested_deltas, in the same order.
+	#[serde(default)]
+	pub deltas: Vec<Bytes>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct PopularChunksMessage {
+	pub chunks: Vec<Bytes>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+	
+	/// The largest UDP payload that QUIC allows, which bounds the size of a datagram.
+	const MAX_DATAGRAM_SIZE: usize = 65527;
+	
+	fn peer_id() -> impl Strategy<Value = VarInt> {
+		prop_oneof![
+			Just(VarInt::from_u32(0)),
+			Just(VarInt::MAX),
+			(0..=VarInt::MAX.into_inner()).prop_map(|peer_id| VarInt::from_u64(peer_id).unwrap()),
+		]
+	}
+	
+	fn round_trip(datagram: &Datagram) -> Datagram {
+		let mut buffer = BytesMut::new();
+		datagram.encode(&mut buffer, None);
+		
+		Datagram::decode(buffer.freeze()).unwrap()
+	}
+	
+	proptest! {
+		#[test]
+		fn datagrams_round_trip(peer_id in peer_id(), data in prop::collection::vec(any::<u8>(), 0..2048)) {
+			let datagram = Datagram::new(peer_id, data.into());
+			let decoded = round_trip(&datagram);
+			
+			prop_assert_eq!(decoded.peer_id, datagram.peer_id);
+			prop_assert_eq!(decoded.data, datagram.data);
+		}
+		
+		#[test]
+		fn compressed_datagrams_round_trip(peer_id in peer_id(), data in prop::collection::vec(0..4u8, 0..2048)) {
+			let datagram = Datagram::new(peer_id, data.into());
+			
+			let mut uncompressed = BytesMut::new();
+			datagram.encode(&mut uncompressed, None);
+			
+			let mut buffer = BytesMut::new();
+			datagram.encode(&mut buffer, Some(0));
+			
+			// Compression is only used when it helps
+			prop_assert!(buffer.len() <= uncompressed.len());
+			prop_assert_eq!(Datagram::decode(buffer.freeze()).unwrap(), datagram);
+		}
+		
+		#[test]
+		fn garbage_datagrams_dont_panic(data in prop::collection::vec(any::<u8>(), 0..64)) {
+			// Any input with a whole peer id and an uncompressed flag is a valid datagram. The peer id may not be
+			//  minimally encoded, so only the payload is sure to be left as it was.
+			if let Ok(datagram) = Datagram::decode(Bytes::from(data.clone())) {
+				prop_assert!(data.ends_with(&datagram.data));
+				prop_assert_eq!(round_trip(&datagram), datagram);
+			}
+		}
+	}
+	
+	#[test]
+	fn datagram_edge_cases_round_trip() {
+		for (peer_id, len) in [(VarInt::from_u32(0), 0), (VarInt::MAX, 0), (VarInt::MAX, MAX_DATAGRAM_SIZE)] {
+			let datagram = Datagram::new(peer_id, vec![0xAB; len].into());
+			let decoded = round_trip(&datagram);
+			
+			assert_eq!(decoded.peer_id, peer_id);
+			assert_eq!(decoded.data, datagram.data);
+		}
+	}
+	
+	#[test]
+	fn truncated_peer_ids_fail_to_decode() {
+		let mut buffer = BytesMut::new();
+		Datagram::new(VarInt::MAX, Bytes::new()).encode(&mut buffer, None);
+		
+		for len in 0..buffer.len() {
+			assert!(Datagram::decode(buffer.clone().freeze().slice(..len)).is_err());
+		}
+	}
+	
+	#[test]
+	fn only_large_compressible_datagrams_are_compressed() {
+		let mut random = vec![0; 1000];
+		blake3::Hasher::new().finalize_xof().fill(&mut random);
+		
+		let compressible = Datagram::new(VarInt::from_u32(1), vec![0; 1000].into());
+		let incompressible = Datagram::new(VarInt::from_u32(1), random.into());
+		
+		for (datagram, compress_above, compressed) in [
+			(&compressible, Some(500), true),
+			(&compressible, Some(1001), false),
+			(&compressible, None, false),
+			(&incompressible, Some(500), false),
+		] {
+			let mut buffer = BytesMut::new();
+			datagram.encode(&mut buffer, compress_above);
+			
+			assert_eq!(buffer[1] == DATAGRAM_ZSTD, compressed);
+			assert_eq!(&Datagram::decode(buffer.freeze()).unwrap(), datagram);
+		}
+		
+		let mut oversized = BytesMut::new();
+		Datagram::new(VarInt::from_u32(1), vec![0; MAX_DATAGRAM_PAYLOAD_SIZE as usize + 1].into()).encode(&mut oversized, Some(0));
+		assert!(Datagram::decode(oversized.freeze()).is_err());
+	}
+	
+	#[tokio::test]
+	async fn bad_messages_are_told_apart() {
+		let mut buffer = BytesMut::new();
+		
+		let oversized = ((MESSAGE_SIZE_LIMIT + 1) as u32).to_le_bytes();
+		let err = read_message(&mut &oversized[..], &mut buffer).await.unwrap_err();
+		assert!(matches!(err, TransferError::MessageTooLarge(size) if size == MESSAGE_SIZE_LIMIT + 1));
+		
+		let truncated = [&10u32.to_le_bytes()[..], b"short"].concat();
+		let err = read_message(&mut &truncated[..], &mut buffer).await.unwrap_err();
+		assert!(err.is_end_of_stream());
+		
+		let err = decode_message::<SendChunksMessage>(b"not zstd").err().unwrap();
+		assert!(matches!(err, TransferError::Decode(_)));
+	}
 }
@@ -1,136 +1,579 @@
-use crate::chunk_cache::ChunkCache;
-use crate::dedup::WorldReconstructor;
-use crate::factorio_protocol::{FactorioPacket, FactorioPacketHeader, PacketType, TransferBlockPacket, TransferBlockRequestPacket, TRANSFER_BLOCK_SIZE};
-use crate::protocol::{Datagram, RequestChunksMessage, SendChunksMessage, WorldReadyMessage, UDP_PEER_IDLE_TIMEOUT};
-use crate::proxy::{PacketDirection, UDP_QUEUE_SIZE};
-use crate::{protocol, utils};
+use crate::chunk_cache::{BatchLimit, ChunkCache};
+use crate::content_hash::CONTENT_HASH;
+use crate::dedup::{ChunkKey, FactorioFileDescription, FactorioWorldDescription, WorldReconstructor};
+use crate::error::{self, TransferError};
+use crate::log_limit::limited_log;
+use crate::factorio_protocol::{FactorioPacket, FactorioPacketHeader, FactorioWorldMetadata, PacketType, TransferBlockPacket, TransferBlockRequestPacket, DEFAULT_TRANSFER_BLOCK_SIZE, FACTORIO_CRC};
+use crate::protocol::{ChunkBatchHeader, Datagram, PopularChunksMessage, RequestChunksMessage, SendChunksMessage, UniStreamType, WorldReadyMessage};
+use crate::packet_trace::PacketTracer;
+use crate::report::{StatsReporter, TransferReport};
+use crate::temp_file::TempFile;
+use crate::proxy::{spawn_dropped_packet_logger, spawn_queue_depth_logger, EarlyPackets, PacketDirection, PeerQueue, QueueGauge, WORLD_DATA_QUEUE_SIZE};
+use crate::world_store::WorldStore;
+use crate::{dedup, delta, factorio_protocol, protocol, utils};
 use anyhow::anyhow;
 use bytes::{Bytes, BytesMut};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use quinn_proto::VarInt;
-use std::collections::{BTreeSet, HashMap};
-use std::io::ErrorKind;
-use std::mem;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::io::{BufWriter, Write};
+use std::{iter, mem};
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
 use tokio::time::Instant;
 
 const WORLD_DATA_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many times a chunk that failed hash verification is fetched again before the transfer gives up.
+const MAX_CHUNK_RETRIES: u32 = 2;
+/// How long a download that stayed under its limit can then go over it for.
+const DOWNLOAD_LIMIT_BURST: Duration = Duration::from_secs(1);
 
+pub struct ClientProxyConfig {
+	pub chunk_batch_size: usize,
+	/// Roughly how many bytes of chunks to request in each batch, if batches are limited by size as well as count.
+	pub chunk_batch_bytes: Option<u64>,
+	pub handshake_timeout: Duration,
+	pub dropped_packet_log_interval: Option<Duration>,
+	/// How often to log how full each peer's queues are, if at all.
+	pub queue_depth_log_interval: Option<Duration>,
+	pub udp_queue_size: usize,
+	pub world_store: Option<Arc<WorldStore>>,
+	pub reconnect_grace_period: Duration,
+	/// How long a peer can go without packets from either side before its proxy is closed.
+	pub peer_idle_timeout: Duration,
+	/// Whether to reconstruct each served world again from the chunk cache and check that it comes out the same.
+	pub verify_reconstruct: bool,
+	/// The minimum time between world blocks sent to a factorio client, if they're paced.
+	pub block_send_interval: Option<Duration>,
+	pub inflight_batches: usize,
+	pub packet_tracer: Option<Arc<PacketTracer>>,
+	pub stats_reporter: Option<Arc<StatsReporter>>,
+	/// Where to write each reconstructed world's save file before serving it, for debugging.
+	pub dump_world: Option<PathBuf>,
+	/// Where temp files go, which is the system temp dir unless it was overridden.
+	pub temp_dir: PathBuf,
+	/// Datagrams to the server with payloads of at least this many bytes are compressed when that makes them smaller.
+	pub compress_datagrams_above: Option<usize>,
+	/// Roughly how much memory the cache and transfers can use together. Chunk batches are made smaller as it's
+	///  approached.
+	pub memory_limit: Option<u64>,
+	/// Paces chunk batch requests from every transfer together, if downloads are limited.
+	pub download_limiter: Option<Arc<DownloadLimiter>>,
+	pub peer_matching: PeerMatching,
+	/// Whether connections to the server may have been used before their handshakes finished.
+	pub zero_rtt: bool,
+}
+
+/// How packets from factorio clients are matched up with peers.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum PeerMatching {
+	/// By IP and port, so every socket is its own peer.
+	Address,
+	/// By IP only, with replies going to whichever port the client last sent from. This keeps a join going when a NAT
+	///  changes the client's port partway through, but mixes up clients that share an IP, like behind carrier-grade NAT.
+	Ip,
+}
+
+impl PeerMatching {
+	/// The address that packets from the client are filed under.
+	fn peer_key(self, addr: SocketAddr) -> SocketAddr {
+		match self {
+			PeerMatching::Address => addr,
+			PeerMatching::Ip => SocketAddr::new(addr.ip().to_canonical(), 0),
+		}
+	}
+}
+
+impl FromStr for PeerMatching {
+	type Err = anyhow::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"address" => Ok(PeerMatching::Address),
+			"ip" => Ok(PeerMatching::Ip),
+			_ => Err(anyhow!("Expected 'address' or 'ip'")),
+		}
+	}
+}
+
+/// Proxies factorio clients through a connection to the server. Any transfer connections are extra connections to
+///  the same server that chunk batches get spread across.
 pub async fn run_client_proxy(
 	socket: Arc<UdpSocket>,
+	mut early_packets: EarlyPackets,
 	connection: Arc<quinn::Connection>,
+	transfer_connections: Vec<Arc<quinn::Connection>>,
 	chunk_cache: Arc<ChunkCache>,
+	config: Arc<ClientProxyConfig>,
 ) -> anyhow::Result<()> {
-	let mut addr_to_queue: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
-	let mut id_to_queue: HashMap<VarInt, mpsc::Sender<Bytes>> = HashMap::new();
+	// Along with where the peer's client last sent from, which is where replies go
+	let mut addr_to_queue: HashMap<SocketAddr, (PeerQueue, watch::Sender<SocketAddr>)> = HashMap::new();
+	let mut id_to_queue: HashMap<VarInt, PeerQueue> = HashMap::new();
 	
 	let mut buffer = BytesMut::new();
 	let mut next_peer_id: u32 = 0;
 	
+	let batch_routes = Arc::new(ChunkBatchRoutes::default());
+	
+	// Aborted when the proxy stops, which drops the transfer connections and closes them
+	let mut transfer_connection_tasks = JoinSet::new();
+	
+	// Each gives back its peer's id and key once it's done, so the peer's queues can be forgotten
+	let mut peer_tasks = JoinSet::new();
+	
+	if !transfer_connections.is_empty() {
+		let group_id = RandomState::new().hash_one(Instant::now());
+		
+		for connection in iter::once(&connection).chain(&transfer_connections) {
+			join_connection_group(connection, group_id, config.zero_rtt).await?;
+		}
+		
+		for transfer_connection in transfer_connections {
+			let chunk_cache = chunk_cache.clone();
+			let batch_routes = batch_routes.clone();
+			
+			transfer_connection_tasks.spawn(receive_transfer_connection_streams(transfer_connection, chunk_cache, batch_routes));
+		}
+	}
+	
 	loop {
 		buffer.clear();
 		buffer.reserve(8192);
 		
 		select! {
-			result = socket.recv_buf_from(&mut buffer) => {
-				let peer_addr = result?.1;
+			result = early_packets.recv_buf_from(&socket, &mut buffer) => {
+				let peer_addr = result?;
+				let peer_key = config.peer_matching.peer_key(peer_addr);
 				
-				let outgoing_queue = match addr_to_queue.get(&peer_addr).filter(|s| !s.is_closed()) {
-					Some(sender) => sender,
+				let outgoing_queue = match addr_to_queue.get(&peer_key).filter(|(queue, _)| !queue.is_closed()) {
+					Some((queue, client_addr)) => {
+						if *client_addr.borrow() != peer_addr {
+							info!("Factorio client moved from {} to {}", *client_addr.borrow(), peer_addr);
+							
+							client_addr.send_replace(peer_addr);
+						}
+						
+						queue
+					}
 					None => {
 						let peer_id: VarInt = next_peer_id.into();
 						next_peer_id = next_peer_id.checked_add(1).ok_or_else(|| anyhow!("Ran out of peer ids"))?;
 						
 						info!("New peer from {} with id {}", peer_addr, peer_id);
 						
-						let (server_receive_queue_tx, server_receive_queue_rx) = mpsc::channel(UDP_QUEUE_SIZE);
-						let (client_receive_queue_tx, client_receive_queue_rx) = mpsc::channel(UDP_QUEUE_SIZE);
+						let (server_receive_queue_tx, server_receive_queue_rx) = mpsc::channel(config.udp_queue_size);
+						let (client_receive_queue_tx, client_receive_queue_rx) = mpsc::channel(config.udp_queue_size);
+						let (client_addr_tx, client_addr_rx) = watch::channel(peer_addr);
 						
-						tokio::spawn(proxy_client(ProxyClientArgs {
+						let peer = proxy_client(ProxyClientArgs {
 							connection: connection.clone(),
 							peer_id,
 							
 							socket: socket.clone(),
-							peer_addr,
+							peer_addr: client_addr_rx,
 							
 							server_receive_queue: server_receive_queue_rx,
 							client_receive_queue: client_receive_queue_rx,
 							chunk_cache: chunk_cache.clone(),
-						}));
+							batch_routes: batch_routes.clone(),
+							config: config.clone(),
+						});
+						
+						peer_tasks.spawn(async move {
+							peer.await;
+							
+							(peer_id, peer_key)
+						});
+						
+						let dropped_packets = Arc::new(AtomicU64::new(0));
+						
+						if let Some(interval) = config.dropped_packet_log_interval {
+							spawn_dropped_packet_logger(&dropped_packets, peer_id, interval);
+						}
 						
-						addr_to_queue.insert(peer_addr, client_receive_queue_tx);
-						id_to_queue.insert(peer_id, server_receive_queue_tx);
+						if let Some(interval) = config.queue_depth_log_interval {
+							let gauges = vec![
+								QueueGauge::new("to server", &client_receive_queue_tx),
+								QueueGauge::new("to client", &server_receive_queue_tx),
+							];
+							
+							spawn_queue_depth_logger(gauges, peer_id, interval);
+						}
 						
-						addr_to_queue.get(&peer_addr).unwrap()
+						addr_to_queue.insert(peer_key, (PeerQueue::new(client_receive_queue_tx, dropped_packets.clone()), client_addr_tx));
+						id_to_queue.insert(peer_id, PeerQueue::new(server_receive_queue_tx, dropped_packets));
+						
+						&addr_to_queue[&peer_key].0
 					}
 				};
 				
-				let _ = outgoing_queue.try_send(buffer.split().freeze());
+				outgoing_queue.try_send(buffer.split().freeze());
 			},
 			result = connection.read_datagram() => {
 				let datagram = Datagram::decode(result?)?;
 				
 				if let Some(outgoing_queue) = id_to_queue.get(&datagram.peer_id) {
-					let _ = outgoing_queue.try_send(datagram.data);
+					outgoing_queue.try_send(datagram.data);
+				}
+			}
+			result = connection.accept_uni() => {
+				let recv_stream = result?;
+				let chunk_cache = chunk_cache.clone();
+				let batch_routes = batch_routes.clone();
+				
+				tokio::spawn(async move {
+					if let Err(err) = handle_uni_stream(recv_stream, chunk_cache, &batch_routes).await {
+						limited_log!(error, "Error trying to receive stream from server: {:?}", err);
+					}
+				});
+			}
+			Some(result) = peer_tasks.join_next(), if !peer_tasks.is_empty() => {
+				let (peer_id, peer_key) = match result {
+					Ok(peer) => peer,
+					Err(err) => {
+						limited_log!(error, "Peer task failed: {:?}", err);
+						continue;
+					}
+				};
+				
+				// Dropping the queues' senders is also what stops the peer's loggers
+				id_to_queue.remove(&peer_id);
+				
+				// A new peer may have taken over the key already, which is only the case if its queue is still open
+				if addr_to_queue.get(&peer_key).is_some_and(|(queue, _)| queue.is_closed()) {
+					addr_to_queue.remove(&peer_key);
 				}
 			}
 		}
 	}
 }
 
+/// With 0-RTT, the stream is checked on until the server has it, since it's lost if it was sent as 0-RTT data that the
+///  server rejected. By the time that's known the handshake is done, so sending it again always works.
+async fn join_connection_group(connection: &quinn::Connection, group_id: u64, zero_rtt: bool) -> anyhow::Result<()> {
+	let mut message = vec![UniStreamType::ConnectionGroup.into()];
+	message.extend_from_slice(&group_id.to_le_bytes());
+	
+	loop {
+		let mut send_stream = connection.open_uni().await?;
+		
+		let rejected = match send_stream.write_all(&message).await {
+			Ok(()) => {
+				send_stream.finish()?;
+				
+				zero_rtt && matches!(send_stream.stopped().await, Err(quinn::StoppedError::ZeroRttRejected))
+			}
+			Err(quinn::WriteError::ZeroRttRejected) => true,
+			Err(err) => return Err(err.into()),
+		};
+		
+		if !rejected {
+			return Ok(());
+		}
+	}
+}
+
+async fn receive_transfer_connection_streams(
+	connection: Arc<quinn::Connection>,
+	chunk_cache: Arc<ChunkCache>,
+	batch_routes: Arc<ChunkBatchRoutes>,
+) {
+	loop {
+		let recv_stream = match connection.accept_uni().await {
+			Ok(recv_stream) => recv_stream,
+			Err(err) => {
+				warn!("Transfer connection to the server closed: {}", err);
+				return;
+			}
+		};
+		
+		let chunk_cache = chunk_cache.clone();
+		let batch_routes = batch_routes.clone();
+		
+		tokio::spawn(async move {
+			if let Err(err) = handle_uni_stream(recv_stream, chunk_cache, &batch_routes).await {
+				limited_log!(error, "Error trying to receive stream from server: {:?}", err);
+			}
+		});
+	}
+}
+
+async fn handle_uni_stream(
+	mut recv_stream: quinn::RecvStream,
+	chunk_cache: Arc<ChunkCache>,
+	batch_routes: &ChunkBatchRoutes,
+) -> anyhow::Result<()> {
+	match UniStreamType::from(recv_stream.read_u8().await?) {
+		UniStreamType::PopularChunks => receive_popular_chunks(recv_stream, chunk_cache).await,
+		UniStreamType::ChunkBatch => {
+			let header = ChunkBatchHeader::read(&mut recv_stream).await?;
+			
+			if !batch_routes.route(header, recv_stream) {
+				limited_log!(warn, "Received chunk batch {} for peer {} which isn't transferring a world", header.batch_id, header.peer_id);
+			}
+			
+			Ok(())
+		}
+		UniStreamType::ConnectionGroup => Err(anyhow!("Server opened a connection group stream")),
+		UniStreamType::Unknown(stream_type) => Err(anyhow!("Unknown stream type {}", stream_type)),
+	}
+}
+
+/// Hands the chunk batch streams opened by the server to the transfer of the peer they belong to.
+#[derive(Default)]
+struct ChunkBatchRoutes {
+	routes: std::sync::Mutex<HashMap<u32, mpsc::UnboundedSender<(u32, quinn::RecvStream)>>>,
+}
+
+impl ChunkBatchRoutes {
+	fn register(self: &Arc<Self>, peer_id: VarInt, connection: Arc<quinn::Connection>) -> ChunkBatchReceiver {
+		let peer_id = peer_id.into_inner() as u32;
+		let (sender, receiver) = mpsc::unbounded_channel();
+		
+		self.routes.lock().unwrap().insert(peer_id, sender);
+		
+		ChunkBatchReceiver {
+			routes: self.clone(),
+			peer_id,
+			receiver,
+			connection,
+		}
+	}
+	
+	/// Returns false if the peer isn't expecting any chunk batches.
+	fn route(&self, header: ChunkBatchHeader, recv_stream: quinn::RecvStream) -> bool {
+		let routes = self.routes.lock().unwrap();
+		
+		routes.get(&header.peer_id)
+			.is_some_and(|sender| sender.send((header.batch_id, recv_stream)).is_ok())
+	}
+}
+
+/// Receives the chunk batches sent to one peer, until dropped.
+struct ChunkBatchReceiver {
+	routes: Arc<ChunkBatchRoutes>,
+	peer_id: u32,
+	receiver: mpsc::UnboundedReceiver<(u32, quinn::RecvStream)>,
+	connection: Arc<quinn::Connection>,
+}
+
+impl ChunkBatchReceiver {
+	/// Reads the next chunk batch to arrive, returning its batch id and encoded size along with the batch.
+	async fn recv(&mut self, buf: &mut BytesMut) -> Result<(u32, u64, SendChunksMessage), TransferError> {
+		let (batch_id, mut recv_stream) = select! {
+			// The routes are only dropped once the connection is being shut down
+			result = self.receiver.recv() => result.ok_or(TransferError::Connection(quinn::ConnectionError::LocallyClosed))?,
+			err = self.connection.closed() => return Err(err.into()),
+		};
+		
+		let response_data = protocol::read_message(&mut recv_stream, buf).await?;
+		let response_size = response_data.len() as u64;
+		let response: SendChunksMessage = protocol::decode_message_async(response_data).await?;
+		
+		Ok((batch_id, response_size, response))
+	}
+}
+
+impl Drop for ChunkBatchReceiver {
+	fn drop(&mut self) {
+		self.routes.routes.lock().unwrap().remove(&self.peer_id);
+	}
+}
+
+/// Requests a batch of chunks, asking for the ones that have a reference chunk as deltas. Chunks are requested by
+///  their index in the world's chunk list if the server accepts that.
+async fn request_chunk_batch(
+	send_stream: &mut quinn::SendStream,
+	batch_id: u32,
+	keys: &[ChunkKey],
+	delta_references: &HashMap<ChunkKey, Bytes>,
+	chunk_indices: Option<&HashMap<ChunkKey, u32>>,
+) -> Result<(), TransferError> {
+	let (requested_deltas, requested_chunks): (Vec<_>, Vec<_>) = keys.iter().partition(|key| delta_references.contains_key(key));
+	
+	let request = match chunk_indices {
+		Some(chunk_indices) => RequestChunksMessage {
+			batch_id,
+			requested_chunks: Vec::new(),
+			requested_deltas: Vec::new(),
+			requested_chunk_indices: requested_chunks.iter().map(|key| chunk_indices[key]).collect(),
+			requested_delta_indices: requested_deltas.iter().map(|key| chunk_indices[key]).collect(),
+		},
+		None => RequestChunksMessage {
+			batch_id,
+			requested_chunks,
+			requested_deltas,
+			requested_chunk_indices: Vec::new(),
+			requested_delta_indices: Vec::new(),
+		},
+	};
+	
+	let request_data = protocol::encode_message_async(request).await?;
+	
+	protocol::write_message(send_stream, request_data).await
+}
+
+/// Puts a batch's chunks back in the order they were requested in, applying any deltas and checking every chunk's hash.
+///  Chunks that don't match their hash are None, so that they can be fetched again on their own.
+fn resolve_chunk_batch(
+	keys: &[ChunkKey],
+	response: SendChunksMessage,
+	delta_references: &HashMap<ChunkKey, Bytes>,
+) -> Result<Vec<Option<Bytes>>, TransferError> {
+	let mut chunks = response.chunks.into_iter();
+	let mut deltas = response.deltas.into_iter();
+	
+	keys.iter()
+		.map(|key| {
+			let chunk = match delta_references.get(key) {
+				Some(reference) => {
+					let delta = deltas.next().ok_or_else(|| TransferError::Protocol("Chunk batch is missing deltas".to_owned()))?;
+					
+					delta::decode_delta(reference, &delta).map_err(|err| TransferError::Decode(err.into()))?
+				}
+				None => chunks.next().ok_or_else(|| TransferError::Protocol("Chunk batch is missing chunks".to_owned()))?,
+			};
+			
+			Ok((CONTENT_HASH.hash(&chunk) == *key).then_some(chunk))
+		})
+		.collect()
+}
+
+async fn receive_popular_chunks(mut recv_stream: quinn::RecvStream, chunk_cache: Arc<ChunkCache>) -> anyhow::Result<()> {
+	let mut buf = BytesMut::new();
+	let mut total_received = 0;
+	let mut total_inserted = 0;
+	
+	loop {
+		let message_data = match protocol::read_message(&mut recv_stream, &mut buf).await {
+			Ok(msg_data) => msg_data,
+			Err(err) if err.is_end_of_stream() => break,
+			Err(err) => return Err(err.into()),
+		};
+		
+		let message: PopularChunksMessage = protocol::decode_message_async(message_data).await?;
+		total_received += message.chunks.len();
+		
+		let chunks = message.chunks.into_iter()
+			.map(|chunk| (CONTENT_HASH.hash(&chunk), chunk))
+			.collect::<Vec<_>>();
+		
+		total_inserted += chunk_cache.insert_chunks(chunks);
+	}
+	
+	info!("Received {} popular chunks from the server, {} were new", total_received, total_inserted);
+	
+	Ok(())
+}
+
 struct ProxyClientArgs {
 	connection: Arc<quinn::Connection>,
 	peer_id: VarInt,
 	
 	socket: Arc<UdpSocket>,
-	peer_addr: SocketAddr,
+	/// Where the factorio client last sent from, which only changes when peers are matched by IP.
+	peer_addr: watch::Receiver<SocketAddr>,
 	
 	server_receive_queue: mpsc::Receiver<Bytes>,
 	client_receive_queue: mpsc::Receiver<Bytes>,
 	chunk_cache: Arc<ChunkCache>,
+	batch_routes: Arc<ChunkBatchRoutes>,
+	config: Arc<ClientProxyConfig>,
 }
 
-async fn proxy_client(mut args: ProxyClientArgs) {
-	let result: anyhow::Result<_> = async {
-		let (mut comp_send, comp_recv) = args.connection.open_bi().await?;
-		comp_send.write_u32_le(args.peer_id.into_inner() as u32).await?;
-		
-		let (world_data_sender, world_data_receiver) = mpsc::channel(32);
-		
-		tokio::spawn(async {
-			if let Err(err) = transfer_world_data(comp_send, comp_recv, world_data_sender, args.chunk_cache).await {
-				error!("Error trying to transfer world data: {:?}", err);
-			}
-		});
+/// Opens the stream that a peer's world transfer goes over, telling the server which peer it's for.
+/// This is always on the main connection, which never uses 0-RTT, so the peer id can't be lost to the server
+///  rejecting it.
+async fn open_peer_stream(
+	connection: &quinn::Connection,
+	peer_id: VarInt,
+	timeout: Duration,
+) -> Result<(quinn::SendStream, quinn::RecvStream), TransferError> {
+	let handshake = async {
+		let (mut comp_send, comp_recv) = connection.open_bi().await?;
+		comp_send.write_u32_le(peer_id.into_inner() as u32).await?;
 		
-		Ok(world_data_receiver)
-	}.await;
+		Ok((comp_send, comp_recv))
+	};
 	
-	let mut world_data_receiver = match result {
-		Ok(r) => r,
+	tokio::time::timeout(timeout, handshake).await
+		.map_err(|_| TransferError::Timeout("initializing a peer's stream"))?
+}
+
+async fn proxy_client(mut args: ProxyClientArgs) {
+	let (comp_send, comp_recv) = match open_peer_stream(&args.connection, args.peer_id, args.config.handshake_timeout).await {
+		Ok(streams) => streams,
 		Err(err) => {
-			error!("Error initializing stream: {:?}", err);
+			error!("Error initializing stream for peer {}: {:?}", args.peer_id, err);
 			return;
 		}
 	};
 	
+	let (world_data_sender, mut world_data_receiver) = mpsc::channel(WORLD_DATA_QUEUE_SIZE);
+	
+	if let Some(interval) = args.config.queue_depth_log_interval {
+		spawn_queue_depth_logger(vec![QueueGauge::new("world data", &world_data_sender)], args.peer_id, interval);
+	}
+	
+	let batch_receiver = args.batch_routes.register(args.peer_id, args.connection.clone());
+	let config = args.config.clone();
+	
+	let mut transfer_task = tokio::spawn(async move {
+		match transfer_world_data(comp_send, comp_recv, batch_receiver, world_data_sender, args.chunk_cache, &config).await {
+			Ok(()) => true,
+			Err(err) if error::is_disconnect(&err) => {
+				info!("Server went away while transferring world data: {:#}", err);
+				false
+			}
+			Err(err) => {
+				error!("Error trying to transfer world data: {:?}", err);
+				false
+			}
+		}
+	});
+	let mut transfer_finished = false;
+	
 	let mut buf = BytesMut::new();
 	let mut out_packets = Vec::new();
 	
 	let mut proxy_state = ClientProxyState::new();
 	let mut world_data_done = false;
 	
+	let local_addr = args.socket.local_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+	let mut block_pacer = args.config.block_send_interval.map(BlockPacer::new);
+	
+	// When the factorio client can't be reached, keep its world data around for a little while in case it comes
+	//  back from the same address
+	let mut client_unreachable_since: Option<Instant> = None;
+	
 	loop {
+		let idle_timeout = if proxy_state.has_world_data() {
+			args.config.peer_idle_timeout + args.config.reconnect_grace_period
+		} else {
+			args.config.peer_idle_timeout
+		};
+		
+		let next_block_time = block_pacer.as_ref().and_then(BlockPacer::next_send_time);
+		let mut due_block = None;
+		
 		select! {
 			result = args.client_receive_queue.recv() => {
 				let Some(packet_data) = result else { return; };
 				
+				if let Some(tracer) = &args.config.packet_tracer {
+					tracer.trace(args.peer_id, *args.peer_addr.borrow(), local_addr, &packet_data);
+				}
+				
 				proxy_state.on_packet_from_client(packet_data, &mut out_packets);
 			}
 			result = args.server_receive_queue.recv() => {
@@ -145,18 +588,61 @@ async fn proxy_client(mut args: ProxyClientArgs) {
 				
 				proxy_state.on_new_world_data(result, &mut out_packets);
 			}
-			_ = tokio::time::sleep(UDP_PEER_IDLE_TIMEOUT) => return
+			result = &mut transfer_task, if !transfer_finished => {
+				transfer_finished = true;
+				
+				// The server already sent the factorio client world info for the deduplicated world, so without it the
+				//  client can't load the world, and closing the peer has it reconnect from scratch
+				if !result.unwrap_or(false) {
+					info!("Closing peer {} since its world data couldn't be transferred", args.peer_id);
+					return;
+				}
+			}
+			_ = tokio::time::sleep_until(next_block_time.unwrap_or_else(Instant::now)), if next_block_time.is_some() => {
+				due_block = block_pacer.as_mut().and_then(|block_pacer| block_pacer.pop(Instant::now()));
+			}
+			_ = tokio::time::sleep(idle_timeout) => return
 		}
 		
-		for (packet_data, dir) in out_packets.drain(..) {
+		let peer_addr = *args.peer_addr.borrow();
+		
+		// Paced blocks wait in the pacer rather than here, so that other packets keep flowing in both directions
+		let due_block = due_block.map(|block| (block, PacketDirection::ToClient, true));
+		let packets = due_block.into_iter().chain(out_packets.drain(..).map(|(packet_data, dir)| (packet_data, dir, false)));
+		
+		for (packet_data, dir, is_due_block) in packets {
 			match dir {
 				PacketDirection::ToClient => {
-					if args.socket.send_to(&packet_data, args.peer_addr).await.is_err() {
-						return;
+					if let Some(block_pacer) = block_pacer.as_mut().filter(|_| !is_due_block) {
+						let is_block = FactorioPacketHeader::decode(packet_data.clone())
+							.is_ok_and(|(header, _)| header.packet_type == PacketType::TransferBlock);
+						
+						if is_block {
+							block_pacer.push(packet_data);
+							continue;
+						}
+					}
+					
+					if let Some(tracer) = &args.config.packet_tracer {
+						tracer.trace(args.peer_id, local_addr, peer_addr, &packet_data);
+					}
+					
+					match args.socket.send_to(&packet_data, peer_addr).await {
+						Ok(_) => client_unreachable_since = None,
+						Err(err) => {
+							let unreachable_since = *client_unreachable_since.get_or_insert_with(|| {
+								warn!("Failed to send packet to factorio client {}: {}", peer_addr, err);
+								Instant::now()
+							});
+							
+							if !proxy_state.has_world_data() || unreachable_since.elapsed() >= args.config.reconnect_grace_period {
+								return;
+							}
+						}
 					}
 				}
 				PacketDirection::ToServer => {
-					Datagram::new(args.peer_id, packet_data).encode(&mut buf);
+					Datagram::new(args.peer_id, packet_data).encode(&mut buf, args.config.compress_datagrams_above);
 					
 					if args.connection.send_datagram(buf.split().freeze()).is_err() {
 						return;
@@ -167,31 +653,182 @@ async fn proxy_client(mut args: ProxyClientArgs) {
 	}
 }
 
-struct ClientProxyState {
+/// Spaces out the world blocks sent to a factorio client, so that lots of requests being fulfilled at once don't turn
+///  into a burst of packets. Blocks are queued until their send slot comes up.
+struct BlockPacer {
+	interval: Duration,
+	next_send: Instant,
+	queued_blocks: VecDeque<Bytes>,
+}
+
+impl BlockPacer {
+	fn new(interval: Duration) -> Self {
+		Self {
+			interval,
+			next_send: Instant::now(),
+			queued_blocks: VecDeque::new(),
+		}
+	}
+	
+	fn push(&mut self, block: Bytes) {
+		self.queued_blocks.push_back(block);
+	}
+	
+	/// When the next queued block can be sent, if there are any.
+	fn next_send_time(&self) -> Option<Instant> {
+		(!self.queued_blocks.is_empty()).then_some(self.next_send)
+	}
+	
+	/// Takes the next queued block, using up its send slot.
+	fn pop(&mut self, now: Instant) -> Option<Bytes> {
+		let block = self.queued_blocks.pop_front()?;
+		self.reserve(now);
+		
+		Some(block)
+	}
+	
+	/// Takes the next free send slot, returning when the block can be sent.
+	fn reserve(&mut self, now: Instant) -> Instant {
+		let send_at = self.next_send.max(now);
+		self.next_send = send_at + self.interval;
+		
+		send_at
+	}
+}
+
+/// A token bucket for the chunks downloaded by every transfer. Batches are paid for once they arrive, and no more are
+///  requested until the bytes received have been paid off at the limited rate.
+pub struct DownloadLimiter {
+	bytes_per_second: u64,
+	/// When everything received so far is paid off.
+	paid_until: Mutex<Instant>,
+}
+
+impl DownloadLimiter {
+	pub fn new(bytes_per_second: u64) -> Self {
+		Self {
+			bytes_per_second,
+			paid_until: Mutex::new(Instant::now()),
+		}
+	}
+	
+	pub fn bytes_per_second(&self) -> u64 {
+		self.bytes_per_second
+	}
+	
+	fn consume(&self, bytes: u64, now: Instant) {
+		let mut paid_until = self.paid_until.lock().unwrap();
+		
+		// Time spent under the limit is only saved up for a short burst
+		let burst_start = now.checked_sub(DOWNLOAD_LIMIT_BURST).unwrap_or(now);
+		*paid_until = (*paid_until).max(burst_start) + Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+	}
+	
+	/// Returns when more chunks can be requested, or None if they can be now.
+	fn delay(&self, now: Instant) -> Option<Instant> {
+		let paid_until = *self.paid_until.lock().unwrap();
+		
+		(paid_until > now).then_some(paid_until)
+	}
+}
+
+/// Batches are never made smaller than this, so that transfers keep making progress over the memory limit.
+const MIN_MEMORY_LIMITED_BATCH_SIZE: usize = 16;
+
+/// Shrinks chunk batch requests so that the chunks being fetched fit in what's left of the memory limit.
+struct MemoryBudget {
+	memory_limit: u64,
+	average_chunk_size: u64,
+	limited: bool,
+}
+
+impl MemoryBudget {
+	fn new(memory_limit: u64, world_desc: &FactorioWorldDescription) -> Self {
+		let chunk_count = world_desc.files.iter().map(|file| file.content_chunks.len() as u64).sum::<u64>();
+		let content_size = world_desc.files.iter().map(|file| file.content_size).sum::<u64>();
+		
+		Self {
+			memory_limit,
+			average_chunk_size: (content_size / chunk_count.max(1)).max(1),
+			limited: false,
+		}
+	}
+	
+	/// Returns how many batches to keep requested and how many chunks to put in each, given how much memory is in use
+	///  outside of inflight batches.
+	fn batch_limits(&mut self, used_memory: u64, inflight_batches: usize, chunk_batch_size: usize) -> (usize, usize) {
+		let available_chunks = (self.memory_limit.saturating_sub(used_memory) / self.average_chunk_size) as usize;
+		
+		let batch_size = (available_chunks / inflight_batches)
+			.clamp(MIN_MEMORY_LIMITED_BATCH_SIZE.min(chunk_batch_size), chunk_batch_size);
+		let batch_count = (available_chunks / batch_size).clamp(1, inflight_batches);
+		
+		let limited = batch_size < chunk_batch_size || batch_count < inflight_batches;
+		
+		if limited != self.limited {
+			match limited {
+				true => info!("Close to the memory limit with {}B in use, requesting {} batches of {} chunks at a time",
+					utils::abbreviate_number(used_memory), batch_count, batch_size),
+				false => info!("No longer close to the memory limit, requesting full chunk batches again"),
+			}
+			
+			self.limited = limited;
+		}
+		
+		(batch_count, batch_size)
+	}
+}
+
+/// Reconstructed world data on its way to a proxy task.
+pub(super) enum WorldData {
+	/// Sent before any data, with the transfer block size the cacher server downloaded the world with and how much
+	///  data is coming.
+	Start {
+		transfer_block_size: u32,
+		data_size: usize,
+	},
+	Data(Bytes),
+	/// The cacher server isn't deduplicating the world, so block requests should go to the factorio server.
+	Forward,
+}
+
+pub(super) struct ClientProxyState {
 	world_data: Vec<u8>,
+	transfer_block_size: u32,
 	last_block_request: Instant,
 	pending_requests: BTreeSet<u32>,
 	pending_requests_swap: BTreeSet<u32>,
 	world_data_done: bool,
+	forward_block_requests: bool,
 }
 
 impl ClientProxyState {
 	pub fn new() -> Self {
 		Self {
 			world_data: Vec::new(),
+			transfer_block_size: DEFAULT_TRANSFER_BLOCK_SIZE,
 			last_block_request: Instant::now(),
 			pending_requests: BTreeSet::new(),
 			pending_requests_swap: BTreeSet::new(),
 			world_data_done: false,
+			forward_block_requests: false,
 		}
 	}
 	
+	pub fn has_world_data(&self) -> bool {
+		!self.world_data.is_empty()
+	}
+	
 	pub fn on_packet_from_client(&mut self, packet_data: Bytes, out_packets: &mut Vec<(Bytes, PacketDirection)>) {
 		if let Ok((header, msg_data)) = FactorioPacketHeader::decode(packet_data.clone()) {
-			if header.packet_type == PacketType::TransferBlockRequest {
+			if header.packet_type == PacketType::TransferBlockRequest && !self.forward_block_requests {
 				if let Ok(request) = TransferBlockRequestPacket::decode(msg_data) {
 					if let Some(response) = self.try_fulfill_block_request(request.block_id) {
 						out_packets.push((response.encode_full_packet(), PacketDirection::ToClient));
+					} else if self.world_data_done {
+						if let Some(response) = self.fulfill_block_request_past_end(request.block_id) {
+							out_packets.push((response.encode_full_packet(), PacketDirection::ToClient));
+						}
 					} else {
 						self.pending_requests.insert(request.block_id);
 					}
@@ -211,12 +848,43 @@ impl ClientProxyState {
 		out_packets.push((packet_data, PacketDirection::ToServer));
 	}
 	
-	pub fn on_new_world_data(&mut self, new_data: Option<Bytes>, out_packets: &mut Vec<(Bytes, PacketDirection)>) {
-		let Some(new_data) = new_data else {
-			self.world_data_done = true;
-			self.last_block_request = Instant::now();
-			
-			return;
+	pub fn on_new_world_data(&mut self, new_data: Option<WorldData>, out_packets: &mut Vec<(Bytes, PacketDirection)>) {
+		let new_data = match new_data {
+			Some(WorldData::Data(new_data)) => new_data,
+			Some(WorldData::Start { transfer_block_size, data_size }) => {
+				self.transfer_block_size = transfer_block_size;
+				
+				// The factorio client can request blocks in any order and again later, so all of the world data is
+				//  kept and the channel it comes over can't bound how much is held. Allocating it all up front at least
+				//  keeps it from taking up to twice as much while the Vec grows. A size that can't be allocated
+				//  is left to fail when the data actually arrives.
+				let _ = self.world_data.try_reserve_exact(data_size);
+				
+				return;
+			}
+			Some(WorldData::Forward) => {
+				self.forward_block_requests = true;
+				
+				for block_id in mem::take(&mut self.pending_requests) {
+					let request = TransferBlockRequestPacket { block_id };
+					out_packets.push((request.encode_full_packet(), PacketDirection::ToServer));
+				}
+				
+				return;
+			}
+			None => {
+				self.world_data_done = true;
+				self.last_block_request = Instant::now();
+				
+				// No more data is coming, so anything still pending would otherwise wait forever
+				for block_id in mem::take(&mut self.pending_requests) {
+					if let Some(response) = self.fulfill_block_request_past_end(block_id) {
+						out_packets.push((response.encode_full_packet(), PacketDirection::ToClient));
+					}
+				}
+				
+				return;
+			}
 		};
 		
 		self.world_data.extend_from_slice(&new_data);
@@ -236,31 +904,60 @@ impl ClientProxyState {
 	}
 	
 	fn try_fulfill_block_request(&self, requested_block_id: u32) -> Option<TransferBlockPacket> {
-		let offset = requested_block_id as usize * TRANSFER_BLOCK_SIZE as usize;
+		let transfer_block_size = self.transfer_block_size as usize;
+		let offset = requested_block_id as usize * transfer_block_size;
 		
-		if offset + TRANSFER_BLOCK_SIZE as usize <= self.world_data.len() {
+		if offset + transfer_block_size <= self.world_data.len() {
 			Some(TransferBlockPacket {
 				block_id: requested_block_id,
-				data: self.world_data[offset..offset + TRANSFER_BLOCK_SIZE as usize].to_vec().into(),
+				data: self.world_data[offset..offset + transfer_block_size].to_vec().into(),
 			})
 		} else {
 			None
 		}
 	}
+	
+	/// Answers a request that the finished world data doesn't have a whole block for. A block that the world data ends
+	///  partway through is sent padded with zeros, like the world data itself is, and requests for blocks past the end
+	///  are dropped.
+	fn fulfill_block_request_past_end(&self, requested_block_id: u32) -> Option<TransferBlockPacket> {
+		let transfer_block_size = self.transfer_block_size as usize;
+		let offset = requested_block_id as usize * transfer_block_size;
+		
+		if offset >= self.world_data.len() {
+			limited_log!(warn, "Factorio client requested block {}, but the world only has {} blocks, ignoring it",
+				requested_block_id, self.world_data.len().div_ceil(transfer_block_size));
+			
+			return None;
+		}
+		
+		let mut data = self.world_data[offset..].to_vec();
+		data.resize(transfer_block_size, 0);
+		
+		Some(TransferBlockPacket {
+			block_id: requested_block_id,
+			data: data.into(),
+		})
+	}
 }
 
 async fn transfer_world_data(
 	mut send_stream: quinn::SendStream,
 	mut recv_stream: quinn::RecvStream,
-	world_data_sender: mpsc::Sender<Bytes>,
+	mut batch_receiver: ChunkBatchReceiver,
+	world_data_sender: mpsc::Sender<WorldData>,
 	chunk_cache: Arc<ChunkCache>,
+	config: &ClientProxyConfig,
 ) -> anyhow::Result<()> {
 	let mut buf = BytesMut::new();
 	
 	let world_ready_message_data = match protocol::read_message(&mut recv_stream, &mut buf).await {
 		Ok(msg_data) => msg_data,
-		Err(err) if err.downcast_ref::<std::io::Error>().is_some_and(|err| err.kind() == ErrorKind::UnexpectedEof) => {
-			info!("Peer shutdown without ever sending world data");
+		Err(err) if err.is_end_of_stream() => {
+			// The server closes the stream when it isn't deduplicating the world, or when the peer shuts down
+			info!("Server closed the stream without sending world data, forwarding block requests to it");
+			
+			let _ = world_data_sender.send(WorldData::Forward).await;
 			
 			return Ok(());
 		}
@@ -274,90 +971,845 @@ async fn transfer_world_data(
 	
 	info!("Received world description, size: {}B", utils::abbreviate_number(world_ready_message_data.len() as u64));
 	
-	let world_ready: WorldReadyMessage = protocol::decode_message_async(world_ready_message_data).await?;
-	let world_desc = world_ready.world;
+	let world_ready: WorldReadyMessage = protocol::decode_message_async(world_ready_message_data.clone()).await?;
 	
-	let mut all_chunks = world_desc.files.iter()
-		.flat_map(|file| file.content_chunks.iter())
-		.copied()
-		.collect::<Vec<_>>();
+	if world_ready.hash_algorithm != CONTENT_HASH {
+		return Err(TransferError::Protocol(format!("Server addresses chunks with {}, but this client uses {}",
+			world_ready.hash_algorithm, CONTENT_HASH)).into());
+	}
 	
-	info!("World description: size: {}, crc: {}, file count: {}, total chunks: {}",
+	if let Some(world_store) = &config.world_store {
+		if let Err(err) = world_store.save(&world_ready.old_info, world_ready_message_data).await {
+			warn!("Failed to store world description: {:?}", err);
+		}
+	}
+	
+	world_data_sender.send(WorldData::Start {
+		transfer_block_size: world_ready.transfer_block_size,
+		data_size: world_data_size(&world_ready.new_info, world_ready.transfer_block_size),
+	}).await?;
+	
+	// Chunks that are similar to one that's already cached are fetched as a delta against it
+	let mut delta_references = world_ready.delta_references.iter()
+		.filter_map(|(&key, reference_key)| Some((key, chunk_cache.get_chunk(reference_key)?)))
+		.collect::<HashMap<_, _>>();
+	
+	let world_desc = world_ready.world;
+	
+	let mut all_chunks = world_desc.files.iter()
+		.flat_map(|file| file.content_chunks.iter())
+		.copied()
+		.collect::<Vec<_>>();
+	
+	info!("World description: size: {}, crc: {}, file count: {}, total chunks: {}",
 		world_ready.new_info.world_size, world_ready.new_info.world_crc, world_desc.files.len(), all_chunks.len());
 	
+	// A chunk's index takes a few bytes to request instead of its whole key. Chunks that appear more than once are
+	//  requested by their first index.
+	let chunk_indices = world_ready.accepts_chunk_indices.then(|| all_chunks.iter()
+		.enumerate()
+		.rev()
+		.map(|(index, &key)| (key, index as u32))
+		.collect::<HashMap<_, _>>());
+	
+	let coverage = chunk_cache.coverage(&world_desc);
+	
+	match coverage.is_complete() {
+		true => info!("World is fully cached"),
+		false => info!("{} of the world's {} distinct chunks ({:.1}%) are already cached", coverage.cached_chunks,
+			coverage.total_chunks, coverage.fraction() * 100.0),
+	}
+	
+	// Keep the world's chunks from being evicted by other transfers until this one is done with them
+	let _chunk_pins = chunk_cache.pin_chunks(&all_chunks);
+	
+	// Cached chunks are all gathered up front, so that the files made of them can be encoded straight away instead of
+	//  after the first batch is requested, which can be held back by the download limit
+	let gather_start_time = Instant::now();
 	let mut local_cache = HashMap::new();
+	
+	chunk_cache.take_cached_chunks(&mut all_chunks, &mut local_cache);
+	
+	info!("Gathered {} cached chunks in {}ms", local_cache.len(), gather_start_time.elapsed().as_millis());
+	
 	let mut world_reconstructor = WorldReconstructor::new();
+	let mut reconstruction_time = ReconstructionTime::default();
 	
-	for file_desc in &world_desc.files {
-		debug!("Reconstructing file {}", &file_desc.file_name);
+	let mut inflight_batches = HashMap::new();
+	let mut next_batch_id: u32 = 0;
+	let mut chunk_retries = HashMap::new();
+	
+	let mut output = WorldDataOutput {
+		sender: world_data_sender,
+		held_data: config.dump_world.is_some().then(Vec::new),
+		served_hash: config.verify_reconstruct.then(blake3::Hasher::new),
+	};
+	
+	let mut memory_budget = config.memory_limit.map(|memory_limit| MemoryBudget::new(memory_limit, &world_desc));
+	
+	let estimated_chunk_sizes = config.chunk_batch_bytes.map(|_| estimate_chunk_sizes(&world_desc));
+	
+	// The proxy task's copy of the world and any held copy take up memory for the whole transfer, as do the read-only
+	//  caches, which never change size
+	let world_data_memory = world_data_size(&world_ready.new_info, world_ready.transfer_block_size) as u64
+		* if output.held_data.is_some() { 2 } else { 1 };
+	let fixed_memory = world_data_memory + chunk_cache.cold_tier_size().1;
+	
+	// Files are encoded on blocking threads as soon as all of their chunks are here, so that compressing one file
+	//  overlaps with fetching the chunks for the next ones. They're still output in the order they're described in.
+	let max_encoding_files = std::thread::available_parallelism().map_or(1, |parallelism| parallelism.get());
+	let mut files_to_encode = world_desc.files.iter().peekable();
+	let mut encoding_files = VecDeque::new();
+	let mut output_files = world_desc.files.iter();
+	
+	loop {
+		while encoding_files.len() < max_encoding_files {
+			let Some(file_desc) = files_to_encode.peek() else { break; };
+			
+			let gather_start_time = Instant::now();
+			
+			if dedup::gather_file_content(file_desc, &local_cache, &mut buf).is_err() {
+				break;
+			}
+			
+			debug!("Reconstructing file {}", &file_desc.file_name);
+			
+			let gather_time = gather_start_time.elapsed();
+			let file_type = file_desc.file_type;
+			let content = buf.split().freeze();
+			
+			encoding_files.push_back(tokio::task::spawn_blocking(move || {
+				let encode_start_time = Instant::now();
+				let file_data = dedup::encode_file_content(file_type, content);
+				
+				(file_data, gather_time + encode_start_time.elapsed())
+			}));
+			files_to_encode.next();
+		}
 		
-		loop {
-			match world_reconstructor.reconstruct_world_file(file_desc, &mut local_cache, &mut buf) {
-				Ok(data_blocks) => {
-					for data in data_blocks {
-						world_data_sender.send(data).await?;
-					}
-					
-					break;
-				}
-				Err(_) => {
-					if all_chunks.is_empty() {
-						panic!("Emptied chunk list but reconstructor wants more data");
-					}
-					
-					if let Some(batch) =
-						chunk_cache.get_chunks_batched(&mut all_chunks, &mut local_cache, 512).await
-					{
-						let request_data = protocol::encode_message_async(RequestChunksMessage {
-							requested_chunks: batch.batch_keys().to_vec(),
-						}).await?;
-						
-						protocol::write_message(&mut send_stream, request_data).await?;
-						
-						let response_data = protocol::read_message(&mut recv_stream, &mut buf).await?;
-						total_transferred += response_data.len() as u64;
-						
-						info!("Received batch of {} chunks, size: {}B",
-							batch.batch_keys().len(),
-							utils::abbreviate_number(response_data.len() as u64)
-						);
-						
-						let response: SendChunksMessage = protocol::decode_message_async(response_data).await?;
-						
-						for (&key, chunk) in batch.batch_keys().iter().zip(response.chunks.iter()) {
-							let data_hash = blake3::hash(&chunk);
-							
-							if data_hash != key.0 {
-								return Err(anyhow::anyhow!("Chunk hash mismatch for {:?}", key));
-							}
-							
-							local_cache.insert(key, chunk.clone());
-						}
-						
-						batch.fulfill(&response.chunks);
-					}
-				}
+		while encoding_files.front().is_some_and(|encoding_file| encoding_file.is_finished()) {
+			let encoded_file = encoding_files.pop_front().unwrap().await?;
+			output_file(&mut world_reconstructor, &mut output, output_files.next().unwrap(), encoded_file, &mut reconstruction_time).await?;
+		}
+		
+		if files_to_encode.peek().is_none() && encoding_files.is_empty() {
+			break;
+		}
+		
+		if files_to_encode.peek().is_some() && encoding_files.is_empty() && inflight_batches.is_empty() && all_chunks.is_empty() {
+			panic!("Emptied chunk list but reconstructor wants more data");
+		}
+		
+		// Keep several batches requested ahead of the reconstructor so that the server always has something to send.
+		//  Waiting on chunks that other transfers are fetching is only done with nothing else to wait on, since those
+		//  transfers could be waiting on our batches in turn.
+		let (max_inflight_batches, chunk_batch_size) = match &mut memory_budget {
+			Some(memory_budget) => {
+				// The chunks this transfer gathered are counted on top of the cache, since it holds on to them until
+				//  it's done
+				let local_cache_size = local_cache.values().map(|chunk| chunk.len() as u64).sum::<u64>();
+				
+				memory_budget.batch_limits(fixed_memory + chunk_cache.total_size() + local_cache_size,
+					config.inflight_batches, config.chunk_batch_size)
+			}
+			None => (config.inflight_batches, config.chunk_batch_size),
+		};
+		
+		let batch_limit = BatchLimit {
+			max_chunks: chunk_batch_size,
+			max_size: config.chunk_batch_bytes.zip(estimated_chunk_sizes.as_ref()),
+		};
+		
+		let download_delay = config.download_limiter.as_ref().and_then(|limiter| limiter.delay(Instant::now()));
+		
+		while download_delay.is_none() && inflight_batches.len() < max_inflight_batches && !all_chunks.is_empty() {
+			let batch = if inflight_batches.is_empty() && encoding_files.is_empty() {
+				chunk_cache.get_chunks_batched(&mut all_chunks, &mut local_cache, batch_limit).await
+			} else {
+				chunk_cache.try_get_chunks_batched(&mut all_chunks, &mut local_cache, batch_limit)
+			};
+			
+			let Some(batch) = batch else { break; };
+			
+			request_chunk_batch(&mut send_stream, next_batch_id, batch.batch_keys(), &delta_references, chunk_indices.as_ref()).await?;
+			
+			inflight_batches.insert(next_batch_id, batch);
+			next_batch_id = next_batch_id.wrapping_add(1);
+		}
+		
+		if inflight_batches.is_empty() {
+			// With no chunks to receive, wait for the next file to be encoded instead. If there isn't one, the chunks
+			//  that other transfers were fetching just arrived.
+			if let Some(encoding_file) = encoding_files.pop_front() {
+				let encoded_file = encoding_file.await?;
+				output_file(&mut world_reconstructor, &mut output, output_files.next().unwrap(), encoded_file, &mut reconstruction_time).await?;
+			} else if let Some(download_delay) = download_delay {
+				tokio::time::sleep_until(download_delay).await;
+			}
+			
+			continue;
+		}
+		
+		let (batch_id, response_size, response) = batch_receiver.recv(&mut buf).await?;
+		
+		let mut batch = inflight_batches.remove(&batch_id)
+			.ok_or_else(|| TransferError::Protocol(format!("Received chunk batch {} which wasn't requested", batch_id)))?;
+		
+		total_transferred += response_size;
+		
+		if let Some(download_limiter) = &config.download_limiter {
+			download_limiter.consume(response_size, Instant::now());
+		}
+		
+		info!("Received batch of {} chunks, size: {}B",
+			batch.batch_keys().len(),
+			utils::abbreviate_number(response_size)
+		);
+		
+		let chunks = resolve_chunk_batch(batch.batch_keys(), response, &delta_references)?;
+		
+		if chunks.iter().all(Option::is_some) {
+			let chunks = chunks.into_iter().flatten().collect::<Vec<_>>();
+			
+			for (&key, chunk) in batch.batch_keys().iter().zip(chunks.iter()) {
+				local_cache.insert(key, chunk.clone());
+			}
+			
+			batch.fulfill(&chunks);
+			continue;
+		}
+		
+		// Chunks are addressed on their own, so ones that arrived corrupted can be fetched again without the rest of
+		//  the batch. They're fetched whole, in case the corruption came from a delta's reference.
+		let received_chunks = batch.batch_keys().iter()
+			.zip(chunks)
+			.filter_map(|(&key, chunk)| Some((key, chunk?)))
+			.collect::<Vec<_>>();
+		
+		for (key, chunk) in &received_chunks {
+			local_cache.insert(*key, chunk.clone());
+		}
+		
+		batch.fulfill_partial(&received_chunks);
+		
+		for &key in batch.batch_keys() {
+			let retries = chunk_retries.entry(key).or_insert(0);
+			*retries += 1;
+			
+			if *retries > MAX_CHUNK_RETRIES {
+				return Err(anyhow::Error::from(TransferError::HashMismatch(key))
+					.context(format!("Chunk still failed hash verification after fetching it {} more times", MAX_CHUNK_RETRIES)));
 			}
+			
+			delta_references.remove(&key);
 		}
+		
+		warn!("{} chunks in batch {} failed hash verification, fetching them again", batch.batch_keys().len(), batch_id);
+		
+		request_chunk_batch(&mut send_stream, next_batch_id, batch.batch_keys(), &delta_references, chunk_indices.as_ref()).await?;
+		
+		inflight_batches.insert(next_batch_id, batch);
+		next_batch_id = next_batch_id.wrapping_add(1);
 	}
 	
 	let elapsed = start_time.elapsed();
 	
-	info!("Finished receiving world in {}s, total transferred: {}B, original size: {}B, dedup ratio: {:.2}%",
+	info!("Reconstructing final data");
+	
+	let finalize_start_time = Instant::now();
+	let last_data = world_reconstructor.finalize_world_file(
+		&world_desc, world_ready.new_info.world_size as usize, world_ready.new_info.world_crc, world_ready.transfer_block_size)?;
+	reconstruction_time.finalizing = finalize_start_time.elapsed();
+	
+	info!("Finished receiving world in {}s, total transferred: {}B, original size: {}B, dedup ratio: {:.2}%, reconstruction time: {}ms",
 		elapsed.as_secs(),
 		utils::abbreviate_number(total_transferred),
 		utils::abbreviate_number(world_ready.old_info.world_size as u64),
 		(total_transferred as f64 / world_ready.old_info.world_size as f64) * 100.0,
+		reconstruction_time.total().as_millis(),
 	);
 	
+	reconstruction_time.log();
+	
+	info!("MTU discovery settled on {}B for the connection to the server", batch_receiver.connection.stats().path.current_mtu);
+	
+	if let Some(download_limiter) = &config.download_limiter {
+		info!("Downloaded at {}B/s with a limit of {}B/s",
+			utils::abbreviate_number((total_transferred as f64 / elapsed.as_secs_f64().max(0.001)) as u64),
+			utils::abbreviate_number(download_limiter.bytes_per_second()),
+		);
+	}
+	
 	chunk_cache.mark_dirty();
 	
-	info!("Reconstructing final data");
+	if let Some(stats_reporter) = &config.stats_reporter {
+		stats_reporter.report(TransferReport::new(world_ready.old_info.world_size as u64, total_transferred));
+	}
 	
-	let last_data = world_reconstructor.finalize_world_file(
-		&world_desc, world_ready.new_info.world_size as usize, world_ready.new_info.world_crc)?;
+	output.send(last_data).await?;
+	
+	let Some(world_data) = output.held_data.take() else {
+		if let Some(served_hash) = output.served_hash.take() {
+			verify_reconstruction(world_desc, &world_ready.new_info, world_ready.transfer_block_size, served_hash.finalize(), &chunk_cache).await;
+		}
+		
+		return Ok(());
+	};
+	
+	let aux_size = world_desc.aux_data.len();
+	
+	if let Some(dump_path) = &config.dump_world {
+		dump_world(dump_path, &config.temp_dir, &world_data, &world_ready.new_info, aux_size, world_ready.transfer_block_size).await;
+	}
+	
+	for data in world_data {
+		output.serve(data).await?;
+	}
+	
+	if let Some(served_hash) = output.served_hash.take() {
+		verify_reconstruction(world_desc, &world_ready.new_info, world_ready.transfer_block_size, served_hash.finalize(), &chunk_cache).await;
+	}
+	
+	Ok(())
+}
+
+/// Adds an encoded file, along with how long it took to encode, to the reconstructed world and outputs it.
+async fn output_file(
+	world_reconstructor: &mut WorldReconstructor,
+	output: &mut WorldDataOutput,
+	file_desc: &FactorioFileDescription,
+	(file_data, encode_time): (Bytes, Duration),
+	reconstruction_time: &mut ReconstructionTime,
+) -> anyhow::Result<()> {
+	let add_start_time = Instant::now();
+	let encoded_file = world_reconstructor.add_encoded_file(&file_desc.file_name, file_data);
+	
+	reconstruction_time.add_file(&file_desc.file_name, encode_time, add_start_time.elapsed());
+	
+	for data in encoded_file {
+		output.send(data).await?;
+	}
+	
+	Ok(())
+}
+
+/// Time spent reconstructing a world, summed over every task that worked on it, to tell joins held up by a slow CPU
+///  apart from ones held up by the network. It's wall-clock time, so it also counts any time a task waited to run.
+#[derive(Default)]
+struct ReconstructionTime {
+	/// Gathering each file's chunks and compressing it.
+	encoding: Duration,
+	/// Adding the files to the zip and hashing them for the CRC.
+	assembling: Duration,
+	/// Padding the world out and forging its CRC.
+	finalizing: Duration,
+	slowest_file: Option<(String, Duration)>,
+}
+
+impl ReconstructionTime {
+	fn add_file(&mut self, file_name: &str, encode_time: Duration, add_time: Duration) {
+		let file_time = encode_time + add_time;
+		
+		debug!("Reconstructed file {} in {}ms", file_name, file_time.as_millis());
+		
+		self.encoding += encode_time;
+		self.assembling += add_time;
+		
+		if self.slowest_file.as_ref().is_none_or(|(_, slowest_time)| file_time > *slowest_time) {
+			self.slowest_file = Some((file_name.to_owned(), file_time));
+		}
+	}
+	
+	fn total(&self) -> Duration {
+		self.encoding + self.assembling + self.finalizing
+	}
+	
+	fn log(&self) {
+		let slowest_file = match &self.slowest_file {
+			Some((file_name, file_time)) => format!(", the slowest file was {} at {}ms", file_name, file_time.as_millis()),
+			None => String::new(),
+		};
+		
+		info!("Reconstruction time: {}ms encoding files, {}ms assembling the zip, {}ms finalizing{}",
+			self.encoding.as_millis(), self.assembling.as_millis(), self.finalizing.as_millis(), slowest_file);
+	}
+}
+
+/// Passes reconstructed world data on to the proxy task, or holds onto all of it if it needs to be dumped first.
+struct WorldDataOutput {
+	sender: mpsc::Sender<WorldData>,
+	held_data: Option<Vec<Bytes>>,
+	/// A hash of all of the data passed on so far, if it's going to be checked against a second reconstruction.
+	served_hash: Option<blake3::Hasher>,
+}
+
+impl WorldDataOutput {
+	async fn send(&mut self, data: Bytes) -> anyhow::Result<()> {
+		match &mut self.held_data {
+			Some(held_data) => held_data.push(data),
+			None => self.serve(data).await?,
+		}
+		
+		Ok(())
+	}
+	
+	/// Passes data on to the proxy task, even if data is being held.
+	async fn serve(&mut self, data: Bytes) -> anyhow::Result<()> {
+		if let Some(served_hash) = &mut self.served_hash {
+			served_hash.update(&data);
+		}
+		
+		self.sender.send(WorldData::Data(data)).await?;
+		
+		Ok(())
+	}
+}
+
+/// Reconstructs a world again using only the chunk cache, the way a later join would, and logs whether it came out the
+///  same as the world that was served. Reconstruction has to be deterministic, or a later join could fail its CRC check.
+async fn verify_reconstruction(
+	world_desc: FactorioWorldDescription,
+	world_info: &FactorioWorldMetadata,
+	transfer_block_size: u32,
+	served_hash: blake3::Hash,
+	chunk_cache: &ChunkCache,
+) {
+	let start_time = Instant::now();
+	
+	let mut chunks = HashMap::new();
+	let mut missing_chunks = HashSet::new();
+	
+	for &key in world_desc.files.iter().flat_map(|file| file.content_chunks.iter()) {
+		if chunks.contains_key(&key) {
+			continue;
+		}
+		
+		match chunk_cache.get_chunk(&key) {
+			Some(chunk) => { chunks.insert(key, chunk); }
+			None => { missing_chunks.insert(key); }
+		}
+	}
+	
+	if !missing_chunks.is_empty() {
+		warn!("Can't check that the world reconstructs the same from the cache, {} of its chunks aren't cached", missing_chunks.len());
+		return;
+	}
+	
+	let world_info = world_info.clone();
+	
+	let result = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+		let world_data = reconstruct_world_data(&world_desc, &world_info, transfer_block_size, &chunks)?;
+		
+		let mut hasher = blake3::Hasher::new();
+		
+		for data in &world_data {
+			hasher.update(data);
+		}
+		
+		Ok(hasher.finalize())
+	}).await;
+	
+	match result {
+		Ok(Ok(reconstructed_hash)) if reconstructed_hash == served_hash => {
+			info!("World reconstructs the same from the cache, checked in {}ms", start_time.elapsed().as_millis());
+		}
+		Ok(Ok(_)) => error!("World reconstructed from the cache differs from the one that was served, later joins may fail their CRC check"),
+		Ok(Err(err)) => error!("Failed to reconstruct the world again from the cache: {:?}", err),
+		Err(err) => error!("Failed to reconstruct the world again from the cache: {:?}", err),
+	}
+}
+
+/// Writes the save file out of a reconstructed world, logging the CRC that the factorio client will compute over it.
+///  Failing to write it is only logged, since it's just for debugging.
+async fn dump_world(
+	path: &Path,
+	temp_dir: &Path,
+	world_data: &[Bytes],
+	world_info: &FactorioWorldMetadata,
+	aux_size: usize,
+	transfer_block_size: u32,
+) {
+	let crc = world_crc(world_data, world_info, aux_size, transfer_block_size);
+	
+	let dump_path = path.to_owned();
+	let temp_dir = temp_dir.to_owned();
+	let world_data = world_data.to_vec();
+	let world_size = world_info.world_size as usize;
+	
+	// Written to a temp file first, so that a dump that fails partway doesn't replace the previous one
+	let result = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+		let mut temp_file = TempFile::new_in(&temp_dir, "world-dump")?;
+		let mut writer = BufWriter::new(temp_file.file());
+		let mut remaining = world_size;
+		
+		// The save file is followed by padding up to the next block and then the aux data, which aren't part of it
+		for data in &world_data {
+			let len = data.len().min(remaining);
+			writer.write_all(&data[..len])?;
+			remaining -= len;
+		}
+		
+		writer.flush()?;
+		drop(writer);
+		
+		temp_file.persist(&dump_path)
+	}).await;
+	
+	match result {
+		Ok(Ok(())) => match crc {
+			Some(crc) => info!("Dumped the reconstructed world to {}, its crc is {} and the expected crc is {}",
+				path.display(), crc, world_info.world_crc),
+			None => warn!("Dumped the reconstructed world to {}, but its data is incomplete", path.display()),
+		},
+		Ok(Err(err)) => warn!("Failed to dump the reconstructed world to {}: {}", path.display(), err),
+		Err(err) => warn!("Failed to dump the reconstructed world to {}: {}", path.display(), err),
+	}
+}
+
+/// Estimates the size of each chunk in a world as the average size of the chunks in the first file that has it, since
+///  world descriptions only give the size of whole files.
+fn estimate_chunk_sizes(world_desc: &FactorioWorldDescription) -> HashMap<ChunkKey, u64> {
+	let mut estimated_sizes = HashMap::new();
+	
+	for file in &world_desc.files {
+		let average_chunk_size = file.content_size / file.content_chunks.len().max(1) as u64;
+		
+		for &key in &file.content_chunks {
+			estimated_sizes.entry(key).or_insert(average_chunk_size);
+		}
+	}
+	
+	estimated_sizes
+}
+
+/// How much data a reconstructed world is made up of, with the world and aux data each padded to whole blocks.
+fn world_data_size(world_info: &FactorioWorldMetadata, transfer_block_size: u32) -> usize {
+	let block_count = factorio_protocol::transfer_block_count(world_info.world_size, transfer_block_size) as usize
+		+ factorio_protocol::transfer_block_count(world_info.aux_size, transfer_block_size) as usize;
+	
+	block_count * transfer_block_size as usize
+}
+
+/// Checks the CRC that the factorio client will compute over the world and aux data, skipping the padding after each.
+pub(super) fn verify_world_crc(world_data: &[Bytes], world_info: &FactorioWorldMetadata, aux_size: usize, transfer_block_size: u32) -> bool {
+	world_crc(world_data, world_info, aux_size, transfer_block_size) == Some(world_info.world_crc)
+}
+
+/// Computes the CRC of the world and aux data, or None if the data ends before the aux data does.
+fn world_crc(world_data: &[Bytes], world_info: &FactorioWorldMetadata, aux_size: usize, transfer_block_size: u32) -> Option<u32> {
+	let world_size = world_info.world_size as usize;
+	let aux_offset = factorio_protocol::transfer_block_count(world_info.world_size, transfer_block_size) as usize
+		* transfer_block_size as usize;
+	
+	let mut crc_hasher = FACTORIO_CRC.digest();
+	let mut offset = 0;
+	
+	for data in world_data {
+		let data_end = offset + data.len();
+		
+		for (start, end) in [(0, world_size), (aux_offset, aux_offset + aux_size)] {
+			let start = start.clamp(offset, data_end);
+			let end = end.clamp(offset, data_end);
+			
+			if start < end {
+				crc_hasher.update(&data[start - offset..end - offset]);
+			}
+		}
+		
+		offset = data_end;
+	}
+	
+	(offset >= aux_offset + aux_size).then(|| crc_hasher.finalize())
+}
+
+pub(super) fn reconstruct_world_data(
+	world_desc: &FactorioWorldDescription,
+	world_info: &FactorioWorldMetadata,
+	transfer_block_size: u32,
+	chunks: &HashMap<ChunkKey, Bytes>,
+) -> anyhow::Result<Vec<Bytes>> {
+	let mut world_reconstructor = WorldReconstructor::new();
+	let mut buf = BytesMut::new();
+	let mut world_data = Vec::new();
+	
+	for file_desc in &world_desc.files {
+		let Ok(data_blocks) = world_reconstructor.reconstruct_world_file(file_desc, chunks, &mut buf) else {
+			return Err(anyhow!("Missing chunks for file {}", file_desc.file_name));
+		};
+		
+		world_data.extend(data_blocks);
+	}
+	
+	world_data.push(world_reconstructor.finalize_world_file(
+		world_desc, world_info.world_size as usize, world_info.world_crc, transfer_block_size)?);
+	
+	Ok(world_data)
+}
+
+/// Reconstructs a world entirely from chunks that are already available locally.
+pub(super) async fn reconstruct_cached_world(
+	world_ready: WorldReadyMessage,
+	chunks: HashMap<ChunkKey, Bytes>,
+	world_data_sender: mpsc::Sender<WorldData>,
+) -> anyhow::Result<()> {
+	let start_time = Instant::now();
+	
+	world_data_sender.send(WorldData::Start {
+		transfer_block_size: world_ready.transfer_block_size,
+		data_size: world_data_size(&world_ready.new_info, world_ready.transfer_block_size),
+	}).await?;
+	
+	let world_data = reconstruct_world_data(&world_ready.world, &world_ready.new_info, world_ready.transfer_block_size, &chunks)?;
+	
+	for data in world_data {
+		world_data_sender.send(WorldData::Data(data)).await?;
+	}
 	
-	world_data_sender.send(last_data).await?;
+	info!("Reconstructed cached world in {}ms", start_time.elapsed().as_millis());
 	
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dedup::FactorioFileType;
+	
+	#[test]
+	fn world_crc_covers_world_and_aux_data_only() {
+		let transfer_block_size = 503;
+		let world = (0..10_000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+		let aux = b"auxiliary data".to_vec();
+		
+		let mut crc_hasher = FACTORIO_CRC.digest();
+		crc_hasher.update(&world);
+		crc_hasher.update(&aux);
+		
+		let world_info = FactorioWorldMetadata {
+			world_size: world.len() as u32,
+			no_idea1: 0,
+			aux_size: aux.len() as u32,
+			no_idea2: 0,
+			world_crc: crc_hasher.finalize(),
+		};
+		
+		// Lay the data out the way it's served, with each part padded to a whole number of blocks
+		let mut padded = world.clone();
+		padded.resize(10_000usize.div_ceil(503) * 503, 0xAA);
+		let aux_offset = padded.len();
+		padded.extend_from_slice(&aux);
+		padded.resize(aux_offset + 503, 0xAA);
+		
+		// Split at boundaries that don't line up with either part
+		let split = |data: &[u8]| data.chunks(777).map(Bytes::copy_from_slice).collect::<Vec<_>>();
+		
+		assert!(verify_world_crc(&split(&padded), &world_info, aux.len(), transfer_block_size));
+		
+		let mut corrupt_padding = padded.clone();
+		corrupt_padding[world.len() + 1] ^= 1;
+		assert!(verify_world_crc(&split(&corrupt_padding), &world_info, aux.len(), transfer_block_size));
+		
+		let mut corrupt_world = padded.clone();
+		corrupt_world[5_000] ^= 1;
+		assert!(!verify_world_crc(&split(&corrupt_world), &world_info, aux.len(), transfer_block_size));
+		
+		let mut corrupt_aux = padded.clone();
+		corrupt_aux[aux_offset + 3] ^= 1;
+		assert!(!verify_world_crc(&split(&corrupt_aux), &world_info, aux.len(), transfer_block_size));
+		
+		assert!(!verify_world_crc(&split(&padded[..aux_offset]), &world_info, aux.len(), transfer_block_size));
+	}
+	
+	#[test]
+	fn block_requests_are_forwarded_when_world_isnt_deduplicated() {
+		let mut state = ClientProxyState::new();
+		let mut out_packets = Vec::new();
+		
+		let request = TransferBlockRequestPacket { block_id: 3 }.encode_full_packet();
+		
+		state.on_packet_from_client(request.clone(), &mut out_packets);
+		assert!(out_packets.is_empty());
+		
+		// Requests made before the server said to forward them are sent on then
+		state.on_new_world_data(Some(WorldData::Forward), &mut out_packets);
+		assert_eq!(out_packets, vec![(request.clone(), PacketDirection::ToServer)]);
+		
+		out_packets.clear();
+		state.on_packet_from_client(request.clone(), &mut out_packets);
+		assert_eq!(out_packets, vec![(request, PacketDirection::ToServer)]);
+	}
+	
+	#[test]
+	fn world_data_is_allocated_up_front() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 1000,
+			no_idea1: 0,
+			aux_size: 10,
+			no_idea2: 0,
+			world_crc: 0,
+		};
+		
+		let data_size = world_data_size(&world_info, 503);
+		assert_eq!(data_size, 3 * 503);
+		
+		let mut state = ClientProxyState::new();
+		let mut out_packets = Vec::new();
+		
+		state.on_new_world_data(Some(WorldData::Start { transfer_block_size: 503, data_size }), &mut out_packets);
+		let world_data_ptr = state.world_data.as_ptr();
+		
+		for _ in 0..3 {
+			state.on_new_world_data(Some(WorldData::Data(vec![0; 503].into())), &mut out_packets);
+		}
+		
+		assert_eq!(state.world_data.as_ptr(), world_data_ptr);
+	}
+	
+	#[test]
+	fn block_requests_past_the_end_of_the_world_dont_stay_pending() {
+		let mut state = ClientProxyState::new();
+		let mut out_packets = Vec::new();
+		
+		state.on_new_world_data(Some(WorldData::Start { transfer_block_size: 4, data_size: 12 }), &mut out_packets);
+		state.on_new_world_data(Some(WorldData::Data(Bytes::from_static(b"aaaabbbbcc"))), &mut out_packets);
+		
+		for block_id in [1, 2, 3] {
+			state.on_packet_from_client(TransferBlockRequestPacket { block_id }.encode_full_packet(), &mut out_packets);
+		}
+		
+		// Only the last whole block can be sent while more data might still be coming
+		assert_eq!(out_packets, vec![(TransferBlockPacket {
+			block_id: 1,
+			data: Bytes::from_static(b"bbbb"),
+		}.encode_full_packet(), PacketDirection::ToClient)]);
+		
+		out_packets.clear();
+		state.on_new_world_data(None, &mut out_packets);
+		
+		// The block that the data ends partway through is padded, and the one past it is dropped
+		assert_eq!(out_packets, vec![(TransferBlockPacket {
+			block_id: 2,
+			data: Bytes::from_static(b"cc\0\0"),
+		}.encode_full_packet(), PacketDirection::ToClient)]);
+		assert!(state.pending_requests.is_empty());
+		
+		out_packets.clear();
+		state.on_packet_from_client(TransferBlockRequestPacket { block_id: 3 }.encode_full_packet(), &mut out_packets);
+		assert!(out_packets.is_empty());
+	}
+	
+	#[test]
+	fn corrupt_chunks_are_singled_out() {
+		let chunks = [Bytes::from_static(b"first"), Bytes::from_static(b"second")];
+		let keys = chunks.iter().map(|chunk| CONTENT_HASH.hash(chunk)).collect::<Vec<_>>();
+		
+		let response = SendChunksMessage {
+			chunks: vec![chunks[0].clone(), Bytes::from_static(b"corrupt")],
+			deltas: Vec::new(),
+		};
+		
+		let resolved = resolve_chunk_batch(&keys, response, &HashMap::new()).unwrap();
+		assert_eq!(resolved, vec![Some(chunks[0].clone()), None]);
+	}
+	
+	#[test]
+	fn batches_shrink_near_the_memory_limit() {
+		let world_desc = FactorioWorldDescription {
+			files: vec![FactorioFileDescription {
+				file_type: FactorioFileType::Normal,
+				file_name: "level.dat0".to_owned(),
+				content_size: 100 * 1000,
+				content_chunks: vec![CONTENT_HASH.hash(b"chunk"); 100],
+			}],
+			aux_data: Bytes::new(),
+		};
+		
+		let mut budget = MemoryBudget::new(1_000_000, &world_desc);
+		
+		// 4 full batches of 100 chunks take up 400KB
+		assert_eq!(budget.batch_limits(500_000, 4, 100), (4, 100));
+		assert_eq!(budget.batch_limits(800_000, 4, 100), (4, 50));
+		assert_eq!(budget.batch_limits(960_000, 4, 100), (2, 16));
+		
+		// Transfers keep going over the limit, one small batch at a time
+		assert_eq!(budget.batch_limits(2_000_000, 4, 100), (1, 16));
+		assert_eq!(budget.batch_limits(2_000_000, 4, 8), (1, 8));
+	}
+	
+	#[test]
+	fn peers_matched_by_ip_ignore_the_port() {
+		let addr: SocketAddr = "203.0.113.5:34197".parse().unwrap();
+		let new_port: SocketAddr = "203.0.113.5:50000".parse().unwrap();
+		let mapped: SocketAddr = "[::ffff:203.0.113.5]:34197".parse().unwrap();
+		let other_ip: SocketAddr = "203.0.113.6:34197".parse().unwrap();
+		
+		assert_ne!(PeerMatching::Address.peer_key(addr), PeerMatching::Address.peer_key(new_port));
+		
+		assert_eq!(PeerMatching::Ip.peer_key(addr), PeerMatching::Ip.peer_key(new_port));
+		assert_eq!(PeerMatching::Ip.peer_key(addr), PeerMatching::Ip.peer_key(mapped));
+		assert_ne!(PeerMatching::Ip.peer_key(addr), PeerMatching::Ip.peer_key(other_ip));
+	}
+	
+	#[test]
+	fn block_pacer_spreads_out_bursts() {
+		let interval = Duration::from_millis(10);
+		let mut pacer = BlockPacer::new(interval);
+		let start = Instant::now();
+		
+		// A burst gets one slot per interval
+		assert_eq!(pacer.reserve(start), start);
+		assert_eq!(pacer.reserve(start), start + interval);
+		assert_eq!(pacer.reserve(start), start + interval * 2);
+		
+		// Idle time isn't saved up for a later burst
+		let later = start + Duration::from_secs(1);
+		assert_eq!(pacer.reserve(later), later);
+		assert_eq!(pacer.reserve(later), later + interval);
+		
+		// Queued blocks come out in order, each waiting for the slot after the last one
+		let mut pacer = BlockPacer::new(interval);
+		assert_eq!(pacer.next_send_time(), None);
+		
+		pacer.push(Bytes::from_static(b"first"));
+		pacer.push(Bytes::from_static(b"second"));
+		
+		let first_slot = pacer.next_send_time().unwrap();
+		assert_eq!(pacer.pop(first_slot), Some(Bytes::from_static(b"first")));
+		assert_eq!(pacer.next_send_time(), Some(first_slot + interval));
+		assert_eq!(pacer.pop(first_slot + interval), Some(Bytes::from_static(b"second")));
+		assert_eq!(pacer.next_send_time(), None);
+	}
+	
+	#[test]
+	fn download_limiter_waits_for_received_bytes_to_be_paid_off() {
+		let limiter = DownloadLimiter::new(1000);
+		let start = Instant::now() + Duration::from_secs(10);
+		
+		// After being idle, a second's worth of data only uses up the saved burst
+		limiter.consume(1000, start);
+		assert_eq!(limiter.delay(start), None);
+		
+		limiter.consume(500, start);
+		assert_eq!(limiter.delay(start), Some(start + Duration::from_millis(500)));
+		assert_eq!(limiter.delay(start + Duration::from_millis(500)), None);
+		
+		// Idle time only saves up one burst
+		let later = start + Duration::from_secs(10);
+		limiter.consume(3000, later);
+		assert_eq!(limiter.delay(later), Some(later + Duration::from_secs(2)));
+	}
+	
+	#[test]
+	fn reconstruction_time_adds_up_every_file() {
+		let mut reconstruction_time = ReconstructionTime::default();
+		
+		reconstruction_time.add_file("save/level.dat0", Duration::from_millis(300), Duration::from_millis(20));
+		reconstruction_time.add_file("save/script.dat", Duration::from_millis(5), Duration::from_millis(1));
+		reconstruction_time.finalizing = Duration::from_millis(50);
+		
+		assert_eq!(reconstruction_time.encoding, Duration::from_millis(305));
+		assert_eq!(reconstruction_time.assembling, Duration::from_millis(21));
+		assert_eq!(reconstruction_time.total(), Duration::from_millis(376));
+		assert_eq!(reconstruction_time.slowest_file, Some(("save/level.dat0".to_owned(), Duration::from_millis(320))));
+	}
+}
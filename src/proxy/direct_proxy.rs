@@ -0,0 +1,241 @@
+use crate::chunk_cache::ChunkCache;
+use crate::content_hash::CONTENT_HASH;
+use crate::factorio_protocol::{FactorioPacketHeader, FactorioWorldMetadata, PacketType, ServerToClientHeartbeatPacket};
+use crate::protocol::UDP_PEER_IDLE_TIMEOUT;
+use crate::proxy::client_proxy::{self, ClientProxyState, WorldData};
+use crate::proxy::{EarlyPackets, PacketDirection, PacketFilter, WORLD_DATA_QUEUE_SIZE};
+use crate::world_store::WorldStore;
+use bytes::{Bytes, BytesMut};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+
+/// Lets the direct proxy serve worlds that were seen before without a cacher server.
+pub struct OfflineWorlds {
+	pub world_store: Arc<WorldStore>,
+	pub chunk_cache: Arc<ChunkCache>,
+}
+
+/// Forwards Factorio traffic straight to the Factorio server without going through a cacher server.
+pub async fn run_direct_proxy(
+	socket: Arc<UdpSocket>,
+	factorio_addr: SocketAddr,
+	udp_queue_size: usize,
+	offline_worlds: Option<Arc<OfflineWorlds>>,
+	mut early_packets: EarlyPackets,
+) -> anyhow::Result<()> {
+	let mut addr_to_queue: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+	
+	let mut buffer = BytesMut::new();
+	
+	loop {
+		buffer.clear();
+		buffer.reserve(8192);
+		
+		let peer_addr = early_packets.recv_buf_from(&socket, &mut buffer).await?;
+		
+		let outgoing_queue = match addr_to_queue.get(&peer_addr).filter(|s| !s.is_closed()) {
+			Some(sender) => sender,
+			None => {
+				info!("New direct peer from {}", peer_addr);
+				
+				let unspecified: IpAddr = if factorio_addr.is_ipv6() {
+					Ipv6Addr::UNSPECIFIED.into()
+				} else {
+					Ipv4Addr::UNSPECIFIED.into()
+				};
+				
+				let upstream_socket = UdpSocket::bind((unspecified, 0)).await?;
+				let (receive_queue_tx, receive_queue_rx) = mpsc::channel(udp_queue_size);
+				
+				tokio::spawn(proxy_direct(ProxyDirectArgs {
+					socket: socket.clone(),
+					peer_addr,
+					
+					upstream_socket,
+					factorio_addr,
+					
+					receive_queue_rx,
+					offline_worlds: offline_worlds.clone(),
+				}));
+				
+				addr_to_queue.insert(peer_addr, receive_queue_tx);
+				
+				addr_to_queue.get(&peer_addr).unwrap()
+			}
+		};
+		
+		let _ = outgoing_queue.try_send(buffer.split().freeze());
+	}
+}
+
+struct ProxyDirectArgs {
+	socket: Arc<UdpSocket>,
+	peer_addr: SocketAddr,
+	
+	upstream_socket: UdpSocket,
+	factorio_addr: SocketAddr,
+	
+	receive_queue_rx: mpsc::Receiver<Bytes>,
+	offline_worlds: Option<Arc<OfflineWorlds>>,
+}
+
+/// State for serving a world to the Factorio client from the local cache.
+struct OfflineServingState {
+	proxy_state: ClientProxyState,
+	/// Dropped once it's stale, like in the server proxy.
+	packet_filter: Option<PacketFilter>,
+	world_data_receiver: mpsc::Receiver<WorldData>,
+	world_data_done: bool,
+}
+
+async fn proxy_direct(mut args: ProxyDirectArgs) {
+	let mut buf = BytesMut::new();
+	let mut out_packets = Vec::new();
+	
+	let mut offline_state: Option<OfflineServingState> = None;
+	
+	loop {
+		buf.clear();
+		buf.reserve(8192);
+		
+		let world_data_pending = offline_state.as_ref().is_some_and(|state| !state.world_data_done);
+		
+		select! {
+			result = args.upstream_socket.recv_buf_from(&mut buf) => {
+				let Ok((_, remote_addr)) = result else { return };
+				
+				// Drop any packets that don't originate from the server
+				if remote_addr != args.factorio_addr { continue; }
+				
+				let mut packet_data = buf.split().freeze();
+				
+				if offline_state.is_none() {
+					if let (Some(offline_worlds), Some(world_info)) = (&args.offline_worlds, decode_map_ready(&packet_data)) {
+						offline_state = try_serve_offline(offline_worlds, world_info).await;
+					}
+				}
+				
+				if let Some(state) = &mut offline_state {
+					if let Some(packet_filter) = &mut state.packet_filter {
+						packet_data = packet_filter.filter(packet_data);
+						
+						if packet_filter.is_stale() {
+							info!("Stopped filtering packets");
+							
+							state.packet_filter = None;
+						}
+					}
+				}
+				
+				out_packets.push((packet_data, PacketDirection::ToClient));
+			}
+			result = args.receive_queue_rx.recv() => {
+				let Some(packet_data) = result else { return; };
+				
+				match &mut offline_state {
+					Some(state) => state.proxy_state.on_packet_from_client(packet_data, &mut out_packets),
+					None => out_packets.push((packet_data, PacketDirection::ToServer)),
+				}
+			}
+			result = recv_world_data(&mut offline_state), if world_data_pending => {
+				let state = offline_state.as_mut().unwrap();
+				
+				if result.is_none() {
+					state.world_data_done = true;
+				}
+				
+				state.proxy_state.on_new_world_data(result, &mut out_packets);
+			}
+			_ = tokio::time::sleep(UDP_PEER_IDLE_TIMEOUT) => return
+		}
+		
+		for (packet_data, dir) in out_packets.drain(..) {
+			match dir {
+				PacketDirection::ToClient => {
+					if args.socket.send_to(&packet_data, args.peer_addr).await.is_err() {
+						return;
+					}
+				}
+				PacketDirection::ToServer => {
+					if let Err(err) = args.upstream_socket.send_to(&packet_data, args.factorio_addr).await {
+						error!("Failed to send packet to factorio server: {:?}", err);
+						
+						return;
+					}
+				}
+			}
+		}
+	}
+}
+
+async fn recv_world_data(offline_state: &mut Option<OfflineServingState>) -> Option<WorldData> {
+	offline_state.as_mut()?.world_data_receiver.recv().await
+}
+
+fn decode_map_ready(packet_data: &Bytes) -> Option<FactorioWorldMetadata> {
+	let (header, msg_data) = FactorioPacketHeader::decode(packet_data.clone()).ok()?;
+	
+	if header.packet_type != PacketType::ServerToClientHeartbeat {
+		return None;
+	}
+	
+	ServerToClientHeartbeatPacket::decode(msg_data)
+		.and_then(ServerToClientHeartbeatPacket::try_decode_map_ready)
+		.ok()
+		.flatten()
+}
+
+/// Starts reconstructing the world locally if its description is stored and all of its chunks are cached.
+async fn try_serve_offline(offline_worlds: &OfflineWorlds, world_info: FactorioWorldMetadata) -> Option<OfflineServingState> {
+	let world_ready = match offline_worlds.world_store.load(&world_info).await {
+		Ok(Some(world_ready)) => world_ready,
+		Ok(None) => {
+			info!("World {:?} hasn't been seen before, downloading it directly", world_info);
+			return None;
+		}
+		Err(err) => {
+			warn!("Failed to load stored world description: {:?}", err);
+			return None;
+		}
+	};
+	
+	if world_ready.hash_algorithm != CONTENT_HASH {
+		info!("World {:?} was stored with {} chunk keys, downloading it directly", world_info, world_ready.hash_algorithm);
+		return None;
+	}
+	
+	let all_chunks = world_ready.world.files.iter()
+		.flat_map(|file| file.content_chunks.iter())
+		.copied()
+		.collect::<Vec<_>>();
+	
+	let Some(chunks) = offline_worlds.chunk_cache.get_cached_chunks(&all_chunks) else {
+		let coverage = offline_worlds.chunk_cache.coverage(&world_ready.world);
+		
+		info!("World {:?} is only {:.1}% cached, downloading it directly", world_info, coverage.fraction() * 100.0);
+		return None;
+	};
+	
+	info!("Serving world {:?} from the cache", world_info);
+	
+	let packet_filter = PacketFilter::new(&world_ready.old_info, &world_ready.new_info);
+	let (world_data_sender, world_data_receiver) = mpsc::channel(WORLD_DATA_QUEUE_SIZE);
+	
+	tokio::spawn(async move {
+		if let Err(err) = client_proxy::reconstruct_cached_world(world_ready, chunks, world_data_sender).await {
+			error!("Error trying to reconstruct cached world: {:?}", err);
+		}
+	});
+	
+	Some(OfflineServingState {
+		proxy_state: ClientProxyState::new(),
+		packet_filter: Some(packet_filter),
+		world_data_receiver,
+		world_data_done: false,
+	})
+}
@@ -1,10 +1,283 @@
+use crate::factorio_protocol::FactorioWorldMetadata;
+use bytes::{Bytes, BytesMut};
+use log::{info, warn};
+use memchr::memmem::Finder;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::time::Instant;
+
 pub mod client_proxy;
+pub mod direct_proxy;
+pub mod replay;
 pub mod server_proxy;
 
-pub const UDP_QUEUE_SIZE: usize = 512;
+pub const DEFAULT_UDP_QUEUE_SIZE: usize = 512;
+pub const MIN_UDP_QUEUE_SIZE: usize = 16;
+/// How many pieces of reconstructed world data can wait for a proxy task to take them.
+const WORLD_DATA_QUEUE_SIZE: usize = 32;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 enum PacketDirection {
 	ToClient,
 	ToServer,
 }
+
+/// Rewrites the world info sent to the Factorio client, so it sees the reconstructed world's info rather than
+///  the original's.
+struct PacketFilter {
+	finder: Finder<'static>,
+	replace_with: Bytes,
+	last_replace: Instant,
+}
+
+impl PacketFilter {
+	const STALE_TIMEOUT: Duration = Duration::from_secs(30);
+	
+	fn new(old_world_info: &FactorioWorldMetadata, new_world_info: &FactorioWorldMetadata) -> Self {
+		let mut old_world_info_encoded = Vec::new();
+		let mut new_world_info_encoded = Vec::new();
+		
+		old_world_info.encode(&mut old_world_info_encoded);
+		new_world_info.encode(&mut new_world_info_encoded);
+		
+		Self {
+			finder: Finder::new(&old_world_info_encoded).into_owned(),
+			replace_with: new_world_info_encoded.into(),
+			last_replace: Instant::now(),
+		}
+	}
+	
+	fn filter(&mut self, packet_data: Bytes) -> Bytes {
+		let mut new_packet_data = None;
+		
+		for pos in self.finder.find_iter(&packet_data) {
+			let new_packet_data = new_packet_data
+				.get_or_insert_with(|| BytesMut::from(packet_data.as_ref()));
+			
+			new_packet_data[pos..pos + self.finder.needle().len()].copy_from_slice(&self.replace_with);
+		}
+		
+		if new_packet_data.is_some() {
+			self.last_replace = Instant::now();
+		}
+		
+		new_packet_data.map(BytesMut::freeze).unwrap_or(packet_data)
+	}
+	
+	/// Whether the world info hasn't been seen for long enough that filtering can stop.
+	fn is_stale(&self) -> bool {
+		self.last_replace.elapsed() > Self::STALE_TIMEOUT
+	}
+}
+
+/// A peer's packet queue that counts packets dropped because the queue was full.
+struct PeerQueue {
+	sender: mpsc::Sender<Bytes>,
+	dropped_packets: Arc<AtomicU64>,
+}
+
+impl PeerQueue {
+	fn new(sender: mpsc::Sender<Bytes>, dropped_packets: Arc<AtomicU64>) -> Self {
+		Self {
+			sender,
+			dropped_packets,
+		}
+	}
+	
+	fn try_send(&self, packet_data: Bytes) {
+		if let Err(TrySendError::Full(_)) = self.sender.try_send(packet_data) {
+			self.dropped_packets.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+	
+	fn is_closed(&self) -> bool {
+		self.sender.is_closed()
+	}
+}
+
+/// Packets from factorio clients that arrived while the client was still connecting to the server, held so that they
+///  can be handled once there's somewhere to send them. Once it's full the oldest are dropped, since factorio keeps
+///  resending the packets that matter while joining.
+pub struct EarlyPackets {
+	packets: VecDeque<(Bytes, SocketAddr)>,
+	capacity: usize,
+	dropped_count: u64,
+}
+
+impl EarlyPackets {
+	pub fn new(capacity: usize) -> Self {
+		Self {
+			packets: VecDeque::new(),
+			capacity,
+			dropped_count: 0,
+		}
+	}
+	
+	/// Holds on to the packets that arrive on the socket until the future completes.
+	pub async fn buffer_until<T>(&mut self, socket: &UdpSocket, future: impl Future<Output = T>) -> T {
+		tokio::pin!(future);
+		
+		let mut buffer = BytesMut::new();
+		
+		let output = loop {
+			buffer.clear();
+			buffer.reserve(8192);
+			
+			select! {
+				output = &mut future => break output,
+				result = socket.recv_buf_from(&mut buffer) => match result {
+					Ok((_, peer_addr)) => self.push(buffer.split().freeze(), peer_addr),
+					// Left for the proxy to run into, rather than spinning on it here
+					Err(_) => break future.await,
+				}
+			}
+		};
+		
+		if self.dropped_count > 0 {
+			warn!("Dropped {} packets from factorio clients that arrived while connecting, keeping the latest {}",
+				self.dropped_count, self.packets.len());
+			
+			self.dropped_count = 0;
+		}
+		
+		output
+	}
+	
+	fn push(&mut self, packet_data: Bytes, peer_addr: SocketAddr) {
+		if self.packets.len() >= self.capacity {
+			self.packets.pop_front();
+			self.dropped_count += 1;
+		}
+		
+		self.packets.push_back((packet_data, peer_addr));
+	}
+	
+	/// Like UdpSocket::recv_buf_from, but hands out the held packets first.
+	pub async fn recv_buf_from(&mut self, socket: &UdpSocket, buffer: &mut BytesMut) -> std::io::Result<SocketAddr> {
+		match self.packets.pop_front() {
+			Some((packet_data, peer_addr)) => {
+				buffer.extend_from_slice(&packet_data);
+				
+				Ok(peer_addr)
+			}
+			None => Ok(socket.recv_buf_from(buffer).await?.1),
+		}
+	}
+}
+
+/// Samples how many items are waiting in a channel, without keeping the channel open.
+struct QueueGauge {
+	name: &'static str,
+	capacity: usize,
+	sample: Box<dyn Fn() -> Option<usize> + Send>,
+}
+
+impl QueueGauge {
+	fn new<T: Send + 'static>(name: &'static str, sender: &mpsc::Sender<T>) -> Self {
+		let sender = sender.downgrade();
+		
+		Self {
+			name,
+			capacity: sender.upgrade().map_or(0, |sender| sender.max_capacity()),
+			sample: Box::new(move || sender.upgrade().map(|sender| sender.max_capacity() - sender.capacity())),
+		}
+	}
+}
+
+/// Periodically logs how full a peer's queues are, for tuning their sizes, stopping once all of them are closed.
+fn spawn_queue_depth_logger(gauges: Vec<QueueGauge>, peer: impl Display + Send + 'static, interval: Duration) {
+	tokio::spawn(async move {
+		loop {
+			tokio::time::sleep(interval).await;
+			
+			let depths = gauges.iter()
+				.filter_map(|gauge| Some(format!("{} {}/{}", gauge.name, (gauge.sample)()?, gauge.capacity)))
+				.collect::<Vec<_>>();
+			
+			if depths.is_empty() {
+				return;
+			}
+			
+			info!("Queue depths for peer {}: {}", peer, depths.join(", "));
+		}
+	});
+}
+
+/// Periodically logs how many packets were dropped for a peer, stopping once the counter is no longer used.
+fn spawn_dropped_packet_logger(dropped_packets: &Arc<AtomicU64>, peer: impl Display + Send + 'static, interval: Duration) {
+	let dropped_packets = Arc::downgrade(dropped_packets);
+	
+	tokio::spawn(async move {
+		let mut last_count = 0;
+		
+		loop {
+			tokio::time::sleep(interval).await;
+			
+			let Some(dropped_packets) = dropped_packets.upgrade() else { return; };
+			let count = dropped_packets.load(Ordering::Relaxed);
+			
+			if count > last_count {
+				warn!("Dropped {} packets for peer {} in the last {}s because its queue was full ({} total)",
+					count - last_count, peer, interval.as_secs(), count);
+				
+				last_count = count;
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[tokio::test]
+	async fn packets_sent_while_connecting_are_held() {
+		let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		client.connect(socket.local_addr().unwrap()).await.unwrap();
+		
+		let mut early_packets = EarlyPackets::new(2);
+		
+		early_packets.buffer_until(&socket, async {
+			for packet in [b"first", b"secnd", b"third"] {
+				client.send(packet).await.unwrap();
+			}
+			
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}).await;
+		
+		client.send(b"later").await.unwrap();
+		
+		// The oldest packet didn't fit
+		for expected in [b"secnd", b"third", b"later"] {
+			let mut buffer = BytesMut::new();
+			let peer_addr = early_packets.recv_buf_from(&socket, &mut buffer).await.unwrap();
+			
+			assert_eq!(peer_addr, client.local_addr().unwrap());
+			assert_eq!(&buffer[..], expected);
+		}
+	}
+	
+	#[test]
+	fn queue_gauges_stop_once_the_queue_is_dropped() {
+		let (sender, _receiver) = mpsc::channel(4);
+		let gauge = QueueGauge::new("test", &sender);
+		
+		sender.try_send(()).unwrap();
+		assert_eq!((gauge.sample)(), Some(1));
+		assert_eq!(gauge.capacity, 4);
+		
+		// Which is what the peer's logger waits for before it stops
+		drop(sender);
+		assert_eq!((gauge.sample)(), None);
+	}
+}
@@ -0,0 +1,214 @@
+use crate::dedup::{self, ChunkingPolicy};
+use crate::factorio_protocol::{FactorioWorldMetadata, FACTORIO_CRC};
+use crate::packet_trace::TracedPacket;
+use crate::proxy::client_proxy;
+use crate::proxy::server_proxy::{self, ServerProxyConfig, ServerProxyState};
+use anyhow::Context;
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// What became of one world download found in a trace.
+#[derive(Debug)]
+pub struct ReplayedWorld {
+	/// The cacher server's address for the peer that downloaded the world.
+	pub peer_addr: SocketAddr,
+	pub world_info: FactorioWorldMetadata,
+	/// Whether the downloaded world and aux data have the CRC the factorio server gave for them, which fails if the
+	///  trace is missing blocks or they were assembled wrong.
+	pub download_crc_matches: bool,
+	/// Whether the world reconstructed from the downloaded one's chunks has the CRC the factorio client is told to
+	///  expect, or None if it couldn't be deconstructed or reconstructed at all.
+	pub reconstruction_crc_matches: Option<bool>,
+}
+
+impl ReplayedWorld {
+	pub fn is_ok(&self) -> bool {
+		self.download_crc_matches && self.reconstruction_crc_matches == Some(true)
+	}
+}
+
+/// Feeds a trace of the packets exchanged with the factorio server through the same state machine the cacher server
+///  runs for each peer, without any network, then deconstructs and reconstructs every world it downloads. Packets
+///  are replayed back to back, so timeouts never fire.
+pub fn replay_server_trace(
+	packets: Vec<TracedPacket>,
+	factorio_addr: SocketAddr,
+	config: Arc<ServerProxyConfig>,
+) -> Vec<ReplayedWorld> {
+	let mut peers = HashMap::new();
+	let mut replayed_worlds = Vec::new();
+	let mut out_packets = Vec::new();
+	
+	for packet in packets {
+		if packet.to == factorio_addr {
+			// The proxy's own block requests are traced too, but only reset the connection reply time like the
+			//  client's packets would
+			peers.entry(packet.from)
+				.or_insert_with(|| ServerProxyState::new(config.clone()))
+				.on_packet_from_client();
+		} else if packet.from == factorio_addr {
+			let proxy_state = peers.entry(packet.to).or_insert_with(|| ServerProxyState::new(config.clone()));
+			
+			if let Some(mut downloaded_world) = proxy_state.on_packet_from_server(packet.data, &mut out_packets) {
+				replayed_worlds.push(check_downloaded_world(packet.to, &mut downloaded_world, &config.chunking_policy));
+			}
+			
+			// The replies are already in the trace, if they were sent
+			out_packets.clear();
+		}
+	}
+	
+	replayed_worlds
+}
+
+fn check_downloaded_world(
+	peer_addr: SocketAddr,
+	downloaded_world: &mut server_proxy::DownloadingWorldState,
+	chunking_policy: &ChunkingPolicy,
+) -> ReplayedWorld {
+	let world_info = downloaded_world.world_info.clone();
+	
+	let Ok((world_data, aux_data)) = server_proxy::assemble_world_data(downloaded_world) else {
+		return ReplayedWorld {
+			peer_addr,
+			world_info,
+			download_crc_matches: false,
+			reconstruction_crc_matches: None,
+		};
+	};
+	
+	let mut crc_hasher = FACTORIO_CRC.digest();
+	crc_hasher.update(&world_data);
+	crc_hasher.update(&aux_data);
+	
+	let download_crc_matches = crc_hasher.finalize() == world_info.world_crc;
+	
+	let reconstruction_crc_matches = dedup::deconstruct_world(&world_data, &aux_data, chunking_policy)
+		.context("Deconstruction failed")
+		.and_then(|(world_desc, chunks)| {
+			let chunks = chunks.into_iter().collect();
+			let new_world_info = &downloaded_world.new_world_info;
+			let transfer_block_size = downloaded_world.transfer_block_size;
+			
+			let reconstructed = client_proxy::reconstruct_world_data(&world_desc, new_world_info, transfer_block_size, &chunks)
+				.context("Reconstruction failed")?;
+			
+			Ok(client_proxy::verify_world_crc(&reconstructed, new_world_info, aux_data.len(), transfer_block_size))
+		});
+	
+	ReplayedWorld {
+		peer_addr,
+		world_info,
+		download_crc_matches,
+		reconstruction_crc_matches: reconstruction_crc_matches
+			.inspect_err(|err| warn!("World downloaded by peer {} couldn't be replayed: {:#}", peer_addr, err))
+			.ok(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::factorio_protocol::{self, FactorioPacket, FactorioPacketHeader, HeartbeatFlags, PacketType, ServerToClientHeartbeatPacket, TransferBlockPacket};
+	use bytes::{BufMut, Bytes, BytesMut};
+	use std::io::{Cursor, Write};
+	use zip::write::SimpleFileOptions;
+	
+	fn make_save() -> Vec<u8> {
+		let mut level_data = vec![0u8; 100_000];
+		blake3::Hasher::new().update(b"level data").finalize_xof().fill(&mut level_data);
+		
+		let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+		let deflated = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+		
+		writer.start_file("test-save/control.lua", deflated).unwrap();
+		writer.write_all(b"script.on_init(function() end)\n").unwrap();
+		writer.start_file("test-save/level.dat0", deflated).unwrap();
+		writer.write_all(&miniz_oxide::deflate::compress_to_vec_zlib(&level_data, 6)).unwrap();
+		
+		writer.finish().unwrap().into_inner()
+	}
+	
+	/// Traces a factorio server sending a world to a peer, with one of the blocks corrupted if given.
+	fn world_trace(world: &[u8], aux: &[u8], corrupt_block: Option<usize>, factorio_addr: SocketAddr, peer_addr: SocketAddr) -> Vec<TracedPacket> {
+		let block_size = factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE as usize;
+		
+		let mut crc_hasher = FACTORIO_CRC.digest();
+		crc_hasher.update(world);
+		crc_hasher.update(aux);
+		
+		let world_info = FactorioWorldMetadata {
+			world_size: world.len() as u32,
+			no_idea1: 0,
+			aux_size: aux.len() as u32,
+			no_idea2: 0,
+			world_crc: crc_hasher.finalize(),
+		};
+		
+		let mut payload = BytesMut::new();
+		payload.put_u8(1); // Action count
+		payload.put_u8(ServerToClientHeartbeatPacket::MAP_READY_FOR_DOWNLOAD_ACTION_ID);
+		world_info.encode(&mut payload);
+		
+		let mut map_ready = BytesMut::new();
+		FactorioPacketHeader::new_unfragmented(PacketType::ServerToClientHeartbeat).encode(&mut map_ready);
+		map_ready.put_u8(HeartbeatFlags::HasSynchronizerActions.bits());
+		map_ready.put_u32_le(0); // Seq number
+		map_ready.put_slice(&payload);
+		
+		let mut layout = world.to_vec();
+		layout.resize(world.len().next_multiple_of(block_size), 0);
+		layout.extend_from_slice(aux);
+		layout.resize(layout.len().next_multiple_of(block_size), 0);
+		
+		let mut packets = vec![TracedPacket { from: factorio_addr, to: peer_addr, data: map_ready.freeze() }];
+		
+		for (block_id, block_data) in layout.chunks(block_size).enumerate() {
+			let mut data = block_data.to_vec();
+			
+			if corrupt_block == Some(block_id) {
+				data[0] ^= 1;
+			}
+			
+			let block = TransferBlockPacket { block_id: block_id as u32, data: data.into() };
+			packets.push(TracedPacket { from: factorio_addr, to: peer_addr, data: block.encode_full_packet() });
+		}
+		
+		packets
+	}
+	
+	#[test]
+	fn traced_worlds_are_replayed_per_peer() {
+		let factorio_addr: SocketAddr = "127.0.0.1:34197".parse().unwrap();
+		let good_peer: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+		let bad_peer: SocketAddr = "127.0.0.1:50001".parse().unwrap();
+		
+		let world = make_save();
+		
+		let good_trace = world_trace(&world, b"auxiliary data", None, factorio_addr, good_peer);
+		let bad_trace = world_trace(&world, b"auxiliary data", Some(3), factorio_addr, bad_peer);
+		
+		// Interleave the two peers' downloads, along with a packet between other addresses
+		let mut packets = vec![TracedPacket {
+			from: good_peer,
+			to: bad_peer,
+			data: Bytes::from_static(b"unrelated"),
+		}];
+		
+		for (good_packet, bad_packet) in good_trace.into_iter().zip(bad_trace) {
+			packets.push(good_packet);
+			packets.push(bad_packet);
+		}
+		
+		let replayed_worlds = replay_server_trace(packets, factorio_addr, Arc::default());
+		
+		assert_eq!(replayed_worlds.len(), 2);
+		
+		for replayed_world in &replayed_worlds {
+			assert_eq!(replayed_world.world_info.world_size, world.len() as u32);
+			assert_eq!(replayed_world.is_ok(), replayed_world.peer_addr == good_peer, "{:?}", replayed_world);
+		}
+	}
+}
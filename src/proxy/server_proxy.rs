@@ -1,125 +1,547 @@
-use crate::factorio_protocol::{FactorioPacket, FactorioPacketHeader, FactorioWorldMetadata, PacketType, ServerToClientHeartbeatPacket, TransferBlockPacket, TransferBlockRequestPacket, TRANSFER_BLOCK_SIZE};
-use crate::protocol::{Datagram, RequestChunksMessage, SendChunksMessage, WorldReadyMessage, UDP_PEER_IDLE_TIMEOUT};
-use crate::proxy::{PacketDirection, UDP_QUEUE_SIZE};
-use crate::{dedup, protocol, utils};
+use crate::chunk_cache::ChunkCache;
+use crate::content_hash::CONTENT_HASH;
+use crate::delta::{DeltaIndex, DeltaReference};
+use crate::dedup::{ChunkKey, ChunkingPolicy};
+use crate::error::{self, TransferError};
+use crate::log_limit::limited_log;
+use crate::factorio_protocol::{FactorioPacket, FactorioPacketHeader, FactorioWorldMetadata, FragmentHeader, FragmentReassembler, PacketType, ServerToClientHeartbeatPacket, TransferBlockPacket, TransferBlockRequestPacket};
+use crate::popular_chunks::PopularChunks;
+use crate::protocol::{ChunkBatchHeader, Datagram, PopularChunksMessage, RequestChunksMessage, SendChunksMessage, UniStreamType, WorldReadyMessage};
+use crate::packet_trace::PacketTracer;
+use crate::proxy::{client_proxy, spawn_dropped_packet_logger, spawn_queue_depth_logger, PacketDirection, PacketFilter, PeerQueue, QueueGauge};
+use crate::stats::TransferStats;
+use crate::net::UpstreamAddress;
+use crate::{dedup, delta, factorio_protocol, net, protocol, proxy, stats, utils};
 use anyhow::Context;
 use bytes::{Bytes, BytesMut};
-use log::{error, info};
-use memchr::memmem::Finder;
+use hashlink::LinkedHashMap;
+use log::{error, info, warn};
 use quinn_proto::VarInt;
 use std::collections::{BTreeSet, HashMap};
 use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::AsyncReadExt;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::UdpSocket;
 use tokio::select;
 use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tokio::time::Instant;
 
+pub struct ServerProxyConfig {
+	pub handshake_timeout: Duration,
+	/// How long a peer can go without packets from either side before its proxy is closed.
+	pub peer_idle_timeout: Duration,
+	pub stats_file: Option<PathBuf>,
+	pub dropped_packet_log_interval: Option<Duration>,
+	/// How often to log how full each peer's queue is, if at all.
+	pub queue_depth_log_interval: Option<Duration>,
+	pub udp_queue_size: usize,
+	pub transfer_block_size: u32,
+	pub world_ready_timeout: Option<Duration>,
+	/// How long a world download can take in total before it's given up on, if there's a limit.
+	pub max_download_time: Option<Duration>,
+	pub min_dedup_size: u32,
+	/// Which files in the world zip are split into chunks, instead of being sent whole.
+	pub chunking_policy: ChunkingPolicy,
+	pub packet_tracer: Option<Arc<PacketTracer>>,
+	pub max_idle_connection_time: Option<Duration>,
+	pub delta_index: Option<Arc<DeltaIndex>>,
+	/// Chunks from recent worlds, so that the chunks a new world has in common with them share their memory.
+	pub chunk_cache: Option<Arc<ChunkCache>>,
+	/// Whether to replace the world size in the server's world info with the reconstructed world's. Turning this off
+	///  makes factorio reject every deduplicated world, so it's only for debugging.
+	pub rewrite_world_info: bool,
+	pub world_cache: Option<WorldCache>,
+	/// Datagrams to the client with payloads of at least this many bytes are compressed when that makes them smaller.
+	pub compress_datagrams_above: Option<usize>,
+	/// Written to the stats file with each transfer.
+	pub instance_name: Option<String>,
+	/// Whether to check downloaded worlds against the CRC the factorio server gave for them before deconstructing them.
+	pub verify_upstream_crc: bool,
+}
+
+/// Everything optional is turned off, which is what replays and tests build on.
+impl Default for ServerProxyConfig {
+	fn default() -> Self {
+		Self {
+			handshake_timeout: Duration::from_secs(10),
+			peer_idle_timeout: protocol::UDP_PEER_IDLE_TIMEOUT,
+			stats_file: None,
+			dropped_packet_log_interval: None,
+			queue_depth_log_interval: None,
+			udp_queue_size: proxy::DEFAULT_UDP_QUEUE_SIZE,
+			transfer_block_size: factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE,
+			world_ready_timeout: None,
+			max_download_time: None,
+			min_dedup_size: 0,
+			chunking_policy: ChunkingPolicy::default(),
+			packet_tracer: None,
+			max_idle_connection_time: None,
+			delta_index: None,
+			chunk_cache: None,
+			rewrite_world_info: true,
+			world_cache: None,
+			compress_datagrams_above: None,
+			instance_name: None,
+			verify_upstream_crc: false,
+		}
+	}
+}
+
+/// Sent when resetting a stream that was opened for a peer id that's already in use.
+const DUPLICATE_PEER_ID_ERROR_CODE: VarInt = VarInt::from_u32(1);
+
 pub async fn run_server_proxy(
 	connection: Arc<quinn::Connection>,
-	factorio_addr: SocketAddr,
+	factorio_addr: Arc<UpstreamAddress>,
+	popular_chunks: Option<Arc<PopularChunks>>,
+	connection_groups: Arc<ConnectionGroups>,
+	config: Arc<ServerProxyConfig>,
 ) -> anyhow::Result<()> {
-	let mut outgoing_queues: HashMap<VarInt, mpsc::Sender<Bytes>> = HashMap::new();
+	let mut outgoing_queues: HashMap<VarInt, PeerQueue> = HashMap::new();
+	
+	let batch_connections = Arc::new(BatchConnections {
+		connection: connection.clone(),
+		groups: connection_groups,
+		group_id: OnceLock::new(),
+	});
+	
+	if let Some(popular_chunks) = &popular_chunks {
+		let connection = connection.clone();
+		let popular_chunks = popular_chunks.clone();
+		
+		tokio::spawn(async move {
+			if let Err(err) = push_popular_chunks(&connection, &popular_chunks).await {
+				error!("Error trying to push popular chunks: {:?}", err);
+			}
+		});
+	}
+	
+	let mut last_activity = Instant::now();
+	
+	// Peer ids and group ids are read off new streams in their own tasks, so that a client that's slow to send one
+	//  doesn't hold up the datagrams of every other peer on the connection
+	let mut new_peer_streams = JoinSet::new();
+	let mut new_group_streams = JoinSet::new();
 	
 	loop {
 		select! {
             result = connection.read_datagram() => {
                 let datagram = Datagram::decode(result?)?;
+				last_activity = Instant::now();
+				batch_connections.record_activity(last_activity);
 
                 if let Some(outgoing_queue) = outgoing_queues.get(&datagram.peer_id) {
-                    let _ = outgoing_queue.try_send(datagram.data);
+                    outgoing_queue.try_send(datagram.data);
                 }
             }
             result = connection.accept_bi() => {
                 let (send_stream, mut recv_stream) = result?;
-                let peer_id: VarInt = recv_stream.read_u32_le().await?.into();
+				let handshake_timeout = config.handshake_timeout;
+				
+				new_peer_streams.spawn(async move {
+					let peer_id = tokio::time::timeout(handshake_timeout, recv_stream.read_u32_le()).await;
+					
+					(peer_id, send_stream, recv_stream)
+				});
+            }
+            Some(result) = new_peer_streams.join_next(), if !new_peer_streams.is_empty() => {
+				let (peer_id, mut send_stream, mut recv_stream) = result?;
+				
+				let peer_id: VarInt = match peer_id {
+					Ok(Ok(peer_id)) => peer_id.into(),
+					Ok(Err(err)) => {
+						limited_log!(error, "Error reading peer id from new stream: {:?}", err);
+						continue;
+					}
+					Err(_) => {
+						limited_log!(error, "Timed out waiting for peer id on new stream");
+						continue;
+					}
+				};
 
+				// Peer ids are picked by the client, so reusing one that's still active would take over that peer's packets
+				if outgoing_queues.get(&peer_id).is_some_and(|outgoing_queue| !outgoing_queue.is_closed()) {
+					limited_log!(warn, "Rejecting new stream for peer {}, which is already in use", peer_id);
+					
+					let _ = send_stream.reset(DUPLICATE_PEER_ID_ERROR_CODE);
+					let _ = recv_stream.stop(DUPLICATE_PEER_ID_ERROR_CODE);
+					continue;
+				}
+				
 				info!("New peer with id {}", peer_id);
+				last_activity = Instant::now();
+				batch_connections.record_activity(last_activity);
 				
-                let localhost: IpAddr = if factorio_addr.is_ipv6() {
+                let localhost: IpAddr = if factorio_addr.get().is_ipv6() {
                     Ipv6Addr::LOCALHOST.into()
                 } else {
                     Ipv4Addr::LOCALHOST.into()
                 };
 
-                let socket = UdpSocket::bind((localhost, 0)).await?;
+                let socket = Arc::new(net::bind_tokio_udp_socket(SocketAddr::new(localhost, 0), false)?);
 				
-                let (receive_queue_tx, receive_queue_rx) = mpsc::channel(UDP_QUEUE_SIZE);
+                let (receive_queue_tx, receive_queue_rx) = mpsc::channel(config.udp_queue_size);
 
                 tokio::spawn(proxy_server(ProxyServerArgs {
                     connection: connection.clone(),
+                    batch_connections: batch_connections.clone(),
                     peer_id,
 
                     socket,
-                    factorio_addr,
+                    factorio_addr: factorio_addr.clone(),
 
                     receive_queue_rx,
 
                     comp_stream: (send_stream, recv_stream),
+                    popular_chunks: popular_chunks.clone(),
+                    config: config.clone(),
                 }));
 
-                outgoing_queues.insert(peer_id, receive_queue_tx);
+				let dropped_packets = Arc::new(AtomicU64::new(0));
+				
+				if let Some(interval) = config.dropped_packet_log_interval {
+					spawn_dropped_packet_logger(&dropped_packets, peer_id, interval);
+				}
+				
+				if let Some(interval) = config.queue_depth_log_interval {
+					spawn_queue_depth_logger(vec![QueueGauge::new("to server", &receive_queue_tx)], peer_id, interval);
+				}
+				
+                outgoing_queues.insert(peer_id, PeerQueue::new(receive_queue_tx, dropped_packets));
+            }
+            result = connection.accept_uni() => {
+                let mut recv_stream = result?;
+				
+				new_group_streams.spawn(tokio::time::timeout(config.handshake_timeout, async move {
+					match UniStreamType::from(recv_stream.read_u8().await?) {
+						UniStreamType::ConnectionGroup => anyhow::Ok(recv_stream.read_u64_le().await?),
+						stream_type => Err(anyhow::anyhow!("Unexpected stream type {:?}", stream_type)),
+					}
+				}));
+            }
+            Some(result) = new_group_streams.join_next(), if !new_group_streams.is_empty() => {
+				match result? {
+					Ok(Ok(group_id)) => batch_connections.join_group(group_id),
+					Ok(Err(err)) => limited_log!(error, "Error reading stream from client: {:?}", err),
+					Err(_) => limited_log!(error, "Timed out reading stream from client"),
+				}
             }
+			_ = idle_deadline(last_activity, config.max_idle_connection_time) => {
+				outgoing_queues.retain(|_, outgoing_queue| !outgoing_queue.is_closed());
+				
+				if !outgoing_queues.is_empty() {
+					last_activity = Instant::now();
+					batch_connections.record_activity(last_activity);
+					continue;
+				}
+				
+				// Transfer connections never carry peers or datagrams themselves, so a connection in a group stays open
+				//  for as long as any connection in the group is active
+				match batch_connections.group_last_activity() {
+					Some(group_activity) if group_activity > last_activity => last_activity = group_activity,
+					_ => {
+						info!("Closing connection that has been idle for {}s", last_activity.elapsed().as_secs());
+						connection.close(0u32.into(), b"idle");
+						
+						return Ok(());
+					}
+				}
+			}
         }
 	}
 }
 
+/// Resolves once a connection has gone max_idle_time without activity, or never if there's no limit.
+async fn idle_deadline(last_activity: Instant, max_idle_time: Option<Duration>) {
+	match max_idle_time {
+		Some(max_idle_time) => tokio::time::sleep_until(last_activity + max_idle_time).await,
+		None => std::future::pending().await,
+	}
+}
+
+/// Connections that a client opened to spread chunk batches over, grouped by an id that the client picked.
+#[derive(Default)]
+pub struct ConnectionGroups {
+	groups: Mutex<HashMap<u64, ConnectionGroup>>,
+}
+
+struct ConnectionGroup {
+	members: Vec<Arc<quinn::Connection>>,
+	/// When any of the members last carried peers or datagrams.
+	last_activity: Instant,
+}
+
+impl ConnectionGroups {
+	/// Returns false if the group belongs to a client at a different address.
+	fn join(&self, group_id: u64, connection: &Arc<quinn::Connection>) -> bool {
+		let mut groups = self.groups.lock().unwrap();
+		let group = groups.entry(group_id).or_insert_with(|| ConnectionGroup {
+			members: Vec::new(),
+			last_activity: Instant::now(),
+		});
+		
+		if group.members.first().is_some_and(|member| member.remote_address().ip() != connection.remote_address().ip()) {
+			return false;
+		}
+		
+		group.members.push(connection.clone());
+		
+		true
+	}
+	
+	fn leave(&self, group_id: u64, connection: &quinn::Connection) {
+		let mut groups = self.groups.lock().unwrap();
+		
+		if let Some(group) = groups.get_mut(&group_id) {
+			group.members.retain(|member| member.stable_id() != connection.stable_id());
+			
+			if group.members.is_empty() {
+				groups.remove(&group_id);
+			}
+		}
+	}
+	
+	fn record_activity(&self, group_id: u64, time: Instant) {
+		if let Some(group) = self.groups.lock().unwrap().get_mut(&group_id) {
+			group.last_activity = group.last_activity.max(time);
+		}
+	}
+	
+	fn last_activity(&self, group_id: u64) -> Option<Instant> {
+		self.groups.lock().unwrap().get(&group_id).map(|group| group.last_activity)
+	}
+	
+	/// Picks one of the group's open connections, spreading consecutive indices across all of them.
+	fn pick(&self, group_id: u64, index: u32) -> Option<Arc<quinn::Connection>> {
+		let groups = self.groups.lock().unwrap();
+		
+		let open_members = groups.get(&group_id)?.members.iter()
+			.filter(|member| member.close_reason().is_none())
+			.collect::<Vec<_>>();
+		
+		if open_members.is_empty() {
+			return None;
+		}
+		
+		Some(open_members[index as usize % open_members.len()].clone())
+	}
+}
+
+/// Picks the connection to send each of a client's chunk batches on, leaving its group once dropped.
+struct BatchConnections {
+	connection: Arc<quinn::Connection>,
+	groups: Arc<ConnectionGroups>,
+	group_id: OnceLock<u64>,
+}
+
+impl BatchConnections {
+	fn join_group(&self, group_id: u64) {
+		if self.group_id.get().is_some() {
+			limited_log!(warn, "Client from {} tried to join a second connection group", self.connection.remote_address());
+			return;
+		}
+		
+		if !self.groups.join(group_id, &self.connection) {
+			limited_log!(warn, "Client from {} tried to join another client's connection group", self.connection.remote_address());
+			return;
+		}
+		
+		let _ = self.group_id.set(group_id);
+	}
+	
+	fn record_activity(&self, time: Instant) {
+		if let Some(&group_id) = self.group_id.get() {
+			self.groups.record_activity(group_id, time);
+		}
+	}
+	
+	/// When any connection in this one's group was last active, if it's in one.
+	fn group_last_activity(&self) -> Option<Instant> {
+		self.group_id.get().and_then(|&group_id| self.groups.last_activity(group_id))
+	}
+	
+	fn pick(&self, batch_id: u32) -> Arc<quinn::Connection> {
+		self.group_id.get()
+			.and_then(|&group_id| self.groups.pick(group_id, batch_id))
+			.unwrap_or_else(|| self.connection.clone())
+	}
+}
+
+impl Drop for BatchConnections {
+	fn drop(&mut self) {
+		if let Some(&group_id) = self.group_id.get() {
+			self.groups.leave(group_id, &self.connection);
+		}
+	}
+}
+
 struct ProxyServerArgs {
 	connection: Arc<quinn::Connection>,
+	batch_connections: Arc<BatchConnections>,
 	peer_id: VarInt,
 	
-	socket: UdpSocket,
-	factorio_addr: SocketAddr,
+	socket: Arc<UdpSocket>,
+	factorio_addr: Arc<UpstreamAddress>,
 	
 	receive_queue_rx: mpsc::Receiver<Bytes>,
 	
 	comp_stream: (quinn::SendStream, quinn::RecvStream),
+	popular_chunks: Option<Arc<PopularChunks>>,
+	config: Arc<ServerProxyConfig>,
+}
+
+async fn push_popular_chunks(connection: &quinn::Connection, popular_chunks: &PopularChunks) -> anyhow::Result<()> {
+	const PUSH_BATCH_SIZE: usize = 512;
+	
+	let chunks = popular_chunks.snapshot();
+	
+	if chunks.is_empty() {
+		return Ok(());
+	}
+	
+	let mut send_stream = connection.open_uni().await?;
+	send_stream.write_u8(UniStreamType::PopularChunks.into()).await?;
+	
+	let mut total_transferred = 0;
+	
+	for batch in chunks.chunks(PUSH_BATCH_SIZE) {
+		let message_data = protocol::encode_message_async(PopularChunksMessage {
+			chunks: batch.iter().map(|(_, chunk)| chunk.clone()).collect(),
+		}).await?;
+		
+		total_transferred += message_data.len() as u64;
+		
+		protocol::write_message(&mut send_stream, message_data).await?;
+	}
+	
+	send_stream.finish()?;
+	
+	info!("Pushed {} popular chunks to client, size: {}B", chunks.len(), utils::abbreviate_number(total_transferred));
+	
+	Ok(())
 }
 
 async fn proxy_server(mut args: ProxyServerArgs) {
 	let mut buf = BytesMut::new();
 	let mut out_packets = Vec::new();
 	
-	let mut proxy_state = ServerProxyState::new(args.comp_stream);
+	let world_ready_timeout = args.config.world_ready_timeout;
+	let world_ready_deadline = Instant::now() + world_ready_timeout.unwrap_or_default();
+	let mut world_ready_warned = world_ready_timeout.is_none();
+	
+	let local_addr = args.socket.local_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+	let mut comp_stream = Some(args.comp_stream);
+	let mut proxy_state = ServerProxyState::new(args.config.clone());
+	
+	// Retrying a failed send to the factorio server, which happens outside the loop so that packets from the server
+	//  keep being handled meanwhile
+	let mut factorio_retry = JoinSet::new();
 	
 	loop {
 		buf.clear();
 		buf.reserve(8192);
 		
+		let rejection_deadline = proxy_state.rejection_deadline();
+		
+		// Packets from the factorio server are handled first, so that gameplay traffic is never held up behind the
+		//  client's queue. Block transfers can't starve the client's queue since only a limited number of blocks are
+		//  requested at once.
 		select! {
+            biased;
+
             result = args.socket.recv_buf_from(&mut buf) => {
                 let Ok((_, remote_addr)) = result else { return };
 
-                // Drop any packets that don't originate from the server
-                if remote_addr != args.factorio_addr { continue; }
+                // Drop any packets that don't originate from the server, which may have moved since the last packet
+                if remote_addr != args.factorio_addr.get() { continue; }
+
+                if let Some(tracer) = &args.config.packet_tracer {
+                    tracer.trace(args.peer_id, remote_addr, local_addr, &buf);
+                }
+
+                if let Some(downloaded_world) = proxy_state.on_packet_from_server(buf.split().freeze(), &mut out_packets) {
+                    let (send_stream, recv_stream) = comp_stream.take().unwrap();
+                    let batch_connections = args.batch_connections.clone();
+                    let peer_id = args.peer_id;
+                    let popular_chunks = args.popular_chunks.clone();
+                    let config = args.config.clone();
+
+                    tokio::spawn(async move {
+                        match transfer_world_data(batch_connections, peer_id, send_stream, recv_stream, downloaded_world, popular_chunks, &config).await {
+                            Ok(()) => {}
+                            Err(err) if error::is_disconnect(&err) => info!("Client went away while transferring world data: {:#}", err),
+                            Err(err) => error!("Error trying to transfer world data: {:?}", err),
+                        }
+                    });
+                } else if proxy_state.is_abandoned() {
+                    // Resetting the stream tells the client to close its peer too, so the factorio client reconnects
+                    //  from scratch instead of waiting on a world that was never downloaded
+                    if let Some((mut send_stream, _)) = comp_stream.take() {
+                        let _ = send_stream.reset(VarInt::from_u32(0));
+                    }
 
-                proxy_state.on_packet_from_server(buf.split().freeze(), &mut out_packets).await;
+                    return;
+                } else if proxy_state.is_done() {
+                    // The world wasn't deduplicated, closing the stream tells the client to forward its block requests
+                    if let Some((mut send_stream, _)) = comp_stream.take() {
+                        let _ = send_stream.finish();
+                    }
+                }
             }
             result = args.receive_queue_rx.recv() => {
                 let Some(packet_data) = result else { return; };
 
+                proxy_state.on_packet_from_client();
                 out_packets.push((packet_data, PacketDirection::ToServer));
             }
-            _ = tokio::time::sleep(UDP_PEER_IDLE_TIMEOUT) => return
+            _ = tokio::time::sleep_until(world_ready_deadline), if !world_ready_warned && proxy_state.is_waiting_for_world() => {
+                // Packets keep being forwarded, since the peer may not need to download a world at all
+                warn!("Peer {} hasn't received a map from the factorio server after {}s, the factorio server may be \
+                    unreachable or rejecting the client", args.peer_id, world_ready_timeout.unwrap_or_default().as_secs());
+
+                world_ready_warned = true;
+            }
+            _ = tokio::time::sleep_until(rejection_deadline.unwrap_or_else(Instant::now)), if rejection_deadline.is_some() => {
+                info!("Factorio server turned peer {} away, closing it", args.peer_id);
+
+                return;
+            }
+            Some(result) = factorio_retry.join_next(), if !factorio_retry.is_empty() => {
+                if !result.unwrap_or(false) {
+                    return;
+                }
+            }
+            _ = tokio::time::sleep(args.config.peer_idle_timeout) => return
         }
 		
 		for (packet_data, dir) in out_packets.drain(..) {
 			match dir {
 				PacketDirection::ToClient => {
-					Datagram::new(args.peer_id, packet_data).encode(&mut buf);
+					Datagram::new(args.peer_id, packet_data).encode(&mut buf, args.config.compress_datagrams_above);
 					
 					if args.connection.send_datagram(buf.split().freeze()).is_err() {
 						return;
 					}
 				}
 				PacketDirection::ToServer => {
-					if let Err(err) = args.socket.send_to(&packet_data, args.factorio_addr).await {
-						error!("Failed to send packet to factorio server: {:?}", err);
-						
-						return;
+					let factorio_addr = args.factorio_addr.get();
+					
+					if let Some(tracer) = &args.config.packet_tracer {
+						tracer.trace(args.peer_id, local_addr, factorio_addr, &packet_data);
+					}
+					
+					// Packets sent while a retry is underway are dropped, factorio resends the ones that matter
+					if let Err(err) = args.socket.send_to(&packet_data, factorio_addr).await {
+						if factorio_retry.is_empty() {
+							warn!("Failed to send packet to factorio server for peer {}: {}, retrying", args.peer_id, err);
+							
+							factorio_retry.spawn(retry_send_to_factorio(args.socket.clone(), packet_data, args.factorio_addr.clone(), args.peer_id));
+						}
 					}
 				}
 			}
@@ -127,52 +549,161 @@ async fn proxy_server(mut args: ProxyServerArgs) {
 	}
 }
 
-struct ServerProxyState {
+const FACTORIO_SEND_RETRIES: u32 = 4;
+const FACTORIO_SEND_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Retries sending a packet that couldn't be sent to the factorio server, with a short backoff, looking the server's
+///  address up again in case it moved. The new address is shared, so every peer picks it up. Returns false if the
+///  server couldn't be reached.
+async fn retry_send_to_factorio(
+	socket: Arc<UdpSocket>,
+	packet_data: Bytes,
+	upstream_addr: Arc<UpstreamAddress>,
+	peer_id: VarInt,
+) -> bool {
+	for attempt in 1..=FACTORIO_SEND_RETRIES {
+		tokio::time::sleep(FACTORIO_SEND_RETRY_DELAY * attempt).await;
+		
+		let factorio_addr = upstream_addr.re_resolve().await;
+		
+		match socket.send_to(&packet_data, factorio_addr).await {
+			Ok(_) => {
+				info!("Reached factorio server at {} again for peer {}", factorio_addr, peer_id);
+				return true;
+			}
+			Err(err) => warn!("Retry {} of sending to factorio server at {} failed: {}", attempt, factorio_addr, err),
+		}
+	}
+	
+	error!("Giving up on reaching the factorio server for peer {}", peer_id);
+	
+	false
+}
+
+/// How long a connection reply has to go unanswered by either side before the peer is assumed to have been rejected.
+const REJECTED_PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(super) struct ServerProxyState {
 	phase: ServerProxyPhase,
-	packet_filter: Option<FilteringPacketsState>,
-	comp_stream: Option<(quinn::SendStream, quinn::RecvStream)>,
+	/// When the server last answered a connection request, if nothing else has been sent since.
+	connection_reply_time: Option<Instant>,
+	packet_filter: Option<PacketFilter>,
+	config: Arc<ServerProxyConfig>,
 }
 
 enum ServerProxyPhase {
 	WaitingForWorld,
-	DownloadingWorld(DownloadingWorldState),
+	DownloadingWorld(Box<DownloadingWorldState>),
 	Done,
+	/// The download was given up on after the client was already sent the rewritten world info.
+	Abandoned,
 }
 
-struct DownloadingWorldState {
-	world_info: FactorioWorldMetadata,
-	new_world_info: FactorioWorldMetadata,
+pub(super) struct DownloadingWorldState {
+	pub(super) world_info: FactorioWorldMetadata,
+	pub(super) new_world_info: FactorioWorldMetadata,
+	pub(super) transfer_block_size: u32,
 	world_block_count: u32,
 	download_start_time: Instant,
 	
 	received_blocks: Vec<TransferBlockPacket>,
 	block_request_queue: BTreeSet<u32>,
 	inflight_block_requests: BTreeSet<u32>,
+	/// How many times each inflight block has been requested again.
+	block_retransmits: HashMap<u32, u32>,
+	/// Blocks that factorio split over several packets, which are only handled once they're whole again.
+	block_fragments: FragmentReassembler,
 	last_block_time: Instant,
+	/// How many times the whole world was downloaded and failed CRC verification.
+	failed_downloads: u32,
 }
 
-struct FilteringPacketsState {
-	finder: Finder<'static>,
-	replace_with: Bytes,
-	last_replace: Instant,
+impl DownloadingWorldState {
+	/// Whether the downloaded world and aux data match the CRC the factorio server gave for them.
+	fn download_crc_matches(&mut self) -> bool {
+		self.received_blocks.sort_by_key(|block| block.block_id);
+		
+		let block_data = self.received_blocks.iter().map(|block| block.data.clone()).collect::<Vec<_>>();
+		
+		client_proxy::verify_world_crc(&block_data, &self.world_info, self.world_info.aux_size as usize, self.transfer_block_size)
+	}
+	
+	/// Throws away every downloaded block so that the whole world is requested again.
+	fn restart_download(&mut self) {
+		let aux_block_count = factorio_protocol::transfer_block_count(self.world_info.aux_size, self.transfer_block_size);
+		
+		self.received_blocks.clear();
+		self.block_request_queue = BTreeSet::from_iter(0..self.world_block_count + aux_block_count);
+		self.inflight_block_requests.clear();
+		self.block_retransmits.clear();
+		self.block_fragments = FragmentReassembler::default();
+		self.last_block_time = Instant::now();
+	}
 }
 
 impl ServerProxyState {
 	const INFLIGHT_BLOCK_REQUEST_LIMIT: usize = 16;
+	/// Retransmits happen at most every 100ms, so this gives up on a block after at least 10s.
+	const MAX_BLOCK_RETRANSMITS: u32 = 100;
+	/// How many times a world that fails CRC verification is downloaded again before giving up on it.
+	const MAX_FAILED_DOWNLOADS: u32 = 3;
 	
-	pub fn new(comp_stream: (quinn::SendStream, quinn::RecvStream)) -> Self {
+	pub fn new(config: Arc<ServerProxyConfig>) -> Self {
 		Self {
 			phase: ServerProxyPhase::WaitingForWorld,
+			connection_reply_time: None,
 			packet_filter: None,
-			comp_stream: Some(comp_stream),
+			config,
 		}
 	}
 	
-	pub async fn on_packet_from_server(
+	pub fn is_waiting_for_world(&self) -> bool {
+		matches!(self.phase, ServerProxyPhase::WaitingForWorld)
+	}
+	
+	pub fn is_done(&self) -> bool {
+		matches!(self.phase, ServerProxyPhase::Done)
+	}
+	
+	pub fn is_abandoned(&self) -> bool {
+		matches!(self.phase, ServerProxyPhase::Abandoned)
+	}
+	
+	/// When to give up on a peer that the factorio server turned away. The server answers both accepted and rejected
+	///  connection requests with the same packet type, but only keeps talking to accepted clients, and a rejected
+	///  client stops talking too unless it tries again.
+	pub fn rejection_deadline(&self) -> Option<Instant> {
+		self.connection_reply_time
+			.filter(|_| self.is_waiting_for_world())
+			.map(|reply_time| reply_time + REJECTED_PEER_TIMEOUT)
+	}
+	
+	pub fn on_packet_from_client(&mut self) {
+		self.connection_reply_time = None;
+	}
+	
+	/// Handles a packet from the factorio server, returning the downloaded world once its last block arrives.
+	/// Packets that aren't part of the world download are forwarded before any block requests they trigger.
+	pub fn on_packet_from_server(
 		&mut self,
 		mut in_packet_data: Bytes,
 		out_packets: &mut Vec<(Bytes, PacketDirection)>,
-	) {
+	) -> Option<DownloadingWorldState> {
+		let is_connection_reply = FactorioPacketHeader::decode(in_packet_data.clone())
+			.is_ok_and(|(header, _)| header.packet_type == PacketType::ConnectionAcceptOrDeny);
+		
+		self.connection_reply_time = is_connection_reply.then(Instant::now);
+		
+		if let (ServerProxyPhase::DownloadingWorld(state), Some(max_download_time)) = (&self.phase, self.config.max_download_time) {
+			if state.download_start_time.elapsed() > max_download_time {
+				error!("Downloading the world took longer than {}s, giving up on it with {} of {} blocks received",
+					max_download_time.as_secs(), state.received_blocks.len(),
+					state.received_blocks.len() + state.inflight_block_requests.len() + state.block_request_queue.len());
+				
+				self.abandon_download();
+			}
+		}
+		
 		match &mut self.phase {
 			ServerProxyPhase::WaitingForWorld => {
 				if let Ok((header, msg_data)) =
@@ -184,7 +715,7 @@ impl ServerProxyState {
 						
 						if let Ok(Some(world_info)) = result {
 							self.transition_to_downloading_world(in_packet_data, world_info, out_packets);
-							return;
+							return None;
 						}
 					}
 				}
@@ -194,44 +725,62 @@ impl ServerProxyState {
 					FactorioPacketHeader::decode(in_packet_data.clone())
 				{
 					if header.packet_type == PacketType::TransferBlock {
-						let Ok(transfer_block) = TransferBlockPacket::decode(msg_data) else { return; };
+						let msg_data = if header.is_fragmented {
+							let Ok((fragment_header, fragment_data)) = FragmentHeader::decode(msg_data) else { return None; };
+							
+							// Fragments show that blocks are arriving, even though none are complete yet
+							state.last_block_time = Instant::now();
+							
+							state.block_fragments.add(fragment_header, header.is_last_fragment, fragment_data)?
+						} else {
+							msg_data
+						};
 						
+						let Ok(transfer_block) = TransferBlockPacket::decode(msg_data) else { return None; };
+						
+						// Blocks that weren't requested yet are taken too, in case the server sends ahead. A block
+						//  leaves both sets once received, so duplicates and ids past the end are ignored.
 						if state.inflight_block_requests.remove(&transfer_block.block_id) ||
 							state.block_request_queue.remove(&transfer_block.block_id)
 						{
+							state.block_retransmits.remove(&transfer_block.block_id);
 							state.received_blocks.push(transfer_block);
 							
 							state.last_block_time = Instant::now();
 						}
 						
 						if state.block_request_queue.is_empty() && state.inflight_block_requests.is_empty() {
-							self.finalize_world();
-						} else {
-							Self::request_next_blocks(state, out_packets);
+							if !self.config.verify_upstream_crc || state.download_crc_matches() {
+								return Some(self.finalize_world());
+							}
+							
+							state.failed_downloads += 1;
+							
+							if state.failed_downloads > Self::MAX_FAILED_DOWNLOADS {
+								error!("World failed CRC verification {} times, giving up on downloading it", state.failed_downloads);
+								
+								self.abandon_download();
+								return None;
+							}
+							
+							warn!("Downloaded world failed CRC verification, downloading it again");
+							
+							state.restart_download();
 						}
 						
-						return;
-					}
-				}
-				
-				if state.last_block_time.elapsed() > Duration::from_millis(100) {
-					for &block_id in &state.inflight_block_requests {
-						let request = TransferBlockRequestPacket { block_id };
-						out_packets.push((request.encode_full_packet(), PacketDirection::ToServer));
+						Self::request_next_blocks(state, out_packets);
+						
+						return None;
 					}
-					
-					Self::request_next_blocks(state, out_packets);
-					
-					state.last_block_time = Instant::now();
 				}
 			}
-			ServerProxyPhase::Done => {}
+			ServerProxyPhase::Done | ServerProxyPhase::Abandoned => {}
 		}
 		
-		if let Some(filtering_state) = &mut self.packet_filter {
-			in_packet_data = Self::filter_packet(filtering_state, in_packet_data);
+		if let Some(packet_filter) = &mut self.packet_filter {
+			in_packet_data = packet_filter.filter(in_packet_data);
 			
-			if filtering_state.last_replace.elapsed() > Duration::from_secs(30) {
+			if packet_filter.is_stale() {
 				info!("Stopped filtering packets");
 				
 				self.packet_filter = None;
@@ -239,6 +788,29 @@ impl ServerProxyState {
 		}
 		
 		out_packets.push((in_packet_data, PacketDirection::ToClient));
+		
+		if let ServerProxyPhase::DownloadingWorld(state) = &mut self.phase {
+			if state.last_block_time.elapsed() > Duration::from_millis(100) {
+				if let Some(block_id) = Self::retransmit_block_requests(state, out_packets) {
+					error!("Block {} was requested {} times without arriving, giving up on downloading the world",
+						block_id, Self::MAX_BLOCK_RETRANSMITS + 1);
+					
+					self.abandon_download();
+				}
+			}
+		}
+		
+		None
+	}
+	
+	/// Gives up on downloading the world. Without the world info rewritten the client can still download the world
+	///  itself, otherwise it expects a world that will never come, so the peer has to be closed.
+	fn abandon_download(&mut self) {
+		self.phase = if self.config.rewrite_world_info {
+			ServerProxyPhase::Abandoned
+		} else {
+			ServerProxyPhase::Done
+		};
 	}
 	
 	fn transition_to_downloading_world(
@@ -249,6 +821,26 @@ impl ServerProxyState {
 	) {
 		info!("Got world info: {:?}", world_info);
 		
+		// An empty world has no blocks to download and nothing to deduplicate, so waiting for blocks would hang
+		if world_info.world_size == 0 {
+			info!("World is empty, forwarding it directly");
+			
+			out_packets.push((in_packet_data, PacketDirection::ToClient));
+			self.phase = ServerProxyPhase::Done;
+			
+			return;
+		}
+		
+		if world_info.world_size < self.config.min_dedup_size {
+			info!("World is smaller than the minimum dedup size of {}B, forwarding it directly",
+				utils::abbreviate_number(self.config.min_dedup_size as u64));
+			
+			out_packets.push((in_packet_data, PacketDirection::ToClient));
+			self.phase = ServerProxyPhase::Done;
+			
+			return;
+		}
+		
 		let estimated_reconstructed_world_size = world_info.world_size * 2;
 		
 		info!("Estimated reconstructed world size: {}", estimated_reconstructed_world_size);
@@ -258,45 +850,44 @@ impl ServerProxyState {
 			..world_info
 		};
 		
-		let mut old_world_info_encoded = Vec::new();
-		let mut new_world_info_encoded = Vec::new();
-		
-		world_info.encode(&mut old_world_info_encoded);
-		new_world_info.encode(&mut new_world_info_encoded);
-		
-		let mut filtering_state = FilteringPacketsState {
-			finder: Finder::new(&old_world_info_encoded).into_owned(),
-			replace_with: new_world_info_encoded.into(),
-			last_replace: Instant::now(),
-		};
+		if self.config.rewrite_world_info {
+			let mut packet_filter = PacketFilter::new(&world_info, &new_world_info);
+			
+			in_packet_data = packet_filter.filter(in_packet_data);
+			self.packet_filter = Some(packet_filter);
+		} else {
+			warn!("Not rewriting the world info, so the factorio client will expect the original world");
+		}
 		
-		in_packet_data = Self::filter_packet(&mut filtering_state, in_packet_data);
 		out_packets.push((in_packet_data, PacketDirection::ToClient));
 		
-		self.packet_filter = Some(filtering_state);
-		
-		let world_block_count = (world_info.world_size + TRANSFER_BLOCK_SIZE - 1) / TRANSFER_BLOCK_SIZE;
-		let aux_block_count = (world_info.aux_size + TRANSFER_BLOCK_SIZE - 1) / TRANSFER_BLOCK_SIZE;
+		let transfer_block_size = self.config.transfer_block_size;
+		let world_block_count = factorio_protocol::transfer_block_count(world_info.world_size, transfer_block_size);
+		let aux_block_count = factorio_protocol::transfer_block_count(world_info.aux_size, transfer_block_size);
 		
 		let total_block_count = world_block_count + aux_block_count;
 		
 		let mut state = DownloadingWorldState {
 			world_info,
 			new_world_info,
+			transfer_block_size,
 			world_block_count,
 			download_start_time: Instant::now(),
 			
 			received_blocks: Vec::new(),
 			block_request_queue: BTreeSet::from_iter(0..total_block_count),
 			inflight_block_requests: BTreeSet::new(),
+			block_retransmits: HashMap::new(),
+			block_fragments: FragmentReassembler::default(),
 			last_block_time: Instant::now(),
+			failed_downloads: 0,
 		};
 		
 		info!("Downloading world from server");
 		
 		Self::request_next_blocks(&mut state, out_packets);
 		
-		self.phase = ServerProxyPhase::DownloadingWorld(state);
+		self.phase = ServerProxyPhase::DownloadingWorld(Box::new(state));
 	}
 	
 	fn request_next_blocks(state: &mut DownloadingWorldState, out_packets: &mut Vec<(Bytes, PacketDirection)>) {
@@ -309,84 +900,66 @@ impl ServerProxyState {
 		}
 	}
 	
-	fn finalize_world(&mut self) {
-		let state = match mem::replace(&mut self.phase, ServerProxyPhase::Done) {
-			ServerProxyPhase::DownloadingWorld(state) => state,
-			_ => unreachable!(),
-		};
+	/// Requests the inflight blocks again, returning a block that has been retransmitted too many times instead if
+	///  there is one.
+	fn retransmit_block_requests(state: &mut DownloadingWorldState, out_packets: &mut Vec<(Bytes, PacketDirection)>) -> Option<u32> {
+		for &block_id in &state.inflight_block_requests {
+			let retransmits = state.block_retransmits.entry(block_id).or_default();
+			
+			if *retransmits >= Self::MAX_BLOCK_RETRANSMITS {
+				return Some(block_id);
+			}
+			
+			*retransmits += 1;
+			
+			let request = TransferBlockRequestPacket { block_id };
+			out_packets.push((request.encode_full_packet(), PacketDirection::ToServer));
+		}
 		
-		info!("Downloading world took {}ms", state.download_start_time.elapsed().as_millis());
+		Self::request_next_blocks(state, out_packets);
 		
-		let comp_stream = self.comp_stream.take().unwrap();
+		state.last_block_time = Instant::now();
 		
-		tokio::spawn(async move {
-			if let Err(err) = transfer_world_data(comp_stream.0, comp_stream.1, state).await {
-				error!("Error trying to transfer world data: {:?}", err);
-			}
-		});
+		None
 	}
 	
-	fn filter_packet(state: &mut FilteringPacketsState, packet_data: Bytes) -> Bytes {
-		let mut new_packet_data = None;
-		
-		for pos in state.finder.find_iter(&packet_data) {
-			let new_packet_data = new_packet_data
-				.get_or_insert_with(|| BytesMut::from(packet_data.as_ref()));
-			
-			new_packet_data[pos..pos + state.finder.needle().len()].copy_from_slice(&state.replace_with);
-		}
+	fn finalize_world(&mut self) -> DownloadingWorldState {
+		let state = match mem::replace(&mut self.phase, ServerProxyPhase::Done) {
+			ServerProxyPhase::DownloadingWorld(state) => *state,
+			_ => unreachable!(),
+		};
 		
-		if new_packet_data.is_some() {
-			state.last_replace = Instant::now();
-		}
+		info!("Downloading world took {}ms", state.download_start_time.elapsed().as_millis());
 		
-		new_packet_data.map(BytesMut::freeze).unwrap_or(packet_data)
+		state
 	}
 }
 
 async fn transfer_world_data(
+	batch_connections: Arc<BatchConnections>,
+	peer_id: VarInt,
 	mut send_stream: quinn::SendStream,
 	mut recv_stream: quinn::RecvStream,
 	mut downloading_state: DownloadingWorldState,
+	popular_chunks: Option<Arc<PopularChunks>>,
+	config: &ServerProxyConfig,
 ) -> anyhow::Result<()> {
-	let start_time = Instant::now();
-	
-	downloading_state.received_blocks.sort_by_key(|block| block.block_id);
-	
-	let mut received_data = BytesMut::new();
-	
-	for block in downloading_state.received_blocks.drain(..) {
-		received_data.extend_from_slice(&block.data);
-	}
-	
-	let received_data = received_data.freeze();
-	
-	let aux_data_offset = downloading_state.world_block_count * TRANSFER_BLOCK_SIZE;
-	
-	if received_data.len() < (aux_data_offset as usize + downloading_state.world_info.aux_size as usize) {
-		return Err(anyhow::anyhow!("Received data length is smaller than expected length, received length: {}",
-			received_data.len()));
-	}
-	
-	let world_data = received_data.slice(..downloading_state.world_info.world_size as usize);
-	let aux_data = received_data.slice(aux_data_offset as usize..(aux_data_offset + downloading_state.world_info.aux_size) as usize);
+	let prepared_world = match &config.world_cache {
+		Some(world_cache) => world_cache.get_or_prepare(&mut downloading_state, config).await?,
+		None => Arc::new(prepare_world(&mut downloading_state, config).await?),
+	};
 	
-	let (world_description, chunks) =
-		tokio::task::spawn_blocking(move || dedup::deconstruct_world(&world_data, &aux_data)).await?
-			.context("Deconstruction failed")?;
+	let chunks = &prepared_world.chunks;
+	let delta_references = &prepared_world.delta_references;
+	let chunk_list = &prepared_world.chunk_list;
 	
-	info!("Deconstructing world took {}ms", start_time.elapsed().as_millis());
 	info!("Transferring world data");
 	
 	let original_world_size = downloading_state.world_info.world_size as u64;
 	let mut total_transferred = 0;
 	let start_time = Instant::now();
 	
-	let world_ready_message = protocol::encode_message_async(WorldReadyMessage {
-		world: world_description,
-		old_info: downloading_state.world_info.clone(),
-		new_info: downloading_state.new_world_info.clone(),
-	}).await?;
+	let world_ready_message = prepared_world.world_ready_message.clone();
 	
 	total_transferred += world_ready_message.len() as u64;
 	info!("Sending world description, size: {}B", utils::abbreviate_number(world_ready_message.len() as u64));
@@ -395,24 +968,69 @@ async fn transfer_world_data(
 	
 	let mut buf = BytesMut::new();
 	
-	while let Ok(request_data) = protocol::read_message(&mut recv_stream, &mut buf).await {
+	let mut delta_chunks_size = 0;
+	let mut deltas_size = 0;
+	
+	// Each batch is encoded and sent on its own stream, so that batches don't hold each other up
+	let mut batch_sends = JoinSet::new();
+	
+	loop {
+		let request_data = match protocol::read_message(&mut recv_stream, &mut buf).await {
+			Ok(request_data) => request_data,
+			// The client finishes the stream once it has every chunk, resets and closed connections are left to the caller
+			Err(err) if err.is_end_of_stream() => break,
+			Err(err) => return Err(err.into()),
+		};
+		
 		let request: RequestChunksMessage = protocol::decode_message_async(request_data).await?;
 		
+		let requested_chunks = resolve_chunk_indices(request.requested_chunks, &request.requested_chunk_indices, chunk_list)?;
+		let requested_deltas = resolve_chunk_indices(request.requested_deltas, &request.requested_delta_indices, chunk_list)?;
+		
+		let delta_sources = requested_deltas.iter()
+			.map(|key| {
+				let reference = delta_references.get(key).ok_or_else(|| TransferError::Protocol(
+					format!("Client requested a delta for {:?}, which doesn't have a reference", key)))?;
+				
+				Ok((reference.data.clone(), requested_chunk(chunks, key)?.clone()))
+			})
+			.collect::<Result<Vec<_>, TransferError>>()?;
+		
+		delta_chunks_size += delta_sources.iter().map(|(_, chunk)| chunk.len() as u64).sum::<u64>();
+		
+		let deltas = tokio::task::spawn_blocking(move || {
+			delta_sources.iter()
+				.map(|(reference, chunk)| delta::encode_delta(reference, chunk))
+				.collect::<anyhow::Result<Vec<_>>>()
+		}).await??;
+		
+		deltas_size += deltas.iter().map(|delta| delta.len() as u64).sum::<u64>();
+		
 		let response = SendChunksMessage {
-			chunks: request.requested_chunks.iter()
-				.map(|&key| chunks.get(&key).expect("Client requested chunk that we don't have").clone())
-				.collect()
+			chunks: requested_chunks.iter()
+				.map(|key| requested_chunk(chunks, key).cloned())
+				.collect::<Result<_, _>>()?,
+			deltas,
 		};
 		
-		let response_data = protocol::encode_message_async(response).await?;
-		total_transferred += response_data.len() as u64;
+		if let Some(popular_chunks) = &popular_chunks {
+			popular_chunks.record_requests(requested_chunks.iter().copied().zip(response.chunks.iter()));
+		}
+		
+		let header = ChunkBatchHeader {
+			peer_id: peer_id.into_inner() as u32,
+			batch_id: request.batch_id,
+		};
 		
-		info!("Sending batch of {} chunks, size: {}B",
-			request.requested_chunks.len(),
-			utils::abbreviate_number(response_data.len() as u64)
-		);
+		batch_sends.spawn(send_chunk_batch(batch_connections.pick(request.batch_id), header, response));
 		
-		protocol::write_message(&mut send_stream, response_data).await?;
+		while let Some(result) = batch_sends.try_join_next() {
+			total_transferred += result??;
+		}
+	}
+	
+	while let Some(result) = batch_sends.join_next().await {
+		total_transferred += result??;
 	}
 	
 	let elapsed = start_time.elapsed();
@@ -425,5 +1043,956 @@ async fn transfer_world_data(
 		utils::abbreviate_number((total_transferred as f64 / elapsed.as_millis() as f64 * 1000.0) as u64),
 	);
 	
+	info!("MTU discovery settled on {}B for the connection to the client", batch_connections.connection.stats().path.current_mtu);
+	
+	if delta_chunks_size > 0 {
+		info!("Sent {}B of chunks as {}B of deltas against similar chunks",
+			utils::abbreviate_number(delta_chunks_size), utils::abbreviate_number(deltas_size));
+	}
+	
+	if let Some(stats_file) = config.stats_file.clone() {
+		let transfer_stats = TransferStats {
+			timestamp: SystemTime::now(),
+			client_address: batch_connections.connection.remote_address(),
+			original_world_size,
+			total_transferred,
+			duration: elapsed,
+			instance_name: config.instance_name.clone(),
+		};
+		
+		tokio::task::spawn_blocking(move || stats::append_transfer_stats(&stats_file, &transfer_stats)).await?
+			.context("Writing transfer stats")?;
+	}
+	
 	Ok(())
-}
\ No newline at end of file
+}
+
+/// A deconstructed world, along with everything needed to send it to clients.
+pub struct PreparedWorld {
+	world_info: FactorioWorldMetadata,
+	prepared_time: Instant,
+	world_ready_message: Bytes,
+	chunks: LinkedHashMap<ChunkKey, Bytes>,
+	delta_references: HashMap<ChunkKey, DeltaReference>,
+	/// Every file's content_chunks one after the other, which clients can request chunks by the index in.
+	chunk_list: Vec<ChunkKey>,
+}
+
+async fn prepare_world(downloading_state: &mut DownloadingWorldState, config: &ServerProxyConfig) -> anyhow::Result<PreparedWorld> {
+	let start_time = Instant::now();
+	
+	let (world_data, aux_data) = assemble_world_data(downloading_state)?;
+	
+	let delta_index = config.delta_index.clone();
+	let chunk_cache = config.chunk_cache.clone();
+	let chunking_policy = config.chunking_policy.clone();
+	
+	let (world_description, chunks, delta_references) = tokio::task::spawn_blocking(move || -> anyhow::Result<_> {
+		let (world_description, mut chunks) = dedup::deconstruct_world(&world_data, &aux_data, &chunking_policy)?;
+		
+		if let Some(chunk_cache) = chunk_cache {
+			let shared_count = chunk_cache.share_chunks(chunks.iter_mut());
+			info!("{} of the world's {} chunks were already in the chunk cache", shared_count, chunks.len());
+		}
+		
+		let delta_references = delta_index.map(|delta_index| delta_index.add_world(&chunks)).unwrap_or_default();
+		
+		Ok((world_description, chunks, delta_references))
+	}).await?.context("Deconstruction failed")?;
+	
+	info!("Deconstructing world took {}ms", start_time.elapsed().as_millis());
+	
+	let chunk_list = world_description.files.iter()
+		.flat_map(|file| file.content_chunks.iter())
+		.copied()
+		.collect();
+	
+	let world_ready_message = protocol::encode_message_async(WorldReadyMessage {
+		world: world_description,
+		old_info: downloading_state.world_info.clone(),
+		new_info: downloading_state.new_world_info.clone(),
+		transfer_block_size: downloading_state.transfer_block_size,
+		hash_algorithm: CONTENT_HASH,
+		delta_references: delta_references.iter().map(|(&key, reference)| (key, reference.key)).collect(),
+		accepts_chunk_indices: true,
+	}).await?;
+	
+	Ok(PreparedWorld {
+		world_info: downloading_state.world_info.clone(),
+		prepared_time: Instant::now(),
+		world_ready_message,
+		chunks,
+		delta_references,
+		chunk_list,
+	})
+}
+
+/// Keeps the most recently prepared world for a while, so that when lots of clients join the same world at once,
+///  like after a server restart, it only has to be deconstructed once.
+pub struct WorldCache {
+	ttl: Duration,
+	world: tokio::sync::Mutex<Option<Arc<PreparedWorld>>>,
+}
+
+impl WorldCache {
+	pub fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			world: tokio::sync::Mutex::new(None),
+		}
+	}
+	
+	async fn get_or_prepare(&self, downloading_state: &mut DownloadingWorldState, config: &ServerProxyConfig) -> anyhow::Result<Arc<PreparedWorld>> {
+		// Held while preparing, so that clients that finish downloading the same world at the same time wait for
+		//  the first one instead of all deconstructing it
+		let mut world = self.world.lock().await;
+		
+		if let Some(world) = world.as_ref() {
+			if world.world_info == downloading_state.world_info && world.prepared_time.elapsed() < self.ttl {
+				info!("Reusing the world deconstructed {}s ago", world.prepared_time.elapsed().as_secs());
+				
+				return Ok(world.clone());
+			}
+		}
+		
+		// A different world replaces the cached one, since the factorio server won't be sending the old one again
+		let prepared_world = Arc::new(prepare_world(downloading_state, config).await?);
+		*world = Some(prepared_world.clone());
+		
+		Ok(prepared_world)
+	}
+}
+
+fn requested_chunk<'a>(chunks: &'a LinkedHashMap<ChunkKey, Bytes>, key: &ChunkKey) -> Result<&'a Bytes, TransferError> {
+	chunks.get(key).ok_or_else(|| TransferError::Protocol(format!("Client requested chunk {:?}, which isn't in the world", key)))
+}
+
+/// Appends the chunks a client requested by index to the ones it requested by key.
+fn resolve_chunk_indices(mut keys: Vec<ChunkKey>, indices: &[u32], chunk_list: &[ChunkKey]) -> Result<Vec<ChunkKey>, TransferError> {
+	for &index in indices {
+		let key = chunk_list.get(index as usize).ok_or_else(|| TransferError::Protocol(
+			format!("Client requested chunk index {}, but the world only has {} chunks", index, chunk_list.len())))?;
+		
+		keys.push(*key);
+	}
+	
+	Ok(keys)
+}
+
+/// Joins the downloaded blocks back together, returning the world data and the aux data with their padding removed.
+pub(super) fn assemble_world_data(downloading_state: &mut DownloadingWorldState) -> anyhow::Result<(Bytes, Bytes)> {
+	downloading_state.received_blocks.sort_by_key(|block| block.block_id);
+	
+	let mut received_data = BytesMut::new();
+	
+	for block in downloading_state.received_blocks.drain(..) {
+		received_data.extend_from_slice(&block.data);
+	}
+	
+	let received_data = received_data.freeze();
+	
+	let world_size = downloading_state.world_info.world_size as usize;
+	let aux_size = downloading_state.world_info.aux_size as usize;
+	let aux_data_offset = downloading_state.world_block_count as usize * downloading_state.transfer_block_size as usize;
+	
+	if received_data.len() < aux_data_offset + aux_size {
+		return Err(anyhow::anyhow!("Received data length is smaller than expected length, received length: {}",
+			received_data.len()));
+	}
+	
+	let world_data = received_data.slice(..world_size);
+	let aux_data = received_data.slice(aux_data_offset..aux_data_offset + aux_size);
+	
+	Ok((world_data, aux_data))
+}
+
+/// Sends a batch of chunks on a new stream, returning the size of the encoded batch.
+async fn send_chunk_batch(connection: Arc<quinn::Connection>, header: ChunkBatchHeader, response: SendChunksMessage) -> Result<u64, TransferError> {
+	let chunk_count = response.chunks.len();
+	let response_data = protocol::encode_message_async(response).await?;
+	let response_size = response_data.len() as u64;
+	
+	info!("Sending batch {} of {} chunks, size: {}B",
+		header.batch_id,
+		chunk_count,
+		utils::abbreviate_number(response_size)
+	);
+	
+	let mut send_stream = connection.open_uni().await?;
+	
+	send_stream.write_u8(UniStreamType::ChunkBatch.into()).await?;
+	header.write(&mut send_stream).await?;
+	protocol::write_message(&mut send_stream, response_data).await?;
+	send_stream.finish().map_err(std::io::Error::from)?;
+	
+	Ok(response_size)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::factorio_protocol::{HeartbeatFlags, FACTORIO_CRC};
+	use crate::quic::{self, CongestionController, MtuConfig};
+	use bytes::BufMut;
+	
+	fn heartbeat_packet(flags: HeartbeatFlags, payload: &[u8]) -> Bytes {
+		let mut buf = BytesMut::new();
+		
+		FactorioPacketHeader::new_unfragmented(PacketType::ServerToClientHeartbeat).encode(&mut buf);
+		buf.put_u8(flags.bits());
+		buf.put_u32_le(0); // Seq number
+		buf.put_slice(payload);
+		
+		buf.freeze()
+	}
+	
+	fn map_ready_packet(world_info: &FactorioWorldMetadata) -> Bytes {
+		let mut payload = BytesMut::new();
+		
+		payload.put_u8(1); // Action count
+		payload.put_u8(ServerToClientHeartbeatPacket::MAP_READY_FOR_DOWNLOAD_ACTION_ID);
+		world_info.encode(&mut payload);
+		
+		heartbeat_packet(HeartbeatFlags::HasSynchronizerActions, &payload)
+	}
+	
+	#[test]
+	fn gameplay_packets_pass_through_during_download() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		assert!(state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets).is_none());
+		assert!(!state.is_waiting_for_world());
+		
+		let block_count = 100_000u32.div_ceil(503) + 1_000u32.div_ceil(503);
+		let gameplay_packet = heartbeat_packet(HeartbeatFlags::None, b"gameplay");
+		
+		for block_id in 0..block_count {
+			out_packets.clear();
+			
+			// Every gameplay packet is forwarded by the same call that received it, ahead of any block requests
+			assert!(state.on_packet_from_server(gameplay_packet.clone(), &mut out_packets).is_none());
+			assert_eq!(out_packets.first(), Some(&(gameplay_packet.clone(), PacketDirection::ToClient)));
+			assert!(out_packets[1..].iter().all(|(_, dir)| *dir == PacketDirection::ToServer));
+			
+			let block = TransferBlockPacket {
+				block_id,
+				data: vec![0; 503].into(),
+			};
+			
+			let downloaded_world = state.on_packet_from_server(block.encode_full_packet(), &mut out_packets);
+			assert_eq!(downloaded_world.is_some(), block_id == block_count - 1);
+		}
+		
+		// Once the download is done, gameplay packets are still forwarded
+		out_packets.clear();
+		state.on_packet_from_server(gameplay_packet.clone(), &mut out_packets);
+		assert_eq!(out_packets, vec![(gameplay_packet, PacketDirection::ToClient)]);
+	}
+	
+	#[test]
+	fn small_worlds_are_forwarded_untouched() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 10_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let config = ServerProxyConfig {
+			min_dedup_size: 50_000,
+			..ServerProxyConfig::default()
+		};
+		
+		let mut state = ServerProxyState::new(Arc::new(config));
+		let mut out_packets = Vec::new();
+		
+		let packet = map_ready_packet(&world_info);
+		assert!(state.on_packet_from_server(packet.clone(), &mut out_packets).is_none());
+		assert!(state.is_done());
+		
+		// The map ready packet keeps its original world info, and no blocks are requested
+		assert_eq!(out_packets, vec![(packet, PacketDirection::ToClient)]);
+	}
+	
+	#[test]
+	fn world_info_is_left_alone_without_rewriting() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let config = ServerProxyConfig {
+			rewrite_world_info: false,
+			..ServerProxyConfig::default()
+		};
+		
+		let mut state = ServerProxyState::new(Arc::new(config));
+		let mut out_packets = Vec::new();
+		
+		let packet = map_ready_packet(&world_info);
+		assert!(state.on_packet_from_server(packet.clone(), &mut out_packets).is_none());
+		
+		// The world is still downloaded, but the factorio client sees the original world info
+		assert_eq!(out_packets[0], (packet, PacketDirection::ToClient));
+		assert!(out_packets[1..].iter().all(|(_, direction)| *direction == PacketDirection::ToServer));
+		assert!(!state.is_done());
+	}
+	
+	#[test]
+	fn block_request_retransmits_follow_gameplay_packets() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		out_packets.clear();
+		
+		// Stall the download so that the next packet triggers retransmits of the inflight requests
+		let ServerProxyPhase::DownloadingWorld(downloading_state) = &mut state.phase else { unreachable!() };
+		downloading_state.last_block_time -= Duration::from_secs(1);
+		
+		let gameplay_packet = heartbeat_packet(HeartbeatFlags::None, b"gameplay");
+		state.on_packet_from_server(gameplay_packet.clone(), &mut out_packets);
+		
+		assert_eq!(out_packets.len(), 1 + ServerProxyState::INFLIGHT_BLOCK_REQUEST_LIMIT);
+		assert_eq!(out_packets[0], (gameplay_packet, PacketDirection::ToClient));
+		assert!(out_packets[1..].iter().all(|(_, dir)| *dir == PacketDirection::ToServer));
+	}
+	
+	#[test]
+	fn downloads_are_abandoned_when_a_block_never_arrives() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		for _ in 0..=ServerProxyState::MAX_BLOCK_RETRANSMITS {
+			assert!(!state.is_abandoned());
+			
+			let ServerProxyPhase::DownloadingWorld(downloading_state) = &mut state.phase else { unreachable!() };
+			downloading_state.last_block_time -= Duration::from_secs(1);
+			
+			out_packets.clear();
+			state.on_packet_from_server(heartbeat_packet(HeartbeatFlags::None, b"gameplay"), &mut out_packets);
+		}
+		
+		assert!(state.is_abandoned());
+		assert_eq!(out_packets.len(), 1);
+	}
+	
+	/// Deterministically drops a fraction of the packets sent over it.
+	struct LossyLink {
+		random_state: u64,
+		loss_rate: f64,
+	}
+	
+	impl LossyLink {
+		fn drops_packet(&mut self) -> bool {
+			// xorshift64, so that every run drops the same packets
+			self.random_state ^= self.random_state << 13;
+			self.random_state ^= self.random_state >> 7;
+			self.random_state ^= self.random_state << 17;
+			
+			(self.random_state as f64 / u64::MAX as f64) < self.loss_rate
+		}
+	}
+	
+	#[test]
+	fn downloads_complete_over_a_lossy_link() {
+		let block_size = factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE as usize;
+		let world = (0..50_000u32).map(|index| (index * 7 % 251) as u8).collect::<Vec<_>>();
+		let aux = b"auxiliary data";
+		
+		let world_info = FactorioWorldMetadata {
+			world_size: world.len() as u32,
+			no_idea1: 0,
+			aux_size: aux.len() as u32,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut layout = world.clone();
+		layout.resize(world.len().next_multiple_of(block_size), 0);
+		layout.extend_from_slice(aux);
+		layout.resize(layout.len().next_multiple_of(block_size), 0);
+		
+		let mut requests_link = LossyLink { random_state: 0x9E3779B97F4A7C15, loss_rate: 0.2 };
+		let mut responses_link = LossyLink { random_state: 0x2545F4914F6CDD1D, loss_rate: 0.2 };
+		let mut request_counts = HashMap::new();
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		let mut downloaded_world = None;
+		
+		while downloaded_world.is_none() {
+			assert!(!state.is_abandoned(), "Download was abandoned");
+			
+			// The mock factorio server answers every request that reaches it, but some answers are lost on the way back
+			let responses = out_packets.drain(..)
+				.filter(|(_, direction)| *direction == PacketDirection::ToServer)
+				.map(|(packet_data, _)| {
+					let (_, msg_data) = FactorioPacketHeader::decode(packet_data).unwrap();
+					TransferBlockRequestPacket::decode(msg_data).unwrap().block_id
+				})
+				.filter(|_| !requests_link.drops_packet())
+				.inspect(|&block_id| *request_counts.entry(block_id).or_insert(0) += 1)
+				.filter(|_| !responses_link.drops_packet())
+				.map(|block_id| {
+					let block_data = layout.chunks(block_size).nth(block_id as usize).unwrap();
+					TransferBlockPacket { block_id, data: Bytes::copy_from_slice(block_data) }.encode_full_packet()
+				})
+				.collect::<Vec<_>>();
+			
+			if responses.is_empty() {
+				// Every block in flight was lost, so stall until the next packet triggers retransmits
+				let ServerProxyPhase::DownloadingWorld(downloading_state) = &mut state.phase else { unreachable!() };
+				downloading_state.last_block_time -= Duration::from_secs(1);
+				
+				state.on_packet_from_server(heartbeat_packet(HeartbeatFlags::None, b"gameplay"), &mut out_packets);
+			}
+			
+			for packet in responses {
+				downloaded_world = state.on_packet_from_server(packet, &mut out_packets);
+			}
+		}
+		
+		assert!(request_counts.values().any(|&count| count > 1), "No blocks were retransmitted");
+		
+		let (world_data, aux_data) = assemble_world_data(&mut downloaded_world.unwrap()).unwrap();
+		assert_eq!(world_data, world);
+		assert_eq!(aux_data, &aux[..]);
+	}
+	
+	#[test]
+	fn downloads_are_abandoned_after_the_max_download_time() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let config = ServerProxyConfig {
+			max_download_time: Some(Duration::from_secs(60)),
+			..ServerProxyConfig::default()
+		};
+		
+		let mut state = ServerProxyState::new(Arc::new(config));
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		let block = TransferBlockPacket {
+			block_id: 0,
+			data: vec![0; 503].into(),
+		}.encode_full_packet();
+		
+		assert!(state.on_packet_from_server(block.clone(), &mut out_packets).is_none());
+		assert!(!state.is_abandoned());
+		
+		let ServerProxyPhase::DownloadingWorld(downloading_state) = &mut state.phase else { unreachable!() };
+		downloading_state.download_start_time -= Duration::from_secs(61);
+		
+		out_packets.clear();
+		assert!(state.on_packet_from_server(block.clone(), &mut out_packets).is_none());
+		assert!(state.is_abandoned());
+		assert_eq!(out_packets, [(block, PacketDirection::ToClient)]);
+	}
+	
+	#[test]
+	fn abandoned_downloads_are_forwarded_without_rewriting() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let config = ServerProxyConfig {
+			max_download_time: Some(Duration::from_secs(60)),
+			rewrite_world_info: false,
+			..ServerProxyConfig::default()
+		};
+		
+		let mut state = ServerProxyState::new(Arc::new(config));
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		let ServerProxyPhase::DownloadingWorld(downloading_state) = &mut state.phase else { unreachable!() };
+		downloading_state.download_start_time -= Duration::from_secs(61);
+		
+		// The factorio client still expects the original world, so it can download it itself
+		state.on_packet_from_server(heartbeat_packet(HeartbeatFlags::None, b"gameplay"), &mut out_packets);
+		assert!(state.is_done());
+	}
+	
+	#[test]
+	fn peers_are_closed_when_the_server_goes_quiet_after_a_connection_reply() {
+		let mut reply_packet = BytesMut::new();
+		FactorioPacketHeader::new_unfragmented(PacketType::ConnectionAcceptOrDeny).encode(&mut reply_packet);
+		reply_packet.put_slice(b"reply");
+		let reply_packet = reply_packet.freeze();
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(reply_packet.clone(), &mut out_packets);
+		assert!(state.rejection_deadline().is_some());
+		assert_eq!(out_packets, [(reply_packet.clone(), PacketDirection::ToClient)]);
+		
+		// Accepted clients get heartbeats right away
+		state.on_packet_from_server(heartbeat_packet(HeartbeatFlags::None, b"gameplay"), &mut out_packets);
+		assert!(state.rejection_deadline().is_none());
+		
+		// Clients that try again, like after being asked for a password, aren't closed either
+		state.on_packet_from_server(reply_packet, &mut out_packets);
+		state.on_packet_from_client();
+		assert!(state.rejection_deadline().is_none());
+	}
+	
+	#[test]
+	fn empty_worlds_are_forwarded_untouched() {
+		for aux_size in [0, 1_000] {
+			let world_info = FactorioWorldMetadata {
+				world_size: 0,
+				no_idea1: 0,
+				aux_size,
+				no_idea2: 0,
+				world_crc: 0x12345678,
+			};
+			
+			let mut state = ServerProxyState::new(Arc::default());
+			let mut out_packets = Vec::new();
+			
+			let packet = map_ready_packet(&world_info);
+			assert!(state.on_packet_from_server(packet.clone(), &mut out_packets).is_none());
+			assert!(state.is_done());
+			assert_eq!(out_packets, vec![(packet, PacketDirection::ToClient)]);
+		}
+	}
+	
+	#[test]
+	fn worlds_without_aux_data_are_assembled() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 10_000,
+			no_idea1: 0,
+			aux_size: 0,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		// Only the world's own blocks are requested, and the last of them finishes the download
+		let block_count = 10_000u32.div_ceil(503);
+		let mut downloaded_world = None;
+		
+		for block_id in 0..block_count {
+			let block = TransferBlockPacket {
+				block_id,
+				data: vec![block_id as u8; 503].into(),
+			};
+			
+			downloaded_world = state.on_packet_from_server(block.encode_full_packet(), &mut out_packets);
+			assert_eq!(downloaded_world.is_some(), block_id == block_count - 1);
+		}
+		
+		let (world_data, aux_data) = assemble_world_data(&mut downloaded_world.unwrap()).unwrap();
+		
+		assert_eq!(world_data.len(), 10_000);
+		assert_eq!(world_data[9_999], (block_count - 1) as u8);
+		assert!(aux_data.is_empty());
+	}
+	
+	#[test]
+	fn aux_data_starts_at_the_block_after_a_partial_world_block() {
+		let block_size = factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE as usize;
+		
+		for world_size in [1, block_size - 1, block_size, block_size + 1, 2 * block_size - 1, 2 * block_size, 2 * block_size + 1] {
+			for aux_size in [0, 1, block_size - 1, block_size, block_size + 1] {
+				let world_info = FactorioWorldMetadata {
+					world_size: world_size as u32,
+					no_idea1: 0,
+					aux_size: aux_size as u32,
+					no_idea2: 0,
+					world_crc: 0x12345678,
+				};
+				
+				// Factorio pads the last world block to a full block, so the aux data starts on a block boundary
+				let world = (0..world_size).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+				let aux = (0..aux_size).map(|index| (index % 241) as u8 | 0x80).collect::<Vec<_>>();
+				
+				let mut layout = world.clone();
+				layout.resize(world_size.next_multiple_of(block_size), 0xEE);
+				layout.extend_from_slice(&aux);
+				layout.resize(layout.len().next_multiple_of(block_size), 0xEE);
+				
+				let mut state = ServerProxyState::new(Arc::default());
+				let mut out_packets = Vec::new();
+				
+				state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+				
+				let mut downloaded_world = None;
+				
+				for (block_id, block_data) in layout.chunks(block_size).enumerate() {
+					assert!(downloaded_world.is_none(), "world {} aux {} finished early", world_size, aux_size);
+					
+					let block = TransferBlockPacket {
+						block_id: block_id as u32,
+						data: Bytes::copy_from_slice(block_data),
+					};
+					
+					downloaded_world = state.on_packet_from_server(block.encode_full_packet(), &mut out_packets);
+				}
+				
+				let (world_data, aux_data) = assemble_world_data(&mut downloaded_world.unwrap()).unwrap();
+				
+				assert_eq!(world_data, world, "world {} aux {}", world_size, aux_size);
+				assert_eq!(aux_data, aux, "world {} aux {}", world_size, aux_size);
+			}
+		}
+	}
+	
+	#[test]
+	fn worlds_failing_upstream_crc_verification_are_downloaded_again() {
+		let block_size = factorio_protocol::DEFAULT_TRANSFER_BLOCK_SIZE as usize;
+		let world = (0..5_000u32).map(|index| (index % 251) as u8).collect::<Vec<_>>();
+		let aux = b"auxiliary data";
+		
+		let mut crc_hasher = FACTORIO_CRC.digest();
+		crc_hasher.update(&world);
+		crc_hasher.update(aux);
+		
+		let world_info = FactorioWorldMetadata {
+			world_size: world.len() as u32,
+			no_idea1: 0,
+			aux_size: aux.len() as u32,
+			no_idea2: 0,
+			world_crc: crc_hasher.finalize(),
+		};
+		
+		let mut layout = world.clone();
+		layout.resize(world.len().next_multiple_of(block_size), 0);
+		layout.extend_from_slice(aux);
+		layout.resize(layout.len().next_multiple_of(block_size), 0);
+		
+		let block_packets = |corrupt: bool| layout.chunks(block_size).enumerate()
+			.map(|(block_id, block_data)| {
+				let mut data = block_data.to_vec();
+				
+				if corrupt && block_id == 3 {
+					data[0] ^= 1;
+				}
+				
+				TransferBlockPacket { block_id: block_id as u32, data: data.into() }.encode_full_packet()
+			})
+			.collect::<Vec<_>>();
+		
+		let config = ServerProxyConfig {
+			verify_upstream_crc: true,
+			..ServerProxyConfig::default()
+		};
+		let config = Arc::new(config);
+		
+		// A corrupted download is thrown away and every block is requested again
+		let mut state = ServerProxyState::new(config.clone());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		for packet in block_packets(true) {
+			out_packets.clear();
+			assert!(state.on_packet_from_server(packet, &mut out_packets).is_none());
+		}
+		
+		let requested_blocks = out_packets.drain(..)
+			.map(|(packet_data, _)| {
+				let (_, msg_data) = FactorioPacketHeader::decode(packet_data).unwrap();
+				TransferBlockRequestPacket::decode(msg_data).unwrap().block_id
+			})
+			.collect::<Vec<_>>();
+		
+		assert_eq!(requested_blocks, (0..(layout.len() / block_size) as u32).collect::<Vec<_>>());
+		
+		let mut downloaded_world = None;
+		
+		for packet in block_packets(false) {
+			assert!(downloaded_world.is_none());
+			downloaded_world = state.on_packet_from_server(packet, &mut out_packets);
+		}
+		
+		let (world_data, aux_data) = assemble_world_data(&mut downloaded_world.unwrap()).unwrap();
+		assert_eq!(world_data, world);
+		assert_eq!(aux_data, &aux[..]);
+		
+		// Worlds that keep failing are given up on
+		let mut state = ServerProxyState::new(config);
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		for _ in 0..=ServerProxyState::MAX_FAILED_DOWNLOADS {
+			assert!(!state.is_abandoned());
+			
+			for packet in block_packets(true) {
+				assert!(state.on_packet_from_server(packet, &mut out_packets).is_none());
+			}
+		}
+		
+		assert!(state.is_abandoned());
+	}
+	
+	#[test]
+	fn fragmented_blocks_are_reassembled() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 5_000,
+			no_idea1: 0,
+			aux_size: 0,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		let block_count = 5_000u32.div_ceil(503);
+		let mut fragment_packets = Vec::new();
+		
+		for block_id in 0..block_count {
+			let mut msg_data = BytesMut::new();
+			TransferBlockPacket {
+				block_id,
+				data: vec![block_id as u8; 503].into(),
+			}.encode(&mut msg_data);
+			
+			let fragments = msg_data.chunks(200).collect::<Vec<_>>();
+			
+			for (fragment_id, fragment) in fragments.iter().enumerate() {
+				let mut buf = BytesMut::new();
+				
+				FactorioPacketHeader {
+					packet_type: PacketType::TransferBlock,
+					is_fragmented: true,
+					is_last_fragment: fragment_id == fragments.len() - 1,
+				}.encode(&mut buf);
+				buf.put_u16_le(1000 + block_id as u16); // Message id
+				buf.put_u8(fragment_id as u8);
+				buf.put_slice(fragment);
+				
+				fragment_packets.push(buf.freeze());
+			}
+		}
+		
+		// Each block's fragments arrive last fragment first, and interleaved with the next block's
+		let mut reordered_packets = Vec::new();
+		
+		for pair in fragment_packets.chunks(6) {
+			reordered_packets.extend(pair.iter().rev().cloned());
+		}
+		
+		let mut downloaded_world = None;
+		
+		for (index, packet) in reordered_packets.into_iter().enumerate() {
+			assert!(downloaded_world.is_none(), "finished early at fragment {}", index);
+			downloaded_world = state.on_packet_from_server(packet, &mut out_packets);
+		}
+		
+		let (world_data, _) = assemble_world_data(&mut downloaded_world.unwrap()).unwrap();
+		
+		assert_eq!(world_data.len(), 5_000);
+		
+		for (block_id, block_data) in world_data.chunks(503).enumerate() {
+			assert!(block_data.iter().all(|&byte| byte == block_id as u8), "block {} is corrupted", block_id);
+		}
+	}
+	
+	#[test]
+	fn unsolicited_blocks_are_accepted_once() {
+		let world_info = FactorioWorldMetadata {
+			world_size: 100_000,
+			no_idea1: 0,
+			aux_size: 1_000,
+			no_idea2: 0,
+			world_crc: 0x12345678,
+		};
+		
+		let mut state = ServerProxyState::new(Arc::default());
+		let mut out_packets = Vec::new();
+		
+		state.on_packet_from_server(map_ready_packet(&world_info), &mut out_packets);
+		
+		let block_count = 100_000u32.div_ceil(503) + 1_000u32.div_ceil(503);
+		let mut requested_blocks = BTreeSet::new();
+		
+		let block_packet = |block_id| TransferBlockPacket {
+			block_id,
+			data: vec![0; 503].into(),
+		}.encode_full_packet();
+		
+		// Send every block back to front, so that most of them arrive before they're requested, along with
+		//  duplicates and a block past the end of the world
+		for block_id in (0..block_count).rev() {
+			for (packet_data, _) in out_packets.drain(..).filter(|(_, dir)| *dir == PacketDirection::ToServer) {
+				let (_, msg_data) = FactorioPacketHeader::decode(packet_data).unwrap();
+				requested_blocks.insert(TransferBlockRequestPacket::decode(msg_data).unwrap().block_id);
+			}
+			
+			assert!(state.on_packet_from_server(block_packet(block_count), &mut out_packets).is_none());
+			
+			if block_id + 1 < block_count {
+				assert!(state.on_packet_from_server(block_packet(block_id + 1), &mut out_packets).is_none());
+			}
+			
+			let downloaded_world = state.on_packet_from_server(block_packet(block_id), &mut out_packets);
+			
+			assert_eq!(downloaded_world.is_some(), block_id == 0);
+			
+			if let Some(downloaded_world) = downloaded_world {
+				assert_eq!(downloaded_world.received_blocks.len(), block_count as usize);
+			}
+		}
+		
+		// Only the first inflight window was ever requested, since every later block had already arrived
+		assert_eq!(requested_blocks, (0..ServerProxyState::INFLIGHT_BLOCK_REQUEST_LIMIT as u32).collect());
+	}
+	
+	#[test]
+	fn chunks_requested_by_index_are_much_smaller_requests() {
+		let chunk_list = (0..2048u32).map(|index| ChunkKey(blake3::hash(&index.to_le_bytes()))).collect::<Vec<_>>();
+		let indices = (0..512).map(|index| index * 4).collect::<Vec<u32>>();
+		let keys = indices.iter().map(|&index| chunk_list[index as usize]).collect::<Vec<_>>();
+		
+		let by_key = protocol::encode_message(&RequestChunksMessage {
+			batch_id: 0,
+			requested_chunks: keys.clone(),
+			requested_deltas: Vec::new(),
+			requested_chunk_indices: Vec::new(),
+			requested_delta_indices: Vec::new(),
+		}).unwrap();
+		
+		let by_index = protocol::encode_message(&RequestChunksMessage {
+			batch_id: 0,
+			requested_chunks: Vec::new(),
+			requested_deltas: Vec::new(),
+			requested_chunk_indices: indices.clone(),
+			requested_delta_indices: Vec::new(),
+		}).unwrap();
+		
+		assert!(by_index.len() * 10 < by_key.len(), "{}B by index, {}B by key", by_index.len(), by_key.len());
+		
+		let request: RequestChunksMessage = protocol::decode_message(&by_index).unwrap();
+		assert_eq!(resolve_chunk_indices(request.requested_chunks, &request.requested_chunk_indices, &chunk_list).unwrap(), keys);
+		
+		// Keys come before indices when a request has both
+		let mixed = resolve_chunk_indices(vec![chunk_list[1]], &[2, 3], &chunk_list).unwrap();
+		assert_eq!(mixed, chunk_list[1..4]);
+		
+		assert!(matches!(resolve_chunk_indices(Vec::new(), &[2048], &chunk_list), Err(TransferError::Protocol(_))));
+	}
+	
+	#[tokio::test]
+	async fn streams_for_active_peer_ids_are_rejected() {
+		let factorio_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let factorio_addr = UpstreamAddress::resolve(&factorio_socket.local_addr().unwrap().to_string()).await.unwrap();
+		
+		let server_config = quic::make_server_config(16, CongestionController::Cubic, MtuConfig::default());
+		let server_endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+		
+		let mut client_endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+		client_endpoint.set_default_client_config(quic::make_client_config(CongestionController::Cubic, MtuConfig::default()));
+		
+		let connecting = client_endpoint.connect(server_endpoint.local_addr().unwrap(), quic::BUNDLED_CERT_SERVER_NAME).unwrap();
+		let (client_connection, server_connection) = tokio::join!(connecting, async { server_endpoint.accept().await.unwrap().await });
+		let client_connection = client_connection.unwrap();
+		let server_connection = Arc::new(server_connection.unwrap());
+		
+		tokio::spawn(run_server_proxy(server_connection, Arc::new(factorio_addr), None, Arc::default(), Arc::default()));
+		
+		let (mut first_send, _first_recv) = client_connection.open_bi().await.unwrap();
+		first_send.write_u32_le(7).await.unwrap();
+		
+		let (mut second_send, mut second_recv) = client_connection.open_bi().await.unwrap();
+		second_send.write_u32_le(7).await.unwrap();
+		
+		let result = tokio::time::timeout(Duration::from_secs(5), second_recv.read(&mut [0; 1])).await.unwrap();
+		assert!(matches!(result, Err(quinn::ReadError::Reset(code)) if code == DUPLICATE_PEER_ID_ERROR_CODE));
+		
+		// The peer that had the id first still gets its packets through
+		let mut buf = BytesMut::new();
+		Datagram::new(VarInt::from_u32(7), Bytes::from_static(b"packet")).encode(&mut buf, None);
+		client_connection.send_datagram(buf.freeze()).unwrap();
+		
+		let mut packet = [0; 16];
+		let (len, _) = tokio::time::timeout(Duration::from_secs(5), factorio_socket.recv_from(&mut packet)).await.unwrap().unwrap();
+		assert_eq!(&packet[..len], b"packet");
+	}	
+	#[tokio::test]
+	async fn joining_a_group_doesnt_keep_a_connection_open() {
+		let factorio_addr = UpstreamAddress::resolve("127.0.0.1:34197").await.unwrap();
+		
+		let server_config = quic::make_server_config(16, CongestionController::Cubic, MtuConfig::default());
+		let server_endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+		
+		let mut client_endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+		client_endpoint.set_default_client_config(quic::make_client_config(CongestionController::Cubic, MtuConfig::default()));
+		
+		let connecting = client_endpoint.connect(server_endpoint.local_addr().unwrap(), quic::BUNDLED_CERT_SERVER_NAME).unwrap();
+		let (client_connection, server_connection) = tokio::join!(connecting, async { server_endpoint.accept().await.unwrap().await });
+		let client_connection = client_connection.unwrap();
+		let server_connection = Arc::new(server_connection.unwrap());
+		
+		let config = ServerProxyConfig {
+			max_idle_connection_time: Some(Duration::from_millis(200)),
+			..ServerProxyConfig::default()
+		};
+		
+		tokio::spawn(run_server_proxy(server_connection, Arc::new(factorio_addr), None, Arc::default(), Arc::new(config)));
+		
+		let mut send_stream = client_connection.open_uni().await.unwrap();
+		send_stream.write_u8(UniStreamType::ConnectionGroup.into()).await.unwrap();
+		send_stream.write_u64_le(1).await.unwrap();
+		send_stream.finish().unwrap();
+		
+		// With nothing else in the group, it's as idle as the connection is
+		let result = tokio::time::timeout(Duration::from_secs(5), client_connection.closed()).await.unwrap();
+		assert!(matches!(result, quinn::ConnectionError::ApplicationClosed(close) if close.reason == b"idle"[..]));
+	}
+}
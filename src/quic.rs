@@ -1,4 +1,8 @@
+use quinn::congestion::{BbrConfig, ControllerFactory, CubicConfig, NewRenoConfig};
+use quinn::MtuDiscoveryConfig;
 use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use rustls::pki_types::pem::PemObject;
@@ -6,12 +10,96 @@ use rustls::pki_types::pem::PemObject;
 pub const QUIC_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 pub const QUIC_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
 
+/// The smallest UDP payload size QUIC can use, which every path has to be able to carry.
+pub const MIN_MTU: u16 = 1200;
+/// The largest UDP payload size allowed over IPv6.
+pub const MAX_MTU: u16 = 65527;
+
+/// The name that the bundled certificate is issued for.
+pub const BUNDLED_CERT_SERVER_NAME: &str = "localhost";
+
 const ROOT_CERT_DATA: &[u8] = include_bytes!("../certs/root-ca.pem");
 
 const END_CERT_DATA: &[u8] = include_bytes!("../certs/cert.pem");
 const END_PRIVATE_KEY_DATA: &[u8] = include_bytes!("../certs/cert.key.pem");
 
-pub fn make_client_config() -> quinn::ClientConfig {
+/// Which congestion control algorithm QUIC connections use.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum CongestionController {
+	Cubic,
+	Bbr,
+	NewReno,
+}
+
+impl CongestionController {
+	fn factory(self) -> Arc<dyn ControllerFactory + Send + Sync> {
+		match self {
+			CongestionController::Cubic => Arc::new(CubicConfig::default()),
+			CongestionController::Bbr => Arc::new(BbrConfig::default()),
+			CongestionController::NewReno => Arc::new(NewRenoConfig::default()),
+		}
+	}
+}
+
+impl FromStr for CongestionController {
+	type Err = anyhow::Error;
+	
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"cubic" => Ok(CongestionController::Cubic),
+			"bbr" => Ok(CongestionController::Bbr),
+			"newreno" => Ok(CongestionController::NewReno),
+			_ => Err(anyhow::anyhow!("Expected 'cubic', 'bbr' or 'newreno'")),
+		}
+	}
+}
+
+impl Display for CongestionController {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			CongestionController::Cubic => "cubic",
+			CongestionController::Bbr => "bbr",
+			CongestionController::NewReno => "newreno",
+		})
+	}
+}
+
+/// How big the packets of QUIC connections are.
+#[derive(Debug, Copy, Clone)]
+pub struct MtuConfig {
+	/// The UDP payload size connections start out with.
+	pub initial_mtu: u16,
+	/// The largest UDP payload size that MTU discovery probes for, or None to stay at the initial size.
+	pub max_mtu: Option<u16>,
+}
+
+impl Default for MtuConfig {
+	fn default() -> Self {
+		Self {
+			initial_mtu: MIN_MTU,
+			// quinn's default, which fits in an ethernet frame over both IPv4 and IPv6
+			max_mtu: Some(1452),
+		}
+	}
+}
+
+impl MtuConfig {
+	fn apply(&self, transport_config: &mut quinn::TransportConfig) {
+		transport_config.initial_mtu(self.initial_mtu);
+		transport_config.mtu_discovery_config(self.max_mtu.map(|max_mtu| {
+			let mut discovery_config = MtuDiscoveryConfig::default();
+			discovery_config.upper_bound(max_mtu);
+			
+			discovery_config
+		}));
+		
+		// Sends runs of packets with one syscall where the platform supports it, quinn falls back to sending them one
+		//  at a time everywhere else
+		transport_config.enable_segmentation_offload(true);
+	}
+}
+
+pub fn make_client_config(congestion: CongestionController, mtu: MtuConfig) -> quinn::ClientConfig {
 	let mut certs = rustls::RootCertStore::empty();
 	certs.add(CertificateDer::from_pem_slice(ROOT_CERT_DATA).unwrap()).unwrap();
 	
@@ -20,13 +108,16 @@ pub fn make_client_config() -> quinn::ClientConfig {
 	let mut transport_config = quinn::TransportConfig::default();
 	transport_config.max_idle_timeout(Some(QUIC_IDLE_TIMEOUT.try_into().unwrap()));
 	transport_config.keep_alive_interval(Some(QUIC_KEEPALIVE_INTERVAL));
+	transport_config.congestion_controller_factory(congestion.factory());
+	mtu.apply(&mut transport_config);
 	
 	client_config.transport_config(Arc::new(transport_config));
 	
 	client_config
 }
 
-pub fn make_server_config() -> quinn::ServerConfig {
+/// max_incoming is how many connections can be waiting to be accepted before new ones are refused.
+pub fn make_server_config(max_incoming: usize, congestion: CongestionController, mtu: MtuConfig) -> quinn::ServerConfig {
 	let cert = CertificateDer::from_pem_slice(END_CERT_DATA).unwrap();
 	let private_key = PrivatePkcs8KeyDer::from_pem_slice(END_PRIVATE_KEY_DATA).unwrap();
 	
@@ -34,8 +125,46 @@ pub fn make_server_config() -> quinn::ServerConfig {
 	
 	let mut transport_config = quinn::TransportConfig::default();
 	transport_config.max_idle_timeout(Some(QUIC_IDLE_TIMEOUT.try_into().unwrap()));
+	transport_config.congestion_controller_factory(congestion.factory());
+	mtu.apply(&mut transport_config);
 	
 	server_config.transport_config(Arc::new(transport_config));
+	server_config.max_incoming(max_incoming);
 	
 	server_config
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[tokio::test]
+	async fn reconnections_can_use_zero_rtt() {
+		let server_config = make_server_config(16, CongestionController::Cubic, MtuConfig::default());
+		let server_endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+		let server_addr = server_endpoint.local_addr().unwrap();
+		
+		tokio::spawn(async move {
+			while let Some(incoming) = server_endpoint.accept().await {
+				let connection = incoming.await.unwrap();
+				tokio::spawn(async move { connection.closed().await });
+			}
+		});
+		
+		let mut client_endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+		client_endpoint.set_default_client_config(make_client_config(CongestionController::Cubic, MtuConfig::default()));
+		
+		// The first connection has no session to resume
+		let connecting = client_endpoint.connect(server_addr, BUNDLED_CERT_SERVER_NAME).unwrap();
+		let connecting = connecting.into_0rtt().err().unwrap();
+		let connection = connecting.await.unwrap();
+		
+		// Session tickets arrive after the handshake
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		connection.close(0u32.into(), b"");
+		
+		let connecting = client_endpoint.connect(server_addr, BUNDLED_CERT_SERVER_NAME).unwrap();
+		let (_connection, zero_rtt_accepted) = connecting.into_0rtt().ok().unwrap();
+		assert!(zero_rtt_accepted.await);
+	}
+}
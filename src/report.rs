@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use rustls::pki_types::ServerName;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use url::{Host, Position, Url};
+
+const REPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reported sizes are rounded down to a multiple of this, so that they can't be matched up with a particular world.
+const SIZE_GRANULARITY: u64 = 64 * 1024;
+
+/// Anonymous statistics about one world transfer. Nothing in here identifies the client, the server or the world.
+#[derive(Debug, PartialEq)]
+pub struct TransferReport {
+	/// The original world size, rounded down to a power of two.
+	pub world_size_bucket: u64,
+	/// Bytes that didn't have to be downloaded, rounded down to SIZE_GRANULARITY.
+	pub bytes_saved: u64,
+	/// How much of the world had to be downloaded, in percent.
+	pub percent_downloaded: u32,
+}
+
+impl TransferReport {
+	pub fn new(original_world_size: u64, bytes_transferred: u64) -> Self {
+		let bytes_saved = original_world_size.saturating_sub(bytes_transferred);
+		
+		Self {
+			world_size_bucket: 1 << original_world_size.max(1).ilog2(),
+			bytes_saved: bytes_saved - bytes_saved % SIZE_GRANULARITY,
+			percent_downloaded: (bytes_transferred * 100 / original_world_size.max(1)) as u32,
+		}
+	}
+	
+	fn to_json(&self) -> String {
+		format!("{{\"world_size_bucket\": {}, \"bytes_saved\": {}, \"percent_downloaded\": {}}}",
+			self.world_size_bucket, self.bytes_saved, self.percent_downloaded)
+	}
+}
+
+/// Sends transfer reports to an HTTPS endpoint that the user opted into.
+pub struct StatsReporter {
+	url: ReportUrl,
+	tls_config: Arc<rustls::ClientConfig>,
+}
+
+impl StatsReporter {
+	pub fn new(url: &str) -> anyhow::Result<Self> {
+		let url = ReportUrl::parse(url)?;
+		
+		// Whichever certificates the system trusts, from wherever the platform keeps them
+		let native_certs = rustls_native_certs::load_native_certs();
+		
+		for err in &native_certs.errors {
+			warn!("Error loading the system's trusted root certificates: {}", err);
+		}
+		
+		let mut roots = rustls::RootCertStore::empty();
+		roots.add_parsable_certificates(native_certs.certs);
+		
+		if roots.is_empty() {
+			return Err(anyhow!("No trusted root certificates found to check the report server's certificate with"));
+		}
+		
+		let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+			.with_safe_default_protocol_versions()?
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+		
+		Ok(Self {
+			url,
+			tls_config: Arc::new(tls_config),
+		})
+	}
+	
+	/// Sends a report in the background, only logging if it fails since reports are best effort.
+	pub fn report(self: &Arc<Self>, transfer_report: TransferReport) {
+		let reporter = self.clone();
+		
+		tokio::task::spawn_blocking(move || {
+			match reporter.post(&transfer_report.to_json()) {
+				Ok(()) => info!("Reported transfer stats to {}", reporter.url.host),
+				Err(err) => warn!("Failed to report transfer stats to {}: {:#}", reporter.url.host, err),
+			}
+		});
+	}
+	
+	fn post(&self, body: &str) -> anyhow::Result<()> {
+		let server_name = ServerName::try_from(self.url.host.clone())?;
+		let connection = rustls::ClientConnection::new(self.tls_config.clone(), server_name)?;
+		
+		let tcp_stream = connect((self.url.host.as_str(), self.url.port)).context("Connecting")?;
+		tcp_stream.set_read_timeout(Some(REPORT_TIMEOUT))?;
+		tcp_stream.set_write_timeout(Some(REPORT_TIMEOUT))?;
+		
+		let mut stream = rustls::StreamOwned::new(connection, tcp_stream);
+		
+		write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+			self.url.path, self.url.authority, body.len(), body)?;
+		stream.flush()?;
+		
+		// Only the status line matters, and some servers close without a TLS close_notify once it's sent
+		let mut response = Vec::new();
+		let mut buf = [0; 1024];
+		
+		while !response.contains(&b'\n') {
+			match stream.read(&mut buf)? {
+				0 => break,
+				len => response.extend_from_slice(&buf[..len]),
+			}
+		}
+		
+		let status_line = String::from_utf8_lossy(&response);
+		let status = status_line.split_whitespace().nth(1).unwrap_or("");
+		
+		if !status.starts_with('2') {
+			return Err(anyhow!("Server responded with {:?}", status_line.lines().next().unwrap_or("")));
+		}
+		
+		Ok(())
+	}
+}
+
+/// Connects to the first of the host's addresses that accepts within the report timeout.
+fn connect(address: impl ToSocketAddrs) -> std::io::Result<TcpStream> {
+	let mut last_err = std::io::Error::new(std::io::ErrorKind::NotFound, "No addresses found");
+	
+	for address in address.to_socket_addrs()? {
+		match TcpStream::connect_timeout(&address, REPORT_TIMEOUT) {
+			Ok(stream) => return Ok(stream),
+			Err(err) => last_err = err,
+		}
+	}
+	
+	Err(last_err)
+}
+
+#[derive(Debug, PartialEq)]
+struct ReportUrl {
+	/// Without brackets if it's an IPv6 address, which is how it's connected to and checked against the certificate.
+	host: String,
+	port: u16,
+	/// The host and port as they appear in the URL, for the Host header.
+	authority: String,
+	/// Along with the query, if there is one.
+	path: String,
+}
+
+impl ReportUrl {
+	fn parse(url: &str) -> anyhow::Result<Self> {
+		let url = Url::parse(url).context("Invalid report URL")?;
+		
+		if url.scheme() != "https" {
+			return Err(anyhow!("Report URL must start with https://"));
+		}
+		
+		let host = match url.host() {
+			Some(Host::Domain(domain)) => domain.to_owned(),
+			Some(Host::Ipv4(ip)) => ip.to_string(),
+			Some(Host::Ipv6(ip)) => ip.to_string(),
+			None => return Err(anyhow!("Report URL has no host")),
+		};
+		
+		Ok(Self {
+			host,
+			port: url.port_or_known_default().unwrap_or(443),
+			authority: url[Position::BeforeHost..Position::AfterPort].to_owned(),
+			path: url[Position::BeforePath..Position::AfterQuery].to_owned(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn report_urls_are_parsed() {
+		assert_eq!(ReportUrl::parse("https://stats.example.com/factorio/report?v=1").unwrap(), ReportUrl {
+			host: "stats.example.com".to_owned(),
+			port: 443,
+			authority: "stats.example.com".to_owned(),
+			path: "/factorio/report?v=1".to_owned(),
+		});
+		
+		assert_eq!(ReportUrl::parse("https://stats.example.com:8443").unwrap(), ReportUrl {
+			host: "stats.example.com".to_owned(),
+			port: 8443,
+			authority: "stats.example.com:8443".to_owned(),
+			path: "/".to_owned(),
+		});
+		
+		assert_eq!(ReportUrl::parse("https://[2001:db8::1]:8443/report").unwrap(), ReportUrl {
+			host: "2001:db8::1".to_owned(),
+			port: 8443,
+			authority: "[2001:db8::1]:8443".to_owned(),
+			path: "/report".to_owned(),
+		});
+		
+		assert!(ReportUrl::parse("http://stats.example.com/").is_err());
+		assert!(ReportUrl::parse("https://").is_err());
+		assert!(ReportUrl::parse("https://stats.example.com:port/").is_err());
+	}
+	
+	#[test]
+	fn reports_only_contain_rounded_world_sizes() {
+		let report = TransferReport::new(40_000_000, 10_000_000);
+		
+		assert_eq!(report, TransferReport {
+			world_size_bucket: 1 << 25,
+			bytes_saved: 457 * SIZE_GRANULARITY,
+			percent_downloaded: 25,
+		});
+		
+		assert_eq!(report.to_json(), "{\"world_size_bucket\": 33554432, \"bytes_saved\": 29949952, \"percent_downloaded\": 25}");
+	}
+}
@@ -1,5 +1,11 @@
+use crate::factorio_protocol::{FACTORIO_CRC, FACTORIO_REV_CRC};
+use anyhow::anyhow;
 use crc::{Algorithm, Crc, Table};
 
+/// The standard check input, and its CRC under the algorithm factorio uses.
+const CHECK_DATA: &[u8] = b"123456789";
+const CHECK_CRC: u32 = 0xCBF43926;
+
 pub struct RevCRC {
 	algorithm: &'static Algorithm<u32>,
 	reverse_table: [u32; 256],
@@ -31,7 +37,7 @@ impl RevCRC {
 		value
 	}
 	
-	pub fn digest(&self, initial_value: u32) -> RevDigest {
+	pub fn digest(&self, initial_value: u32) -> RevDigest<'_> {
 		RevDigest {
 			crc: self,
 			value: initial_value ^ self.algorithm.xorout,
@@ -64,3 +70,52 @@ pub fn forge_crc(mut before_digest: u32, mut after_digest: RevDigest) -> [u8; 4]
 	after_digest.update(&before_digest.to_le_bytes());
 	after_digest.finalize().to_le_bytes()
 }
+
+/// Checks that factorio's CRC and forging it work in this build, since otherwise every reconstructed world fails CRC
+///  verification only after it has been downloaded.
+pub fn self_test() -> anyhow::Result<()> {
+	check_crc(&FACTORIO_CRC, &FACTORIO_REV_CRC)
+}
+
+fn check_crc(crc: &Crc<u32>, rev_crc: &RevCRC) -> anyhow::Result<()> {
+	let check_crc = crc.checksum(CHECK_DATA);
+	
+	if check_crc != CHECK_CRC {
+		return Err(anyhow!("CRC of the check data is {:#010x}, expected {:#010x}", check_crc, CHECK_CRC));
+	}
+	
+	let target_crc = 0x12345678;
+	
+	let mut rev_digest = rev_crc.digest(target_crc);
+	rev_digest.update(CHECK_DATA);
+	
+	let forge_bytes = forge_crc(crc.checksum(CHECK_DATA), rev_digest);
+	
+	let mut digest = crc.digest();
+	digest.update(CHECK_DATA);
+	digest.update(&forge_bytes);
+	digest.update(CHECK_DATA);
+	
+	let forged_crc = digest.finalize();
+	
+	if forged_crc != target_crc {
+		return Err(anyhow!("Forged CRC is {:#010x}, expected {:#010x}", forged_crc, target_crc));
+	}
+	
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	
+	#[test]
+	fn self_test_catches_broken_crcs() {
+		self_test().unwrap();
+		
+		// Forging with the reverse of a different algorithm lands on the wrong CRC
+		const OTHER_CRC: Crc<u32> = Crc::<u32>::new(&crc::CRC_32_BZIP2);
+		assert!(check_crc(&FACTORIO_CRC, &RevCRC::new(&OTHER_CRC)).is_err());
+		assert!(check_crc(&OTHER_CRC, &RevCRC::new(&OTHER_CRC)).is_err());
+	}
+}
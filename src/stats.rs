@@ -0,0 +1,274 @@
+use log::warn;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const STATS_FILE_HEADER: &str = "timestamp,client_address,original_world_size,total_transferred,dedup_ratio,duration_ms,instance_name";
+
+/// Summary of a single completed world transfer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransferStats {
+	pub timestamp: SystemTime,
+	pub client_address: SocketAddr,
+	pub original_world_size: u64,
+	pub total_transferred: u64,
+	pub duration: Duration,
+	/// The --instance-name of the server that made the transfer, if it had one.
+	pub instance_name: Option<String>,
+}
+
+impl TransferStats {
+	pub fn dedup_ratio(&self) -> f64 {
+		self.total_transferred as f64 / self.original_world_size as f64
+	}
+	
+	fn to_csv_record(&self) -> String {
+		format!("{},{},{},{},{:.4},{},{}",
+			self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+			self.client_address,
+			self.original_world_size,
+			self.total_transferred,
+			self.dedup_ratio(),
+			self.duration.as_millis(),
+			self.instance_name.as_deref().unwrap_or(""),
+		)
+	}
+	
+	/// Parses a record written by to_csv_record, or by older versions without the instance name. The dedup ratio isn't
+	///  read, since it's derived from the sizes.
+	fn from_csv_record(record: &str) -> Option<Self> {
+		let fields = record.trim().split(',').collect::<Vec<_>>();
+		
+		let (timestamp, client_address, original_world_size, total_transferred, duration_ms, instance_name) = match fields[..] {
+			[timestamp, client_address, original_world_size, total_transferred, _dedup_ratio, duration_ms] =>
+				(timestamp, client_address, original_world_size, total_transferred, duration_ms, ""),
+			[timestamp, client_address, original_world_size, total_transferred, _dedup_ratio, duration_ms, instance_name] =>
+				(timestamp, client_address, original_world_size, total_transferred, duration_ms, instance_name),
+			_ => return None,
+		};
+		
+		Some(Self {
+			timestamp: UNIX_EPOCH + Duration::from_secs(timestamp.parse().ok()?),
+			client_address: client_address.parse().ok()?,
+			original_world_size: original_world_size.parse().ok()?,
+			total_transferred: total_transferred.parse().ok()?,
+			duration: Duration::from_millis(duration_ms.parse().ok()?),
+			instance_name: (!instance_name.is_empty()).then(|| instance_name.to_owned()),
+		})
+	}
+}
+
+/// Reads every record from a CSV stats file, returning them along with how many lines couldn't be parsed.
+pub fn read_transfer_stats(stats_path: &Path) -> anyhow::Result<(Vec<TransferStats>, usize)> {
+	let contents = std::fs::read_to_string(stats_path)?;
+	
+	let mut records = Vec::new();
+	let mut bad_lines = 0;
+	
+	// Headers can show up partway through when files were concatenated, and older files have a shorter one
+	for line in contents.lines().filter(|line| !line.trim().is_empty() && !line.starts_with("timestamp,")) {
+		match TransferStats::from_csv_record(line) {
+			Some(stats) => records.push(stats),
+			None => bad_lines += 1,
+		}
+	}
+	
+	Ok((records, bad_lines))
+}
+
+/// Totals over the transfers in one or more stats files.
+#[derive(Debug, PartialEq)]
+pub struct StatsSummary {
+	pub transfer_count: usize,
+	/// Records that showed up more than once, like when files cover overlapping time ranges, which are only counted
+	///  once.
+	pub duplicate_count: usize,
+	pub first_timestamp: SystemTime,
+	pub last_timestamp: SystemTime,
+	pub total_original_size: u64,
+	pub total_transferred: u64,
+	/// Transfer durations at the 50th, 90th and 99th percentiles and the longest one.
+	pub duration_percentiles: [Duration; 4],
+}
+
+impl StatsSummary {
+	pub fn new(records: &[TransferStats]) -> Option<Self> {
+		let mut seen = HashSet::new();
+		
+		let unique_records = records.iter()
+			.filter(|stats| seen.insert(stats.to_csv_record()))
+			.collect::<Vec<_>>();
+		
+		if unique_records.is_empty() {
+			return None;
+		}
+		
+		let mut durations = unique_records.iter().map(|stats| stats.duration).collect::<Vec<_>>();
+		durations.sort_unstable();
+		
+		let percentile = |percent: usize| durations[(durations.len() - 1) * percent / 100];
+		
+		Some(Self {
+			transfer_count: unique_records.len(),
+			duplicate_count: records.len() - unique_records.len(),
+			first_timestamp: unique_records.iter().map(|stats| stats.timestamp).min()?,
+			last_timestamp: unique_records.iter().map(|stats| stats.timestamp).max()?,
+			total_original_size: unique_records.iter().map(|stats| stats.original_world_size).sum(),
+			total_transferred: unique_records.iter().map(|stats| stats.total_transferred).sum(),
+			duration_percentiles: [percentile(50), percentile(90), percentile(99), percentile(100)],
+		})
+	}
+	
+	/// The fraction of the original world data that was transferred, over all transfers.
+	pub fn dedup_ratio(&self) -> f64 {
+		self.total_transferred as f64 / self.total_original_size.max(1) as f64
+	}
+	
+	pub fn bytes_saved(&self) -> u64 {
+		self.total_original_size.saturating_sub(self.total_transferred)
+	}
+}
+
+/// Appends a record to a CSV stats file, writing the header first if the file is new. A file with a different header is
+///  moved aside first, so that records never end up under a header with other columns.
+pub fn append_transfer_stats(stats_path: &Path, stats: &TransferStats) -> anyhow::Result<()> {
+	rotate_outdated_stats_file(stats_path)?;
+	
+	let mut file = std::fs::OpenOptions::new()
+		.create(true)
+		.append(true)
+		.open(stats_path)?;
+	
+	let mut record = String::new();
+	
+	if file.metadata()?.len() == 0 {
+		record.push_str(STATS_FILE_HEADER);
+		record.push('\n');
+	}
+	
+	record.push_str(&stats.to_csv_record());
+	record.push('\n');
+	
+	// Written in one go so that concurrent transfers don't interleave their records
+	file.write_all(record.as_bytes())?;
+	
+	Ok(())
+}
+
+fn rotate_outdated_stats_file(stats_path: &Path) -> anyhow::Result<()> {
+	let mut header = String::new();
+	
+	match std::fs::File::open(stats_path) {
+		Ok(file) => BufReader::new(file).read_line(&mut header)?,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err.into()),
+	};
+	
+	if header.is_empty() || header.trim_end() == STATS_FILE_HEADER {
+		return Ok(());
+	}
+	
+	let rotated_path = rotated_stats_path(stats_path, SystemTime::now());
+	warn!("{} has a header from another version, moving it to {}", stats_path.display(), rotated_path.display());
+	
+	// Another transfer may have just moved it
+	match std::fs::rename(stats_path, &rotated_path) {
+		Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+		_ => Ok(()),
+	}
+}
+
+/// Where an outdated stats file is moved to, which read_transfer_stats can still read.
+fn rotated_stats_path(stats_path: &Path, now: SystemTime) -> PathBuf {
+	let mut file_name = stats_path.file_name().map(OsString::from).unwrap_or_default();
+	file_name.push(format!(".{}.old", now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()));
+	
+	stats_path.with_file_name(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::temp_file::TestDir;
+	
+	fn transfer(timestamp: u64, original_world_size: u64, total_transferred: u64, duration_ms: u64) -> TransferStats {
+		TransferStats {
+			timestamp: UNIX_EPOCH + Duration::from_secs(timestamp),
+			client_address: "[::1]:34197".parse().unwrap(),
+			original_world_size,
+			total_transferred,
+			duration: Duration::from_millis(duration_ms),
+			instance_name: None,
+		}
+	}
+	
+	#[test]
+	fn records_round_trip_through_csv() {
+		let stats = transfer(1_700_000_000, 1000, 250, 1234);
+		
+		assert_eq!(TransferStats::from_csv_record(&stats.to_csv_record()), Some(stats.clone()));
+		assert_eq!(TransferStats::from_csv_record(STATS_FILE_HEADER), None);
+		assert_eq!(TransferStats::from_csv_record("1,[::1]:1,2,3,0.5"), None);
+		
+		let named_stats = TransferStats {
+			instance_name: Some("eu-1".to_owned()),
+			..stats.clone()
+		};
+		
+		assert_eq!(TransferStats::from_csv_record(&named_stats.to_csv_record()), Some(named_stats));
+		
+		// Records from before instance names were added have one less field
+		assert_eq!(TransferStats::from_csv_record("1700000000,[::1]:34197,1000,250,0.2500,1234"), Some(stats));
+	}
+	
+	#[test]
+	fn overlapping_records_are_summarized_once() {
+		let early = [transfer(100, 1000, 100, 10), transfer(200, 1000, 300, 20)];
+		let late = [transfer(200, 1000, 300, 20), transfer(300, 2000, 600, 30)];
+		
+		let records = early.iter().chain(&late).cloned().collect::<Vec<_>>();
+		let summary = StatsSummary::new(&records).unwrap();
+		
+		assert_eq!(summary, StatsSummary {
+			transfer_count: 3,
+			duplicate_count: 1,
+			first_timestamp: UNIX_EPOCH + Duration::from_secs(100),
+			last_timestamp: UNIX_EPOCH + Duration::from_secs(300),
+			total_original_size: 4000,
+			total_transferred: 1000,
+			duration_percentiles: [20, 20, 20, 30].map(Duration::from_millis),
+		});
+		
+		assert_eq!(summary.dedup_ratio(), 0.25);
+		assert_eq!(summary.bytes_saved(), 3000);
+		assert_eq!(StatsSummary::new(&[]), None);
+	}
+	
+	#[test]
+	fn files_with_an_old_header_are_moved_aside() {
+		let temp_dir = TestDir::new("stats");
+		
+		let stats_path = temp_dir.join("stats.csv");
+		let old_file = "timestamp,client_address,original_world_size,total_transferred,dedup_ratio,duration_ms\n\
+			1700000000,[::1]:34197,1000,250,0.2500,1234\n";
+		std::fs::write(&stats_path, old_file).unwrap();
+		
+		let stats = transfer(1_700_000_100, 1000, 250, 1234);
+		append_transfer_stats(&stats_path, &stats).unwrap();
+		append_transfer_stats(&stats_path, &stats).unwrap();
+		
+		let new_file = std::fs::read_to_string(&stats_path).unwrap();
+		assert_eq!(new_file.lines().collect::<Vec<_>>(), [STATS_FILE_HEADER, &stats.to_csv_record(), &stats.to_csv_record()]);
+		
+		let rotated_files = std::fs::read_dir(&*temp_dir).unwrap()
+			.map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+			.filter(|contents| *contents != new_file)
+			.collect::<Vec<_>>();
+		assert_eq!(rotated_files, [old_file]);
+		
+		assert_eq!(rotated_stats_path(&stats_path, UNIX_EPOCH + Duration::from_secs(5)), temp_dir.join("stats.csv.5.old"));
+	}
+}
@@ -0,0 +1,137 @@
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+#[cfg(test)]
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tells apart the temp files made by this process.
+static NEXT_TEMP_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A file in the temp dir that's deleted when it's dropped, unless it's persisted first. Everything that needs
+///  scratch space on disk goes through this, so that --temp-dir applies to all of it.
+pub struct TempFile {
+	path: PathBuf,
+	/// Only None once it's being persisted or dropped, since Windows can't rename or remove a file that's still open.
+	file: Option<File>,
+}
+
+impl TempFile {
+	pub fn new_in(temp_dir: &Path, name: &str) -> std::io::Result<Self> {
+		let id = NEXT_TEMP_FILE_ID.fetch_add(1, Ordering::Relaxed);
+		let path = temp_dir.join(format!("factorio-cacher-{}-{}-{}.tmp", name, std::process::id(), id));
+		
+		let file = OpenOptions::new()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&path)?;
+		
+		Ok(Self { path, file: Some(file) })
+	}
+	
+	pub fn file(&mut self) -> &mut File {
+		self.file.as_mut().unwrap()
+	}
+	
+	/// Moves the file to the given path, replacing whatever was there. When the temp dir is on another filesystem it's
+	///  copied instead, so the file at the path can briefly be incomplete.
+	pub fn persist(mut self, path: &Path) -> std::io::Result<()> {
+		if let Some(file) = self.file.take() {
+			file.sync_all()?;
+		}
+		
+		match std::fs::rename(&self.path, path) {
+			Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+				std::fs::copy(&self.path, path)?;
+			}
+			result => result?,
+		}
+		
+		Ok(())
+	}
+}
+
+impl Drop for TempFile {
+	fn drop(&mut self) {
+		drop(self.file.take());
+		
+		// Already gone if it was persisted by renaming it
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Checks that the temp dir can be used, creating it if needed, so that a bad --temp-dir shows up at startup instead
+///  of the first time a temp file is needed.
+pub fn check_temp_dir(temp_dir: &Path) -> std::io::Result<()> {
+	std::fs::create_dir_all(temp_dir)?;
+	
+	TempFile::new_in(temp_dir, "check").map(drop)
+}
+
+/// A fresh directory for a test's files, which is removed along with them when dropped, even if the test panics.
+#[cfg(test)]
+pub struct TestDir {
+	path: PathBuf,
+}
+
+#[cfg(test)]
+impl TestDir {
+	pub fn new(name: &str) -> Self {
+		let id = NEXT_TEMP_FILE_ID.fetch_add(1, Ordering::Relaxed);
+		let path = std::env::temp_dir().join(format!("factorio-cacher-{}-test-{}-{}", name, std::process::id(), id));
+		
+		std::fs::create_dir_all(&path).unwrap();
+		
+		Self { path }
+	}
+}
+
+#[cfg(test)]
+impl Deref for TestDir {
+	type Target = Path;
+	
+	fn deref(&self) -> &Path {
+		&self.path
+	}
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.path);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+	
+	#[test]
+	fn temp_files_are_removed_unless_persisted() {
+		let temp_dir = TestDir::new("temp-file");
+		check_temp_dir(&temp_dir).unwrap();
+		
+		let file_count = || std::fs::read_dir(&*temp_dir).unwrap().count();
+		
+		let mut dropped = TempFile::new_in(&temp_dir, "dropped").unwrap();
+		dropped.file().write_all(b"scratch").unwrap();
+		
+		assert_eq!(file_count(), 1);
+		drop(dropped);
+		assert_eq!(file_count(), 0);
+		
+		let persisted_path = temp_dir.join("persisted");
+		std::fs::write(&persisted_path, b"old").unwrap();
+		
+		let mut persisted = TempFile::new_in(&temp_dir, "persisted").unwrap();
+		persisted.file().write_all(b"new").unwrap();
+		persisted.persist(&persisted_path).unwrap();
+		
+		assert_eq!(std::fs::read(&persisted_path).unwrap(), b"new");
+		
+		// Only the persisted file is left
+		assert_eq!(file_count(), 1);
+	}
+}
@@ -0,0 +1,81 @@
+use crate::factorio_protocol::FactorioWorldMetadata;
+use crate::protocol;
+use crate::protocol::WorldReadyMessage;
+use bytes::Bytes;
+use log::warn;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Keeps the world descriptions received from cacher servers on disk, keyed by the factorio server and the original
+///  world's metadata, so a world that's been seen before can be reconstructed without a cacher server.
+pub struct WorldStore {
+	dir: PathBuf,
+	/// The factorio server that the cacher servers forward to, which is the one worlds are served for when falling back.
+	server_addr: SocketAddr,
+}
+
+impl WorldStore {
+	const MAX_STORED_WORLDS: usize = 16;
+	
+	pub fn new(dir: PathBuf, server_addr: SocketAddr) -> Self {
+		Self {
+			dir,
+			server_addr,
+		}
+	}
+	
+	fn world_path(&self, world_info: &FactorioWorldMetadata) -> PathBuf {
+		let server = self.server_addr.to_string().replace([':', '[', ']'], "_");
+		
+		self.dir.join(format!("{}-{:08x}-{}", server, world_info.world_crc, world_info.world_size))
+	}
+	
+	/// Stores an encoded WorldReadyMessage, evicting the oldest stored worlds if there are too many.
+	pub async fn save(&self, world_info: &FactorioWorldMetadata, message_data: Bytes) -> anyhow::Result<()> {
+		let path = self.world_path(world_info);
+		let dir = self.dir.clone();
+		
+		tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+			std::fs::create_dir_all(&dir)?;
+			
+			let temp_path = path.with_extension("tmp");
+			std::fs::write(&temp_path, &message_data)?;
+			std::fs::rename(&temp_path, &path)?;
+			
+			let mut stored_worlds = std::fs::read_dir(&dir)?
+				.filter_map(|entry| {
+					let entry = entry.ok()?;
+					Some((entry.metadata().ok()?.modified().ok()?, entry.path()))
+				})
+				.collect::<Vec<_>>();
+			
+			if stored_worlds.len() > Self::MAX_STORED_WORLDS {
+				stored_worlds.sort();
+				
+				for (_, old_path) in &stored_worlds[..stored_worlds.len() - Self::MAX_STORED_WORLDS] {
+					if let Err(err) = std::fs::remove_file(old_path) {
+						warn!("Failed to remove old world description {}: {}", old_path.display(), err);
+					}
+				}
+			}
+			
+			Ok(())
+		}).await?
+	}
+	
+	pub async fn load(&self, world_info: &FactorioWorldMetadata) -> anyhow::Result<Option<WorldReadyMessage>> {
+		let message_data = match tokio::fs::read(self.world_path(world_info)).await {
+			Ok(data) => data,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+			Err(err) => return Err(err.into()),
+		};
+		
+		let world_ready: WorldReadyMessage = protocol::decode_message_async(message_data.into()).await?;
+		
+		if world_ready.old_info != *world_info {
+			return Ok(None);
+		}
+		
+		Ok(Some(world_ready))
+	}
+}